@@ -0,0 +1,12 @@
+#include <stdio.h>
+
+int add(int a, int b) {
+    int sum = a + b;
+    return sum;
+}
+
+int main() {
+    int result = add(2, 3);
+    printf("2 + 3 = %d\n", result);
+    return 0;
+}
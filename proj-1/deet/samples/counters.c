@@ -0,0 +1,27 @@
+#include <pthread.h>
+
+#define WORKERS 2
+#define COUNT 1000
+
+unsigned long counters[WORKERS];
+
+void *count(void *arg) {
+    int id = *(int *)arg;
+    for (int i = 0; i < COUNT; i++) {
+        counters[id]++;
+    }
+    return NULL;
+}
+
+int main() {
+    pthread_t threads[WORKERS];
+    int ids[WORKERS];
+    for (int i = 0; i < WORKERS; i++) {
+        ids[i] = i;
+        pthread_create(&threads[i], NULL, count, &ids[i]);
+    }
+    for (int i = 0; i < WORKERS; i++) {
+        pthread_join(threads[i], NULL);
+    }
+    return 0;
+}
@@ -0,0 +1,15 @@
+#include <stdio.h>
+#include <unistd.h>
+
+int main(int argc, char *argv[]) {
+    if (argc == 1) {
+        printf("Running myself again\n");
+        execl(argv[0], argv[0], "again", NULL);
+    }
+    if (argc > 2) {
+        printf("Running %s instead\n", argv[2]);
+        execv(argv[2], argv + 2);
+    }
+    printf("Ran again\n");
+    return 0;
+}
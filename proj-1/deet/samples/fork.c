@@ -0,0 +1,28 @@
+#include <stdio.h>
+#include <string.h>
+#include <sys/wait.h>
+#include <unistd.h>
+
+void in_parent(int child) {
+    printf("Parent of %d\n", child);
+}
+
+void in_child(void) {
+    printf("Child\n");
+}
+
+int main(int argc, char *argv[]) {
+    pid_t pid = fork();
+    if (pid == 0) {
+        in_child();
+        return 3;
+    }
+    in_parent(pid);
+    // With "wait", pass on how the child exited
+    if (argc > 1 && strcmp(argv[1], "wait") == 0) {
+        int status;
+        waitpid(pid, &status, 0);
+        return WIFEXITED(status) ? WEXITSTATUS(status) : 100;
+    }
+    return 0;
+}
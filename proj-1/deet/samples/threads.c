@@ -0,0 +1,33 @@
+#include <pthread.h>
+#include <stdio.h>
+
+#define WORKERS 3
+
+int started;
+unsigned long counters[WORKERS];
+
+void *spin(void *arg) {
+    int id = *(int *)arg;
+    __atomic_add_fetch(&started, 1, __ATOMIC_SEQ_CST);
+    while (1) {
+        counters[id]++;
+    }
+    return NULL;
+}
+
+void all_started(void) {
+    printf("All workers started\n");
+}
+
+int main() {
+    pthread_t threads[WORKERS];
+    int ids[WORKERS];
+    for (int i = 0; i < WORKERS; i++) {
+        ids[i] = i;
+        pthread_create(&threads[i], NULL, spin, &ids[i]);
+    }
+    while (__atomic_load_n(&started, __ATOMIC_SEQ_CST) < WORKERS) {
+    }
+    all_started();
+    return 0;
+}
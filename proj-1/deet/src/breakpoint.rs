@@ -0,0 +1,502 @@
+use crate::dwarf_data::{DwarfData, Type};
+use crate::expr::{self, Evaluator, Expr};
+use crate::target::Target;
+use std::fmt;
+use std::ops::Range;
+
+/// A breakpoint condition: an expression (see the expr module) that is evaluated in the context
+/// of the breakpoint each time it is hit. The breakpoint only stops when the result is non-zero.
+pub struct Condition {
+    text: String,
+    expr: Expr,
+}
+
+impl Condition {
+    pub fn parse(text: &str) -> Result<Condition, String> {
+        Ok(Condition {
+            text: text.to_string(),
+            expr: expr::parse(text)?,
+        })
+    }
+
+    pub fn evaluate(&self, inferior: &dyn Target, debug_data: &DwarfData) -> Result<bool, String> {
+        let evaluator = Evaluator::new(debug_data, Some(inferior));
+        evaluator.is_true(&evaluator.evaluate(&self.expr)?)
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+pub struct Breakpoint {
+    pub number: usize,
+    pub addr: usize,
+    /// Where the breakpoint was asked for, e.g. "func2" or "foo.c:10", which is looked up again
+    /// if the program is replaced by another
+    pub spec: String,
+    /// Human-readable description of where the breakpoint is, e.g. "func2 at samples/foo.c:10"
+    pub location: String,
+    pub enabled: bool,
+    pub hit_count: usize,
+    /// Number of upcoming hits to skip without stopping
+    pub ignore_count: usize,
+    pub condition: Option<Condition>,
+    /// Command lines to run each time the breakpoint stops the program, set with `commands`
+    pub commands: Vec<String>,
+}
+
+/// What kind of access a watchpoint stops on: `watch`, `rwatch` or `awatch`.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum WatchKind {
+    Write,
+    Read,
+    Access,
+}
+
+/// A watchpoint on the memory an lvalue expression occupies. Hardware watchpoints are armed in
+/// the CPU's debug registers; software ones are checked after every single-stepped instruction.
+pub struct Watchpoint {
+    pub number: usize,
+    pub kind: WatchKind,
+    pub hardware: bool,
+    /// The expression as the user typed it
+    pub expression: String,
+    pub addr: usize,
+    pub ty: Type,
+    /// The contents of the watched memory as of the last check
+    pub value: Vec<u8>,
+    pub enabled: bool,
+    pub hit_count: usize,
+}
+
+impl Watchpoint {
+    pub fn len(&self) -> usize {
+        self.value.len()
+    }
+
+    /// How gdb introduces the watchpoint, e.g. "Hardware read watchpoint 2: x".
+    pub fn describe(&self) -> String {
+        let kind = match self.kind {
+            WatchKind::Write => "watchpoint",
+            WatchKind::Read => "read watchpoint",
+            WatchKind::Access => "access (read/write) watchpoint",
+        };
+        let hardware = if self.hardware { "Hardware " } else { "" };
+        let mut description = format!("{}{} {}: {}", hardware, kind, self.number, self.expression);
+        description[..1].make_ascii_uppercase();
+        description
+    }
+}
+
+/// A watchpoint that triggered on the last resume. `old` is None when the value didn't change.
+pub struct WatchHit {
+    pub number: usize,
+    pub old: Option<Vec<u8>>,
+    pub new: Vec<u8>,
+}
+
+/// All breakpoints the user has created. The table is owned by the Debugger, so it survives
+/// across `run` invocations; the Inferior only knows about the addresses it has patched.
+pub struct BreakpointTable {
+    breakpoints: Vec<Breakpoint>,
+    /// Watchpoints share the breakpoints' numbering, as in gdb
+    watchpoints: Vec<Watchpoint>,
+    /// Watchpoints that triggered since the last call to take_watch_hits
+    watch_hits: Vec<WatchHit>,
+    /// Conditions that couldn't be evaluated since the last call to take_condition_errors
+    condition_errors: Vec<String>,
+    next_number: usize,
+}
+
+impl BreakpointTable {
+    pub fn new() -> BreakpointTable {
+        BreakpointTable {
+            breakpoints: Vec::new(),
+            watchpoints: Vec::new(),
+            watch_hits: Vec::new(),
+            condition_errors: Vec::new(),
+            next_number: 1,
+        }
+    }
+
+    /// Adds a new enabled breakpoint and returns it.
+    pub fn add(&mut self, addr: usize, spec: String, location: String) -> &Breakpoint {
+        self.breakpoints.push(Breakpoint {
+            number: self.next_number,
+            addr,
+            spec,
+            location,
+            enabled: true,
+            hit_count: 0,
+            ignore_count: 0,
+            condition: None,
+            commands: Vec::new(),
+        });
+        self.next_number += 1;
+        self.breakpoints.last().unwrap()
+    }
+
+    pub fn get_mut(&mut self, number: usize) -> Option<&mut Breakpoint> {
+        self.breakpoints.iter_mut().find(|bp| bp.number == number)
+    }
+
+    pub fn remove(&mut self, number: usize) -> Option<Breakpoint> {
+        let index = self.breakpoints.iter().position(|bp| bp.number == number)?;
+        Some(self.breakpoints.remove(index))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Breakpoint> {
+        self.breakpoints.iter()
+    }
+
+    /// Numbers of all breakpoints and watchpoints, in order.
+    pub fn numbers(&self) -> Vec<usize> {
+        let mut numbers: Vec<usize> = self
+            .breakpoints
+            .iter()
+            .map(|bp| bp.number)
+            .chain(self.watchpoints.iter().map(|wp| wp.number))
+            .collect();
+        numbers.sort_unstable();
+        numbers
+    }
+
+    /// Adds a new enabled watchpoint and returns it. `value` is the current contents of the
+    /// watched memory, whose length determines how much memory is watched.
+    pub fn add_watchpoint(
+        &mut self,
+        kind: WatchKind,
+        hardware: bool,
+        expression: String,
+        addr: usize,
+        ty: Type,
+        value: Vec<u8>,
+    ) -> &Watchpoint {
+        self.watchpoints.push(Watchpoint {
+            number: self.next_number,
+            kind,
+            hardware,
+            expression,
+            addr,
+            ty,
+            value,
+            enabled: true,
+            hit_count: 0,
+        });
+        self.next_number += 1;
+        self.watchpoints.last().unwrap()
+    }
+
+    pub fn watchpoint_mut(&mut self, number: usize) -> Option<&mut Watchpoint> {
+        self.watchpoints.iter_mut().find(|wp| wp.number == number)
+    }
+
+    pub fn remove_watchpoint(&mut self, number: usize) -> Option<Watchpoint> {
+        let index = self.watchpoints.iter().position(|wp| wp.number == number)?;
+        Some(self.watchpoints.remove(index))
+    }
+
+    pub fn watchpoints(&self) -> impl Iterator<Item = &Watchpoint> {
+        self.watchpoints.iter()
+    }
+
+    /// Returns true if some enabled hardware watchpoint needs a debug register watching `len`
+    /// bytes at `addr` for `kind` accesses.
+    pub fn is_watching(&self, addr: usize, len: usize, kind: WatchKind) -> bool {
+        self.watchpoints.iter().any(|wp| {
+            wp.enabled && wp.hardware && wp.addr == addr && wp.len() == len && wp.kind == kind
+        })
+    }
+
+    pub fn has_software_watchpoints(&self) -> bool {
+        self.watchpoints.iter().any(|wp| wp.enabled && !wp.hardware)
+    }
+
+    /// Called when the debug registers watching the given addresses fire. Compares the watched
+    /// values against what we saw last, records hits, and returns whether the inferior should
+    /// stay stopped. A `watch` only stops if the value actually changed.
+    pub fn hardware_watchpoints_triggered(
+        &mut self,
+        addrs: &[usize],
+        inferior: &dyn Target,
+    ) -> bool {
+        let mut stop = false;
+        for wp in self.watchpoints.iter_mut() {
+            if !wp.enabled || !wp.hardware || !addrs.contains(&wp.addr) {
+                continue;
+            }
+            if let Some(hit) = check_watchpoint(wp, inferior, true) {
+                self.watch_hits.push(hit);
+                stop = true;
+            }
+        }
+        stop
+    }
+
+    /// Called after each single-stepped instruction while software watchpoints exist. Returns
+    /// whether any watched value changed.
+    pub fn software_watchpoints_changed(&mut self, inferior: &dyn Target) -> bool {
+        let mut stop = false;
+        for wp in self.watchpoints.iter_mut() {
+            if !wp.enabled || wp.hardware {
+                continue;
+            }
+            if let Some(hit) = check_watchpoint(wp, inferior, false) {
+                self.watch_hits.push(hit);
+                stop = true;
+            }
+        }
+        stop
+    }
+
+    /// Hands over the watchpoints that triggered since the last call, for reporting.
+    pub fn take_watch_hits(&mut self) -> Vec<WatchHit> {
+        std::mem::take(&mut self.watch_hits)
+    }
+
+    /// Hands over the errors from conditions that stopped the inferior because they couldn't be
+    /// evaluated, for reporting.
+    pub fn take_condition_errors(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.condition_errors)
+    }
+
+    /// Returns true if some enabled breakpoint wants the INT3 at `addr` in place.
+    pub fn is_enabled_at(&self, addr: usize) -> bool {
+        self.breakpoints
+            .iter()
+            .any(|bp| bp.enabled && bp.addr == addr)
+    }
+
+    /// Addresses that should be patched into a freshly started inferior.
+    pub fn enabled_addrs(&self) -> Vec<usize> {
+        let mut addrs: Vec<usize> = self
+            .breakpoints
+            .iter()
+            .filter(|bp| bp.enabled)
+            .map(|bp| bp.addr)
+            .collect();
+        addrs.sort_unstable();
+        addrs.dedup();
+        addrs
+    }
+
+    /// Moves the breakpoints and watchpoints in `range` by `delta` bytes (wrapping), after the
+    /// code or data they were set on turned out to be loaded somewhere else.
+    pub fn relocate(&mut self, range: Range<usize>, delta: usize) {
+        let addrs = self
+            .breakpoints
+            .iter_mut()
+            .map(|bp| &mut bp.addr)
+            .chain(self.watchpoints.iter_mut().map(|wp| &mut wp.addr));
+        for addr in addrs.filter(|addr| range.contains(addr)) {
+            *addr = addr.wrapping_add(delta);
+        }
+    }
+
+    /// Moves each breakpoint to wherever `resolve` finds its spec now, with the description it
+    /// gives, and deletes those it can't find. Returns the numbers of the deleted breakpoints.
+    pub fn resolve_again<F>(&mut self, resolve: F) -> Vec<usize>
+    where
+        F: Fn(&str) -> Option<(usize, String)>,
+    {
+        let mut deleted = Vec::new();
+        for bp in self.breakpoints.iter_mut() {
+            match resolve(&bp.spec) {
+                Some((addr, location)) => {
+                    bp.addr = addr;
+                    bp.location = location;
+                }
+                None => deleted.push(bp.number),
+            }
+        }
+        self.breakpoints.retain(|bp| !deleted.contains(&bp.number));
+        deleted
+    }
+
+    /// Enabled breakpoints located at `addr`.
+    pub fn at(&self, addr: usize) -> impl Iterator<Item = &Breakpoint> {
+        self.breakpoints
+            .iter()
+            .filter(move |bp| bp.enabled && bp.addr == addr)
+    }
+
+    /// Called when the inferior traps on the breakpoint at `addr`. Evaluates conditions, bumps hit
+    /// counts and consumes ignore counts, and returns whether the inferior should stay stopped.
+    /// Traps at addresses no user breakpoint claims (internal breakpoints) always stop.
+    pub fn should_stop(
+        &mut self,
+        addr: usize,
+        inferior: &dyn Target,
+        debug_data: &DwarfData,
+    ) -> bool {
+        if !self.is_enabled_at(addr) {
+            return true;
+        }
+        let mut stop = false;
+        for bp in self
+            .breakpoints
+            .iter_mut()
+            .filter(|bp| bp.enabled && bp.addr == addr)
+        {
+            if let Some(condition) = &bp.condition {
+                match condition.evaluate(inferior, debug_data) {
+                    Ok(true) => {}
+                    Ok(false) => continue,
+                    Err(err) => {
+                        // Like gdb, stop so that the user can fix the condition
+                        self.condition_errors.push(format!(
+                            "Error in condition of breakpoint {}: {}",
+                            bp.number, err
+                        ));
+                    }
+                }
+            }
+            bp.hit_count += 1;
+            if bp.ignore_count > 0 {
+                bp.ignore_count -= 1;
+                continue;
+            }
+            stop = true;
+        }
+        stop
+    }
+}
+
+/// Re-reads a watchpoint's memory and decides whether it fired. `accessed` says whether the
+/// hardware saw an access, which is all a read or access watchpoint needs.
+fn check_watchpoint(
+    wp: &mut Watchpoint,
+    inferior: &dyn Target,
+    accessed: bool,
+) -> Option<WatchHit> {
+    let new = match inferior.read_memory(wp.addr, wp.len()) {
+        Ok(new) => new,
+        Err(_) => return None,
+    };
+    let changed = new != wp.value;
+    // The hardware can't tell reads from writes, so like gdb, assume a read watchpoint whose
+    // value changed saw a write
+    let fired = match wp.kind {
+        WatchKind::Write => changed,
+        WatchKind::Read => accessed && !changed,
+        WatchKind::Access => accessed,
+    };
+    let old = std::mem::replace(&mut wp.value, new.clone());
+    if !fired {
+        return None;
+    }
+    wp.hit_count += 1;
+    Some(WatchHit {
+        number: wp.number,
+        old: if changed { Some(old) } else { None },
+        new,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::dwarf_data::{Encoding, TypeKind};
+    use crate::expr::BinaryOp;
+
+    #[test]
+    fn test_parse_condition() {
+        let condition = Condition::parse("a <= -0x10").unwrap();
+        assert!(matches!(condition.expr, Expr::Binary(BinaryOp::Le, _, _)));
+        assert_eq!(condition.to_string(), "a <= -0x10");
+
+        let condition = Condition::parse("$rip").unwrap();
+        assert!(matches!(condition.expr, Expr::Register(ref name) if name == "rip"));
+    }
+
+    #[test]
+    fn test_parse_condition_invalid() {
+        assert!(Condition::parse("$notareg == 1").is_err());
+        assert!(Condition::parse("1abc > 2").is_err());
+        assert!(Condition::parse("a ==").is_err());
+    }
+
+    #[test]
+    fn test_breakpoint_table() {
+        let mut table = BreakpointTable::new();
+        table.add(0x401000, "a".to_string(), "a".to_string());
+        table.add(0x402000, "b".to_string(), "b".to_string());
+        table.add(0x401000, "c".to_string(), "c".to_string());
+        assert_eq!(table.numbers(), vec![1, 2, 3]);
+        assert_eq!(table.enabled_addrs(), vec![0x401000, 0x402000]);
+
+        table.get_mut(1).unwrap().enabled = false;
+        assert!(table.is_enabled_at(0x401000));
+        table.remove(3);
+        assert!(!table.is_enabled_at(0x401000));
+        assert_eq!(table.enabled_addrs(), vec![0x402000]);
+        assert_eq!(table.numbers(), vec![1, 2]);
+    }
+
+    #[test]
+    fn test_relocate() {
+        let mut table = BreakpointTable::new();
+        table.add(0x1139, "main".to_string(), "main".to_string());
+        table.add(0x7ffff7e50000, "puts".to_string(), "puts".to_string());
+        table.relocate(0x1000..0x2000, 0x555555554000);
+        assert_eq!(table.enabled_addrs(), vec![0x555555555139, 0x7ffff7e50000]);
+        // Moving back down wraps around
+        table.relocate(
+            0x555555555000..0x555555556000,
+            0x555555554000usize.wrapping_neg(),
+        );
+        assert_eq!(table.enabled_addrs(), vec![0x1139, 0x7ffff7e50000]);
+    }
+
+    #[test]
+    fn test_resolve_again() {
+        let mut table = BreakpointTable::new();
+        table.add(0x401000, "main".to_string(), "main at a.c:3".to_string());
+        table.add(0x401100, "gone".to_string(), "gone at a.c:9".to_string());
+        let deleted = table.resolve_again(|spec| match spec {
+            "main" => Some((0x401200, "main at b.c:5".to_string())),
+            _ => None,
+        });
+        assert_eq!(deleted, vec![2]);
+        assert_eq!(table.numbers(), vec![1]);
+        assert_eq!(table.enabled_addrs(), vec![0x401200]);
+        assert_eq!(table.iter().next().unwrap().location, "main at b.c:5");
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        let int_type = Type::new("int".to_string(), 4, TypeKind::Base(Encoding::Signed));
+        let mut table = BreakpointTable::new();
+        table.add(0x401000, "a".to_string(), "a".to_string());
+        let wp = table.add_watchpoint(
+            WatchKind::Read,
+            true,
+            "x".to_string(),
+            0x404040,
+            int_type.clone(),
+            vec![0; 4],
+        );
+        assert_eq!(wp.describe(), "Hardware read watchpoint 2: x");
+        table.add_watchpoint(
+            WatchKind::Write,
+            false,
+            "y".to_string(),
+            0x404048,
+            int_type,
+            vec![0; 4],
+        );
+        assert_eq!(table.numbers(), vec![1, 2, 3]);
+        assert!(table.is_watching(0x404040, 4, WatchKind::Read));
+        assert!(!table.is_watching(0x404040, 4, WatchKind::Write));
+        assert!(table.has_software_watchpoints());
+
+        table.watchpoint_mut(3).unwrap().enabled = false;
+        assert!(!table.has_software_watchpoints());
+        assert!(table.remove_watchpoint(2).is_some());
+        assert!(table.remove(2).is_none());
+        assert_eq!(table.numbers(), vec![1, 3]);
+    }
+}
@@ -0,0 +1,181 @@
+use crate::debugger_command::{command_word, find_command, Complete, COMMANDS};
+use rustyline::completion::{Completer, FilenameCompleter, Pair};
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::validate::Validator;
+use rustyline::{Context, Helper};
+use std::path::Path;
+
+/// Tab completion for the command line. What there is to complete besides the command table (the
+/// commands made with `define`, and names from the program) is filled in by the debugger before
+/// each prompt.
+pub struct DeetHelper {
+    filenames: FilenameCompleter,
+    pub user_commands: Vec<String>,
+    /// Functions with debug info, for `break` and `list`
+    pub functions: Vec<String>,
+    /// Source files, as named in the debug info
+    pub files: Vec<String>,
+    /// The variables in scope in the selected frame, and the globals
+    pub variables: Vec<String>,
+}
+
+fn is_identifier_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// Where the last word of `line` starts, a word being made of the characters `in_word` accepts.
+fn word_start(line: &str, in_word: impl Fn(char) -> bool) -> usize {
+    line.len()
+        - line
+            .chars()
+            .rev()
+            .take_while(|c| in_word(*c))
+            .map(char::len_utf8)
+            .sum::<usize>()
+}
+
+/// The words that start with `prefix`.
+fn matching<S: AsRef<str>>(words: impl Iterator<Item = S>, prefix: &str) -> Vec<String> {
+    words
+        .filter(|word| word.as_ref().starts_with(prefix))
+        .map(|word| word.as_ref().to_string())
+        .collect()
+}
+
+impl DeetHelper {
+    pub fn new() -> DeetHelper {
+        DeetHelper {
+            filenames: FilenameCompleter::new(),
+            user_commands: Vec::new(),
+            functions: Vec::new(),
+            files: Vec::new(),
+            variables: Vec::new(),
+        }
+    }
+
+    /// Completes the word `line` (everything before the cursor) ends with: returns where the word
+    /// starts and what it could be. None means the word is a file name, which the
+    /// FilenameCompleter takes care of.
+    fn complete_words(&self, line: &str) -> Option<(usize, Vec<String>)> {
+        let start = word_start(line, |c| !c.is_whitespace());
+        let first = line.split_whitespace().next().unwrap_or("");
+        if line[..start].trim().is_empty() {
+            return Some((start, self.command_names(&line[start..])));
+        }
+        let complete = find_command(command_word(first))
+            .map(|command| command.complete)
+            .unwrap_or(Complete::Nothing);
+        let (start, candidates): (usize, Vec<&str>) = match complete {
+            Complete::Nothing => (start, Vec::new()),
+            Complete::File => return None,
+            Complete::Command => return Some((start, self.command_names(&line[start..]))),
+            Complete::Words(words) => (start, words.to_vec()),
+            Complete::Location => {
+                let word = &line[start..];
+                // Files are offered as "file.c:" to go on with a line number, by their base name
+                // unless a path is being typed
+                let files = self.files.iter().filter_map(|file| {
+                    if word.contains('/') {
+                        Some(file.as_str())
+                    } else {
+                        Path::new(file).file_name()?.to_str()
+                    }
+                });
+                let files: Vec<String> = files.map(|file| format!("{}:", file)).collect();
+                let mut candidates = matching(self.functions.iter(), word);
+                candidates.extend(matching(files.iter(), word));
+                candidates.sort();
+                candidates.dedup();
+                return Some((start, candidates));
+            }
+            Complete::Expression => (
+                word_start(line, is_identifier_char),
+                self.variables.iter().map(String::as_str).collect(),
+            ),
+        };
+        let mut candidates = matching(candidates.into_iter(), &line[start..]);
+        candidates.sort();
+        candidates.dedup();
+        Some((start, candidates))
+    }
+
+    fn command_names(&self, prefix: &str) -> Vec<String> {
+        let names = COMMANDS
+            .iter()
+            .map(|command| command.name)
+            .chain(self.user_commands.iter().map(String::as_str));
+        let mut names = matching(names, prefix);
+        names.sort();
+        names.dedup();
+        names
+    }
+}
+
+impl Completer for DeetHelper {
+    type Candidate = Pair;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        ctx: &Context<'_>,
+    ) -> Result<(usize, Vec<Pair>), ReadlineError> {
+        match self.complete_words(&line[..pos]) {
+            Some((start, words)) => Ok((
+                start,
+                words
+                    .into_iter()
+                    .map(|word| Pair {
+                        display: word.clone(),
+                        replacement: word,
+                    })
+                    .collect(),
+            )),
+            None => self.filenames.complete(line, pos, ctx),
+        }
+    }
+}
+
+impl Hinter for DeetHelper {}
+
+impl Highlighter for DeetHelper {}
+
+impl Validator for DeetHelper {}
+
+impl Helper for DeetHelper {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_complete() {
+        let mut helper = DeetHelper::new();
+        helper.user_commands = vec!["brk".to_string()];
+        helper.functions = vec!["main".to_string(), "func1".to_string(), "func2".to_string()];
+        helper.files = vec!["/src/samples/segfault.c".to_string()];
+        helper.variables = vec!["argc".to_string(), "argv".to_string(), "count".to_string()];
+        let complete = |line: &str| helper.complete_words(line).unwrap();
+
+        assert_eq!(
+            complete("br"),
+            (0, vec!["break".to_string(), "brk".to_string()])
+        );
+        assert_eq!(complete("  disas"), (2, vec!["disassemble".to_string()]));
+        assert_eq!(complete("help wat"), (5, vec!["watch".to_string()]));
+        assert_eq!(
+            complete("b func"),
+            (2, vec!["func1".to_string(), "func2".to_string()])
+        );
+        assert_eq!(complete("break seg"), (6, vec!["segfault.c:".to_string()]));
+        assert_eq!(
+            complete("p *argv[ar"),
+            (8, vec!["argc".to_string(), "argv".to_string()])
+        );
+        assert_eq!(complete("info thr"), (5, vec!["threads".to_string()]));
+        assert_eq!(complete("next "), (5, Vec::<String>::new()));
+        assert!(helper.complete_words("source ~/.dee").is_none());
+    }
+}
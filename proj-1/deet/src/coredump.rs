@@ -0,0 +1,526 @@
+//! Post-mortem debugging: the memory and registers of a process as the kernel dumped them when it
+//! crashed. A core file is an ELF file whose PT_LOAD segments hold the process's memory and whose
+//! PT_NOTE segment describes everything else: the registers of each thread (NT_PRSTATUS), the
+//! command line (NT_PRPSINFO), the auxiliary vector (NT_AUXV) and which files were mapped where
+//! (NT_FILE). Memory that came straight from a file, like the program's code, usually isn't
+//! dumped, so it is read from that file instead.
+//!
+//! `gcore` goes the other way, writing the same kind of file for a live inferior so that its state
+//! can be looked at later, by deet or gdb.
+
+use nix::errno::Errno;
+use nix::sys::signal::Signal;
+use nix::unistd::Pid;
+use std::convert::TryInto;
+use std::fs::{self, File};
+use std::mem;
+use std::os::unix::fs::FileExt;
+
+use crate::solib;
+use crate::target::Target;
+
+const PT_LOAD: u32 = 1;
+const PT_NOTE: u32 = 4;
+
+const NT_PRSTATUS: u32 = 1;
+const NT_PRFPREG: u32 = 2;
+const NT_PRPSINFO: u32 = 3;
+const NT_AUXV: u32 = 6;
+const NT_FILE: u32 = 0x4649_4c45;
+
+/// Offsets into `struct elf_prstatus`
+const PR_CURSIG: usize = 12;
+const PR_PID: usize = 32;
+const PR_REG: usize = 112;
+const PR_FPVALID: usize = 328;
+const PRSTATUS_SIZE: usize = 336;
+/// Offsets into `struct elf_prpsinfo`: its state, its pid, the program name (cut off at 16 bytes)
+/// and the command line (cut off at 80)
+const PR_SNAME: usize = 1;
+const PR_PSINFO_PID: usize = 24;
+const PR_FNAME: usize = 40;
+const FNAME_LEN: usize = 16;
+const PR_PSARGS: usize = 56;
+const PSARGS_LEN: usize = 80;
+const PRPSINFO_SIZE: usize = 136;
+
+const ELF_HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+const PAGE_SIZE: usize = 0x1000;
+
+/// A PT_LOAD segment: the `filesz` bytes of memory at `vaddr` are in the core file at `offset`.
+/// Any more of the mapping than that wasn't dumped.
+struct Segment {
+    vaddr: usize,
+    offset: usize,
+    filesz: usize,
+}
+
+/// An NT_FILE entry: the file at `path` is mapped from `offset` onwards at `start..end`.
+struct MappedFile {
+    start: usize,
+    end: usize,
+    offset: u64,
+    path: String,
+}
+
+/// The registers of one thread of a live process, and the signal it is stopped with.
+pub struct ThreadState {
+    pub tid: Pid,
+    pub signal: Option<Signal>,
+    pub regs: libc::user_regs_struct,
+    pub fp_regs: Option<libc::user_fpregs_struct>,
+}
+
+pub struct CoreFile {
+    data: Vec<u8>,
+    segments: Vec<Segment>,
+    files: Vec<MappedFile>,
+    /// The pid of the process that crashed
+    pub pid: i32,
+    /// The signal that killed it
+    pub signal: i32,
+    /// Its command line
+    pub command: String,
+    regs: Option<libc::user_regs_struct>,
+    fp_regs: Option<libc::user_fpregs_struct>,
+    auxv: Option<Vec<u8>>,
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(
+        data.get(offset..offset + 2)?.try_into().ok()?,
+    ))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        data.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+fn read_u64(data: &[u8], offset: usize) -> Option<usize> {
+    Some(u64::from_le_bytes(data.get(offset..offset + 8)?.try_into().ok()?) as usize)
+}
+
+/// Reinterprets the bytes of a register set the kernel wrote out as the libc struct with the same
+/// layout.
+fn read_struct<T: Copy>(bytes: &[u8]) -> Option<T> {
+    if bytes.len() < mem::size_of::<T>() {
+        return None;
+    }
+    Some(unsafe { std::ptr::read_unaligned(bytes.as_ptr() as *const T) })
+}
+
+/// Splits the contents of a PT_NOTE segment into (type, descriptor) pairs. Names and descriptors
+/// are padded to 4 bytes.
+fn parse_notes(notes: &[u8]) -> Vec<(u32, &[u8])> {
+    let align = |n: usize| (n + 3) & !3;
+    let mut parsed = Vec::new();
+    let mut offset = 0;
+    while let (Some(namesz), Some(descsz), Some(kind)) = (
+        read_u32(notes, offset),
+        read_u32(notes, offset + 4),
+        read_u32(notes, offset + 8),
+    ) {
+        let desc = offset + 12 + align(namesz as usize);
+        match notes.get(desc..desc + descsz as usize) {
+            Some(bytes) => parsed.push((kind, bytes)),
+            None => break,
+        }
+        offset = desc + align(descsz as usize);
+    }
+    parsed
+}
+
+/// Parses an NT_FILE note: a count and a page size, then a (start, end, offset in pages) triple
+/// for each mapping, then their NUL-terminated paths.
+fn parse_file_note(desc: &[u8]) -> Vec<MappedFile> {
+    let (count, page_size) = match (read_u64(desc, 0), read_u64(desc, 8)) {
+        (Some(count), Some(page_size)) => (count, page_size),
+        _ => return Vec::new(),
+    };
+    let names = match desc.get(16 + count.saturating_mul(24)..) {
+        Some(names) => names.split(|&byte| byte == 0),
+        None => return Vec::new(),
+    };
+    (0..count)
+        .zip(names)
+        .filter_map(|(i, name)| {
+            let entry = 16 + i * 24;
+            Some(MappedFile {
+                start: read_u64(desc, entry)?,
+                end: read_u64(desc, entry + 8)?,
+                offset: (read_u64(desc, entry + 16)? * page_size) as u64,
+                path: String::from_utf8_lossy(name).into_owned(),
+            })
+        })
+        .collect()
+}
+
+/// The raw bytes of a register set, which is how the kernel writes them out.
+fn struct_bytes<T: Copy>(value: &T) -> &[u8] {
+    unsafe { std::slice::from_raw_parts(value as *const T as *const u8, mem::size_of::<T>()) }
+}
+
+/// Appends a note, named "CORE" like the ones the kernel writes, to the contents of a PT_NOTE
+/// segment.
+fn push_note(notes: &mut Vec<u8>, kind: u32, desc: &[u8]) {
+    let name = b"CORE\0";
+    notes.extend_from_slice(&(name.len() as u32).to_le_bytes());
+    notes.extend_from_slice(&(desc.len() as u32).to_le_bytes());
+    notes.extend_from_slice(&kind.to_le_bytes());
+    notes.extend_from_slice(name);
+    notes.resize((notes.len() + 3) & !3, 0);
+    notes.extend_from_slice(desc);
+    notes.resize((notes.len() + 3) & !3, 0);
+}
+
+fn prstatus(thread: &ThreadState) -> Vec<u8> {
+    let mut desc = vec![0; PRSTATUS_SIZE];
+    let signal = thread.signal.map_or(0, |signal| signal as i32);
+    desc[..4].copy_from_slice(&signal.to_le_bytes());
+    desc[PR_CURSIG..PR_CURSIG + 2].copy_from_slice(&(signal as u16).to_le_bytes());
+    desc[PR_PID..PR_PID + 4].copy_from_slice(&thread.tid.as_raw().to_le_bytes());
+    let regs = struct_bytes(&thread.regs);
+    desc[PR_REG..PR_REG + regs.len()].copy_from_slice(regs);
+    let fpvalid = thread.fp_regs.is_some() as u32;
+    desc[PR_FPVALID..PR_FPVALID + 4].copy_from_slice(&fpvalid.to_le_bytes());
+    desc
+}
+
+fn prpsinfo(pid: Pid) -> Vec<u8> {
+    let mut desc = vec![0; PRPSINFO_SIZE];
+    // Stopped by a tracer
+    desc[PR_SNAME] = b'T';
+    desc[PR_PSINFO_PID..PR_PSINFO_PID + 4].copy_from_slice(&pid.as_raw().to_le_bytes());
+    let mut copy = |offset: usize, len: usize, text: &[u8]| {
+        let len = text.len().min(len - 1);
+        desc[offset..offset + len].copy_from_slice(&text[..len]);
+    };
+    if let Ok(name) = fs::read_to_string(format!("/proc/{}/comm", pid)) {
+        copy(PR_FNAME, FNAME_LEN, name.trim_end().as_bytes());
+    }
+    if let Ok(mut args) = fs::read(format!("/proc/{}/cmdline", pid)) {
+        // The arguments are separated by NULs; the kernel puts spaces between them here
+        args.iter_mut()
+            .filter(|byte| **byte == 0)
+            .for_each(|byte| *byte = b' ');
+        let len = args
+            .iter()
+            .rposition(|&byte| byte != b' ')
+            .map_or(0, |last| last + 1);
+        copy(PR_PSARGS, PSARGS_LEN, &args[..len]);
+    }
+    desc
+}
+
+/// The NT_FILE note describing the file-backed mappings among `mappings`.
+fn file_note(mappings: &[solib::Mapping]) -> Vec<u8> {
+    let files: Vec<&solib::Mapping> = mappings
+        .iter()
+        .filter(|mapping| mapping.path.starts_with('/'))
+        .collect();
+    let mut desc = Vec::new();
+    desc.extend_from_slice(&(files.len() as u64).to_le_bytes());
+    desc.extend_from_slice(&(PAGE_SIZE as u64).to_le_bytes());
+    for file in &files {
+        desc.extend_from_slice(&(file.start as u64).to_le_bytes());
+        desc.extend_from_slice(&(file.end as u64).to_le_bytes());
+        desc.extend_from_slice(&(file.offset / PAGE_SIZE as u64).to_le_bytes());
+    }
+    for file in &files {
+        desc.extend_from_slice(file.path.as_bytes());
+        desc.push(0);
+    }
+    desc
+}
+
+/// Writes a core file of a stopped process to `path`: a PT_LOAD segment for each readable
+/// mapping, and notes with the registers of `threads` (the first of which is the one a debugger
+/// looks at when it loads the core), the command line, the auxiliary vector and the mapped files.
+pub fn write_core(
+    path: &str,
+    target: &dyn Target,
+    pid: Pid,
+    threads: &[ThreadState],
+) -> Result<(), String> {
+    let mappings = solib::memory_maps(pid)
+        .ok_or_else(|| format!("Can't read the memory map of process {}", pid))?;
+
+    let mut notes = Vec::new();
+    for (i, thread) in threads.iter().enumerate() {
+        push_note(&mut notes, NT_PRSTATUS, &prstatus(thread));
+        if i == 0 {
+            push_note(&mut notes, NT_PRPSINFO, &prpsinfo(pid));
+            if let Some(auxv) = target.auxv() {
+                push_note(&mut notes, NT_AUXV, &auxv);
+            }
+            push_note(&mut notes, NT_FILE, &file_note(&mappings));
+        }
+        if let Some(fp_regs) = &thread.fp_regs {
+            push_note(&mut notes, NT_PRFPREG, struct_bytes(fp_regs));
+        }
+    }
+
+    // [vvar] can't be read, and [vsyscall] is at the same place in every process
+    let segments: Vec<(&solib::Mapping, Vec<u8>)> = mappings
+        .iter()
+        .filter(|mapping| mapping.perms.starts_with('r'))
+        .filter(|mapping| mapping.path != "[vvar]" && mapping.path != "[vsyscall]")
+        .map(|mapping| {
+            let bytes = target
+                .read_memory(mapping.start, mapping.end - mapping.start)
+                .unwrap_or_default();
+            (mapping, bytes)
+        })
+        .collect();
+
+    let phnum = segments.len() + 1;
+    if phnum > u16::MAX as usize {
+        return Err(format!("Process {} has too many mappings", pid));
+    }
+    let notes_offset = ELF_HEADER_SIZE + phnum * PROGRAM_HEADER_SIZE;
+    let mut data_offset = (notes_offset + notes.len() + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+
+    let mut file = Vec::with_capacity(data_offset);
+    file.extend_from_slice(b"\x7fELF\x02\x01\x01");
+    file.resize(16, 0);
+    file.extend_from_slice(&4u16.to_le_bytes()); // ET_CORE
+    file.extend_from_slice(&62u16.to_le_bytes()); // EM_X86_64
+    file.extend_from_slice(&1u32.to_le_bytes()); // EV_CURRENT
+    file.extend_from_slice(&0u64.to_le_bytes()); // entry point
+    file.extend_from_slice(&(ELF_HEADER_SIZE as u64).to_le_bytes());
+    file.extend_from_slice(&0u64.to_le_bytes()); // no section headers
+    file.extend_from_slice(&0u32.to_le_bytes()); // flags
+    file.extend_from_slice(&(ELF_HEADER_SIZE as u16).to_le_bytes());
+    file.extend_from_slice(&(PROGRAM_HEADER_SIZE as u16).to_le_bytes());
+    file.extend_from_slice(&(phnum as u16).to_le_bytes());
+    file.extend_from_slice(&[0; 6]);
+
+    // The fields after the type and flags: offset, virtual and physical address, size in the
+    // file, size in memory and alignment
+    let mut program_header = |kind: u32, flags: u32, fields: [usize; 6]| {
+        file.extend_from_slice(&kind.to_le_bytes());
+        file.extend_from_slice(&flags.to_le_bytes());
+        for field in &fields {
+            file.extend_from_slice(&(*field as u64).to_le_bytes());
+        }
+    };
+    program_header(PT_NOTE, 0, [notes_offset, 0, 0, notes.len(), 0, 4]);
+    for (mapping, bytes) in &segments {
+        let perms = mapping.perms.as_bytes();
+        // PF_R, PF_W and PF_X
+        let flags = (perms[0] == b'r') as u32 * 4
+            + (perms.get(1) == Some(&b'w')) as u32 * 2
+            + (perms.get(2) == Some(&b'x')) as u32;
+        let size = mapping.end - mapping.start;
+        program_header(
+            PT_LOAD,
+            flags,
+            [data_offset, mapping.start, 0, bytes.len(), size, PAGE_SIZE],
+        );
+        data_offset += bytes.len();
+    }
+    file.extend_from_slice(&notes);
+    file.resize((file.len() + PAGE_SIZE - 1) & !(PAGE_SIZE - 1), 0);
+    for (_, bytes) in &segments {
+        file.extend_from_slice(bytes);
+    }
+    fs::write(path, file).map_err(|e| format!("Can't create a corefile: {}", e))
+}
+
+impl CoreFile {
+    /// Loads a core file, or explains why it isn't one we can read.
+    pub fn open(path: &str) -> Result<CoreFile, String> {
+        let data = fs::read(path).map_err(|e| format!("{}: {}.", path, e))?;
+        let not_a_core = || {
+            format!(
+                "\"{}\" is not a core dump: file format not recognized",
+                path
+            )
+        };
+        // An x86-64 (64-bit, little-endian) ELF file of type ET_CORE
+        if data.get(..6) != Some(b"\x7fELF\x02\x01") || read_u16(&data, 0x10) != Some(4) {
+            return Err(not_a_core());
+        }
+        let phoff = read_u64(&data, 0x20).ok_or_else(not_a_core)?;
+        let phentsize = read_u16(&data, 0x36).ok_or_else(not_a_core)? as usize;
+        let phnum = read_u16(&data, 0x38).ok_or_else(not_a_core)? as usize;
+
+        let mut segments = Vec::new();
+        let mut notes = Vec::new();
+        for i in 0..phnum {
+            let header = phoff + i * phentsize;
+            let field = |offset| read_u64(&data, header + offset).ok_or_else(not_a_core);
+            let (offset, vaddr, filesz) = (field(8)?, field(16)?, field(32)?);
+            match read_u32(&data, header) {
+                Some(PT_LOAD) => segments.push(Segment {
+                    vaddr,
+                    offset,
+                    filesz,
+                }),
+                Some(PT_NOTE) => notes.push(offset..offset + filesz),
+                _ => {}
+            }
+        }
+
+        // Threads besides the one that crashed
+        let mut threads = 0;
+        let mut core = CoreFile {
+            data: Vec::new(),
+            segments,
+            files: Vec::new(),
+            pid: 0,
+            signal: 0,
+            command: String::new(),
+            regs: None,
+            fp_regs: None,
+            auxv: None,
+        };
+        for range in notes {
+            let notes = data.get(range).ok_or_else(not_a_core)?;
+            for (kind, desc) in parse_notes(notes) {
+                match kind {
+                    // Each thread has its own NT_PRSTATUS, followed by its NT_PRFPREG. The one
+                    // that crashed comes first, and that's the one we look at.
+                    NT_PRSTATUS if core.regs.is_none() => {
+                        core.signal = read_u16(desc, PR_CURSIG).unwrap_or(0) as i32;
+                        core.pid = read_u32(desc, PR_PID).unwrap_or(0) as i32;
+                        core.regs = Some(
+                            desc.get(PR_REG..)
+                                .and_then(read_struct)
+                                .ok_or_else(not_a_core)?,
+                        );
+                    }
+                    NT_PRSTATUS => threads += 1,
+                    NT_PRFPREG if threads == 0 && core.fp_regs.is_none() => {
+                        core.fp_regs = read_struct(desc)
+                    }
+                    NT_PRPSINFO => {
+                        let args = desc.get(PR_PSARGS..PR_PSARGS + PSARGS_LEN).unwrap_or(&[]);
+                        let len = args
+                            .iter()
+                            .position(|&byte| byte == 0)
+                            .unwrap_or(args.len());
+                        core.command = String::from_utf8_lossy(&args[..len]).trim_end().to_string();
+                    }
+                    NT_AUXV => core.auxv = Some(desc.to_vec()),
+                    NT_FILE => core.files = parse_file_note(desc),
+                    _ => {}
+                }
+            }
+        }
+        if core.regs.is_none() {
+            return Err(format!("\"{}\" has no registers to look at", path));
+        }
+        core.data = data;
+        Ok(core)
+    }
+
+    /// Reads as much of `buf` as we can find, starting at `addr`. Returns how many bytes that was.
+    fn read_chunk(&self, addr: usize, buf: &mut [u8]) -> usize {
+        let segment = self
+            .segments
+            .iter()
+            .find(|segment| segment.vaddr <= addr && addr - segment.vaddr < segment.filesz);
+        if let Some(segment) = segment {
+            let start = segment.offset + (addr - segment.vaddr);
+            let len = buf.len().min(segment.filesz - (addr - segment.vaddr));
+            return match self.data.get(start..start + len) {
+                Some(bytes) => {
+                    buf[..len].copy_from_slice(bytes);
+                    len
+                }
+                None => 0,
+            };
+        }
+        // Not dumped, but maybe it is still in the file it was mapped from
+        let file = self
+            .files
+            .iter()
+            .find(|file| file.start <= addr && addr < file.end);
+        if let Some(file) = file {
+            let len = buf.len().min(file.end - addr);
+            let offset = file.offset + (addr - file.start) as u64;
+            if let Ok(read) =
+                File::open(&file.path).and_then(|f| f.read_at(&mut buf[..len], offset))
+            {
+                return read;
+            }
+        }
+        0
+    }
+}
+
+impl Target for CoreFile {
+    fn read_memory(&self, addr: usize, len: usize) -> Result<Vec<u8>, nix::Error> {
+        let mut bytes = vec![0; len];
+        let mut done = 0;
+        while done < len {
+            match self.read_chunk(addr.wrapping_add(done), &mut bytes[done..]) {
+                0 => return Err(nix::Error::Sys(Errno::EIO)),
+                read => done += read,
+            }
+        }
+        Ok(bytes)
+    }
+
+    fn get_registers(&self) -> Result<libc::user_regs_struct, nix::Error> {
+        self.regs.ok_or(nix::Error::UnsupportedOperation)
+    }
+
+    fn get_fp_registers(&self) -> Result<libc::user_fpregs_struct, nix::Error> {
+        self.fp_regs.ok_or(nix::Error::UnsupportedOperation)
+    }
+
+    fn auxv(&self) -> Option<Vec<u8>> {
+        self.auxv.clone()
+    }
+
+    fn mapped_file(&self, addr: usize) -> Option<(String, u64)> {
+        let file = self
+            .files
+            .iter()
+            .find(|file| file.start <= addr && addr < file.end)?;
+        let base = self
+            .files
+            .iter()
+            .filter(|other| other.path == file.path)
+            .map(|other| other.start)
+            .min()?;
+        Some((file.path.clone(), base as u64))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_file_note() {
+        let mut desc = Vec::new();
+        for word in &[2u64, 0x1000, 0x400000, 0x401000, 0, 0x401000, 0x403000, 1] {
+            desc.extend_from_slice(&word.to_le_bytes());
+        }
+        desc.extend_from_slice(b"/bin/a\0/bin/a\0");
+        let files = parse_file_note(&desc);
+        assert_eq!(files.len(), 2);
+        assert_eq!((files[1].start, files[1].end), (0x401000, 0x403000));
+        assert_eq!(files[1].offset, 0x1000);
+        assert_eq!(files[1].path, "/bin/a");
+    }
+
+    #[test]
+    fn test_notes_round_trip() {
+        let mut notes = Vec::new();
+        // The name and the 5-byte descriptor are both padded
+        push_note(&mut notes, NT_AUXV, b"abcde");
+        push_note(&mut notes, NT_PRPSINFO, b"xyzw");
+        assert_eq!(notes.len(), 2 * (12 + 8) + 8 + 4);
+        assert_eq!(
+            parse_notes(&notes),
+            vec![(NT_AUXV, &b"abcde"[..]), (NT_PRPSINFO, &b"xyzw"[..])]
+        );
+    }
+}
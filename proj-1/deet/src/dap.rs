@@ -0,0 +1,834 @@
+//! `deet --dap`: a Debug Adapter Protocol server, so that VS Code and other editors speaking DAP
+//! can drive the debugger.
+//!
+//! The client talks to us over stdin and stdout. Each message is a JSON object preceded by a
+//! `Content-Length: <bytes>` header and a blank line. The client sends requests; we answer each
+//! one with a response, and tell the client about things happening to the program (mostly that
+//! it stopped, or exited) with events.
+//!
+//! Like the command line, we wait for the program while it runs, so requests only get looked at
+//! once it has stopped again.
+
+use nix::sys::signal::{self, Signal};
+use nix::unistd::Pid;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+
+use crate::breakpoint::{BreakpointTable, Condition};
+use crate::debugger::{load_debug_data, process_executable, resolve_breakpoints_again};
+use crate::dwarf_data::{DwarfData, Variable};
+use crate::expr::{self, Evaluator};
+use crate::inferior::{Inferior, Status};
+use crate::interpreter::take_stdout;
+use crate::signals::{describe_signal, SignalTable};
+use crate::solib;
+use crate::target::Target;
+use crate::unwind::Frame;
+use crate::value;
+
+/// Reads a message, or returns None once the client has closed its end.
+fn read_message<R: BufRead>(reader: &mut R) -> io::Result<Option<Value>> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("Content-Length") {
+                length = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+    let length = length.ok_or_else(|| invalid("message without a Content-Length".to_string()))?;
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body)?;
+    serde_json::from_slice(&body)
+        .map(Some)
+        .map_err(|e| invalid(format!("invalid message: {}", e)))
+}
+
+fn write_message<W: Write>(writer: &mut W, message: &Value) -> io::Result<()> {
+    let body = message.to_string();
+    write!(writer, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    writer.flush()
+}
+
+/// Ways of resuming the program.
+#[derive(Clone, Copy, PartialEq)]
+enum Resume {
+    Continue,
+    /// `next`: step over calls
+    Next,
+    /// `stepIn`
+    Step,
+    /// `stepOut`: run until the innermost frame returns
+    Finish,
+}
+
+/// The variables a variablesReference stands for.
+#[derive(Clone, Copy)]
+enum Scope {
+    Arguments,
+    Locals,
+}
+
+struct Session<W: Write> {
+    out: W,
+    /// Number of the next message we send
+    seq: u64,
+    inferior: Option<Inferior>,
+    /// Whether we attached to the program rather than starting it
+    attached: bool,
+    /// The executable the program was started from
+    program: String,
+    debug_data: Option<DwarfData>,
+    breakpoints: BreakpointTable,
+    /// The breakpoints set in each source file, which setBreakpoints replaces as a whole
+    source_breakpoints: HashMap<String, Vec<usize>>,
+    signals: SignalTable,
+    /// Stop before the program runs any of its code, rather than letting it run once the client
+    /// is done setting breakpoints
+    stop_on_entry: bool,
+    /// The frames handed out since the program last stopped, as (thread, level). A frameId is an
+    /// index into this plus one.
+    frames: Vec<(usize, usize)>,
+    /// The scopes handed out since the program last stopped, as (frameId, scope). A
+    /// variablesReference is an index into this plus one, as 0 means "no children".
+    scopes: Vec<(usize, Scope)>,
+    /// Events to send after the response to the request being handled
+    events: Vec<(&'static str, Value)>,
+    /// Set once the client has disconnected
+    done: bool,
+}
+
+impl<W: Write> Session<W> {
+    fn new(out: W) -> Session<W> {
+        Session {
+            out,
+            seq: 1,
+            inferior: None,
+            attached: false,
+            program: String::new(),
+            debug_data: None,
+            breakpoints: BreakpointTable::new(),
+            source_breakpoints: HashMap::new(),
+            signals: SignalTable::new(),
+            stop_on_entry: false,
+            frames: Vec::new(),
+            scopes: Vec::new(),
+            events: Vec::new(),
+            done: false,
+        }
+    }
+
+    fn send(&mut self, mut message: Value) -> io::Result<()> {
+        message["seq"] = json!(self.seq);
+        self.seq += 1;
+        write_message(&mut self.out, &message)
+    }
+
+    /// Carries out a request, and sends the response followed by any events it gave rise to.
+    fn handle(&mut self, request: &Value) -> io::Result<()> {
+        let command = request["command"].as_str().unwrap_or("");
+        let args = &request["arguments"];
+        let result = match command {
+            "initialize" => Ok(json!({
+                "supportsConfigurationDoneRequest": true,
+                "supportsConditionalBreakpoints": true,
+                "supportsEvaluateForHovers": true,
+            })),
+            "launch" => self.launch(args),
+            "attach" => self.attach(args),
+            "setBreakpoints" => self.set_breakpoints(args),
+            "configurationDone" => self.configuration_done(),
+            "threads" => self.threads(),
+            "stackTrace" => self.stack_trace(args),
+            "scopes" => self.scopes(args),
+            "variables" => self.variables(args),
+            "continue" => self.resume(args, Resume::Continue),
+            "next" => self.resume(args, Resume::Next),
+            "stepIn" => self.resume(args, Resume::Step),
+            "stepOut" => self.resume(args, Resume::Finish),
+            "evaluate" => self.evaluate(args),
+            "disconnect" => self.disconnect(args),
+            _ => Err(format!("Unsupported request {}", command)),
+        };
+        let mut response = json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": command,
+            "success": result.is_ok(),
+        });
+        match result {
+            Ok(Value::Null) => {}
+            Ok(body) => response["body"] = body,
+            Err(message) => response["message"] = json!(message),
+        }
+        self.send(response)?;
+        for (event, body) in std::mem::take(&mut self.events) {
+            self.send(json!({"type": "event", "event": event, "body": body}))?;
+        }
+        Ok(())
+    }
+
+    fn inferior(&self) -> Result<&Inferior, String> {
+        self.inferior
+            .as_ref()
+            .ok_or_else(|| "The program is not being run.".to_string())
+    }
+
+    fn debug_data(&self) -> Result<&DwarfData, String> {
+        self.debug_data
+            .as_ref()
+            .ok_or_else(|| "No program has been launched.".to_string())
+    }
+
+    /// Takes charge of a program that has just been started or attached to. Its symbols are
+    /// moved to where it was loaded, and the client is told it can set breakpoints now.
+    fn start(&mut self, inferior: Inferior, program: &str, mut debug_data: DwarfData) {
+        locate_program(&inferior, &mut debug_data);
+        self.inferior = Some(inferior);
+        self.program = program.to_string();
+        self.debug_data = Some(debug_data);
+        self.events.push(("initialized", json!({})));
+    }
+
+    /// Catches up with an exec: switches to the symbols of the new program and puts the
+    /// breakpoints, which went with the old memory image, back in. If it is a different program,
+    /// they are looked up again in it, and those it doesn't have are removed.
+    fn follow_exec(&mut self) -> Result<(), String> {
+        let pid = self.inferior()?.pid();
+        let program = process_executable(pid)
+            .map_err(|e| format!("Could not find the executable of process {}: {}", pid, e))?;
+        let mut debug_data = load_debug_data(&program)?;
+        let inferior = self.inferior.as_mut().unwrap();
+        locate_program(inferior, &mut debug_data);
+        let canonical = |path: &str| std::fs::canonicalize(path).ok();
+        let same_program = program == self.program
+            || canonical(&program).is_some() && canonical(&program) == canonical(&self.program);
+        match self.debug_data.take() {
+            Some(old) if same_program => {
+                let delta = debug_data.bias().wrapping_sub(old.bias());
+                self.breakpoints.relocate(old.address_range(), delta);
+            }
+            _ => {
+                for number in resolve_breakpoints_again(&mut self.breakpoints, &debug_data) {
+                    self.events.push((
+                        "breakpoint",
+                        json!({"reason": "removed", "breakpoint": {"id": number, "verified": false}}),
+                    ));
+                }
+                for breakpoint in self.breakpoints.iter() {
+                    let line = debug_data.get_line_from_addr(breakpoint.addr);
+                    self.events.push((
+                        "breakpoint",
+                        json!({"reason": "changed", "breakpoint": {
+                            "id": breakpoint.number,
+                            "verified": true,
+                            "line": line.map(|line| line.number),
+                        }}),
+                    ));
+                }
+            }
+        }
+        for addr in self.breakpoints.enabled_addrs() {
+            // As in sync_breakpoint, those in libraries go in once the library is loaded
+            let _ = inferior.insert_breakpoint(addr);
+        }
+        self.program = program;
+        self.debug_data = Some(debug_data);
+        Ok(())
+    }
+
+    /// Starts `program` with `args`, stopped before its first instruction until the client is
+    /// done configuring.
+    fn launch(&mut self, args: &Value) -> Result<Value, String> {
+        let program = args["program"]
+            .as_str()
+            .ok_or_else(|| "launch needs a \"program\" to run.".to_string())?;
+        let program_args: Vec<String> = args["args"]
+            .as_array()
+            .map(|args| {
+                args.iter()
+                    .filter_map(|arg| arg.as_str().map(String::from))
+                    .collect()
+            })
+            .unwrap_or_default();
+        let debug_data = load_debug_data(program)?;
+        let inferior = Inferior::new(program, &program_args)
+            .ok_or_else(|| format!("Error starting subprocess {}", program))?;
+        self.stop_on_entry = args["stopOnEntry"].as_bool().unwrap_or(false);
+        self.attached = false;
+        self.start(inferior, program, debug_data);
+        Ok(Value::Null)
+    }
+
+    /// Attaches to the process with the given "pid".
+    fn attach(&mut self, args: &Value) -> Result<Value, String> {
+        let pid = args["pid"]
+            .as_i64()
+            .or_else(|| args["processId"].as_i64())
+            .ok_or_else(|| "attach needs the \"pid\" of a process.".to_string())?;
+        let pid = Pid::from_raw(pid as i32);
+        let program = process_executable(pid)
+            .map_err(|e| format!("Could not find the executable of process {}: {}", pid, e))?;
+        let debug_data = load_debug_data(&program)?;
+        let inferior = Inferior::attach(pid)
+            .map_err(|e| format!("Could not attach to process {}: {}", pid, e))?;
+        self.stop_on_entry = args["stopOnEntry"].as_bool().unwrap_or(false);
+        self.attached = true;
+        self.start(inferior, &program, debug_data);
+        Ok(Value::Null)
+    }
+
+    /// Replaces the breakpoints in a source file with those at the given lines. Each goes on the
+    /// first line at or after the one asked for that has code, which is the line we report back.
+    fn set_breakpoints(&mut self, args: &Value) -> Result<Value, String> {
+        let path = args["source"]["path"]
+            .as_str()
+            .ok_or_else(|| "setBreakpoints needs a source path.".to_string())?;
+        for number in self.source_breakpoints.remove(path).unwrap_or_default() {
+            if let Some(breakpoint) = self.breakpoints.remove(number) {
+                self.sync_breakpoint(breakpoint.addr);
+            }
+        }
+        let requested = args["breakpoints"].as_array().cloned().unwrap_or_default();
+        let mut numbers = Vec::new();
+        let mut results = Vec::new();
+        for requested in requested {
+            let line = requested["line"].as_u64().unwrap_or(0) as usize;
+            let resolved = self.debug_data.as_ref().and_then(|debug_data| {
+                let file = debug_data.get_file(path)?;
+                let addr = debug_data.get_addr_for_line(Some(&file.name), line)?;
+                let spec = format!("{}:{}", file.name, line);
+                Some((addr, spec, debug_data.get_line_from_addr(addr)?))
+            });
+            let (addr, spec, line) = match resolved {
+                Some(resolved) => resolved,
+                None => {
+                    results.push(json!({
+                        "verified": false,
+                        "line": line,
+                        "message": "No code at this line.",
+                    }));
+                    continue;
+                }
+            };
+            let condition = match requested["condition"].as_str() {
+                Some(text) => match Condition::parse(text) {
+                    Ok(condition) => Some(condition),
+                    Err(err) => {
+                        results
+                            .push(json!({"verified": false, "line": line.number, "message": err}));
+                        continue;
+                    }
+                },
+                None => None,
+            };
+            let number = self.breakpoints.add(addr, spec, line.to_string()).number;
+            if let Some(breakpoint) = self.breakpoints.get_mut(number) {
+                breakpoint.condition = condition;
+            }
+            self.sync_breakpoint(addr);
+            numbers.push(number);
+            results.push(json!({
+                "id": number,
+                "verified": true,
+                "line": line.number,
+                "source": {"path": path},
+            }));
+        }
+        self.source_breakpoints.insert(path.to_string(), numbers);
+        Ok(json!({ "breakpoints": results }))
+    }
+
+    /// Brings the INT3 at `addr` in line with the breakpoint table.
+    fn sync_breakpoint(&mut self, addr: usize) {
+        if let Some(inferior) = self.inferior.as_mut() {
+            // A breakpoint in a library that isn't loaded yet goes in when it is
+            let _ = if self.breakpoints.is_enabled_at(addr) {
+                inferior.insert_breakpoint(addr)
+            } else {
+                inferior.remove_breakpoint(addr)
+            };
+        }
+    }
+
+    /// The client has set its breakpoints, so off we go.
+    fn configuration_done(&mut self) -> Result<Value, String> {
+        let thread = self.inferior()?.current_thread();
+        if self.stop_on_entry {
+            self.events.push((
+                "stopped",
+                json!({"reason": "entry", "threadId": thread, "allThreadsStopped": true}),
+            ));
+            return Ok(Value::Null);
+        }
+        self.resume(&Value::Null, Resume::Continue)
+    }
+
+    fn threads(&mut self) -> Result<Value, String> {
+        let inferior = match &self.inferior {
+            Some(inferior) => inferior,
+            None => return Ok(json!({ "threads": [] })),
+        };
+        let threads: Vec<Value> = inferior
+            .threads()
+            .into_iter()
+            .map(|(number, tid)| {
+                let name = inferior
+                    .thread_name(tid)
+                    .unwrap_or_else(|| format!("LWP {}", tid));
+                json!({"id": number, "name": format!("{} (LWP {})", name, tid)})
+            })
+            .collect();
+        Ok(json!({ "threads": threads }))
+    }
+
+    /// Makes the given thread current, if the arguments name one.
+    fn select_thread(&mut self, args: &Value) -> Result<(), String> {
+        let inferior = self
+            .inferior
+            .as_mut()
+            .ok_or_else(|| "The program is not being run.".to_string())?;
+        match args["threadId"].as_u64() {
+            Some(number) if !inferior.select_thread(number as usize) => {
+                Err(format!("Invalid thread ID: {}", number))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// The path of a source file named in the line table, which may be relative to the directory
+    /// it was compiled in.
+    fn source_path(&self, file: &str) -> String {
+        let comp_dir = self
+            .debug_data
+            .as_ref()
+            .and_then(|debug_data| debug_data.get_file(file))
+            .map(|file| file.comp_dir.clone())
+            .unwrap_or_default();
+        Path::new(&comp_dir)
+            .join(file)
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    fn stack_trace(&mut self, args: &Value) -> Result<Value, String> {
+        self.select_thread(args)?;
+        let inferior = self.inferior()?;
+        let debug_data = self.debug_data()?;
+        let thread = inferior.current_thread();
+        let frames = inferior
+            .frames(debug_data, None)
+            .map_err(|e| format!("failed to unwind the stack, err: {}", e))?;
+        let total = frames.len();
+        let start = args["startFrame"].as_u64().unwrap_or(0) as usize;
+        let count = match args["levels"].as_u64() {
+            Some(levels) if levels > 0 => levels as usize,
+            _ => total,
+        };
+        let mut stack = Vec::new();
+        for frame in frames.iter().skip(start).take(count) {
+            let pc = frame.lookup_pc();
+            let name = debug_data
+                .get_function_from_addr(pc)
+                .unwrap_or_else(|| "??".to_string());
+            let mut value = json!({
+                "name": name,
+                "line": 0,
+                "column": 0,
+                "instructionPointerReference": format!("{:#x}", frame.pc),
+            });
+            if let Some(line) = debug_data.get_line_from_addr(pc) {
+                let path = self.source_path(&line.file);
+                let file_name = Path::new(&path)
+                    .file_name()
+                    .map(|name| name.to_string_lossy().into_owned());
+                value["source"] = json!({"name": file_name, "path": path});
+                value["line"] = json!(line.number);
+                value["column"] = json!(1);
+            }
+            stack.push((thread, frame.level, value));
+        }
+        let stack: Vec<Value> = stack
+            .into_iter()
+            .map(|(thread, level, mut value)| {
+                self.frames.push((thread, level));
+                value["id"] = json!(self.frames.len());
+                value
+            })
+            .collect();
+        Ok(json!({"stackFrames": stack, "totalFrames": total}))
+    }
+
+    /// Looks up a frameId, making its thread current and unwinding to it.
+    fn frame(&mut self, frame_id: Option<u64>) -> Result<Frame, String> {
+        let (thread, level) = frame_id
+            .and_then(|id| self.frames.get((id as usize).checked_sub(1)?))
+            .copied()
+            .ok_or_else(|| "Unknown frame.".to_string())?;
+        self.select_thread(&json!({ "threadId": thread }))?;
+        let frames = self
+            .inferior()?
+            .frames(self.debug_data()?, Some(level + 1))
+            .map_err(|e| format!("failed to unwind the stack, err: {}", e))?;
+        frames
+            .into_iter()
+            .nth(level)
+            .ok_or_else(|| format!("No frame at level {}.", level))
+    }
+
+    fn scopes(&mut self, args: &Value) -> Result<Value, String> {
+        let frame_id = args["frameId"]
+            .as_u64()
+            .filter(|id| *id >= 1 && (*id as usize) <= self.frames.len())
+            .ok_or_else(|| "Unknown frame.".to_string())? as usize;
+        let mut scopes = Vec::new();
+        for (name, hint, scope) in &[
+            ("Arguments", "arguments", Scope::Arguments),
+            ("Locals", "locals", Scope::Locals),
+        ] {
+            self.scopes.push((frame_id, *scope));
+            scopes.push(json!({
+                "name": name,
+                "presentationHint": hint,
+                "variablesReference": self.scopes.len(),
+                "expensive": false,
+            }));
+        }
+        Ok(json!({ "scopes": scopes }))
+    }
+
+    fn variables(&mut self, args: &Value) -> Result<Value, String> {
+        let (frame_id, scope) = args["variablesReference"]
+            .as_u64()
+            .and_then(|id| self.scopes.get((id as usize).checked_sub(1)?))
+            .copied()
+            .ok_or_else(|| "Unknown variables reference.".to_string())?;
+        let frame = self.frame(Some(frame_id as u64))?;
+        let inferior = self.inferior()?;
+        let debug_data = self.debug_data()?;
+        let function = match debug_data.get_function_containing(frame.lookup_pc()) {
+            Some(function) => function,
+            None => return Ok(json!({ "variables": [] })),
+        };
+        let parameters = match scope {
+            Scope::Arguments => true,
+            Scope::Locals => false,
+        };
+        let variables: Vec<Value> = function
+            .variables
+            .iter()
+            .filter(|var| var.is_parameter == parameters)
+            .map(|var| {
+                json!({
+                    "name": var.name,
+                    "value": format_variable(inferior, debug_data, var, &frame),
+                    "type": debug_data.declare(Some(&var.entity_type), ""),
+                    "variablesReference": 0,
+                })
+            })
+            .collect();
+        Ok(json!({ "variables": variables }))
+    }
+
+    fn evaluate(&mut self, args: &Value) -> Result<Value, String> {
+        let text = args["expression"]
+            .as_str()
+            .ok_or_else(|| "evaluate needs an expression.".to_string())?;
+        let frame = match args["frameId"].as_u64() {
+            Some(id) => Some(self.frame(Some(id))?),
+            None => None,
+        };
+        let debug_data = self.debug_data()?;
+        let evaluator =
+            Evaluator::new(debug_data, self.inferior.as_ref().map(|i| i as &dyn Target))
+                .in_frame(frame);
+        let value = expr::parse(text).and_then(|expr| evaluator.evaluate(&expr))?;
+        Ok(json!({"result": evaluator.format(&value), "variablesReference": 0}))
+    }
+
+    /// Resumes the program (all threads, or just the one given for stepping) and waits for it to
+    /// stop. The stop is reported with an event after the response.
+    fn resume(&mut self, args: &Value, how: Resume) -> Result<Value, String> {
+        self.select_thread(args)?;
+        let debug_data = self.debug_data.as_ref().unwrap();
+        let inferior = self.inferior.as_mut().unwrap();
+        self.frames.clear();
+        self.scopes.clear();
+        let (breakpoints, signals) = (&mut self.breakpoints, &self.signals);
+        let status = match how {
+            Resume::Continue => inferior.continues(breakpoints, debug_data, signals),
+            Resume::Next => inferior.step_line(true, breakpoints, debug_data, signals),
+            Resume::Step => inferior.step_line(false, breakpoints, debug_data, signals),
+            Resume::Finish => inferior.finish(breakpoints, debug_data, signals, 0),
+        }
+        .map_err(|e| format!("failed to resume the program, err: {}", e))?;
+        self.report(status, how != Resume::Continue)?;
+        Ok(json!({ "allThreadsContinued": how == Resume::Continue }))
+    }
+
+    /// Queues up the events describing why the program stopped, or that it is gone. An exec
+    /// isn't a reason to stop, so after catching up with it the program carries on.
+    fn report(&mut self, status: Status, stepping: bool) -> Result<(), String> {
+        let body = match status {
+            Status::Stopped(signal, addr) => {
+                let inferior = self.inferior.as_ref().unwrap();
+                if let Some(debug_data) = self.debug_data.as_mut() {
+                    debug_data.update_libraries(&inferior.shared_libraries());
+                }
+                let thread = inferior.current_thread();
+                let hit: Vec<usize> = self.breakpoints.at(addr).map(|bp| bp.number).collect();
+                let mut body = json!({"threadId": thread, "allThreadsStopped": true});
+                if !hit.is_empty() {
+                    body["reason"] = json!("breakpoint");
+                    body["hitBreakpointIds"] = json!(hit);
+                    let errors = self.breakpoints.take_condition_errors();
+                    if !errors.is_empty() {
+                        body["description"] = json!(errors.join("\n"));
+                    }
+                } else if signal == Signal::SIGTRAP && stepping {
+                    body["reason"] = json!("step");
+                } else {
+                    body["reason"] = json!("exception");
+                    body["description"] = json!(describe_signal(signal));
+                    body["text"] = json!(signal.as_ref());
+                }
+                body
+            }
+            Status::Exec => {
+                self.follow_exec()?;
+                let inferior = self.inferior.as_mut().unwrap();
+                let debug_data = self.debug_data.as_ref().unwrap();
+                let status = inferior
+                    .continues(&mut self.breakpoints, debug_data, &self.signals)
+                    .map_err(|e| format!("failed to resume the program, err: {}", e))?;
+                return self.report(status, false);
+            }
+            Status::Exited(code) => {
+                self.exited(code);
+                return Ok(());
+            }
+            // The exit status a shell would report
+            Status::Signaled(signal) => {
+                self.exited(128 + signal as i32);
+                return Ok(());
+            }
+        };
+        self.events.push(("stopped", body));
+        Ok(())
+    }
+
+    fn exited(&mut self, code: i32) {
+        self.inferior = None;
+        self.events.push(("exited", json!({ "exitCode": code })));
+        self.events.push(("terminated", json!({})));
+    }
+
+    /// Ends the session. A program we started is killed and one we attached to is let go,
+    /// unless the client asks otherwise with "terminateDebuggee".
+    fn disconnect(&mut self, args: &Value) -> Result<Value, String> {
+        self.done = true;
+        let inferior = match self.inferior.take() {
+            Some(inferior) => inferior,
+            None => return Ok(Value::Null),
+        };
+        let terminate = args["terminateDebuggee"]
+            .as_bool()
+            .unwrap_or(!self.attached);
+        if !terminate {
+            return inferior
+                .detach()
+                .map(|_| Value::Null)
+                .map_err(|e| format!("failed to detach, err: {}", e));
+        }
+        if self.attached {
+            let _ = signal::kill(inferior.pid(), Signal::SIGKILL);
+        }
+        // Dropping a program we started kills it
+        drop(inferior);
+        Ok(Value::Null)
+    }
+}
+
+/// Moves the symbols of a program that has just been started, attached to or exec'd to where it
+/// was loaded, and catches up with its shared libraries.
+fn locate_program(inferior: &Inferior, debug_data: &mut DwarfData) {
+    if let Some(entry) = solib::entry_point(inferior) {
+        debug_data.relocate(entry.wrapping_sub(debug_data.entry()));
+    }
+    debug_data.update_libraries(&inferior.shared_libraries());
+}
+
+/// Reads a variable out of the program as seen from `frame` and formats it according to its type.
+fn format_variable(
+    inferior: &dyn Target,
+    debug_data: &DwarfData,
+    var: &Variable,
+    frame: &Frame,
+) -> String {
+    match inferior.read_variable(var, frame) {
+        Ok(bytes) => value::format_value(&bytes, &var.entity_type, debug_data, Some(inferior)),
+        Err(e) => format!("<error reading variable: {}>", e),
+    }
+}
+
+/// Serves a client on stdin and stdout until it disconnects.
+pub fn run() -> Result<(), String> {
+    let out = take_stdout().map_err(|e| format!("Could not take over stdout: {}", e))?;
+    let stdin = io::stdin();
+    let mut input = stdin.lock();
+    let mut session = Session::new(out);
+    let io_error = |e: io::Error| format!("Lost the connection to the client: {}", e);
+    while let Some(request) = read_message(&mut input).map_err(io_error)? {
+        session.handle(&request).map_err(io_error)?;
+        if session.done {
+            break;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::inferior::test::{build_sample, lock_tracing};
+
+    /// Sends a request and returns the response along with the events that followed it.
+    fn request(session: &mut Session<Vec<u8>>, command: &str, args: Value) -> (Value, Vec<Value>) {
+        session.out.clear();
+        let request = json!({"seq": 1, "type": "request", "command": command, "arguments": args});
+        session.handle(&request).unwrap();
+        let mut output = io::Cursor::new(std::mem::take(&mut session.out));
+        let response = read_message(&mut output).unwrap().unwrap();
+        assert_eq!(response["type"], "response");
+        assert_eq!(response["command"], command);
+        let mut events = Vec::new();
+        while let Some(event) = read_message(&mut output).unwrap() {
+            events.push(event);
+        }
+        (response, events)
+    }
+
+    #[test]
+    fn test_messages() {
+        let mut buffer = Vec::new();
+        write_message(&mut buffer, &json!({"seq": 1})).unwrap();
+        assert_eq!(buffer, b"Content-Length: 9\r\n\r\n{\"seq\":1}".to_vec());
+        let mut input = io::Cursor::new(buffer);
+        assert_eq!(read_message(&mut input).unwrap(), Some(json!({"seq": 1})));
+        assert_eq!(read_message(&mut input).unwrap(), None);
+    }
+
+    #[test]
+    fn test_debug_sample() {
+        let _tracing = lock_tracing();
+        let program = build_sample("segfault");
+        let source = format!("{}/samples/segfault.c", env!("CARGO_MANIFEST_DIR"));
+        let mut session = Session::new(Vec::new());
+
+        let (response, _) = request(&mut session, "initialize", json!({}));
+        assert_eq!(response["body"]["supportsConfigurationDoneRequest"], true);
+        let (response, events) = request(&mut session, "launch", json!({ "program": &*program }));
+        assert_eq!(response["success"], true);
+        assert_eq!(events[0]["event"], "initialized");
+        let (response, _) = request(
+            &mut session,
+            "setBreakpoints",
+            json!({"source": {"path": source}, "breakpoints": [{"line": 4}, {"line": 100}]}),
+        );
+        let breakpoints = &response["body"]["breakpoints"];
+        assert_eq!(breakpoints[0]["verified"], true);
+        assert_eq!(breakpoints[0]["line"], 4);
+        assert_eq!(breakpoints[1]["verified"], false);
+
+        let (_, events) = request(&mut session, "configurationDone", json!({}));
+        assert_eq!(events[0]["event"], "stopped");
+        assert_eq!(events[0]["body"]["reason"], "breakpoint");
+        let (response, _) = request(&mut session, "threads", json!({}));
+        let thread = response["body"]["threads"][0]["id"].clone();
+        let (response, _) = request(&mut session, "stackTrace", json!({ "threadId": thread }));
+        let frames = &response["body"]["stackFrames"];
+        assert_eq!(frames[0]["name"], "func2");
+        assert_eq!(frames[0]["line"], 4);
+        assert_eq!(frames[1]["name"], "func1");
+        let (response, _) = request(&mut session, "scopes", json!({"frameId": frames[1]["id"]}));
+        let arguments = response["body"]["scopes"][0]["variablesReference"].clone();
+        let (response, _) = request(
+            &mut session,
+            "variables",
+            json!({ "variablesReference": arguments }),
+        );
+        let variables = &response["body"]["variables"];
+        assert_eq!(variables[0]["name"], "a");
+        assert_eq!(variables[0]["value"], "42");
+        let (response, _) = request(
+            &mut session,
+            "evaluate",
+            json!({"expression": "a + 1", "frameId": frames[0]["id"]}),
+        );
+        assert_eq!(response["body"]["result"], "3");
+
+        let (_, events) = request(&mut session, "next", json!({ "threadId": thread }));
+        assert_eq!(events[0]["body"]["reason"], "step");
+        let (_, events) = request(&mut session, "continue", json!({ "threadId": thread }));
+        assert_eq!(events[0]["body"]["reason"], "exception");
+        assert_eq!(events[0]["body"]["text"], "SIGSEGV");
+        let (_, events) = request(&mut session, "continue", json!({ "threadId": thread }));
+        assert_eq!(events[0]["event"], "exited");
+        assert_eq!(events[0]["body"]["exitCode"], 128 + 11);
+        assert_eq!(events[1]["event"], "terminated");
+        let (response, _) = request(&mut session, "disconnect", json!({}));
+        assert_eq!(response["success"], true);
+        assert!(session.done);
+    }
+
+    #[test]
+    fn test_follow_exec() {
+        let _tracing = lock_tracing();
+        let program = build_sample("exec");
+        let source = format!("{}/samples/exec.c", env!("CARGO_MANIFEST_DIR"));
+        let mut session = Session::new(Vec::new());
+
+        request(&mut session, "launch", json!({ "program": &*program }));
+        let (response, _) = request(
+            &mut session,
+            "setBreakpoints",
+            json!({"source": {"path": source}, "breakpoints": [{"line": 13}]}),
+        );
+        assert_eq!(response["body"]["breakpoints"][0]["verified"], true);
+        // The breakpoint is only reached by the program the first one execs
+        let (_, events) = request(&mut session, "configurationDone", json!({}));
+        assert_eq!(events[0]["event"], "stopped");
+        assert_eq!(events[0]["body"]["reason"], "breakpoint");
+        let (_, events) = request(&mut session, "continue", json!({ "threadId": 1 }));
+        assert_eq!(events[0]["event"], "exited");
+        assert_eq!(events[0]["body"]["exitCode"], 0);
+        request(&mut session, "disconnect", json!({}));
+
+        // Another program doesn't have the line, so the breakpoint goes
+        let other = build_sample("function_calls");
+        let mut session = Session::new(Vec::new());
+        let args = json!({ "program": &*program, "args": ["again", &*other] });
+        request(&mut session, "launch", args);
+        request(
+            &mut session,
+            "setBreakpoints",
+            json!({"source": {"path": source}, "breakpoints": [{"line": 13}]}),
+        );
+        let (_, events) = request(&mut session, "configurationDone", json!({}));
+        assert_eq!(events[0]["event"], "breakpoint");
+        assert_eq!(events[0]["body"]["reason"], "removed");
+        assert_eq!(events[1]["event"], "exited");
+        request(&mut session, "disconnect", json!({}));
+    }
+}
@@ -1,36 +1,196 @@
-use std::ops::Deref;
-
-use crate::debugger_command::DebuggerCommand;
+use crate::breakpoint::{Breakpoint, BreakpointTable, Condition, WatchHit, WatchKind};
+use crate::completion::DeetHelper;
+use crate::coredump::{self, CoreFile};
+use crate::debugger_command::{self, DebuggerCommand, DisassembleOptions, ExamineFormat};
+use crate::disasm::{self, Instruction};
 use crate::dwarf_data::{DwarfData, Error as DwarfError};
-use crate::inferior::{self, Inferior, Status};
+use crate::dwarf_data::{Encoding, Line, Type, TypeKind, Variable};
+use crate::expr::{self, Evaluator, Expr, Lvalue};
+use crate::inferior::{self, ForkPolicy, Inferior, Status};
+use crate::interpreter::JsonInterpreter;
+use crate::registers;
+use crate::script::{self, BlockReader, Script};
+use crate::signals::{self, SignalTable};
+use crate::solib;
+use crate::source::SourcePath;
+use crate::target::Target;
+use crate::unwind::Frame;
+use crate::value;
+use nix::sys::signal::Signal;
+use nix::unistd::Pid;
 use rustyline::error::ReadlineError;
 use rustyline::Editor;
+use serde_json::{json, Map, Value};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::ops::Range;
+use std::path::Path;
+
+/// How deeply scripts and user-defined commands can nest, which stops a command that calls
+/// itself from running forever.
+const MAX_SCRIPT_DEPTH: usize = 256;
+
+/// The most bytes `disassemble` decodes in one go, which is plenty for any function and keeps a
+/// mistyped range from reading the whole address space.
+const MAX_DISASSEMBLY_LEN: usize = 0x10000;
+
+/// The most units `x` shows in one go, for the same reason.
+const MAX_EXAMINE_COUNT: usize = 0x10000;
 
 pub struct Debugger {
     target: String,
     history_path: String,
-    readline: Editor<()>,
+    readline: Editor<DeetHelper>,
     inferior: Option<Inferior>,
+    /// The core dump we are looking at when there is no live process
+    core: Option<CoreFile>,
     debug_data: DwarfData,
+    breakpoints: BreakpointTable,
+    /// The format letter and unit size `x` used last, which are the defaults for the next `x`
+    examine_format: (char, usize),
+    /// Where a bare `x` carries on from
+    examine_next: Option<usize>,
+    /// The level of the frame `print`, `info locals` and friends look at; 0 is the innermost
+    selected_frame: usize,
+    signals: SignalTable,
+    fork_policy: ForkPolicy,
+    source_path: SourcePath,
+    /// The file and the first and last lines `list` showed last, which a bare `list` carries on
+    /// from. None when the program has stopped or another frame was selected since, and `list`
+    /// should show the lines around the selected frame.
+    listed: Option<(String, usize, usize)>,
+    /// Set with `--interpreter=json`. Printing things is all most commands do, so it lives in a
+    /// RefCell to let them add to their result without needing `&mut self`.
+    json: Option<RefCell<JsonInterpreter>>,
+    /// The commands made with `define`, by name
+    user_commands: HashMap<String, Vec<String>>,
+    /// The scripts being carried out, innermost last. `define` and `commands` read their blocks
+    /// from the innermost one, or from the user when there is none.
+    scripts: Vec<Script>,
+    /// The commands of the breakpoints the program last stopped at, which are still to be run
+    breakpoint_actions: Option<Vec<String>>,
+}
+
+/// Parses a hexadecimal address, with or without a leading "0x".
+fn parse_address(addr: &str) -> Option<usize> {
+    let addr_without_0x = if addr.to_lowercase().starts_with("0x") {
+        &addr[2..]
+    } else {
+        addr
+    };
+    usize::from_str_radix(addr_without_0x, 16).ok()
+}
+
+/// Loads the debugging symbols of `target`, or explains why we couldn't.
+pub fn load_debug_data(target: &str) -> Result<DwarfData, String> {
+    match DwarfData::from_file(target) {
+        Ok(val) => Ok(val),
+        Err(DwarfError::ErrorOpeningFile) => Err(format!("Could not open file {}", target)),
+        Err(DwarfError::DwarfFormatError(err)) => Err(format!(
+            "Could not debugging symbols from {}: {:?}",
+            target, err
+        )),
+    }
+}
+
+/// A breakpoint as JSON, for `break` and `info breakpoints`.
+fn breakpoint_json(breakpoint: &Breakpoint) -> Value {
+    let mut value = json!({
+        "number": breakpoint.number,
+        "type": "breakpoint",
+        "enabled": breakpoint.enabled,
+        "addr": format!("{:#x}", breakpoint.addr),
+        "location": breakpoint.location,
+        "hits": breakpoint.hit_count,
+    });
+    if let Some(condition) = &breakpoint.condition {
+        value["condition"] = json!(condition.to_string());
+    }
+    if breakpoint.ignore_count > 0 {
+        value["ignore"] = json!(breakpoint.ignore_count);
+    }
+    if !breakpoint.commands.is_empty() {
+        value["commands"] = json!(breakpoint.commands);
+    }
+    value
+}
+
+/// Resolves a breakpoint location to an address. Accepts `*0xADDR` for a raw address,
+/// `file:line` or a bare line number for a source line, and otherwise treats the location as a
+/// function name.
+fn resolve_location(debug_data: &DwarfData, location: &str) -> Option<usize> {
+    if let Some(addr) = location.strip_prefix('*') {
+        return parse_address(addr);
+    }
+    if let Some(colon) = location.rfind(':') {
+        let line_number = location[colon + 1..].parse::<usize>().ok()?;
+        return debug_data.get_addr_for_line(Some(&location[..colon]), line_number);
+    }
+    if let Ok(line_number) = location.parse::<usize>() {
+        return debug_data.get_addr_for_line(None, line_number);
+    }
+    let func_addr = debug_data.get_addr_for_function(None, location)?;
+    Some(
+        debug_data
+            .get_addr_after_prologue(func_addr)
+            .unwrap_or(func_addr),
+    )
+}
+
+/// Describes an address as "function at file:line", falling back to the raw address.
+fn describe_location(debug_data: &DwarfData, addr: usize) -> String {
+    match (
+        debug_data.get_function_from_addr(addr),
+        debug_data.get_line_from_addr(addr),
+    ) {
+        (Some(function), Some(line)) => format!("{} at {}", function, line),
+        (Some(function), None) => format!("{} at {:#x}", function, addr),
+        (None, Some(line)) => format!("{}", line),
+        (None, None) => format!("{:#x}", addr),
+    }
+}
+
+/// Looks the breakpoints up again, the way they were set, in a program that took the place of
+/// the one they were set in (after an exec). Those that aren't in it are deleted, and so are the
+/// watchpoints, whose memory went with the old program. Returns the numbers of all that were
+/// deleted.
+pub fn resolve_breakpoints_again(
+    breakpoints: &mut BreakpointTable,
+    debug_data: &DwarfData,
+) -> Vec<usize> {
+    let mut deleted = breakpoints.resolve_again(|spec| {
+        let addr = resolve_location(debug_data, spec)?;
+        Some((addr, describe_location(debug_data, addr)))
+    });
+    let watchpoints: Vec<usize> = breakpoints.watchpoints().map(|wp| wp.number).collect();
+    for number in watchpoints {
+        breakpoints.remove_watchpoint(number);
+        deleted.push(number);
+    }
+    deleted
+}
+
+/// Finds the executable a running process was started from.
+pub fn process_executable(pid: Pid) -> std::io::Result<String> {
+    let path = std::fs::read_link(format!("/proc/{}/exe", pid))?;
+    Ok(path.to_string_lossy().into_owned())
 }
 
 impl Debugger {
     /// Initializes the debugger.
     pub fn new(target: &str) -> Debugger {
-        let debug_data = match DwarfData::from_file(target) {
+        let debug_data = match load_debug_data(target) {
             Ok(val) => val,
-            Err(DwarfError::ErrorOpeningFile) => {
-                println!("Could not open file {}", target);
-                std::process::exit(1);
-            }
-            Err(DwarfError::DwarfFormatError(err)) => {
-                println!("Could not debugging symbols from {}: {:?}", target, err);
+            Err(err) => {
+                println!("{}", err);
                 std::process::exit(1);
             }
         };
 
         let history_path = format!("{}/.deet_history", std::env::var("HOME").unwrap());
-        let mut readline = Editor::<()>::new();
+        let mut readline = Editor::<DeetHelper>::new();
+        readline.set_helper(Some(DeetHelper::new()));
         // Attempt to load history from ~/.deet_history if it exists
         let _ = readline.load_history(&history_path);
 
@@ -39,113 +199,2917 @@ impl Debugger {
             history_path,
             readline,
             inferior: None,
+            core: None,
             debug_data,
+            breakpoints: BreakpointTable::new(),
+            examine_format: ('x', 4),
+            examine_next: None,
+            selected_frame: 0,
+            signals: SignalTable::new(),
+            fork_policy: ForkPolicy {
+                follow_child: false,
+                detach_on_fork: true,
+            },
+            source_path: SourcePath::new(),
+            listed: None,
+            json: None,
+            user_commands: HashMap::new(),
+            scripts: Vec::new(),
+            breakpoint_actions: None,
+        }
+    }
+
+    /// Switches to the JSON interpreter: commands are read as JSON lines from stdin, and results
+    /// and events are written to stdout as JSON records.
+    pub fn use_json_interpreter(&mut self) -> Result<(), String> {
+        let json = JsonInterpreter::new()
+            .map_err(|e| format!("Could not set up the JSON interpreter: {}", e))?;
+        self.json = Some(RefCell::new(json));
+        Ok(())
+    }
+
+    /// Adds a field to the result of the command being carried out, when talking JSON.
+    fn record(&self, key: &str, value: Value) {
+        if let Some(json) = &self.json {
+            json.borrow_mut().set(key, value);
+        }
+    }
+
+    /// Prints an error message, which also makes the command fail when talking JSON.
+    fn report_error(&self, message: &str) {
+        println!("{}", message);
+        if let Some(json) = &self.json {
+            json.borrow_mut().fail(message);
         }
     }
 
-    fn output_wait_status(status: Status) {
+    fn output_wait_status(&mut self, status: Status) {
+        // Whenever the inferior has run, we are back in the innermost frame
+        self.selected_frame = 0;
+        self.load_shared_libraries();
+        let hits = self.breakpoints.take_watch_hits();
+        self.notify_stop(&status, &hits, false);
+        self.breakpoint_actions = None;
+        for err in self.breakpoints.take_condition_errors() {
+            self.report_error(&err);
+        }
         match status {
             Status::Exited(code) => {
                 println!("Child exited {:?}", code);
+                // The process is gone, so there is nothing left to kill or resume
+                self.inferior = None;
             }
             Status::Signaled(code) => {
                 println!("Child received signal {:?}", code);
+                self.inferior = None;
             }
+            Status::Exec => self.follow_exec(),
             Status::Stopped(sig, address) => {
                 println!(
                     "\nChild stopped at {:#x}, received signal {:?}",
                     address, sig
                 );
+                self.print_watch_hits(&hits);
+                let mut at_breakpoint = false;
+                let mut actions = Vec::new();
+                for breakpoint in self.breakpoints.at(address) {
+                    println!("Breakpoint {}, {}", breakpoint.number, breakpoint.location);
+                    at_breakpoint = true;
+                    actions.extend(breakpoint.commands.iter().cloned());
+                }
+                if !actions.is_empty() {
+                    self.breakpoint_actions = Some(actions);
+                }
+                if !at_breakpoint {
+                    if let Some(line) = self.debug_data.get_line_from_addr(address) {
+                        match self.debug_data.get_function_from_addr(address) {
+                            Some(function) => println!("Stopped at {} ({})", function, line),
+                            None => println!("Stopped at {}", line),
+                        }
+                    }
+                }
+                self.print_source_line(address);
             }
         };
     }
 
     pub fn run(&mut self) {
         loop {
-            match self.get_next_command() {
-                DebuggerCommand::Run(args) => {
-                    if let Some(inferior) = Inferior::new(&self.target, &args) {
-                        // Create the inferior
-                        self.inferior = Some(inferior);
-                        // TODO (milestone 1): make the inferior run
-                        // You may use self.inferior.as_mut().unwrap() to get a mutable reference
-                        // to the Inferior object
-                        let inferior = self.inferior.as_mut().unwrap();
-                        match inferior.continues() {
-                            Err(e) => println!("failed to start {}, err: {}", self.target, e),
-                            Ok(status) => Self::output_wait_status(status),
-                        };
-                    } else {
-                        println!("Error starting subprocess");
-                    }
+            let line = match &self.json {
+                Some(json) => json.borrow_mut().read_command(),
+                None => self.get_next_line(),
+            };
+            if !self.run_command(line) {
+                return;
+            }
+        }
+    }
+
+    /// Carries out a command read from the user (or the JSON client), reporting any error it
+    /// ends with. Returns false once it is time to quit.
+    fn run_command(&mut self, line: Option<String>) -> bool {
+        let result = match line {
+            // A JSON client can send a define or commands block as one multi-line command
+            Some(text) if text.contains('\n') => {
+                let lines = text.lines().map(String::from).collect();
+                self.run_script(Script::new("<command>", lines))
+            }
+            Some(line) => self.run_line(&line),
+            // The end of the input means quit
+            None => Ok(false),
+        };
+        let more = result.unwrap_or_else(|err| {
+            self.report_error(&err);
+            true
+        });
+        if let Some(json) = &self.json {
+            json.borrow_mut().finish();
+        }
+        more
+    }
+
+    /// Carries out a line of input, then the commands of any breakpoint it stopped at. Returns
+    /// false once it is time to quit.
+    fn run_line(&mut self, line: &str) -> Result<bool, String> {
+        if !self.execute_line(line)? {
+            return Ok(false);
+        }
+        if self.breakpoint_actions.is_none() || self.scripts.iter().any(Script::is_actions) {
+            // Breakpoint commands that resume the program leave the commands of the next stop
+            // to the loop below, rather than nesting deeper with every stop
+            return Ok(true);
+        }
+        while let Some(actions) = self.breakpoint_actions.take() {
+            if !self.run_script(Script::actions(actions))? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Carries out a line of input: a built-in command, or one made with `define`.
+    fn execute_line(&mut self, line: &str) -> Result<bool, String> {
+        if script::is_blank(line) {
+            return Ok(true);
+        }
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        if let Some(body) = self.user_commands.get(tokens[0]) {
+            let body = body
+                .iter()
+                .map(|line| script::substitute_args(line, &tokens[1..]))
+                .collect();
+            return self.run_script(Script::new(tokens[0], body));
+        }
+        if let Some(command) = DebuggerCommand::from_tokens(&tokens) {
+            return Ok(self.execute(command));
+        }
+        // Say whether it is the command or its arguments that we don't understand
+        let command = debugger_command::find_command(debugger_command::command_word(tokens[0]))?;
+        Err(format!("Usage: {}", command.usage()))
+    }
+
+    /// Carries out the lines of a script, stopping at the first one that fails. Returns false
+    /// once it is time to quit.
+    fn run_script(&mut self, script: Script) -> Result<bool, String> {
+        if self.scripts.len() >= MAX_SCRIPT_DEPTH {
+            return Err("Max user call depth exceeded -- command aborted.".to_string());
+        }
+        self.scripts.push(script);
+        let result = loop {
+            let script = self.scripts.last_mut().unwrap();
+            let line = match script.next_line() {
+                Some(line) => line,
+                None => break Ok(true),
+            };
+            match self.run_line(&line) {
+                Ok(true) => {}
+                Ok(false) => break Ok(false),
+                Err(err) => {
+                    break Err(format!(
+                        "{}: {}",
+                        self.scripts.last().unwrap().location(),
+                        err
+                    ))
                 }
-                DebuggerCommand::Continue => {
-                    let inferior = self.inferior.as_mut();
-                    match inferior {
-                        Some(inferior) => match inferior.continues() {
-                            Err(e) => println!("failed to continue {}, err: {}", self.target, e),
-                            Ok(status) => Self::output_wait_status(status),
-                        },
-                        None => {
-                            println!("No target running");
-                        }
-                    }
+            }
+            // The program stopped at another breakpoint with commands, which replace the rest
+            // of the ones being run
+            if self.breakpoint_actions.is_some() {
+                break Ok(true);
+            }
+        };
+        self.scripts.pop();
+        result
+    }
+
+    /// Runs the commands in a file. Returns false if one of them was `quit`.
+    pub fn source_file(&mut self, path: &str) -> bool {
+        match Script::from_file(path).and_then(|script| self.run_script(script)) {
+            Ok(more) => more,
+            Err(err) => {
+                self.report_error(&err);
+                true
+            }
+        }
+    }
+
+    /// Runs ~/.deetinit and then ./.deetinit, those of them that exist. Returns false if one of
+    /// them quit.
+    pub fn source_init_files(&mut self) -> bool {
+        let home = format!("{}/.deetinit", std::env::var("HOME").unwrap());
+        let mut paths = vec![home.clone()];
+        // Unless the current directory is the home directory
+        let local = Path::new(".deetinit");
+        if local.canonicalize().ok() != Path::new(&home).canonicalize().ok() {
+            paths.push(".deetinit".to_string());
+        }
+        for path in paths {
+            if Path::new(&path).is_file() && !self.source_file(&path) {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Reads the lines of a `define` or `commands` block, up to the "end", from the script being
+    /// run or else from the user. Returns None if the input ends first.
+    fn read_block(&mut self, intro: &str) -> Option<Vec<String>> {
+        let interactive = self.scripts.is_empty() && self.json.is_none();
+        if interactive {
+            println!("{}\nEnd with a line saying just \"end\".", intro);
+        }
+        let mut reader = BlockReader::new();
+        loop {
+            let line = match self.scripts.last_mut() {
+                Some(script) => script.next_line()?,
+                None if interactive => match self.readline.readline(">") {
+                    Ok(line) => line,
+                    // ctrl+c or ctrl+d gives up on the block
+                    Err(_) => return None,
+                },
+                None => return None,
+            };
+            if let Some(block) = reader.push(&line) {
+                return Some(block);
+            }
+        }
+    }
+
+    /// Lists the commands with a line about each, or describes one in full.
+    fn help(&self, topic: Option<&str>) {
+        let topic = match topic {
+            Some(topic) => topic,
+            None => {
+                println!("List of commands:\n");
+                for command in debugger_command::COMMANDS {
+                    println!(
+                        "{} -- {}",
+                        command.name,
+                        command.doc.lines().next().unwrap()
+                    );
                 }
-                DebuggerCommand::BackTrace => match &self.inferior {
-                    Some(inferior) => {
-                        if let Err(e) = inferior.print_backtrace(&self.debug_data) {
-                            println!("failed to backtrace, error: {}", e);
-                        }
-                    }
-                    None => {
-                        println!("No target running")
+                let mut names: Vec<&String> = self.user_commands.keys().collect();
+                names.sort();
+                if !names.is_empty() {
+                    println!("\nUser-defined commands:\n");
+                    for name in names {
+                        println!("{} -- User-defined.", name);
                     }
-                },
-                DebuggerCommand::Quit => {
-                    return;
                 }
+                println!("\nType \"help\" followed by command name for full documentation.");
+                println!("Command name abbreviations are allowed if unambiguous.");
+                return;
             }
+        };
+        if self.user_commands.contains_key(topic) {
+            println!("User-defined.");
+            return;
+        }
+        match debugger_command::find_command(topic) {
+            Ok(command) => println!("{}", command.describe()),
+            Err(err) => self.report_error(&err),
         }
     }
 
-    /// This function prompts the user to enter a command, and continues re-prompting until the user
-    /// enters a valid command. It uses DebuggerCommand::from_tokens to do the command parsing.
-    ///
-    /// You don't need to read, understand, or modify this function.
-    fn get_next_command(&mut self) -> DebuggerCommand {
-        loop {
-            // Print prompt and get next line of user input
-            match self.readline.readline("(deet) ") {
-                Err(ReadlineError::Interrupted) => {
-                    // User pressed ctrl+c. We're going to ignore it
-                    println!("Type \"quit\" to exit");
+    /// Gives tab completion what it needs to know about the program and the user's commands.
+    fn update_completions(&mut self) {
+        let mut functions = Vec::new();
+        let mut files = Vec::new();
+        let mut variables = Vec::new();
+        for file in self.debug_data.files() {
+            files.push(file.name.clone());
+            // Declarations of functions defined elsewhere (printf in stdio.h) have no address
+            functions.extend(
+                file.functions
+                    .iter()
+                    .filter(|function| function.address != 0)
+                    .map(|function| function.name.clone()),
+            );
+            variables.extend(file.global_variables.iter().map(|var| var.name.clone()));
+        }
+        if self.target().is_some() {
+            if let Ok(frame) = self.selected_frame() {
+                if let Some(function) = self.debug_data.get_function_containing(frame.lookup_pc()) {
+                    variables.extend(function.variables.iter().map(|var| var.name.clone()));
                 }
-                Err(ReadlineError::Eof) => {
-                    // User pressed ctrl+d, which is the equivalent of "quit" for our purposes
-                    return DebuggerCommand::Quit;
+            }
+        }
+        let user_commands = self.user_commands.keys().cloned().collect();
+        if let Some(helper) = self.readline.helper_mut() {
+            helper.user_commands = user_commands;
+            helper.functions = functions;
+            helper.files = files;
+            helper.variables = variables;
+        }
+    }
+
+    /// Skips the block of a `define` or `commands` that failed, so that a script doesn't take its
+    /// lines for commands. The user isn't asked for one in the first place.
+    fn skip_block(&mut self) {
+        if !self.scripts.is_empty() {
+            self.read_block("");
+        }
+    }
+
+    /// Defines a command that runs the block of lines that follows.
+    fn define(&mut self, name: &str) {
+        if let Ok(command) = debugger_command::find_command(name) {
+            if command.is_called(name) {
+                self.report_error(&format!("Command \"{}\" is built-in.", name));
+                self.skip_block();
+                return;
+            }
+        }
+        match self.read_block(&format!("Type commands for definition of \"{}\".", name)) {
+            Some(body) => {
+                self.user_commands.insert(name.to_string(), body);
+            }
+            None => self.report_error(&format!("Definition of \"{}\" not finished.", name)),
+        }
+    }
+
+    /// Gives a breakpoint (the last one set, if `number` is None) the block of lines that follows
+    /// to run whenever it stops the program. An empty block removes its commands.
+    fn breakpoint_commands(&mut self, number: Option<usize>) {
+        let number = number.or_else(|| self.breakpoints.iter().map(|bp| bp.number).max());
+        let error = match number {
+            Some(number) if self.breakpoints.get_mut(number).is_some() => None,
+            Some(number) => Some(format!("No breakpoint number {}.", number)),
+            None => Some("No breakpoints specified.".to_string()),
+        };
+        if let Some(error) = error {
+            self.report_error(&error);
+            self.skip_block();
+            return;
+        }
+        let number = number.unwrap();
+        let intro = format!("Type commands for breakpoint(s) {}, one per line.", number);
+        match self.read_block(&intro) {
+            Some(commands) => self.breakpoints.get_mut(number).unwrap().commands = commands,
+            None => self.report_error("Commands not finished."),
+        }
+    }
+
+    /// Tells a JSON client why and where the program stopped, or that it is gone.
+    fn notify_stop(&self, status: &Status, hits: &[WatchHit], stepping: bool) {
+        if self.json.is_none() {
+            return;
+        }
+        let mut event = Map::new();
+        match *status {
+            Status::Exited(code) => {
+                event.insert("reason".to_string(), json!("exited"));
+                event.insert("exit-code".to_string(), json!(code));
+            }
+            Status::Signaled(signal) => {
+                event.insert("reason".to_string(), json!("exited-signalled"));
+                event.insert("signal".to_string(), json!(signal.as_ref()));
+            }
+            Status::Exec => return,
+            Status::Stopped(signal, address) => {
+                let breakpoints: Vec<usize> =
+                    self.breakpoints.at(address).map(|bp| bp.number).collect();
+                let reason = if !breakpoints.is_empty() {
+                    "breakpoint-hit"
+                } else if !hits.is_empty() {
+                    "watchpoint-trigger"
+                } else if stepping && signal == Signal::SIGTRAP {
+                    "end-stepping-range"
+                } else {
+                    "signal-received"
+                };
+                event.insert("reason".to_string(), json!(reason));
+                event.insert("signal".to_string(), json!(signal.as_ref()));
+                if !breakpoints.is_empty() {
+                    event.insert("breakpoints".to_string(), json!(breakpoints));
                 }
-                Err(err) => {
-                    panic!("Unexpected I/O error: {:?}", err);
+                if !hits.is_empty() {
+                    event.insert("watchpoints".to_string(), self.watch_hits_json(hits));
                 }
-                Ok(line) => {
-                    if line.trim().len() == 0 {
-                        continue;
+                if let Some(inferior) = &self.inferior {
+                    event.insert(
+                        "thread".to_string(),
+                        json!({"id": inferior.current_thread(), "lwp": inferior.tid().as_raw()}),
+                    );
+                }
+                if let Ok(frames) = self.frames(Some(1)) {
+                    event.insert("frame".to_string(), self.frame_json(&frames[0]));
+                }
+            }
+        }
+        if let Some(json) = &self.json {
+            json.borrow_mut().notify("stopped", event);
+        }
+    }
+
+    /// A frame as JSON: its level and address, and the function, arguments and source line,
+    /// where known.
+    fn frame_json(&self, frame: &Frame) -> Value {
+        let pc = frame.lookup_pc();
+        let mut value = json!({"level": frame.level, "addr": format!("{:#x}", frame.pc)});
+        if let Some(function) = self.debug_data.get_function_from_addr(pc) {
+            value["func"] = json!(function);
+        }
+        if let (Some(function), Some(target)) =
+            (self.debug_data.get_function_containing(pc), self.target())
+        {
+            let args: Vec<Value> = function
+                .variables
+                .iter()
+                .filter(|var| var.is_parameter)
+                .map(|var| self.variable_json(target, var, frame))
+                .collect();
+            value["args"] = json!(args);
+        }
+        if let Some(line) = self.debug_data.get_line_from_addr(pc) {
+            value["file"] = json!(line.file);
+            value["line"] = json!(line.number);
+        } else if let Some(path) = self.debug_data.library_path(pc) {
+            value["from"] = json!(path);
+        }
+        value
+    }
+
+    /// A variable as JSON: its name, type and value as seen from `frame`.
+    fn variable_json(&self, target: &dyn Target, var: &Variable, frame: &Frame) -> Value {
+        json!({
+            "name": var.name,
+            "type": self.debug_data.declare(Some(&var.entity_type), ""),
+            "value": self.format_variable(target, var, frame),
+        })
+    }
+
+    /// The watchpoints that triggered, with their old and new values.
+    fn watch_hits_json(&self, hits: &[WatchHit]) -> Value {
+        let hits: Vec<Value> = hits
+            .iter()
+            .filter_map(|hit| {
+                let wp = self
+                    .breakpoints
+                    .watchpoints()
+                    .find(|wp| wp.number == hit.number)?;
+                let format = |bytes: &[u8]| {
+                    value::format_value(bytes, &wp.ty, &self.debug_data, self.target())
+                };
+                let mut value = json!({
+                    "number": wp.number,
+                    "expression": wp.expression,
+                    "new": format(&hit.new),
+                });
+                if let Some(old) = &hit.old {
+                    value["old"] = json!(format(old));
+                }
+                Some(value)
+            })
+            .collect();
+        json!(hits)
+    }
+
+    /// Carries out a command. Returns false once it is time to quit.
+    fn execute(&mut self, command: DebuggerCommand) -> bool {
+        match command {
+            DebuggerCommand::Run(args) => {
+                // Kill any inferior that is already running before starting a new one
+                self.inferior = None;
+                self.core = None;
+                if let Some(mut inferior) = Inferior::new(&self.target, &args) {
+                    // Create the inferior
+                    inferior.set_fork_policy(self.fork_policy);
+                    self.inferior = Some(inferior);
+                    self.locate_program();
+                    self.insert_breakpoints();
+                    self.arm_watchpoints();
+                    let inferior = self.inferior.as_mut().unwrap();
+                    match inferior.continues(&mut self.breakpoints, &self.debug_data, &self.signals)
+                    {
+                        Err(e) => self
+                            .report_error(&format!("failed to start {}, err: {}", self.target, e)),
+                        Ok(status) => self.output_wait_status(status),
+                    };
+                } else {
+                    self.report_error("Error starting subprocess");
+                }
+            }
+            DebuggerCommand::Attach(None) => {
+                self.report_error("Argument required (process-id to attach).")
+            }
+            DebuggerCommand::Attach(Some(pid)) => self.attach(Pid::from_raw(pid)),
+            DebuggerCommand::Detach => match self.inferior.take() {
+                Some(inferior) => {
+                    println!(
+                        "Detaching from program: {}, process {}",
+                        self.target,
+                        inferior.pid()
+                    );
+                    if let Err(e) = inferior.detach() {
+                        self.report_error(&format!("failed to detach, err: {}", e));
                     }
-                    self.readline.add_history_entry(line.as_str());
-                    if let Err(err) = self.readline.save_history(&self.history_path) {
+                }
+                None => self.report_error("The program is not being run."),
+            },
+            DebuggerCommand::GenerateCore(path) => self.generate_core(path),
+            DebuggerCommand::Continue => {
+                let inferior = self.inferior.as_mut();
+                match inferior {
+                    Some(inferior) => match inferior.continues(
+                        &mut self.breakpoints,
+                        &self.debug_data,
+                        &self.signals,
+                    ) {
+                        Err(e) => self.report_error(&format!(
+                            "failed to continue {}, err: {}",
+                            self.target, e
+                        )),
+                        Ok(status) => self.output_wait_status(status),
+                    },
+                    None => {
+                        self.report_error("No target running");
+                    }
+                }
+            }
+            DebuggerCommand::Signal(None) => {
+                self.report_error("Argument required (signal number).")
+            }
+            DebuggerCommand::Signal(Some(text)) => self.resume_with_signal(&text),
+            DebuggerCommand::Handle(args) => self.handle_signals(&args),
+            DebuggerCommand::InfoSignals(text) => self.print_signals(text.as_deref()),
+            DebuggerCommand::BackTrace => match self.target() {
+                Some(inferior) => {
+                    if let Err(e) = inferior.print_backtrace(&self.debug_data) {
+                        self.report_error(&format!("failed to backtrace, error: {}", e));
+                    } else if self.json.is_some() {
+                        if let Ok(frames) = self.frames(None) {
+                            let stack: Vec<Value> =
+                                frames.iter().map(|frame| self.frame_json(frame)).collect();
+                            self.record("stack", json!(stack));
+                        }
+                    }
+                }
+                None => self.report_error("No target running"),
+            },
+            DebuggerCommand::Frame(level) => {
+                self.select_frame(level.unwrap_or(self.selected_frame))
+            }
+            DebuggerCommand::Up(count) => self.move_frame(count as isize),
+            DebuggerCommand::Down(count) => self.move_frame(-(count as isize)),
+            DebuggerCommand::InfoFrame => self.print_frame_info(),
+            DebuggerCommand::Break(None) => {
+                self.report_error("Usage: break <*address|file:line|line|function>");
+            }
+            DebuggerCommand::Break(Some(location)) => {
+                match resolve_location(&self.debug_data, &location) {
+                    Some(addr) => {
+                        let description = describe_location(&self.debug_data, addr);
+                        let breakpoint = self.breakpoints.add(addr, location, description);
                         println!(
-                            "Warning: failed to save history file at {}: {}",
-                            self.history_path, err
+                            "Set breakpoint {} at {:#x}: {}",
+                            breakpoint.number, addr, breakpoint.location
                         );
+                        let value = breakpoint_json(breakpoint);
+                        self.record("breakpoint", value);
+                        self.sync_breakpoint(addr);
                     }
-                    let tokens: Vec<&str> = line.split_whitespace().collect();
-                    if let Some(cmd) = DebuggerCommand::from_tokens(&tokens) {
-                        return cmd;
+                    None => self.report_error(&format!(
+                        "Could not resolve breakpoint location {}",
+                        location
+                    )),
+                }
+            }
+            DebuggerCommand::InfoBreakpoints => self.print_breakpoints(),
+            DebuggerCommand::Delete(numbers) => {
+                // With no arguments, delete every breakpoint
+                let numbers = if numbers.is_empty() {
+                    self.breakpoints.numbers()
+                } else {
+                    numbers
+                };
+                for number in numbers {
+                    if let Some(breakpoint) = self.breakpoints.remove(number) {
+                        self.sync_breakpoint(breakpoint.addr);
+                    } else if let Some(wp) = self.breakpoints.remove_watchpoint(number) {
+                        self.sync_watchpoint(wp.addr, wp.len(), wp.kind);
                     } else {
-                        println!("Unrecognized command.");
+                        self.report_error(&format!("No breakpoint number {}.", number));
+                    }
+                }
+            }
+            DebuggerCommand::Disable(numbers) => self.set_breakpoints_enabled(numbers, false),
+            DebuggerCommand::Enable(numbers) => self.set_breakpoints_enabled(numbers, true),
+            DebuggerCommand::Ignore(number, count) => match self.breakpoints.get_mut(number) {
+                Some(breakpoint) => {
+                    breakpoint.ignore_count = count;
+                    println!(
+                        "Will ignore next {} crossings of breakpoint {}.",
+                        count, number
+                    );
+                }
+                None => self.report_error(&format!("No breakpoint number {}.", number)),
+            },
+            DebuggerCommand::Condition(number, text) => {
+                let condition = match text.as_ref().map(|text| Condition::parse(text)) {
+                    Some(Err(err)) => {
+                        self.report_error(&err);
+                        return true;
+                    }
+                    Some(Ok(condition)) => Some(condition),
+                    None => None,
+                };
+                match self.breakpoints.get_mut(number) {
+                    Some(breakpoint) => {
+                        if condition.is_none() {
+                            println!("Breakpoint {} now unconditional.", number);
+                        }
+                        breakpoint.condition = condition;
                     }
+                    None => self.report_error(&format!("No breakpoint number {}.", number)),
+                }
+            }
+            DebuggerCommand::Watch(_, None) => {
+                self.report_error("Argument required (expression to compute).")
+            }
+            DebuggerCommand::Watch(kind, Some(text)) => self.watch(kind, &text),
+            DebuggerCommand::Step => self.step(|inferior, breakpoints, debug_data, signals| {
+                inferior.step_line(false, breakpoints, debug_data, signals)
+            }),
+            DebuggerCommand::Next => self.step(|inferior, breakpoints, debug_data, signals| {
+                inferior.step_line(true, breakpoints, debug_data, signals)
+            }),
+            DebuggerCommand::StepInstruction => {
+                self.step(|inferior, breakpoints, debug_data, signals| {
+                    inferior.step_instruction(false, breakpoints, debug_data, signals)
+                })
+            }
+            DebuggerCommand::NextInstruction => {
+                self.step(|inferior, breakpoints, debug_data, signals| {
+                    inferior.step_instruction(true, breakpoints, debug_data, signals)
+                })
+            }
+            DebuggerCommand::Finish => self.finish(),
+            DebuggerCommand::Print(None) => self.report_error("Usage: print <expression>"),
+            DebuggerCommand::Print(Some(text)) => self.print_expression(&text),
+            DebuggerCommand::Set(text) => self.set_variable(&text),
+            DebuggerCommand::SetOption(name, value) => self.set_option(&name, value.as_deref()),
+            DebuggerCommand::Show(name) => self.show_option(&name),
+            DebuggerCommand::List(location) => self.list(location.as_deref()),
+            DebuggerCommand::Directory(Some(dir)) => {
+                self.source_path.add_directory(&dir);
+                println!("{}", self.source_path.describe());
+            }
+            DebuggerCommand::Directory(None) => {
+                self.source_path.reset();
+                println!("{}", self.source_path.describe());
+            }
+            DebuggerCommand::Examine(format, location) => self.examine_memory(format, location),
+            DebuggerCommand::Disassemble(options, location) => {
+                self.disassemble(options, location.as_deref())
+            }
+            DebuggerCommand::Ptype(None) => self.report_error("Usage: ptype <expression>"),
+            DebuggerCommand::Ptype(Some(text)) => self.print_expression_type(&text),
+            DebuggerCommand::InfoLocals => self.print_frame_variables(false),
+            DebuggerCommand::InfoArgs => self.print_frame_variables(true),
+            DebuggerCommand::InfoRegisters(names) => self.print_registers(&names, false),
+            DebuggerCommand::InfoAllRegisters => self.print_registers(&[], true),
+            DebuggerCommand::InfoThreads => self.print_threads(),
+            DebuggerCommand::Thread(None) => match &self.inferior {
+                Some(inferior) => println!(
+                    "[Current thread is {} (LWP {})]",
+                    inferior.current_thread(),
+                    inferior.tid()
+                ),
+                None => self.report_error("No thread selected."),
+            },
+            DebuggerCommand::Thread(Some(number)) => self.select_thread(number),
+            DebuggerCommand::ThreadApply(numbers, command) => {
+                return self.thread_apply(numbers, *command)
+            }
+            DebuggerCommand::Source(None) => {
+                self.report_error("source command requires file name of file to source.")
+            }
+            DebuggerCommand::Source(Some(path)) => return self.source_file(&path),
+            DebuggerCommand::Define(None) => {
+                self.report_error("Argument required (name of command to define).")
+            }
+            DebuggerCommand::Define(Some(name)) => self.define(&name),
+            DebuggerCommand::Commands(number) => self.breakpoint_commands(number),
+            DebuggerCommand::Help(topic) => self.help(topic.as_deref()),
+            DebuggerCommand::Quit => return false,
+        }
+        true
+    }
+
+    /// Runs one of the Inferior's stepping operations and reports where we ended up.
+    fn step<F>(&mut self, step_fn: F)
+    where
+        F: FnOnce(
+            &mut Inferior,
+            &mut BreakpointTable,
+            &DwarfData,
+            &SignalTable,
+        ) -> Result<Status, nix::Error>,
+    {
+        let inferior = match self.inferior.as_mut() {
+            Some(inferior) => inferior,
+            None => {
+                self.report_error("The program is not being run.");
+                return;
+            }
+        };
+        match step_fn(
+            inferior,
+            &mut self.breakpoints,
+            &self.debug_data,
+            &self.signals,
+        ) {
+            Err(e) => self.report_error(&format!("failed to step {}, err: {}", self.target, e)),
+            Ok(status) => self.output_step_status(status),
+        }
+    }
+
+    /// Like output_wait_status, but a plain SIGTRAP is the expected outcome of a step, so we only
+    /// report the new location.
+    fn output_step_status(&mut self, status: Status) {
+        match status {
+            Status::Stopped(nix::sys::signal::Signal::SIGTRAP, address)
+                if self.breakpoints.at(address).next().is_none() =>
+            {
+                self.load_shared_libraries();
+                let hits = self.breakpoints.take_watch_hits();
+                self.notify_stop(&status, &hits, true);
+                self.print_watch_hits(&hits);
+                if self.debug_data.is_line_start(address) {
+                    println!("{}", describe_location(&self.debug_data, address));
+                } else {
+                    println!(
+                        "{:#x} in {}",
+                        address,
+                        describe_location(&self.debug_data, address)
+                    );
+                }
+                self.print_source_line(address);
+            }
+            other => self.output_wait_status(other),
+        }
+    }
+
+    /// Resumes the inferior with a signal of our choosing (`signal SIG`), or with none at all
+    /// (`signal 0`).
+    fn resume_with_signal(&mut self, text: &str) {
+        let signal = if text == "0" {
+            None
+        } else {
+            match signals::parse_signal(text) {
+                Some(signal) => Some(signal),
+                None => {
+                    self.report_error(&format!("Bad signal name: {}", text));
+                    return;
+                }
+            }
+        };
+        let inferior = match self.inferior.as_mut() {
+            Some(inferior) => inferior,
+            None => {
+                self.report_error("The program is not being run.");
+                return;
+            }
+        };
+        match signal {
+            Some(signal) => println!("Continuing with signal {}.", signal.as_ref()),
+            None => println!("Continuing with no signal."),
+        }
+        inferior.set_pending_signal(signal);
+        match inferior.continues(&mut self.breakpoints, &self.debug_data, &self.signals) {
+            Err(e) => self.report_error(&format!("failed to continue {}, err: {}", self.target, e)),
+            Ok(status) => self.output_wait_status(status),
+        }
+    }
+
+    /// Changes what happens when the inferior receives the signals named in `args` (`handle
+    /// SIGUSR1 nostop noprint`), then shows their settings. "all" stands for every signal the
+    /// debugger doesn't use itself.
+    fn handle_signals(&mut self, args: &[String]) {
+        let mut targets = Vec::new();
+        let mut keywords = Vec::new();
+        for arg in args {
+            if arg == "all" {
+                targets.extend(
+                    Signal::iterator()
+                        .filter(|signal| *signal != Signal::SIGTRAP && *signal != Signal::SIGINT),
+                );
+            } else if let Some(signal) = signals::parse_signal(arg) {
+                targets.push(signal);
+            } else {
+                keywords.push(arg.as_str());
+            }
+        }
+        if targets.is_empty() {
+            self.report_error("Argument required (signal to handle).");
+            return;
+        }
+        for keyword in keywords {
+            for signal in &targets {
+                if let Err(err) = self.signals.update(*signal, keyword) {
+                    self.report_error(&err);
+                    return;
+                }
+            }
+        }
+        println!("{}", signals::format_signal_table(&self.signals, &targets));
+    }
+
+    /// Shows the `handle` settings of one signal, or of all of them (`info signals`).
+    fn print_signals(&self, text: Option<&str>) {
+        let targets: Vec<Signal> = match text {
+            Some(text) => match signals::parse_signal(text) {
+                Some(signal) => vec![signal],
+                None => {
+                    self.report_error("Only signals 1-15 are valid as numeric signals.");
+                    return;
+                }
+            },
+            None => Signal::iterator().collect(),
+        };
+        println!("{}", signals::format_signal_table(&self.signals, &targets));
+    }
+
+    /// Lists the inferior's threads (`info threads`), marking the current one with a `*`.
+    fn print_threads(&mut self) {
+        let inferior = match self.inferior.as_mut() {
+            Some(inferior) => inferior,
+            None => {
+                println!("No threads.");
+                return;
+            }
+        };
+        let current = inferior.current_thread();
+        let threads = inferior.threads();
+        println!("  Id   Target Id                 Frame");
+        let mut records = Vec::new();
+        for (number, tid) in threads {
+            let inferior = self.inferior.as_mut().unwrap();
+            inferior.select_thread(number);
+            let name = inferior.thread_name(tid).unwrap_or_default();
+            let target_id = format!("LWP {} \"{}\"", tid, name);
+            let mut record = json!({
+                "id": number,
+                "lwp": tid.as_raw(),
+                "name": name,
+                "current": number == current,
+            });
+            let frame = match inferior.frames(&self.debug_data, Some(1)) {
+                Ok(frames) => {
+                    record["frame"] = self.frame_json(&frames[0]);
+                    self.summarize_frame(&frames[0])
+                }
+                Err(e) => format!("<unavailable: {}>", e),
+            };
+            records.push(record);
+            println!(
+                "{} {:<4} {:<25} {}",
+                if number == current { "*" } else { " " },
+                number,
+                target_id,
+                frame
+            );
+        }
+        self.inferior.as_mut().unwrap().select_thread(current);
+        self.record("threads", json!(records));
+    }
+
+    /// Switches to another thread (`thread N`), whose innermost frame becomes the selected frame.
+    fn select_thread(&mut self, number: usize) {
+        let inferior = match self.inferior.as_mut() {
+            Some(inferior) => inferior,
+            None => {
+                self.report_error("No threads.");
+                return;
+            }
+        };
+        if !inferior.select_thread(number) {
+            self.report_error(&format!("Invalid thread ID: {}", number));
+            return;
+        }
+        println!("[Switching to thread {} (LWP {})]", number, inferior.tid());
+        let thread = json!({"id": number, "lwp": inferior.tid().as_raw()});
+        self.record("thread", thread);
+        self.selected_frame = 0;
+        match self.selected_frame() {
+            Ok(frame) => {
+                println!("{}", self.describe_frame(&frame));
+                self.record("frame", self.frame_json(&frame));
+                self.print_source_line(frame.lookup_pc());
+            }
+            Err(err) => self.report_error(&err),
+        }
+    }
+
+    /// Runs a command in each of the given threads (`thread apply 1 2 bt`), or in all of them,
+    /// newest first, if no numbers are given (`thread apply all bt`). The current thread and
+    /// frame are restored afterwards. Returns false if the command was `quit`.
+    fn thread_apply(&mut self, numbers: Option<Vec<usize>>, command: DebuggerCommand) -> bool {
+        let (current, threads) = match &self.inferior {
+            Some(inferior) => (inferior.current_thread(), inferior.threads()),
+            None => {
+                self.report_error("No threads.");
+                return true;
+            }
+        };
+        let numbers =
+            numbers.unwrap_or_else(|| threads.iter().rev().map(|(number, _)| *number).collect());
+        let selected_frame = self.selected_frame;
+        for number in numbers {
+            let tid = match threads.iter().find(|(n, _)| *n == number) {
+                Some((_, tid)) => *tid,
+                None => {
+                    self.report_error(&format!("Invalid thread ID: {}", number));
+                    continue;
                 }
+            };
+            match self.inferior.as_mut() {
+                Some(inferior) => inferior.select_thread(number),
+                // The command ended the process
+                None => break,
+            };
+            self.selected_frame = 0;
+            println!("\nThread {} (LWP {}):", number, tid);
+            if !self.execute(command.clone()) {
+                return false;
+            }
+        }
+        if let Some(inferior) = self.inferior.as_mut() {
+            if inferior.select_thread(current) {
+                self.selected_frame = selected_frame;
             }
         }
+        true
+    }
+
+    /// Loads the symbols of `exe` if it isn't the program we have been debugging.
+    fn switch_program(&mut self, exe: String) -> Result<(), String> {
+        let canonical = |path: &str| std::fs::canonicalize(path).ok();
+        if exe == self.target
+            || canonical(&exe).is_some() && canonical(&exe) == canonical(&self.target)
+        {
+            return Ok(());
+        }
+        self.debug_data = load_debug_data(&exe)?;
+        // Their addresses meant something in the old program, so look them up again in this one
+        for number in resolve_breakpoints_again(&mut self.breakpoints, &self.debug_data) {
+            println!("Deleting breakpoint {}, which isn't in {}.", number, exe);
+        }
+        self.target = exe;
+        Ok(())
+    }
+
+    /// Catches up with an exec: switches to the new program's symbols, puts the breakpoints
+    /// (which went with the old memory image) back, and carries on running.
+    fn follow_exec(&mut self) {
+        let pid = match &self.inferior {
+            Some(inferior) => inferior.pid(),
+            None => return,
+        };
+        match process_executable(pid) {
+            Ok(exe) => {
+                println!("process {} is executing new program: {}", pid, exe);
+                if let Err(err) = self.switch_program(exe) {
+                    self.report_error(&err);
+                }
+            }
+            Err(e) => self.report_error(&format!(
+                "Could not find the executable of process {}: {}",
+                pid, e
+            )),
+        }
+        self.locate_program();
+        self.insert_breakpoints();
+        self.arm_watchpoints();
+        let inferior = self.inferior.as_mut().unwrap();
+        match inferior.continues(&mut self.breakpoints, &self.debug_data, &self.signals) {
+            Err(e) => self.report_error(&format!("failed to continue {}, err: {}", self.target, e)),
+            Ok(status) => self.output_wait_status(status),
+        }
+    }
+
+    /// Works out where the program and its shared libraries are loaded in a new (or newly
+    /// exec'd) inferior. A position-independent executable isn't loaded at its link time
+    /// address, so its symbols move there, along with breakpoints and watchpoints that were set
+    /// before it ran.
+    fn locate_program(&mut self) {
+        let entry = match self.target() {
+            Some(target) => solib::entry_point(target),
+            None => return,
+        };
+        if let Some(entry) = entry {
+            let bias = entry.wrapping_sub(self.debug_data.entry());
+            let delta = bias.wrapping_sub(self.debug_data.bias());
+            self.breakpoints
+                .relocate(self.debug_data.address_range(), delta);
+            self.debug_data.relocate(bias);
+        }
+        self.load_shared_libraries();
+    }
+
+    /// Catches up with the shared libraries the inferior has loaded or unloaded since we last
+    /// looked.
+    fn load_shared_libraries(&mut self) {
+        if let Some(inferior) = &self.inferior {
+            self.debug_data
+                .update_libraries(&inferior.shared_libraries());
+        } else if let Some(core) = &self.core {
+            if let Some(linker) = solib::dynamic_linker(core) {
+                self.debug_data
+                    .update_libraries(&solib::loaded_libraries(core, &linker));
+            }
+        }
+    }
+
+    /// Patches the enabled breakpoints into a new (or newly exec'd) inferior. Those in shared
+    /// libraries that haven't been loaded yet are put in by the inferior when they are.
+    fn insert_breakpoints(&mut self) {
+        let inferior = match self.inferior.as_mut() {
+            Some(inferior) => inferior,
+            None => return,
+        };
+        let mut failed = Vec::new();
+        for addr in self.breakpoints.enabled_addrs() {
+            if let Err(e) = inferior.insert_breakpoint(addr) {
+                if self.debug_data.address_range().contains(&addr) {
+                    failed.push(format!("Failed to set breakpoint at {:#x}: {}", addr, e));
+                }
+            }
+        }
+        for message in failed {
+            self.report_error(&message);
+        }
+    }
+
+    /// Changes one of the settings `set` knows about besides variables.
+    fn set_option(&mut self, name: &str, value: Option<&str>) {
+        match (name, value) {
+            ("follow-fork-mode", Some("parent")) => self.fork_policy.follow_child = false,
+            ("follow-fork-mode", Some("child")) => self.fork_policy.follow_child = true,
+            ("follow-fork-mode", _) => {
+                self.report_error("Requires an argument. Valid arguments are child, parent.");
+                return;
+            }
+            ("detach-on-fork", Some("on")) | ("detach-on-fork", None) => {
+                self.fork_policy.detach_on_fork = true
+            }
+            ("detach-on-fork", Some("off")) => self.fork_policy.detach_on_fork = false,
+            ("detach-on-fork", Some(_)) => {
+                self.report_error("\"on\" or \"off\" expected.");
+                return;
+            }
+            _ => {
+                self.report_error(&format!("No setting named \"{}\".", name));
+                return;
+            }
+        }
+        if let Some(inferior) = self.inferior.as_mut() {
+            inferior.set_fork_policy(self.fork_policy);
+        }
+    }
+
+    fn show_option(&self, name: &str) {
+        match name {
+            "follow-fork-mode" => println!(
+                "Debugger response to a program call of fork or vfork is \"{}\".",
+                if self.fork_policy.follow_child {
+                    "child"
+                } else {
+                    "parent"
+                }
+            ),
+            "detach-on-fork" => println!(
+                "Whether deet will detach the child of a fork is {}.",
+                if self.fork_policy.detach_on_fork {
+                    "on"
+                } else {
+                    "off"
+                }
+            ),
+            "directories" => println!("{}", self.source_path.describe()),
+            _ => self.report_error(&format!("No setting named \"{}\".", name)),
+        }
+    }
+
+    /// Attaches to a running process. If it is running a different program from the one we were
+    /// debugging, that program's symbols are loaded instead.
+    pub fn attach(&mut self, pid: Pid) {
+        // Kill (or detach from) whatever we were debugging before
+        self.inferior = None;
+        self.core = None;
+        let exe = match process_executable(pid) {
+            Ok(exe) => exe,
+            Err(e) => {
+                self.report_error(&format!(
+                    "Could not find the executable of process {}: {}",
+                    pid, e
+                ));
+                return;
+            }
+        };
+        if let Err(err) = self.switch_program(exe) {
+            self.report_error(&err);
+            return;
+        }
+        println!("Attaching to program: {}, process {}", self.target, pid);
+        match Inferior::attach(pid) {
+            Ok(mut inferior) => {
+                inferior.set_fork_policy(self.fork_policy);
+                self.inferior = Some(inferior);
+                self.selected_frame = 0;
+                self.locate_program();
+                self.insert_breakpoints();
+                self.arm_watchpoints();
+                match self.selected_frame() {
+                    Ok(frame) => {
+                        println!("{}", self.describe_frame(&frame));
+                        self.print_source_line(frame.lookup_pc());
+                    }
+                    Err(err) => self.report_error(&err),
+                }
+            }
+            Err(e) => self.report_error(&format!("ptrace: {}", e)),
+        }
+    }
+
+    /// Loads a core dump for post-mortem debugging (`deet <program> --core <corefile>`) and shows
+    /// where the program was when it died.
+    pub fn load_core(&mut self, path: &str) {
+        let core = match CoreFile::open(path) {
+            Ok(core) => core,
+            Err(err) => {
+                self.report_error(&err);
+                return;
+            }
+        };
+        self.inferior = None;
+        println!("Core was generated by `{}'.", core.command);
+        // A core written by gcore is of a process that was stopped rather than killed
+        match Signal::try_from(core.signal) {
+            _ if core.signal == 0 => {}
+            Ok(signal) => println!(
+                "Program terminated with signal {}, {}.",
+                signal,
+                signals::describe_signal(signal)
+            ),
+            Err(_) => println!("Program terminated with signal {}.", core.signal),
+        }
+        self.core = Some(core);
+        self.selected_frame = 0;
+        self.locate_program();
+        match self.selected_frame() {
+            Ok(frame) => {
+                println!("{}", self.describe_frame(&frame));
+                self.print_source_line(frame.lookup_pc());
+            }
+            Err(err) => self.report_error(&err),
+        }
+    }
+
+    /// Reads the lines of a source file named in the debugging information.
+    fn source_lines(&self, file: &str) -> Result<Vec<String>, String> {
+        match self.debug_data.get_file(file) {
+            Some(unit) => self
+                .source_path
+                .read_lines(&unit.name, Some(&unit.comp_dir)),
+            None => self.source_path.read_lines(file, None),
+        }
+    }
+
+    /// Prints the source line at `addr`, which is how gdb follows up a stop or a change of frame.
+    /// Nothing is printed when there is no line information or the source can't be found.
+    fn print_source_line(&mut self, addr: usize) {
+        self.listed = None;
+        let line = match self.debug_data.get_line_from_addr(addr) {
+            Some(line) => line,
+            None => return,
+        };
+        if let Ok(lines) = self.source_lines(&line.file) {
+            if let Some(text) = lines.get(line.number.wrapping_sub(1)) {
+                println!("{}\t{}", line.number, text);
+            }
+        }
+    }
+
+    /// Works out which file and line `list <location>` means: `file:line`, a line of the file
+    /// listed last, or a function.
+    fn resolve_list_location(&self, location: &str) -> Result<(String, usize), String> {
+        if let Some(colon) = location.rfind(':') {
+            let file = &location[..colon];
+            let number = location[colon + 1..]
+                .parse()
+                .map_err(|_| format!("malformed line number in \"{}\"", location))?;
+            return match self.debug_data.get_file(file) {
+                Some(unit) => Ok((unit.name.clone(), number)),
+                None => Err(format!("No source file named {}.", file)),
+            };
+        }
+        if let Ok(number) = location.parse() {
+            let file = match &self.listed {
+                Some((file, _, _)) => file.clone(),
+                None => self.default_list_location()?.0,
+            };
+            return Ok((file, number));
+        }
+        self.debug_data
+            .get_addr_for_function(None, location)
+            .and_then(|addr| self.debug_data.get_line_from_addr(addr))
+            .map(|line| (line.file, line.number))
+            .ok_or_else(|| format!("Function \"{}\" not defined.", location))
+    }
+
+    /// Where a `list` with nothing to carry on from looks: the selected frame if there is a
+    /// program (running or dumped), and otherwise `main`.
+    fn default_list_location(&self) -> Result<(String, usize), String> {
+        let addr = match self.selected_frame() {
+            Ok(frame) => Some(frame.lookup_pc()),
+            Err(_) => self.debug_data.get_addr_for_function(None, "main"),
+        };
+        addr.and_then(|addr| self.debug_data.get_line_from_addr(addr))
+            .map(|line| (line.file, line.number))
+            .ok_or_else(|| "No default source file.".to_string())
+    }
+
+    /// Lists ten lines of source (`list`): around a location, after the lines listed last, or
+    /// with `list -`, before them.
+    fn list(&mut self, location: Option<&str>) {
+        // The line we are interested in comes sixth, like in gdb
+        let around = |(file, number): (String, usize)| {
+            let first = number.saturating_sub(5).max(1);
+            (file, first, first + 9)
+        };
+        let range = match (location, &self.listed) {
+            (None, Some((file, _, last))) => Ok((file.clone(), last + 1, last + 10)),
+            (Some("-"), Some((file, 1, _))) => Err(format!("Already at the start of {}.", file)),
+            (Some("-"), Some((file, first, _))) => {
+                Ok((file.clone(), first.saturating_sub(10).max(1), first - 1))
+            }
+            (None, None) | (Some("-"), None) => self.default_list_location().map(around),
+            (Some(location), _) => self.resolve_list_location(location).map(around),
+        };
+        let (file, first, last) = match range {
+            Ok(range) => range,
+            Err(err) => {
+                self.report_error(&err);
+                return;
+            }
+        };
+        let lines = match self.source_lines(&file) {
+            Ok(lines) => lines,
+            Err(err) => {
+                self.report_error(&err);
+                return;
+            }
+        };
+        if first > lines.len() {
+            println!(
+                "Line number {} out of range; \"{}\" has {} lines.",
+                first,
+                file,
+                lines.len()
+            );
+            return;
+        }
+        let last = last.min(lines.len());
+        for number in first..=last {
+            println!("{}\t{}", number, lines[number - 1]);
+        }
+        self.listed = Some((file, first, last));
+    }
+
+    /// Writes a core file of the inferior (`gcore [file]`), by default to core.<pid> like gdb.
+    fn generate_core(&self, path: Option<String>) {
+        let inferior = match &self.inferior {
+            Some(inferior) => inferior,
+            None => {
+                self.report_error("The program is not being run.");
+                return;
+            }
+        };
+        let path = path.unwrap_or_else(|| format!("core.{}", inferior.pid()));
+        let result = inferior
+            .thread_states()
+            .map_err(|e| format!("Can't read the registers: {}", e))
+            .and_then(|threads| coredump::write_core(&path, inferior, inferior.pid(), &threads));
+        match result {
+            Ok(()) => println!("Saved corefile {}", path),
+            Err(err) => self.report_error(&err),
+        }
+    }
+
+    /// What commands that only look at the program see: the live process if there is one, or
+    /// else the core dump.
+    fn target(&self) -> Option<&dyn Target> {
+        match (&self.inferior, &self.core) {
+            (Some(inferior), _) => Some(inferior),
+            (None, Some(core)) => Some(core),
+            (None, None) => None,
+        }
+    }
+
+    /// Unwinds up to `limit` frames of the program's stack.
+    fn frames(&self, limit: Option<usize>) -> Result<Vec<Frame>, String> {
+        match self.target() {
+            Some(target) => target
+                .frames(&self.debug_data, limit)
+                .map_err(|e| format!("failed to unwind the stack, err: {}", e)),
+            None => Err("No stack.".to_string()),
+        }
+    }
+
+    fn selected_frame(&self) -> Result<Frame, String> {
+        let level = self.selected_frame;
+        self.frames(Some(level + 1))?
+            .into_iter()
+            .nth(level)
+            .ok_or_else(|| format!("No frame at level {}.", level))
+    }
+
+    /// An evaluator for expressions the user types, which see the selected frame's variables.
+    fn evaluator(&self) -> Evaluator<'_> {
+        Evaluator::new(&self.debug_data, self.target()).in_frame(self.selected_frame().ok())
+    }
+
+    /// Describes a frame the way `frame` and `up` print it: "#1  0x401176 in depth (n=1) at
+    /// samples/bt.c:16".
+    fn describe_frame(&self, frame: &Frame) -> String {
+        format!("#{:<2} {}", frame.level, self.summarize_frame(frame))
+    }
+
+    /// Describes where a frame is, and the arguments it was called with. The address is left out
+    /// when the innermost frame is at the start of a line.
+    fn summarize_frame(&self, frame: &Frame) -> String {
+        let pc = frame.lookup_pc();
+        let mut description = String::new();
+        if frame.level > 0 || !self.debug_data.is_line_start(frame.pc) {
+            description += &format!("{:#018x} in ", frame.pc);
+        }
+        match (self.debug_data.get_function_containing(pc), self.target()) {
+            (Some(function), Some(inferior)) => {
+                let args: Vec<String> = function
+                    .variables
+                    .iter()
+                    .filter(|var| var.is_parameter)
+                    .map(|var| {
+                        format!(
+                            "{}={}",
+                            var.name,
+                            self.format_variable(inferior, var, frame)
+                        )
+                    })
+                    .collect();
+                description += &format!("{} ({})", function.name, args.join(", "));
+            }
+            _ => {
+                description += &format!(
+                    "{} ()",
+                    self.debug_data
+                        .get_function_from_addr(pc)
+                        .unwrap_or_else(|| "??".to_string())
+                )
+            }
+        }
+        if let Some(line) = self.debug_data.get_line_from_addr(pc) {
+            description += &format!(" at {}", line);
+        } else if let Some(path) = self.debug_data.library_path(pc) {
+            description += &format!(" from {}", path);
+        }
+        description
+    }
+
+    /// Selects the frame at `level` (`frame N`) and prints it.
+    fn select_frame(&mut self, level: usize) {
+        match self.frames(Some(level + 1)) {
+            Ok(frames) => match frames.get(level) {
+                Some(frame) => {
+                    self.selected_frame = level;
+                    println!("{}", self.describe_frame(frame));
+                    self.record("frame", self.frame_json(frame));
+                    self.print_source_line(frame.lookup_pc());
+                }
+                None => self.report_error(&format!("No frame at level {}.", level)),
+            },
+            Err(err) => self.report_error(&err),
+        }
+    }
+
+    /// Moves the selection `delta` frames outwards (`up`) or inwards (`down`), stopping at either
+    /// end of the stack.
+    fn move_frame(&mut self, delta: isize) {
+        let frames = match self.frames(None) {
+            Ok(frames) => frames,
+            Err(err) => {
+                self.report_error(&err);
+                return;
+            }
+        };
+        if delta > 0 && self.selected_frame + 1 >= frames.len() {
+            self.report_error("Initial frame selected; you cannot go up.");
+            return;
+        }
+        if delta < 0 && self.selected_frame == 0 {
+            self.report_error("Bottom (innermost) frame selected; you cannot go down.");
+            return;
+        }
+        let level = (self.selected_frame as isize + delta).max(0) as usize;
+        self.selected_frame = level.min(frames.len() - 1);
+        let frame = &frames[self.selected_frame];
+        println!("{}", self.describe_frame(frame));
+        self.record("frame", self.frame_json(frame));
+        self.print_source_line(frame.lookup_pc());
+    }
+
+    /// Prints `info frame`: where the selected frame lives on the stack, its neighbours, its
+    /// arguments and where it saved its caller's registers.
+    fn print_frame_info(&self) {
+        let inferior = match self.target() {
+            Some(inferior) => inferior,
+            None => {
+                self.report_error("No stack.");
+                return;
+            }
+        };
+        let level = self.selected_frame;
+        let frames = match self.frames(Some(level + 2)) {
+            Ok(frames) => frames,
+            Err(err) => {
+                self.report_error(&err);
+                return;
+            }
+        };
+        let frame = match frames.get(level) {
+            Some(frame) => frame,
+            None => {
+                self.report_error(&format!("No frame at level {}.", level));
+                return;
+            }
+        };
+        let caller = frames.get(level + 1);
+        let describe_cfa = |frame: &Frame| match frame.cfa {
+            Some(cfa) => format!("{:#x}", cfa),
+            None => "<unknown>".to_string(),
+        };
+
+        self.record("frame", self.frame_json(frame));
+        println!("Stack level {}, frame at {}:", level, describe_cfa(frame));
+        let pc = frame.lookup_pc();
+        let function = self.debug_data.get_function_containing(pc);
+        print!(" rip = {:#x}", frame.pc);
+        if let Some(name) = self.debug_data.get_function_from_addr(pc) {
+            print!(" in {}", name);
+        }
+        if let Some(line) = self.debug_data.get_line_from_addr(pc) {
+            print!(" ({})", line);
+        }
+        match caller {
+            Some(caller) => println!("; saved rip = {:#x}", caller.pc),
+            None => println!(),
+        }
+        let mut neighbours = Vec::new();
+        if let Some(caller) = caller {
+            neighbours.push(format!("called by frame at {}", describe_cfa(caller)));
+        }
+        if level > 0 {
+            neighbours.push(format!(
+                "caller of frame at {}",
+                describe_cfa(&frames[level - 1])
+            ));
+        }
+        if !neighbours.is_empty() {
+            println!(" {}", neighbours.join(", "));
+        }
+        if let Some(rbp) = frame.register("rbp") {
+            let args = match function {
+                Some(function) => function
+                    .variables
+                    .iter()
+                    .filter(|var| var.is_parameter)
+                    .map(|var| {
+                        format!(
+                            "{}={}",
+                            var.name,
+                            self.format_variable(inferior, var, frame)
+                        )
+                    })
+                    .collect::<Vec<String>>()
+                    .join(", "),
+                None => String::new(),
+            };
+            println!(" Arglist at {:#x}, args: {}", rbp, args);
+            print!(" Locals at {:#x},", rbp);
+        }
+        match frame.cfa {
+            Some(cfa) => println!(" Previous frame's sp is {:#x}", cfa),
+            None => println!(" Previous frame's sp is unknown"),
+        }
+        if !frame.saved_registers.is_empty() {
+            println!(" Saved registers:");
+            let saved: Vec<String> = frame
+                .saved_registers
+                .iter()
+                .map(|(name, addr)| format!("{} at {:#x}", name, addr))
+                .collect();
+            println!("  {}", saved.join(", "));
+        }
+    }
+
+    /// Runs until the selected frame returns to its caller.
+    fn finish(&mut self) {
+        if self.inferior.is_none() {
+            self.report_error("The program is not being run.");
+            return;
+        }
+        let frame = match self.selected_frame() {
+            Ok(frame) => frame,
+            Err(err) => {
+                self.report_error(&err);
+                return;
+            }
+        };
+        // There has to be a caller to return to
+        let has_caller = self
+            .frames(Some(frame.level + 2))
+            .is_ok_and(|frames| frames.len() > frame.level + 1);
+        if !has_caller {
+            self.report_error("\"finish\" not meaningful in the outermost frame.");
+            return;
+        }
+        let pc = frame.lookup_pc();
+        println!("Run till exit from {}", self.describe_frame(&frame));
+        let return_type = self
+            .debug_data
+            .get_function_containing(pc)
+            .and_then(|function| function.return_type.clone());
+        let inferior = self.inferior.as_mut().unwrap();
+        match inferior.finish(
+            &mut self.breakpoints,
+            &self.debug_data,
+            &self.signals,
+            frame.level,
+        ) {
+            Err(e) => self.report_error(&format!("failed to finish, err: {}", e)),
+            Ok(status) => {
+                let returned = match status {
+                    Status::Stopped(nix::sys::signal::Signal::SIGTRAP, address) => {
+                        self.breakpoints.at(address).next().is_none()
+                    }
+                    _ => false,
+                };
+                self.output_step_status(status);
+                if returned {
+                    self.print_return_value(return_type);
+                }
+            }
+        }
+    }
+
+    /// Prints the value a function just returned: integers, enums and pointers come back in rax,
+    /// and floats and doubles in xmm0. Other types (long doubles, structs) aren't shown.
+    fn print_return_value(&self, return_type: Option<Type>) {
+        let (inferior, return_type) = match (&self.inferior, return_type) {
+            (Some(inferior), Some(return_type)) => (inferior, return_type),
+            // void functions have nothing to show
+            _ => return,
+        };
+        let is_float = matches!(
+            self.debug_data.resolve_type(&return_type).kind,
+            TypeKind::Base(Encoding::Float)
+        );
+        let bytes = if value::is_integral(&return_type, &self.debug_data) {
+            match inferior.get_registers() {
+                Ok(regs) => regs.rax.to_le_bytes(),
+                Err(_) => return,
+            }
+        } else if is_float {
+            match inferior.get_fp_registers() {
+                // The low 64 bits of xmm0
+                Ok(fpregs) => (u64::from(fpregs.xmm_space[1]) << 32
+                    | u64::from(fpregs.xmm_space[0]))
+                .to_le_bytes(),
+                Err(_) => return,
+            }
+        } else {
+            return;
+        };
+        if return_type.size > bytes.len() {
+            return;
+        }
+        println!(
+            "Value returned is {}",
+            value::format_value(
+                &bytes[..return_type.size],
+                &return_type,
+                &self.debug_data,
+                Some(inferior)
+            )
+        );
+    }
+
+    /// Reads a variable out of the inferior as seen from `frame` and formats it according to its
+    /// type.
+    fn format_variable(&self, inferior: &dyn Target, var: &Variable, frame: &Frame) -> String {
+        match inferior.read_variable(var, frame) {
+            Ok(bytes) => {
+                value::format_value(&bytes, &var.entity_type, &self.debug_data, Some(inferior))
+            }
+            Err(e) => format!("<error reading variable: {}>", e),
+        }
+    }
+
+    /// Evaluates an expression and prints its value. Assignments are carried out first.
+    fn print_expression(&mut self, text: &str) {
+        let expr = match expr::parse(text) {
+            Ok(expr) => expr,
+            Err(err) => {
+                self.report_error(&err);
+                return;
+            }
+        };
+        let expr = match expr {
+            Expr::Assign(lhs, rhs) => match self.assign(&lhs, &rhs) {
+                Ok(()) => *lhs,
+                Err(err) => {
+                    self.report_error(&err);
+                    return;
+                }
+            },
+            expr => expr,
+        };
+        let evaluator = self.evaluator();
+        match evaluator.evaluate(&expr) {
+            Ok(value) => {
+                let value = evaluator.format(&value);
+                println!("{} = {}", text, value);
+                self.record("value", json!(value));
+            }
+            Err(err) => self.report_error(&err),
+        }
+    }
+
+    /// Handles `set var <lvalue> = <expr>`.
+    fn set_variable(&mut self, text: &str) {
+        let result = match expr::parse(text) {
+            Ok(Expr::Assign(lhs, rhs)) => self.assign(&lhs, &rhs),
+            Ok(_) => Err("Expected an assignment, e.g. set var x = 1".to_string()),
+            Err(err) => Err(err),
+        };
+        if let Err(err) = result {
+            self.report_error(&err);
+        }
+    }
+
+    /// Stores the value of `rhs` into `lhs`.
+    fn assign(&mut self, lhs: &Expr, rhs: &Expr) -> Result<(), String> {
+        let (lvalue, bytes) = self.evaluator().prepare_assignment(lhs, rhs)?;
+        if self.core.is_some() {
+            return Err("A core file can't be modified.".to_string());
+        }
+        let inferior = self
+            .inferior
+            .as_mut()
+            .ok_or_else(|| "The program is not being run.".to_string())?;
+        match lvalue {
+            Lvalue::Memory(addr) => inferior
+                .write_memory(addr, &bytes)
+                .map_err(|_| format!("Cannot access memory at address {:#x}", addr)),
+            Lvalue::Register(name) => {
+                let mut raw = [0u8; 8];
+                raw[..bytes.len()].copy_from_slice(&bytes);
+                let mut regs = inferior.get_registers().map_err(|e| e.to_string())?;
+                registers::set_register(&mut regs, &name, u64::from_le_bytes(raw));
+                inferior.set_registers(regs).map_err(|e| e.to_string())
+            }
+        }
+    }
+
+    /// Dumps memory in the style of gdb's `x/NFU ADDR`.
+    fn examine_memory(&mut self, format: ExamineFormat, location: Option<String>) {
+        let evaluator = self.evaluator();
+        let addr = match location {
+            Some(text) => {
+                match expr::parse(&text)
+                    .and_then(|expr| evaluator.evaluate(&expr))
+                    .and_then(|value| evaluator.as_address(&value))
+                {
+                    Ok(addr) => addr,
+                    Err(err) => {
+                        self.report_error(&err);
+                        return;
+                    }
+                }
+            }
+            None => match self.examine_next {
+                Some(addr) => addr,
+                None => {
+                    self.report_error("Argument required (starting display address).");
+                    return;
+                }
+            },
+        };
+        let inferior = match self.target() {
+            Some(inferior) => inferior,
+            None => {
+                self.report_error(&format!("Cannot access memory at address {:#x}", addr));
+                return;
+            }
+        };
+        let count = format.count.unwrap_or(1);
+        if count > MAX_EXAMINE_COUNT {
+            self.report_error(&format!(
+                "Count too large to examine (more than {:#x} units).",
+                MAX_EXAMINE_COUNT
+            ));
+            return;
+        }
+        let letter = format.format.unwrap_or(self.examine_format.0);
+        // Characters are single bytes unless asked otherwise
+        let unit = match (letter, format.unit) {
+            (_, Some(unit)) => unit,
+            ('c', None) => 1,
+            (_, None) => self.examine_format.1,
+        };
+        let symbol = |addr| match value::describe_code_address(addr, &self.debug_data) {
+            Some(symbol) => format!(" {}", symbol),
+            None => String::new(),
+        };
+
+        let next = match letter {
+            's' => {
+                let mut addr = addr;
+                for _ in 0..count {
+                    match value::read_string(Some(inferior), addr) {
+                        Some((string, truncated)) => {
+                            println!(
+                                "{:#x}{}:\t{}",
+                                addr,
+                                symbol(addr),
+                                value::format_string(&string, truncated)
+                            );
+                            addr += string.len() + 1;
+                        }
+                        None => {
+                            self.report_error(&format!(
+                                "Cannot access memory at address {:#x}",
+                                addr
+                            ));
+                            return;
+                        }
+                    }
+                }
+                addr
+            }
+            'i' => {
+                let bytes = disasm::read_code(inferior, addr, count * disasm::MAX_INSTRUCTION_LEN);
+                let instructions = disasm::decode(&bytes, addr, Some(count));
+                if instructions.is_empty() {
+                    self.report_error(&format!("Cannot access memory at address {:#x}", addr));
+                    return;
+                }
+                let pc = self.selected_frame().ok().map(|frame| frame.pc);
+                for instruction in &instructions {
+                    println!(
+                        "{}{:#x}{}:\t{}",
+                        if Some(instruction.addr) == pc {
+                            "=> "
+                        } else {
+                            "   "
+                        },
+                        instruction.addr,
+                        symbol(instruction.addr),
+                        self.format_instruction(instruction)
+                    );
+                }
+                let last = instructions.last().unwrap();
+                last.addr + last.bytes.len()
+            }
+            _ => {
+                let len = count.checked_mul(unit);
+                let bytes = match len.map(|len| inferior.read_memory(addr, len)) {
+                    Some(Ok(bytes)) => bytes,
+                    _ => {
+                        self.report_error(&format!("Cannot access memory at address {:#x}", addr));
+                        return;
+                    }
+                };
+                // Like gdb, fit about 16 bytes worth of output on each line
+                let per_line = match unit {
+                    8 => 2,
+                    4 => 4,
+                    _ => 8,
+                };
+                for (line, chunk) in bytes.chunks(unit * per_line).enumerate() {
+                    let line_addr = addr + line * unit * per_line;
+                    let units: Vec<String> = chunk
+                        .chunks(unit)
+                        .map(|bytes| value::format_memory_unit(bytes, letter))
+                        .collect();
+                    println!(
+                        "{:#x}{}:\t{}",
+                        line_addr,
+                        symbol(line_addr),
+                        units.join("\t")
+                    );
+                }
+                addr + bytes.len()
+            }
+        };
+        self.examine_format = (letter, unit);
+        self.examine_next = Some(next);
+    }
+
+    /// An instruction's text, with the function a direct call or jump goes to, and the address a
+    /// %rip-relative operand refers to.
+    fn format_instruction(&self, instruction: &Instruction) -> String {
+        let describe = |addr| value::describe_code_address(addr, &self.debug_data);
+        let mut text = instruction.text.clone();
+        if let Some(target) = instruction.target.and_then(describe) {
+            text += &format!(" {}", target);
+        }
+        if let Some(addr) = instruction.rip_relative {
+            text += &format!("        # {:#x}", addr);
+            if let Some(symbol) = describe(addr) {
+                text += &format!(" {}", symbol);
+            }
+        }
+        text
+    }
+
+    /// Works out what `disassemble` should show: the function containing the selected frame's
+    /// pc, a function (by name, or any address in it), or `start,end` or `start,+length`. Returns
+    /// the range along with the function's name when it is a whole function.
+    fn resolve_disassembly_range(
+        &self,
+        location: Option<&str>,
+    ) -> Result<(Range<usize>, Option<String>), String> {
+        let evaluator = self.evaluator();
+        let address = |text: &str| {
+            expr::parse(text)
+                .and_then(|expr| evaluator.evaluate(&expr))
+                .and_then(|value| evaluator.as_address(&value))
+        };
+        let addr = match location {
+            None => self
+                .selected_frame()
+                .map_err(|_| "No frame selected.".to_string())?
+                .lookup_pc(),
+            Some(text) if text.contains(',') => {
+                let comma = text.find(',').unwrap();
+                let start = address(&text[..comma])?;
+                let end = text[comma + 1..].trim();
+                let end = match end.strip_prefix('+') {
+                    Some(len) => start
+                        .checked_add(address(len)?)
+                        .ok_or_else(|| "Invalid range".to_string())?,
+                    None => address(end)?,
+                };
+                if end < start {
+                    return Err("Invalid range".to_string());
+                }
+                if end - start > MAX_DISASSEMBLY_LEN {
+                    return Err(format!(
+                        "Range too large to disassemble (more than {:#x} bytes).",
+                        MAX_DISASSEMBLY_LEN
+                    ));
+                }
+                return Ok((start..end, None));
+            }
+            Some(text) => match self.debug_data.get_addr_for_function(None, text.trim()) {
+                Some(addr) => addr,
+                None => address(text)?,
+            },
+        };
+        match self.debug_data.get_function_bounds(addr) {
+            Some((name, range)) => Ok((range, Some(name))),
+            None => Err("No function contains specified address.".to_string()),
+        }
+    }
+
+    /// Prints the instructions of a function or an address range (`disassemble`), marking the
+    /// selected frame's pc with an arrow. Breakpoints show up as the instructions they replaced.
+    fn disassemble(&self, options: DisassembleOptions, location: Option<&str>) {
+        let (range, function) = match self.resolve_disassembly_range(location) {
+            Ok(resolved) => resolved,
+            Err(err) => {
+                self.report_error(&err);
+                return;
+            }
+        };
+        let target = match self.target() {
+            Some(target) => target,
+            None => {
+                self.report_error(&format!(
+                    "Cannot access memory at address {:#x}",
+                    range.start
+                ));
+                return;
+            }
+        };
+        let bytes = match target.read_memory(range.start, range.end - range.start) {
+            Ok(bytes) => bytes,
+            Err(_) => {
+                self.report_error(&format!(
+                    "Cannot access memory at address {:#x}",
+                    range.start
+                ));
+                return;
+            }
+        };
+        match &function {
+            Some(name) => println!("Dump of assembler code for function {}:", name),
+            None => println!(
+                "Dump of assembler code from {:#x} to {:#x}:",
+                range.start, range.end
+            ),
+        }
+        let pc = self.selected_frame().ok().map(|frame| frame.pc);
+        let mut last_line = None;
+        for instruction in disasm::decode(&bytes, range.start, None) {
+            if options.source && self.debug_data.is_line_start(instruction.addr) {
+                if let Some(line) = self.debug_data.get_line_from_addr(instruction.addr) {
+                    self.print_disassembly_source(&line, last_line.as_ref());
+                    last_line = Some(line);
+                }
+            }
+            let location = match &function {
+                Some(_) => format!("<+{}>", instruction.addr - range.start),
+                None => value::describe_code_address(instruction.addr, &self.debug_data)
+                    .unwrap_or_default(),
+            };
+            let raw = if options.raw {
+                let bytes: Vec<String> = instruction
+                    .bytes
+                    .iter()
+                    .map(|byte| format!("{:02x}", byte))
+                    .collect();
+                format!("{}\t", bytes.join(" "))
+            } else {
+                String::new()
+            };
+            println!(
+                "{}{:#018x} {}:\t{}{}",
+                if Some(instruction.addr) == pc {
+                    "=> "
+                } else {
+                    "   "
+                },
+                instruction.addr,
+                location,
+                raw,
+                self.format_instruction(&instruction)
+            );
+        }
+        println!("End of assembler dump.");
+    }
+
+    /// Prints the source line a group of instructions in `disassemble /s` comes from, preceded by
+    /// the file name when it changes and a blank line between groups.
+    fn print_disassembly_source(&self, line: &Line, last: Option<&Line>) {
+        if last.is_some() {
+            println!();
+        }
+        if last.map(|last| &last.file) != Some(&line.file) {
+            println!("{}:", line.file);
+        }
+        let text = self
+            .source_lines(&line.file)
+            .ok()
+            .and_then(|lines| lines.get(line.number.wrapping_sub(1)).cloned())
+            .unwrap_or_default();
+        println!("{}\t{}", line.number, text);
+    }
+
+    /// Prints the type of an expression (`ptype`).
+    fn print_expression_type(&self, text: &str) {
+        let expr = match expr::parse(text) {
+            Ok(expr) => expr,
+            Err(err) => {
+                self.report_error(&err);
+                return;
+            }
+        };
+        // Globals can be looked up without a running process
+        let rip = match self.selected_frame() {
+            Ok(frame) => frame.lookup_pc(),
+            Err(_) => 0,
+        };
+        let entity_type = match &expr {
+            Expr::Variable(name) if self.debug_data.get_variable(rip, name).is_some() => self
+                .debug_data
+                .get_variable(rip, name)
+                .unwrap()
+                .entity_type
+                .clone(),
+            _ => match self.evaluator().evaluate(&expr) {
+                Ok(value) => value.ty,
+                Err(err) => {
+                    self.report_error(&err);
+                    return;
+                }
+            },
+        };
+        println!(
+            "type = {}",
+            value::format_type_definition(&entity_type, &self.debug_data)
+        );
+    }
+
+    /// Prints registers for `info registers` and `info all-registers`. With no names, lists the
+    /// general-purpose registers, plus the x87 and SSE ones if `all` is set.
+    fn print_registers(&self, names: &[String], all: bool) {
+        let inferior = match self.target() {
+            Some(inferior) => inferior,
+            None => {
+                self.report_error("The program has no registers now.");
+                return;
+            }
+        };
+        let regs = match inferior.get_registers() {
+            Ok(regs) => regs,
+            Err(e) => {
+                self.report_error(&format!("failed to read registers, err: {}", e));
+                return;
+            }
+        };
+        let fpregs = if all || !names.is_empty() {
+            match inferior.get_fp_registers() {
+                Ok(fpregs) => Some(fpregs),
+                Err(e) => {
+                    self.report_error(&format!(
+                        "failed to read floating point registers, err: {}",
+                        e
+                    ));
+                    return;
+                }
+            }
+        } else {
+            None
+        };
+
+        let mut listed: Vec<&str> = names.iter().map(|name| name.as_str()).collect();
+        if listed.is_empty() {
+            listed.extend(registers::REGISTER_NAMES.iter());
+            if all {
+                listed.extend(registers::FP_REGISTER_NAMES.iter());
+            }
+        }
+        for name in listed {
+            let name = registers::canonical_name(name);
+            let row = match registers::get_register(&regs, name) {
+                Some(raw) => {
+                    let natural = match name {
+                        "rip" => match value::describe_code_address(raw as usize, &self.debug_data)
+                        {
+                            Some(symbol) => format!("{:#x} {}", raw, symbol),
+                            None => format!("{:#x}", raw),
+                        },
+                        "eflags" => registers::format_eflags(raw),
+                        "rsp" | "rbp" => format!("{:#x}", raw),
+                        _ => (raw as i64).to_string(),
+                    };
+                    Some((format!("{:#x}", raw), natural))
+                }
+                None => fpregs
+                    .as_ref()
+                    .and_then(|fpregs| registers::describe_fp_register(fpregs, name)),
+            };
+            match row {
+                Some((raw, natural)) => {
+                    println!(
+                        "{}",
+                        format!("{:<15}{:<19}{}", name, raw, natural).trim_end()
+                    )
+                }
+                None => {
+                    self.report_error(&format!("Invalid register `{}'", name));
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Prints the arguments (`info args`) or the locals (`info locals`) of the function in the
+    /// selected frame.
+    fn print_frame_variables(&self, parameters: bool) {
+        let inferior = match self.target() {
+            Some(inferior) => inferior,
+            None => {
+                self.report_error("No frame selected.");
+                return;
+            }
+        };
+        let frame = match self.selected_frame() {
+            Ok(frame) => frame,
+            Err(err) => {
+                self.report_error(&err);
+                return;
+            }
+        };
+        let function = match self.debug_data.get_function_containing(frame.lookup_pc()) {
+            Some(function) => function,
+            None => {
+                self.report_error("No symbol table info available.");
+                return;
+            }
+        };
+        let mut variables = Vec::new();
+        for var in function
+            .variables
+            .iter()
+            .filter(|var| var.is_parameter == parameters)
+        {
+            println!(
+                "{} = {}",
+                var.name,
+                self.format_variable(inferior, var, &frame)
+            );
+            variables.push(self.variable_json(inferior, var, &frame));
+        }
+        self.record("variables", json!(variables));
+        if variables.is_empty() {
+            println!(
+                "{}",
+                if parameters {
+                    "No arguments."
+                } else {
+                    "No locals."
+                }
+            );
+        }
+    }
+
+    /// Brings the INT3 at `addr` in a running inferior in line with the breakpoint table: it is
+    /// patched in if any enabled breakpoint lives there and removed otherwise.
+    fn sync_breakpoint(&mut self, addr: usize) {
+        if let Some(inferior) = self.inferior.as_mut() {
+            let result = if self.breakpoints.is_enabled_at(addr) {
+                inferior.insert_breakpoint(addr)
+            } else {
+                inferior.remove_breakpoint(addr)
+            };
+            if let Err(e) = result {
+                self.report_error(&format!(
+                    "Failed to update breakpoint at {:#x}: {}",
+                    addr, e
+                ));
+            }
+        }
+    }
+
+    /// Enables or disables the given breakpoints (all of them if `numbers` is empty).
+    fn set_breakpoints_enabled(&mut self, numbers: Vec<usize>, enabled: bool) {
+        let numbers = if numbers.is_empty() {
+            self.breakpoints.numbers()
+        } else {
+            numbers
+        };
+        for number in numbers {
+            if let Some(breakpoint) = self.breakpoints.get_mut(number) {
+                breakpoint.enabled = enabled;
+                let addr = breakpoint.addr;
+                self.sync_breakpoint(addr);
+            } else if let Some(wp) = self.breakpoints.watchpoint_mut(number) {
+                wp.enabled = enabled;
+                // The value may have changed while we weren't looking
+                if let Some(Ok(value)) = self
+                    .inferior
+                    .as_ref()
+                    .map(|inferior| inferior.read_memory(wp.addr, wp.len()))
+                {
+                    wp.value = value;
+                }
+                let (addr, len, kind) = (wp.addr, wp.len(), wp.kind);
+                self.sync_watchpoint(addr, len, kind);
+            } else {
+                self.report_error(&format!("No breakpoint number {}.", number));
+            }
+        }
+    }
+
+    /// Sets a watchpoint (`watch`, `rwatch` or `awatch`) on the memory an lvalue occupies. It is
+    /// put in debug registers if enough of them are free, and otherwise becomes a (slow) software
+    /// watchpoint, which only `watch` can use.
+    fn watch(&mut self, kind: WatchKind, text: &str) {
+        let evaluator = self.evaluator();
+        let value = match expr::parse(text).and_then(|expr| evaluator.evaluate(&expr)) {
+            Ok(value) => value,
+            Err(err) => {
+                self.report_error(&err);
+                return;
+            }
+        };
+        let addr = match value.lvalue {
+            Some(Lvalue::Memory(addr)) => addr,
+            Some(Lvalue::Register(_)) => {
+                self.report_error("Cannot watch a register.");
+                return;
+            }
+            None => {
+                self.report_error(&format!("Cannot watch constant value `{}'.", text));
+                return;
+            }
+        };
+        let in_use: usize = self
+            .breakpoints
+            .watchpoints()
+            .filter(|wp| wp.enabled && wp.hardware)
+            .map(|wp| inferior::debug_register_ranges(wp.addr, wp.len()).len())
+            .sum();
+        let needed = inferior::debug_register_ranges(addr, value.bytes.len()).len();
+        let hardware = in_use + needed <= inferior::DEBUG_REGISTER_COUNT;
+        if !hardware && kind != WatchKind::Write {
+            self.report_error("Expression cannot be implemented with read/access watchpoint.");
+            return;
+        }
+        let len = value.bytes.len();
+        let wp = self.breakpoints.add_watchpoint(
+            kind,
+            hardware,
+            text.to_string(),
+            addr,
+            value.ty,
+            value.bytes,
+        );
+        println!("{}", wp.describe());
+        self.sync_watchpoint(addr, len, kind);
+    }
+
+    /// Brings the debug registers of a running inferior in line with the breakpoint table, as
+    /// sync_breakpoint does for INT3s.
+    fn sync_watchpoint(&mut self, addr: usize, len: usize, kind: WatchKind) {
+        if let Some(inferior) = self.inferior.as_mut() {
+            let result = if self.breakpoints.is_watching(addr, len, kind) {
+                inferior.insert_watchpoint(addr, len, kind)
+            } else {
+                inferior.remove_watchpoint(addr, len, kind).map(|_| true)
+            };
+            match result {
+                Ok(true) => {}
+                Ok(false) => self.report_error(
+                    "Could not insert hardware watchpoint: all debug registers are in use.",
+                ),
+                Err(e) => self.report_error(&format!(
+                    "Failed to update watchpoint at {:#x}: {}",
+                    addr, e
+                )),
+            }
+        }
+    }
+
+    /// Arms the hardware watchpoints in a freshly started inferior and takes note of the starting
+    /// values of everything watched.
+    fn arm_watchpoints(&mut self) {
+        let inferior = match self.inferior.as_ref() {
+            Some(inferior) => inferior,
+            None => return,
+        };
+        let mut hardware = Vec::new();
+        for number in self.breakpoints.numbers() {
+            if let Some(wp) = self.breakpoints.watchpoint_mut(number) {
+                if let Ok(value) = inferior.read_memory(wp.addr, wp.len()) {
+                    wp.value = value;
+                }
+                if wp.enabled && wp.hardware {
+                    hardware.push((wp.addr, wp.len(), wp.kind));
+                }
+            }
+        }
+        for (addr, len, kind) in hardware {
+            self.sync_watchpoint(addr, len, kind);
+        }
+    }
+
+    /// Reports the watchpoints that triggered on the last resume, gdb style.
+    fn print_watch_hits(&self, hits: &[WatchHit]) {
+        for hit in hits {
+            let wp = match self
+                .breakpoints
+                .watchpoints()
+                .find(|wp| wp.number == hit.number)
+            {
+                Some(wp) => wp,
+                None => continue,
+            };
+            let format =
+                |bytes: &[u8]| value::format_value(bytes, &wp.ty, &self.debug_data, self.target());
+            println!("\n{}\n", wp.describe());
+            match &hit.old {
+                Some(old) => {
+                    println!("Old value = {}", format(old));
+                    println!("New value = {}", format(&hit.new));
+                }
+                None => println!("Value = {}", format(&hit.new)),
+            }
+        }
+    }
+
+    fn print_breakpoints(&self) {
+        if self.breakpoints.iter().next().is_none()
+            && self.breakpoints.watchpoints().next().is_none()
+        {
+            println!("No breakpoints or watchpoints.");
+            self.record("breakpoints", json!([]));
+            return;
+        }
+        println!(
+            "{:<7} {:<3} {:<18} {:<5} What",
+            "Num", "Enb", "Address", "Hits"
+        );
+        let mut records = Vec::new();
+        for breakpoint in self.breakpoints.iter() {
+            records.push(breakpoint_json(breakpoint));
+            println!(
+                "{:<7} {:<3} {:#018x} {:<5} {}",
+                breakpoint.number,
+                if breakpoint.enabled { "y" } else { "n" },
+                breakpoint.addr,
+                breakpoint.hit_count,
+                breakpoint.location
+            );
+            if let Some(condition) = &breakpoint.condition {
+                println!("\tstop only if {}", condition);
+            }
+            if breakpoint.ignore_count > 0 {
+                println!(
+                    "\twill ignore next {} crossings of breakpoint.",
+                    breakpoint.ignore_count
+                );
+            }
+            for line in &breakpoint.commands {
+                println!("        {}", line);
+            }
+        }
+        for wp in self.breakpoints.watchpoints() {
+            let kind = match (wp.kind, wp.hardware) {
+                (WatchKind::Write, true) => "hw watchpoint",
+                (WatchKind::Write, false) => "watchpoint",
+                (WatchKind::Read, _) => "read watchpoint",
+                (WatchKind::Access, _) => "acc watchpoint",
+            };
+            println!(
+                "{:<7} {:<3} {:#018x} {:<5} {} {}",
+                wp.number,
+                if wp.enabled { "y" } else { "n" },
+                wp.addr,
+                wp.hit_count,
+                kind,
+                wp.expression
+            );
+            records.push(json!({
+                "number": wp.number,
+                "type": kind,
+                "enabled": wp.enabled,
+                "addr": format!("{:#x}", wp.addr),
+                "expression": wp.expression,
+                "hits": wp.hit_count,
+            }));
+        }
+        self.record("breakpoints", json!(records));
+    }
+
+    /// This function prompts the user to enter a command, and continues re-prompting until the user
+    /// enters something. Returns None when the user is done (ctrl+d).
+    fn get_next_line(&mut self) -> Option<String> {
+        loop {
+            self.update_completions();
+            // Print prompt and get next line of user input
+            match self.readline.readline("(deet) ") {
+                Err(ReadlineError::Interrupted) => {
+                    // User pressed ctrl+c. We're going to ignore it
+                    println!("Type \"quit\" to exit");
+                }
+                Err(ReadlineError::Eof) => {
+                    // User pressed ctrl+d, which is the equivalent of "quit" for our purposes
+                    return None;
+                }
+                Err(err) => {
+                    panic!("Unexpected I/O error: {:?}", err);
+                }
+                Ok(line) => {
+                    if line.trim().len() == 0 {
+                        continue;
+                    }
+                    self.readline.add_history_entry(line.as_str());
+                    if let Err(err) = self.readline.save_history(&self.history_path) {
+                        println!(
+                            "Warning: failed to save history file at {}: {}",
+                            self.history_path, err
+                        );
+                    }
+                    return Some(line);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::inferior::test::{build_sample, lock_tracing, Sample};
+    use std::io::{self, Write};
+    use std::rc::Rc;
+
+    /// Collects what the JSON interpreter writes.
+    #[derive(Clone)]
+    struct Records(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Records {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// A debugger talking JSON, the way a client would see it. It holds the tracing lock for as
+    /// long as it lives, and the sample it debugs is removed along with it.
+    struct Session {
+        debugger: Debugger,
+        records: Records,
+        program: Sample,
+        _tracing: std::sync::MutexGuard<'static, ()>,
+    }
+
+    impl Session {
+        /// Builds the sample `name` and starts debugging it.
+        fn start(name: &str) -> Session {
+            let _tracing = lock_tracing();
+            let program = build_sample(name);
+            let records = Records(Rc::new(RefCell::new(Vec::new())));
+            let mut debugger = Debugger::new(&program);
+            let json = JsonInterpreter::with_output(Box::new(records.clone()));
+            debugger.json = Some(RefCell::new(json));
+            Session {
+                debugger,
+                records,
+                program,
+                _tracing,
+            }
+        }
+
+        /// Carries out a command, returning its result along with the notifications ahead of
+        /// it.
+        fn command(&mut self, line: &str) -> (Value, Vec<Value>) {
+            self.records.0.borrow_mut().clear();
+            if let Some(json) = &self.debugger.json {
+                json.borrow_mut().begin(json!(1));
+            }
+            self.debugger.run_command(Some(line.to_string()));
+            let output = self.records.0.borrow();
+            let mut records: Vec<Value> = String::from_utf8_lossy(&output)
+                .lines()
+                .map(|line| serde_json::from_str(line).unwrap())
+                .collect();
+            let result = records.pop().unwrap();
+            assert_eq!(result["type"], "result");
+            (result, records)
+        }
+
+        /// Carries out a command that should succeed, returning its result.
+        fn done(&mut self, line: &str) -> Value {
+            let (result, _) = self.command(line);
+            assert_eq!(result["class"], "done", "{}: {}", line, result);
+            result
+        }
+
+        /// Carries out a command that should fail, returning its error message.
+        fn error(&mut self, line: &str) -> String {
+            let (result, _) = self.command(line);
+            assert_eq!(result["class"], "error", "{}: {}", line, result);
+            result["message"].as_str().unwrap().to_string()
+        }
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut session = Session::start("segfault");
+
+        assert_eq!(
+            session.error("break"),
+            "Usage: break <*address|file:line|line|function>"
+        );
+        assert_eq!(session.error("print"), "Usage: print <expression>");
+        assert_eq!(session.error("frame"), "No stack.");
+        assert_eq!(
+            session.error("disassemble 0x10,+0xffffffffffffffff"),
+            "Invalid range"
+        );
+        assert_eq!(session.error("disassemble 0x20,0x10"), "Invalid range");
+        assert_eq!(
+            session.error("disassemble 0x10,+0x10000000"),
+            "Range too large to disassemble (more than 0x10000 bytes)."
+        );
+        assert_eq!(session.error("info locals"), "No frame selected.");
+        assert_eq!(
+            session.error("set follow-fork-mode sideways"),
+            "Requires an argument. Valid arguments are child, parent."
+        );
+        session.done("break func2");
+        session.done("condition 1 nothing == 1");
+        assert_eq!(
+            session.error("run"),
+            "Error in condition of breakpoint 1: No symbol \"nothing\" in current context."
+        );
+        session.done("condition 1");
+        assert_eq!(
+            session.error("signal SIGNOTHING"),
+            "Bad signal name: SIGNOTHING"
+        );
+        assert_eq!(session.error("thread 7"), "Invalid thread ID: 7");
+        assert_eq!(
+            session.error("x/4x 0x10"),
+            "Cannot access memory at address 0x10"
+        );
+        assert_eq!(
+            session.error("x/2xg 0xfffffffffffffffc"),
+            "Cannot access memory at address 0xfffffffffffffffc"
+        );
+        assert_eq!(
+            session.error("x/100000000000xg &a"),
+            "Count too large to examine (more than 0x10000 units)."
+        );
+        assert_eq!(
+            session.error("info registers rfoo"),
+            "Invalid register `rfoo'"
+        );
+        assert_eq!(session.error("watch 3"), "Cannot watch constant value `3'.");
+    }
+
+    #[test]
+    fn test_break() {
+        let mut session = Session::start("function_calls");
+        let func1 = session
+            .debugger
+            .debug_data
+            .get_addr_for_function(None, "func1")
+            .unwrap();
+        // All set before the program runs
+        let breakpoint = session.done("break func3")["breakpoint"].clone();
+        assert_eq!(breakpoint["number"], 1);
+        let breakpoint = session.done("break function_calls.c:11")["breakpoint"].clone();
+        assert_eq!(breakpoint["number"], 2);
+        let breakpoint = session.done(&format!("break *{:#x}", func1))["breakpoint"].clone();
+        assert_eq!(breakpoint["addr"], format!("{:#x}", func1));
+        assert_eq!(
+            session.error("break nowhere"),
+            "Could not resolve breakpoint location nowhere"
+        );
+        let stop = |session: &mut Session, command: &str| {
+            let (result, events) = session.command(command);
+            assert_eq!(result["class"], "done", "{}: {}", command, result);
+            events.last().unwrap().clone()
+        };
+
+        let event = stop(&mut session, "run");
+        assert_eq!(event["breakpoints"], json!([3]));
+        assert_eq!(event["frame"]["addr"], format!("{:#x}", func1));
+        let event = stop(&mut session, "continue");
+        assert_eq!(event["breakpoints"], json!([2]));
+        assert_eq!(
+            (&event["frame"]["func"], &event["frame"]["line"]),
+            (&json!("func2"), &json!(11))
+        );
+        // func3 is called from func2 and then from func1, and the breakpoint is put back after
+        // the first hit so that it stops the second call too
+        let event = stop(&mut session, "continue");
+        assert_eq!(event["breakpoints"], json!([1]));
+        assert_eq!(session.done("bt")["stack"][1]["func"], "func2");
+        let event = stop(&mut session, "continue");
+        assert_eq!(event["breakpoints"], json!([1]));
+        assert_eq!(session.done("bt")["stack"][1]["func"], "func1");
+        assert_eq!(
+            session.debugger.breakpoints.get_mut(1).unwrap().hit_count,
+            2
+        );
+        let event = stop(&mut session, "continue");
+        assert_eq!(event["reason"], "exited");
+    }
+
+    #[test]
+    fn test_memory_round_trip() {
+        let mut session = Session::start("segfault");
+        session.done("break func2");
+        session.done("run");
+        let value = session.done("print &a")["value"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        let addr = parse_address(value.rsplit(' ').next().unwrap()).unwrap();
+
+        session.done("set var *(int *)&a = 0x12345678");
+        assert_eq!(session.done("print a")["value"], "305419896");
+        session.done(&format!("set *(char *){:#x} = 0x7f", addr + 3));
+        assert_eq!(session.done("print a")["value"], "2134136440");
+        let target = session.debugger.target().unwrap();
+        assert_eq!(
+            target.read_memory(addr, 4).unwrap(),
+            vec![0x78, 0x56, 0x34, 0x7f]
+        );
+
+        // x carries on where it left off, in the format it used last
+        session.done(&format!("x/2xb {:#x}", addr));
+        assert_eq!(session.debugger.examine_format, ('x', 1));
+        assert_eq!(session.debugger.examine_next, Some(addr + 2));
+        session.done("x");
+        assert_eq!(session.debugger.examine_next, Some(addr + 3));
+        session.done(&format!("x/dw {:#x}", addr));
+        assert_eq!(session.debugger.examine_format, ('d', 4));
+        assert_eq!(session.debugger.examine_next, Some(addr + 4));
+        assert_eq!(
+            session.error("set *(int *)0x10 = 1"),
+            "Cannot access memory at address 0x10"
+        );
+    }
+
+    #[test]
+    fn test_select_frames() {
+        let mut session = Session::start("segfault");
+        session.done("break func2");
+        session.done("run");
+
+        let frame = &session.done("frame")["frame"];
+        assert_eq!(
+            (&frame["level"], &frame["func"], &frame["line"]),
+            (&json!(0), &json!("func2"), &json!(4))
+        );
+        let frame = &session.done("up")["frame"];
+        assert_eq!(
+            (&frame["level"], &frame["func"], &frame["line"]),
+            (&json!(1), &json!("func1"), &json!(11))
+        );
+        assert_eq!(frame["args"][0]["value"], "42");
+        // Other commands look at the selected frame
+        assert_eq!(session.done("info frame")["frame"]["level"], 1);
+        assert_eq!(session.done("print a")["value"], "42");
+        assert_eq!(session.done("down")["frame"]["func"], "func2");
+        assert_eq!(session.done("print a")["value"], "2");
+        assert_eq!(
+            session.error("down"),
+            "Bottom (innermost) frame selected; you cannot go down."
+        );
+        assert_eq!(session.done("frame 2")["frame"]["func"], "main");
+        assert_eq!(session.done("info frame")["frame"]["func"], "main");
+
+        // up stops at the outermost frame, whatever that is
+        let outermost = session.done("up 1000")["frame"]["level"].as_u64().unwrap();
+        assert!(outermost >= 2);
+        assert_eq!(session.debugger.selected_frame, outermost as usize);
+        assert_eq!(
+            session.error("up"),
+            "Initial frame selected; you cannot go up."
+        );
+        assert_eq!(session.error("frame 1000"), "No frame at level 1000.");
+        assert_eq!(session.debugger.selected_frame, outermost as usize);
+        assert_eq!(
+            session.error("finish"),
+            "\"finish\" not meaningful in the outermost frame."
+        );
+        assert_eq!(session.done("down 1000")["frame"]["level"], 0);
+        // Running again goes back to the innermost frame
+        session.done("up");
+        session.done("continue");
+        assert_eq!(session.debugger.selected_frame, 0);
+    }
+
+    /// The state letter of a process or thread, as in ps: t for stopped by a debugger.
+    fn process_state(pid: u32) -> char {
+        let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).unwrap();
+        let (_, fields) = stat.rsplit_once(')').unwrap();
+        fields.trim_start().chars().next().unwrap()
+    }
+
+    #[test]
+    fn test_attach_detach() {
+        let mut session = Session::start("sleepy_print");
+        let mut child = std::process::Command::new(&*session.program)
+            .arg("3")
+            .stdout(std::process::Stdio::null())
+            .spawn()
+            .unwrap();
+        // Give it time to get going
+        std::thread::sleep(std::time::Duration::from_millis(300));
+
+        session.done(&format!("attach {}", child.id()));
+        assert_eq!(process_state(child.id()), 't');
+        let stack = session.done("bt")["stack"].clone();
+        let functions: Vec<&Value> = stack
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|f| &f["func"])
+            .collect();
+        assert!(functions.contains(&&json!("main")));
+        session.done("detach");
+        assert!(session.debugger.inferior.is_none());
+        assert_ne!(process_state(child.id()), 't');
+        assert_eq!(session.error("detach"), "The program is not being run.");
+        // Left alone, it finishes counting
+        assert!(child.wait().unwrap().success());
+    }
+
+    #[test]
+    fn test_all_threads_stop() {
+        let mut session = Session::start("threads");
+        session.done("break all_started");
+        let (_, events) = session.command("run");
+        assert_eq!(events[0]["reason"], "breakpoint-hit");
+        assert_eq!(events[0]["thread"]["id"], 1);
+
+        let threads = session.done("info threads")["threads"].clone();
+        let threads = threads.as_array().unwrap();
+        assert_eq!(threads.len(), 4);
+        assert_eq!(threads[0]["current"], true);
+        for thread in &threads[1..] {
+            assert_eq!(thread["frame"]["func"], "spin");
+        }
+        // The workers spin for as long as they are let run, but they stopped along with main
+        for thread in threads {
+            assert_eq!(process_state(thread["lwp"].as_u64().unwrap() as u32), 't');
+        }
+        let counters = session.done("print counters")["value"].clone();
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        assert_eq!(session.done("print counters")["value"], counters);
+
+        assert_eq!(session.done("thread 3")["frame"]["func"], "spin");
+        assert_eq!(session.done("print id")["value"], "1");
+        session.done("thread apply all bt");
+        let result = session.done("thread apply 2 print id");
+        assert_eq!(result["value"], "0");
+        // thread apply leaves the selection as it found it
+        assert_eq!(
+            session.debugger.inferior.as_ref().unwrap().current_thread(),
+            3
+        );
+        assert_eq!(session.error("thread apply 9 bt"), "Invalid thread ID: 9");
+
+        // Returning from main ends the workers too
+        session.done("thread 1");
+        let (_, events) = session.command("continue");
+        assert_eq!(events[0]["reason"], "exited");
+        assert_eq!(events[0]["exit-code"], 0);
+    }
+
+    #[test]
+    fn test_watch_threads() {
+        let mut session = Session::start("counters");
+        session.done("break main");
+        session.command("run");
+        session.done("watch counters[0]");
+        session.done("watch counters[1]");
+        // Two workers write to what is watched at once, so one often hits its watchpoint while
+        // the other's hit is being reported. That hit is reported next, so no count is skipped.
+        for _ in 0..40 {
+            let (_, events) = session.command("continue");
+            let event = events.last().unwrap();
+            assert_eq!(event["reason"], "watchpoint-trigger", "{}", event);
+            for hit in event["watchpoints"].as_array().unwrap() {
+                let count = |key: &str| hit[key].as_str().unwrap().parse::<u64>().unwrap();
+                assert_eq!(count("new"), count("old") + 1, "{}", hit);
+            }
+        }
+    }
+
+    #[test]
+    fn test_follow_fork() {
+        let mut session = Session::start("fork");
+        session.done("break in_parent");
+        session.done("break in_child");
+        let stop = |session: &mut Session, command: &str| {
+            let (result, events) = session.command(command);
+            assert_eq!(result["class"], "done", "{}: {}", command, result);
+            events.last().unwrap().clone()
+        };
+
+        // By default we stay with the parent, and the child goes its own way without our
+        // breakpoints
+        let event = stop(&mut session, "run wait");
+        assert_eq!(event["frame"]["func"], "in_parent");
+        let event = stop(&mut session, "continue");
+        assert_eq!(
+            (&event["reason"], &event["exit-code"]),
+            (&json!("exited"), &json!(3))
+        );
+
+        // Following the child, the parent is the one let go of
+        session.done("set follow-fork-mode child");
+        let event = stop(&mut session, "run wait");
+        assert_eq!(event["frame"]["func"], "in_child");
+        let event = stop(&mut session, "continue");
+        assert_eq!(
+            (&event["reason"], &event["exit-code"]),
+            (&json!("exited"), &json!(3))
+        );
+
+        // Or kept stopped
+        session.done("set follow-fork-mode parent");
+        session.done("set detach-on-fork off");
+        let event = stop(&mut session, "run");
+        assert_eq!(event["frame"]["func"], "in_parent");
+        let child: u32 = event["frame"]["args"][0]["value"]
+            .as_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert_eq!(process_state(child), 't');
+        // It stays that way while the parent runs
+        session.done("next");
+        assert_eq!(process_state(child), 't');
+        let event = stop(&mut session, "continue");
+        assert_eq!(
+            (&event["reason"], &event["exit-code"]),
+            (&json!("exited"), &json!(0))
+        );
+    }
+
+    #[test]
+    fn test_follow_exec() {
+        let mut session = Session::start("exec");
+        // Only reached by the program the first one execs, which needs the breakpoint put back
+        session.done("break 13");
+        let (_, events) = session.command("run");
+        let event = events.last().unwrap();
+        assert_eq!(event["reason"], "breakpoint-hit");
+        assert_eq!(event["frame"]["line"], 13);
+        assert_eq!(session.done("print argc")["value"], "2");
+        let (_, events) = session.command("continue");
+        assert_eq!(events.last().unwrap()["exit-code"], 0);
+
+        // Exec'ing another program, breakpoints are looked up again in it, and those it doesn't
+        // have are deleted
+        let other = build_sample("function_calls");
+        session.done("delete");
+        session.done("break main");
+        session.done("break exec.c:13");
+        let (_, events) = session.command(&format!("run again {}", &*other));
+        assert_eq!(events.last().unwrap()["frame"]["line"], 5);
+        let (_, events) = session.command("continue");
+        let event = events.last().unwrap();
+        assert_eq!(event["breakpoints"], json!([2]));
+        assert!(event["frame"]["file"]
+            .as_str()
+            .unwrap()
+            .ends_with("function_calls.c"));
+        let breakpoints = session.done("info breakpoints")["breakpoints"].clone();
+        assert_eq!(breakpoints.as_array().unwrap().len(), 1);
+        assert_eq!(breakpoints[0]["number"], 2);
+        let (_, events) = session.command("continue");
+        assert_eq!(events.last().unwrap()["exit-code"], 0);
+    }
+
+    #[test]
+    fn test_generate_core() {
+        let mut session = Session::start("segfault");
+        let core = format!("{}.core", &*session.program);
+        session.done("break func2");
+        session.done("run");
+        let target = session.debugger.target().unwrap();
+        let regs = target.get_registers().unwrap();
+        let stack = target.read_memory(regs.rsp as usize, 64).unwrap();
+        let code = target.read_memory(regs.rip as usize, 16).unwrap();
+
+        session.done(&format!("generate-core-file {}", core));
+        session.debugger.load_core(&core);
+        assert!(session.debugger.inferior.is_none());
+        let target = session.debugger.target().unwrap();
+        let loaded = target.get_registers().unwrap();
+        assert_eq!(
+            (loaded.rip, loaded.rsp, loaded.rbp, loaded.rdi),
+            (regs.rip, regs.rsp, regs.rbp, regs.rdi)
+        );
+        assert_eq!(target.read_memory(regs.rsp as usize, 64).unwrap(), stack);
+        // Breakpoints don't end up in the core
+        assert_eq!(target.read_memory(regs.rip as usize, 16).unwrap(), code);
+        assert_eq!(session.debugger.selected_frame, 0);
+        assert_eq!(session.done("print a")["value"], "2");
+        assert_eq!(session.done("up")["frame"]["func"], "func1");
+        assert_eq!(session.done("print a")["value"], "42");
+        assert_eq!(
+            session.error("set var a = 1"),
+            "A core file can't be modified."
+        );
+        std::fs::remove_file(core).unwrap();
     }
 }
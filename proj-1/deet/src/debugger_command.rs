@@ -1,24 +1,778 @@
+use crate::breakpoint::WatchKind;
+
+#[derive(Clone)]
 pub enum DebuggerCommand {
     Quit,
     Run(Vec<String>),
+    Attach(Option<i32>),
+    Detach,
+    /// `gcore`, with the file to write the core to
+    GenerateCore(Option<String>),
     Continue,
+    Signal(Option<String>),
+    Handle(Vec<String>),
+    InfoSignals(Option<String>),
     BackTrace,
+    Frame(Option<usize>),
+    Up(usize),
+    Down(usize),
+    InfoFrame,
+    Break(Option<String>),
+    InfoBreakpoints,
+    Delete(Vec<usize>),
+    Disable(Vec<usize>),
+    Enable(Vec<usize>),
+    Ignore(usize, usize),
+    Condition(usize, Option<String>),
+    Watch(WatchKind, Option<String>),
+    Step,
+    Next,
+    Finish,
+    StepInstruction,
+    NextInstruction,
+    Print(Option<String>),
+    Ptype(Option<String>),
+    Set(String),
+    /// A debugger setting, such as `set follow-fork-mode child`
+    SetOption(String, Option<String>),
+    Show(String),
+    Examine(ExamineFormat, Option<String>),
+    Disassemble(DisassembleOptions, Option<String>),
+    /// `list`, with where to list around (or `-` for the lines before the last listing)
+    List(Option<String>),
+    /// `directory`, with a directory to add to the source path (or None to reset it)
+    Directory(Option<String>),
+    InfoLocals,
+    InfoArgs,
+    InfoRegisters(Vec<String>),
+    InfoAllRegisters,
+    InfoThreads,
+    Thread(Option<usize>),
+    /// Thread numbers, or None for all threads, and the command to run in each
+    ThreadApply(Option<Vec<usize>>, Box<DebuggerCommand>),
+    /// `source`, with the file of commands to run
+    Source(Option<String>),
+    /// `define`, with the name of the command whose body follows
+    Define(Option<String>),
+    /// `commands`, with the breakpoint the lines that follow are for (None for the last one set)
+    Commands(Option<usize>),
+    /// `help`, with the command to describe
+    Help(Option<String>),
+}
+
+/// The `/NFU` suffix of `x`: a repeat count, a format letter and a unit size letter. Whatever is
+/// left out defaults to what the previous `x` used.
+#[derive(Clone, Default)]
+pub struct ExamineFormat {
+    pub count: Option<usize>,
+    pub format: Option<char>,
+    pub unit: Option<usize>,
+}
+
+impl ExamineFormat {
+    fn parse(text: &str) -> Option<ExamineFormat> {
+        let digits = text.len() - text.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+        let mut format = ExamineFormat {
+            count: if digits > 0 {
+                Some(text[..digits].parse().ok()?)
+            } else {
+                None
+            },
+            ..Default::default()
+        };
+        for c in text[digits..].chars() {
+            match c {
+                'x' | 'd' | 'u' | 'o' | 't' | 'c' | 's' | 'i' => format.format = Some(c),
+                'b' => format.unit = Some(1),
+                'h' => format.unit = Some(2),
+                'w' => format.unit = Some(4),
+                'g' => format.unit = Some(8),
+                _ => return None,
+            }
+        }
+        Some(format)
+    }
+}
+
+/// The modifiers of `disassemble`: `/s` puts the source lines in between the instructions, and
+/// `/r` shows the raw bytes of each instruction.
+#[derive(Clone, Copy, Default)]
+pub struct DisassembleOptions {
+    pub source: bool,
+    pub raw: bool,
+}
+
+impl DisassembleOptions {
+    fn parse(text: &str) -> Option<DisassembleOptions> {
+        let mut options = DisassembleOptions::default();
+        for c in text.chars() {
+            match c {
+                's' | 'm' => options.source = true,
+                'r' => options.raw = true,
+                _ => return None,
+            }
+        }
+        Some(options)
+    }
+}
+
+/// Joins the arguments of a command that takes an expression.
+fn rest_of_line(tokens: &[&str]) -> Option<String> {
+    if tokens.len() > 1 {
+        Some(tokens[1..].join(" "))
+    } else {
+        None
+    }
+}
+
+fn parse_numbers(tokens: &[&str]) -> Option<Vec<usize>> {
+    tokens
+        .iter()
+        .map(|token| token.parse::<usize>().ok())
+        .collect()
+}
+
+/// What the arguments of a command are, which decides what tab completion offers.
+#[derive(Clone, Copy)]
+pub enum Complete {
+    Nothing,
+    /// The name of another command
+    Command,
+    /// A function, or a source file for `file:line`
+    Location,
+    /// An expression, whose variables are completed
+    Expression,
+    /// A file name
+    File,
+    /// One of a fixed set of words, such as what there is to `info`
+    Words(&'static [&'static str]),
+}
+
+/// An entry in the command table, which `from_tokens`, `help` and tab completion all work from.
+pub struct Command {
+    pub name: &'static str,
+    /// Other names for the command. Unlike abbreviations, these win even when other commands
+    /// start with them (`s` is `step`, not `set` or `show`).
+    pub aliases: &'static [&'static str],
+    /// What goes after the name, for the usage line
+    pub args: &'static str,
+    /// What the command does. The first line is the summary `help` lists.
+    pub doc: &'static str,
+    pub complete: Complete,
+    /// Turns the tokens of a command line (the first being the command as typed) into a command,
+    /// or None if the arguments make no sense
+    parse: fn(&[&str]) -> Option<DebuggerCommand>,
+}
+
+impl Command {
+    pub fn usage(&self) -> String {
+        if self.args.is_empty() {
+            self.name.to_string()
+        } else {
+            format!("{} {}", self.name, self.args)
+        }
+    }
+
+    /// Whether `word` is the command's name or an alias, rather than an abbreviation.
+    pub fn is_called(&self, word: &str) -> bool {
+        self.name == word || self.aliases.contains(&word)
+    }
+
+    /// What `help <command>` prints.
+    pub fn describe(&self) -> String {
+        let mut text = format!("Usage: {}\n{}", self.usage(), self.doc);
+        if !self.aliases.is_empty() {
+            text.push_str(&format!("\nAliases: {}", self.aliases.join(", ")));
+        }
+        text
+    }
+}
+
+const INFO_TOPICS: &[&str] = &[
+    "all-registers",
+    "args",
+    "breakpoints",
+    "frame",
+    "locals",
+    "registers",
+    "signals",
+    "threads",
+];
+const SETTINGS: &[&str] = &["detach-on-fork", "follow-fork-mode"];
+
+pub const COMMANDS: &[Command] = &[
+    Command {
+        name: "run",
+        aliases: &["r"],
+        args: "[args...]",
+        doc: "Start the program, with the given arguments.\nA program that is already running is killed first.",
+        complete: Complete::File,
+        parse: |tokens| {
+            Some(DebuggerCommand::Run(
+                tokens[1..].iter().map(|s| s.to_string()).collect(),
+            ))
+        },
+    },
+    Command {
+        name: "attach",
+        aliases: &[],
+        args: "<pid>",
+        doc: "Attach to a running process and stop it.",
+        complete: Complete::Nothing,
+        parse: |tokens| {
+            Some(DebuggerCommand::Attach(match tokens.get(1) {
+                Some(pid) => Some(pid.parse().ok()?),
+                None => None,
+            }))
+        },
+    },
+    Command {
+        name: "detach",
+        aliases: &[],
+        args: "",
+        doc: "Let the program go on running without the debugger.",
+        complete: Complete::Nothing,
+        parse: |_| Some(DebuggerCommand::Detach),
+    },
+    Command {
+        name: "gcore",
+        aliases: &["generate-core-file"],
+        args: "[file]",
+        doc: "Write a core file of the program as it is now.\nThe file is core.<pid> unless one is given.",
+        complete: Complete::File,
+        parse: |tokens| Some(DebuggerCommand::GenerateCore(tokens.get(1).map(|s| s.to_string()))),
+    },
+    Command {
+        name: "continue",
+        aliases: &["c", "cont"],
+        args: "",
+        doc: "Resume the program until it stops again.",
+        complete: Complete::Nothing,
+        parse: |_| Some(DebuggerCommand::Continue),
+    },
+    Command {
+        name: "signal",
+        aliases: &[],
+        args: "<signal>",
+        doc: "Resume the program, delivering it a signal (0 for none).",
+        complete: Complete::Nothing,
+        parse: |tokens| Some(DebuggerCommand::Signal(tokens.get(1).map(|s| s.to_string()))),
+    },
+    Command {
+        name: "handle",
+        aliases: &[],
+        args: "<signal...> [no]stop|[no]print|[no]pass...",
+        doc: "Set what happens when the program receives a signal.",
+        complete: Complete::Nothing,
+        parse: |tokens| {
+            Some(DebuggerCommand::Handle(
+                tokens[1..].iter().map(|s| s.to_string()).collect(),
+            ))
+        },
+    },
+    Command {
+        name: "backtrace",
+        aliases: &["bt", "back"],
+        args: "",
+        doc: "Print the stack of the current thread, innermost frame first.",
+        complete: Complete::Nothing,
+        parse: |_| Some(DebuggerCommand::BackTrace),
+    },
+    Command {
+        name: "frame",
+        aliases: &["f"],
+        args: "[level]",
+        doc: "Select the frame at a level of the stack, or describe the selected one.",
+        complete: Complete::Nothing,
+        parse: |tokens| {
+            Some(DebuggerCommand::Frame(match tokens.get(1) {
+                Some(level) => Some(level.parse().ok()?),
+                None => None,
+            }))
+        },
+    },
+    Command {
+        name: "up",
+        aliases: &[],
+        args: "[count]",
+        doc: "Select the frame that called the selected one (count frames out).",
+        complete: Complete::Nothing,
+        parse: |tokens| {
+            Some(DebuggerCommand::Up(match tokens.get(1) {
+                Some(count) => count.parse().ok()?,
+                None => 1,
+            }))
+        },
+    },
+    Command {
+        name: "down",
+        aliases: &[],
+        args: "[count]",
+        doc: "Select the frame the selected one called (count frames in).",
+        complete: Complete::Nothing,
+        parse: |tokens| {
+            Some(DebuggerCommand::Down(match tokens.get(1) {
+                Some(count) => count.parse().ok()?,
+                None => 1,
+            }))
+        },
+    },
+    Command {
+        name: "thread",
+        aliases: &[],
+        args: "[number] | apply <numbers...|all> <command>",
+        doc: "Select a thread, or describe the current one.\n`thread apply` runs a command in each of the given threads.",
+        complete: Complete::Nothing,
+        parse: |tokens| {
+            if tokens.get(1) != Some(&"apply") {
+                return Some(DebuggerCommand::Thread(match tokens.get(1) {
+                    Some(number) => Some(number.parse().ok()?),
+                    None => None,
+                }));
+            }
+            let (numbers, rest) = if tokens.get(2) == Some(&"all") {
+                (None, &tokens[3..])
+            } else {
+                let count = tokens[2..]
+                    .iter()
+                    .take_while(|token| token.parse::<usize>().is_ok())
+                    .count();
+                if count == 0 {
+                    return None;
+                }
+                (parse_numbers(&tokens[2..2 + count]), &tokens[2 + count..])
+            };
+            if rest.is_empty() {
+                return None;
+            }
+            Some(DebuggerCommand::ThreadApply(
+                numbers,
+                Box::new(DebuggerCommand::from_tokens(&rest.to_vec())?),
+            ))
+        },
+    },
+    Command {
+        name: "break",
+        aliases: &["b"],
+        args: "<*address|file:line|line|function>",
+        doc: "Set a breakpoint at an address, a source line or the start of a function.",
+        complete: Complete::Location,
+        parse: |tokens| Some(DebuggerCommand::Break(tokens.get(1).map(|s| s.to_string()))),
+    },
+    Command {
+        name: "info",
+        aliases: &["i"],
+        args: "<topic>",
+        doc: "Describe the state of the program or the debugger. The topics are:\n  breakpoints, frame, locals, args, registers [names...], all-registers, threads,\n  signals [signal]",
+        complete: Complete::Words(INFO_TOPICS),
+        parse: |tokens| match tokens.get(1) {
+            Some(&"b") | Some(&"break") | Some(&"breakpoints") => {
+                Some(DebuggerCommand::InfoBreakpoints)
+            }
+            Some(&"f") | Some(&"frame") => Some(DebuggerCommand::InfoFrame),
+            Some(&"locals") => Some(DebuggerCommand::InfoLocals),
+            Some(&"args") => Some(DebuggerCommand::InfoArgs),
+            Some(&"r") | Some(&"reg") | Some(&"registers") => Some(DebuggerCommand::InfoRegisters(
+                tokens[2..]
+                    .iter()
+                    .map(|name| name.trim_start_matches('$').to_string())
+                    .collect(),
+            )),
+            Some(&"all-registers") => Some(DebuggerCommand::InfoAllRegisters),
+            Some(&"threads") => Some(DebuggerCommand::InfoThreads),
+            Some(&"signals") | Some(&"handle") => Some(DebuggerCommand::InfoSignals(
+                tokens.get(2).map(|s| s.to_string()),
+            )),
+            _ => None,
+        },
+    },
+    Command {
+        name: "delete",
+        aliases: &["d"],
+        args: "[numbers...]",
+        doc: "Delete breakpoints and watchpoints, or all of them if no numbers are given.",
+        complete: Complete::Nothing,
+        parse: |tokens| Some(DebuggerCommand::Delete(parse_numbers(&tokens[1..])?)),
+    },
+    Command {
+        name: "disable",
+        aliases: &[],
+        args: "[numbers...]",
+        doc: "Disable breakpoints and watchpoints, or all of them if no numbers are given.",
+        complete: Complete::Nothing,
+        parse: |tokens| Some(DebuggerCommand::Disable(parse_numbers(&tokens[1..])?)),
+    },
+    Command {
+        name: "enable",
+        aliases: &[],
+        args: "[numbers...]",
+        doc: "Enable breakpoints and watchpoints, or all of them if no numbers are given.",
+        complete: Complete::Nothing,
+        parse: |tokens| Some(DebuggerCommand::Enable(parse_numbers(&tokens[1..])?)),
+    },
+    Command {
+        name: "ignore",
+        aliases: &[],
+        args: "<number> <count>",
+        doc: "Let a breakpoint be crossed count times before it stops the program.",
+        complete: Complete::Nothing,
+        parse: |tokens| {
+            if tokens.len() != 3 {
+                return None;
+            }
+            Some(DebuggerCommand::Ignore(
+                tokens[1].parse().ok()?,
+                tokens[2].parse().ok()?,
+            ))
+        },
+    },
+    Command {
+        name: "condition",
+        aliases: &[],
+        args: "<number> [expression]",
+        doc: "Make a breakpoint stop only when an expression is true, or always if none is given.",
+        complete: Complete::Expression,
+        parse: |tokens| {
+            Some(DebuggerCommand::Condition(
+                tokens.get(1)?.parse().ok()?,
+                if tokens.len() > 2 {
+                    Some(tokens[2..].join(" "))
+                } else {
+                    None
+                },
+            ))
+        },
+    },
+    Command {
+        name: "watch",
+        aliases: &[],
+        args: "<expression>",
+        doc: "Stop the program whenever the value of an lvalue changes.",
+        complete: Complete::Expression,
+        parse: |tokens| Some(DebuggerCommand::Watch(WatchKind::Write, rest_of_line(tokens))),
+    },
+    Command {
+        name: "rwatch",
+        aliases: &[],
+        args: "<expression>",
+        doc: "Stop the program whenever it reads an lvalue.",
+        complete: Complete::Expression,
+        parse: |tokens| Some(DebuggerCommand::Watch(WatchKind::Read, rest_of_line(tokens))),
+    },
+    Command {
+        name: "awatch",
+        aliases: &[],
+        args: "<expression>",
+        doc: "Stop the program whenever it reads or writes an lvalue.",
+        complete: Complete::Expression,
+        parse: |tokens| Some(DebuggerCommand::Watch(WatchKind::Access, rest_of_line(tokens))),
+    },
+    Command {
+        name: "step",
+        aliases: &["s"],
+        args: "",
+        doc: "Run to the next source line, stepping into calls.",
+        complete: Complete::Nothing,
+        parse: |_| Some(DebuggerCommand::Step),
+    },
+    Command {
+        name: "next",
+        aliases: &["n"],
+        args: "",
+        doc: "Run to the next source line, stepping over calls.",
+        complete: Complete::Nothing,
+        parse: |_| Some(DebuggerCommand::Next),
+    },
+    Command {
+        name: "finish",
+        aliases: &["fin"],
+        args: "",
+        doc: "Run until the selected frame returns, and print the value it returns.",
+        complete: Complete::Nothing,
+        parse: |_| Some(DebuggerCommand::Finish),
+    },
+    Command {
+        name: "stepi",
+        aliases: &["si"],
+        args: "",
+        doc: "Run one instruction, stepping into calls.",
+        complete: Complete::Nothing,
+        parse: |_| Some(DebuggerCommand::StepInstruction),
+    },
+    Command {
+        name: "nexti",
+        aliases: &["ni"],
+        args: "",
+        doc: "Run one instruction, stepping over calls.",
+        complete: Complete::Nothing,
+        parse: |_| Some(DebuggerCommand::NextInstruction),
+    },
+    Command {
+        name: "print",
+        aliases: &["p"],
+        args: "<expression>",
+        doc: "Evaluate an expression in the selected frame and print its value.",
+        complete: Complete::Expression,
+        parse: |tokens| Some(DebuggerCommand::Print(rest_of_line(tokens))),
+    },
+    Command {
+        name: "ptype",
+        aliases: &[],
+        args: "<expression|type>",
+        doc: "Print the type of an expression, or the definition of a type.",
+        complete: Complete::Expression,
+        parse: |tokens| Some(DebuggerCommand::Ptype(rest_of_line(tokens))),
+    },
+    Command {
+        name: "set",
+        aliases: &[],
+        args: "var <lvalue> = <expression> | <setting> <value>",
+        doc: "Assign to a variable or a location in memory, or change a setting.\nThe settings are follow-fork-mode (parent|child) and detach-on-fork (on|off).",
+        complete: Complete::Expression,
+        parse: |tokens| match *tokens.get(1)? {
+            "var" | "variable" if tokens.len() > 2 => {
+                Some(DebuggerCommand::Set(tokens[2..].join(" ")))
+            }
+            "var" | "variable" => None,
+            "follow-fork-mode" | "detach-on-fork" => Some(DebuggerCommand::SetOption(
+                tokens[1].to_string(),
+                tokens.get(2).map(|s| s.to_string()),
+            )),
+            // Like gdb, `set *0x601040 = 1` is short for `set var *0x601040 = 1`
+            _ => Some(DebuggerCommand::Set(tokens[1..].join(" "))),
+        },
+    },
+    Command {
+        name: "show",
+        aliases: &[],
+        args: "<setting>",
+        doc: "Print the value of a setting.",
+        complete: Complete::Words(SETTINGS),
+        parse: |tokens| {
+            if tokens.len() != 2 {
+                return None;
+            }
+            Some(DebuggerCommand::Show(tokens[1].to_string()))
+        },
+    },
+    Command {
+        name: "x",
+        aliases: &[],
+        args: "[/NFU] <address>",
+        doc: "Examine memory: N units of size U (b, h, w or g) in format F (x, d, u, o, t, c, s or i).\nWith no address, carry on from where the last x stopped.",
+        complete: Complete::Expression,
+        parse: |tokens| {
+            let format = match tokens[0].split_once('/') {
+                Some((_, format)) => ExamineFormat::parse(format)?,
+                None => ExamineFormat::default(),
+            };
+            Some(DebuggerCommand::Examine(format, rest_of_line(tokens)))
+        },
+    },
+    Command {
+        name: "disassemble",
+        aliases: &["disas"],
+        args: "[/rs] [function|start,end|start,+length]",
+        doc: "Disassemble a function or a range of addresses, by default the function of the selected frame.\n/s puts the source lines in between the instructions and /r shows their bytes.",
+        complete: Complete::Location,
+        parse: |tokens| {
+            // The modifiers can be stuck to the command (`disas/s`) or come after it
+            let mut modifiers = tokens[0]
+                .split_once('/')
+                .map_or("", |(_, modifiers)| modifiers)
+                .to_string();
+            let mut rest = &tokens[1..];
+            while let Some(token) = rest.first().and_then(|token| token.strip_prefix('/')) {
+                modifiers.push_str(token);
+                rest = &rest[1..];
+            }
+            Some(DebuggerCommand::Disassemble(
+                DisassembleOptions::parse(&modifiers)?,
+                if rest.is_empty() {
+                    None
+                } else {
+                    Some(rest.join(" "))
+                },
+            ))
+        },
+    },
+    Command {
+        name: "list",
+        aliases: &["l"],
+        args: "[file:line|line|function|-]",
+        doc: "List source lines around a location, after the last listing, or before it with -.",
+        complete: Complete::Location,
+        parse: |tokens| Some(DebuggerCommand::List(rest_of_line(tokens))),
+    },
+    Command {
+        name: "directory",
+        aliases: &["dir"],
+        args: "[directory]",
+        doc: "Add a directory to search for source files in, or forget them all if none is given.",
+        complete: Complete::File,
+        parse: |tokens| Some(DebuggerCommand::Directory(rest_of_line(tokens))),
+    },
+    Command {
+        name: "source",
+        aliases: &[],
+        args: "<file>",
+        doc: "Run the commands in a file.",
+        complete: Complete::File,
+        parse: |tokens| Some(DebuggerCommand::Source(rest_of_line(tokens))),
+    },
+    Command {
+        name: "define",
+        aliases: &[],
+        args: "<name>",
+        doc: "Define a command running the lines that follow, up to \"end\".\nIn them, $arg0 to $argN stand for its arguments and $argc for how many there are.",
+        complete: Complete::Nothing,
+        parse: |tokens| {
+            if tokens.len() > 2 {
+                return None;
+            }
+            Some(DebuggerCommand::Define(tokens.get(1).map(|s| s.to_string())))
+        },
+    },
+    Command {
+        name: "commands",
+        aliases: &[],
+        args: "[number]",
+        doc: "Run the lines that follow, up to \"end\", whenever a breakpoint stops the program.\nWithout a number, the commands are for the last breakpoint set.",
+        complete: Complete::Nothing,
+        parse: |tokens| {
+            Some(DebuggerCommand::Commands(match tokens.get(1) {
+                Some(number) => Some(number.parse().ok()?),
+                None => None,
+            }))
+        },
+    },
+    Command {
+        name: "help",
+        aliases: &["h"],
+        args: "[command]",
+        doc: "List the commands, or describe one.",
+        complete: Complete::Command,
+        parse: |tokens| Some(DebuggerCommand::Help(rest_of_line(tokens))),
+    },
+    Command {
+        name: "quit",
+        aliases: &["q"],
+        args: "",
+        doc: "Exit the debugger, killing the program if it was started by it.",
+        complete: Complete::Nothing,
+        parse: |_| Some(DebuggerCommand::Quit),
+    },
+];
+
+/// Finds the command a word stands for: a command by name or alias, or else the only one whose
+/// name starts with the word.
+pub fn find_command(word: &str) -> Result<&'static Command, String> {
+    if let Some(command) = COMMANDS.iter().find(|command| command.is_called(word)) {
+        return Ok(command);
+    }
+    let matches: Vec<&Command> = COMMANDS
+        .iter()
+        .filter(|command| !word.is_empty() && command.name.starts_with(word))
+        .collect();
+    match matches.as_slice() {
+        [command] => Ok(command),
+        [] => Err(format!("Undefined command: \"{}\".", word)),
+        _ => {
+            let mut names: Vec<&str> = matches.iter().map(|command| command.name).collect();
+            names.sort_unstable();
+            Err(format!(
+                "Ambiguous command \"{}\": {}.",
+                word,
+                names.join(", ")
+            ))
+        }
+    }
+}
+
+/// The command word of a line, without the modifiers some commands take (`x/4xw`, `disas/s`).
+pub fn command_word(token: &str) -> &str {
+    token.split('/').next().unwrap()
 }
 
 impl DebuggerCommand {
     pub fn from_tokens(tokens: &Vec<&str>) -> Option<DebuggerCommand> {
-        match tokens[0] {
-            "q" | "quit" => Some(DebuggerCommand::Quit),
-            "r" | "run" => {
-                let args = tokens[1..].to_vec();
-                Some(DebuggerCommand::Run(
-                    args.iter().map(|s| s.to_string()).collect(),
-                ))
-            }
-            "c" | "cont" | "continue" => Some(DebuggerCommand::Continue),
-            "bt" | "back" | "backtrace" => Some(DebuggerCommand::BackTrace),
-            // Default case:
-            _ => None,
+        let command = find_command(command_word(tokens[0])).ok()?;
+        if tokens[0].contains('/') && !matches!(command.name, "x" | "disassemble") {
+            return None;
+        }
+        (command.parse)(tokens)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_find_command() {
+        assert_eq!(find_command("b").unwrap().name, "break");
+        assert_eq!(find_command("disas").unwrap().name, "disassemble");
+        assert_eq!(find_command("brea").unwrap().name, "break");
+        assert_eq!(find_command("wa").unwrap().name, "watch");
+        // An alias wins over the commands it abbreviates
+        assert_eq!(find_command("s").unwrap().name, "step");
+        assert_eq!(
+            find_command("con").err(),
+            Some("Ambiguous command \"con\": condition, continue.".to_string())
+        );
+        assert!(find_command("frobnicate").is_err());
+
+        assert!(matches!(
+            DebuggerCommand::from_tokens(&vec!["x/4xw", "&x"]),
+            Some(DebuggerCommand::Examine(..))
+        ));
+        assert!(matches!(
+            DebuggerCommand::from_tokens(&vec!["thread", "apply", "all", "bt"]),
+            Some(DebuggerCommand::ThreadApply(None, _))
+        ));
+        assert!(DebuggerCommand::from_tokens(&vec!["print/x", "1"]).is_none());
+        assert!(DebuggerCommand::from_tokens(&vec!["ignore", "1"]).is_none());
+
+        // Every name and alias leads back to its own command
+        for command in COMMANDS {
+            for word in std::iter::once(&command.name).chain(command.aliases) {
+                assert_eq!(find_command(word).unwrap().name, command.name);
+            }
+        }
+    }
+
+    #[test]
+    fn test_examine_format() {
+        let parse = |text| {
+            let format = ExamineFormat::parse(text)?;
+            Some((format.count, format.format, format.unit))
+        };
+        // Whatever is left out comes from the previous x
+        assert_eq!(parse(""), Some((None, None, None)));
+        assert_eq!(parse("4"), Some((Some(4), None, None)));
+        assert_eq!(parse("x"), Some((None, Some('x'), None)));
+        assert_eq!(parse("g"), Some((None, None, Some(8))));
+        assert_eq!(parse("16xb"), Some((Some(16), Some('x'), Some(1))));
+        // The format and unit letters can come in either order, and the last of each wins
+        assert_eq!(parse("2hd"), Some((Some(2), Some('d'), Some(2))));
+        assert_eq!(parse("3sic"), Some((Some(3), Some('c'), None)));
+        assert_eq!(parse("wg"), Some((None, None, Some(8))));
+        assert_eq!(parse("4q"), None);
+        assert_eq!(parse("x4"), None);
+        assert_eq!(parse("99999999999999999999999"), None);
+
+        match DebuggerCommand::from_tokens(&vec!["x/2dw", "&x"]) {
+            Some(DebuggerCommand::Examine(format, location)) => {
+                assert_eq!(format.count, Some(2));
+                assert_eq!(format.format, Some('d'));
+                assert_eq!(format.unit, Some(4));
+                assert_eq!(location, Some("&x".to_string()));
+            }
+            _ => panic!("x/2dw &x isn't an x command"),
+        }
+        match DebuggerCommand::from_tokens(&vec!["x"]) {
+            Some(DebuggerCommand::Examine(format, None)) => assert!(format.count.is_none()),
+            _ => panic!("x isn't an x command"),
         }
     }
 }
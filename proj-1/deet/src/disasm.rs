@@ -0,0 +1,107 @@
+use iced_x86::{Decoder, DecoderOptions, Formatter, GasFormatter, Instruction as Decoded, OpKind};
+
+use crate::target::Target;
+
+/// The longest an x86-64 instruction can be.
+pub const MAX_INSTRUCTION_LEN: usize = 15;
+
+/// A decoded instruction, in the AT&T syntax gdb uses.
+pub struct Instruction {
+    pub addr: usize,
+    pub bytes: Vec<u8>,
+    pub text: String,
+    /// Where a direct call or jump goes, for annotating with the function it lands in
+    pub target: Option<usize>,
+    /// The address a %rip-relative operand works out to, which gdb shows in a comment
+    pub rip_relative: Option<usize>,
+}
+
+/// Reads up to `len` bytes of code at `addr`. If that runs off the end of the mapping, we make do
+/// with what is left of the page.
+pub fn read_code(target: &dyn Target, addr: usize, len: usize) -> Vec<u8> {
+    target
+        .read_memory(addr, len)
+        .or_else(|_| target.read_memory(addr, len.min(0x1000 - addr % 0x1000)))
+        .unwrap_or_default()
+}
+
+/// Decodes the machine code in `bytes`, which was read from `addr`, stopping after `count`
+/// instructions if given. An instruction cut off by the end of `bytes` is left out; bytes that
+/// don't make an instruction come out as "(bad)".
+pub fn decode(bytes: &[u8], addr: usize, count: Option<usize>) -> Vec<Instruction> {
+    let mut decoder = Decoder::with_ip(64, bytes, addr as u64, DecoderOptions::NONE);
+    let mut formatter = GasFormatter::new();
+    // "mov    %rsp,%rbp", like objdump and gdb
+    formatter.options_mut().set_first_operand_char_index(7);
+    formatter.options_mut().set_uppercase_hex(false);
+    formatter.options_mut().set_hex_prefix("0x");
+    formatter.options_mut().set_hex_suffix("");
+    formatter.options_mut().set_branch_leading_zeros(false);
+    formatter.options_mut().set_rip_relative_addresses(true);
+
+    let mut instructions = Vec::new();
+    let mut decoded = Decoded::default();
+    while decoder.can_decode() && count != Some(instructions.len()) {
+        let start = decoder.position();
+        decoder.decode_out(&mut decoded);
+        let end = decoder.position();
+        if decoded.is_invalid() && end == bytes.len() && bytes.len() - start < MAX_INSTRUCTION_LEN {
+            // Probably just cut short by the end of what we read
+            break;
+        }
+        let mut text = String::new();
+        if decoded.is_invalid() {
+            text.push_str("(bad)");
+        } else {
+            formatter.format(&decoded, &mut text);
+        }
+        let target = match decoded.op0_kind() {
+            OpKind::NearBranch64 => Some(decoded.near_branch_target() as usize),
+            _ => None,
+        };
+        let rip_relative = if decoded.is_ip_rel_memory_operand() {
+            Some(decoded.ip_rel_memory_address() as usize)
+        } else {
+            None
+        };
+        instructions.push(Instruction {
+            addr: addr + start,
+            bytes: bytes[start..end].to_vec(),
+            text,
+            target,
+            rip_relative,
+        });
+    }
+    instructions
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_decode() {
+        // push %rbp; mov %rsp,%rbp; call .+5; lea 0x10(%rip),%rax; and the first byte of
+        // another call
+        let code = [
+            0x55, 0x48, 0x89, 0xe5, 0xe8, 0x00, 0x00, 0x00, 0x00, 0x48, 0x8d, 0x05, 0x10, 0x00,
+            0x00, 0x00, 0xe8,
+        ];
+        let instructions = decode(&code, 0x401000, None);
+        let text: Vec<&str> = instructions.iter().map(|i| i.text.as_str()).collect();
+        assert_eq!(
+            text,
+            vec![
+                "push   %rbp",
+                "mov    %rsp,%rbp",
+                "call   0x401009",
+                "lea    0x10(%rip),%rax"
+            ]
+        );
+        assert_eq!(instructions[1].addr, 0x401001);
+        assert_eq!(instructions[1].bytes, vec![0x48, 0x89, 0xe5]);
+        assert_eq!(instructions[2].target, Some(0x401009));
+        assert_eq!(instructions[3].rip_relative, Some(0x401020));
+        assert_eq!(decode(&code, 0x401000, Some(1)).len(), 1);
+    }
+}
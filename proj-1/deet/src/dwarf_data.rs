@@ -1,7 +1,12 @@
 use crate::gimli_wrapper;
+use crate::unwind::CallFrameInfo;
 use addr2line::Context;
-use object::Object;
+use object::{Object, ObjectSegment, SymbolKind};
+use std::cmp::Reverse;
+use std::collections::HashMap;
 use std::convert::TryInto;
+use std::ops::Range;
+use std::path::Path;
 use std::{fmt, fs};
 
 #[derive(Debug)]
@@ -10,9 +15,33 @@ pub enum Error {
     DwarfFormatError(gimli_wrapper::Error),
 }
 
+/// The debugging information of an executable, together with that of the shared libraries it has
+/// loaded. Addresses going in and out are run time addresses: `relocate` moves everything to
+/// where the file is loaded, and lookups by address find their way into the right library.
 pub struct DwarfData {
+    path: String,
     files: Vec<File>,
+    /// Every type in the program, keyed by its .debug_info offset
+    types: HashMap<usize, Type>,
     addr2line: Context<addr2line::gimli::EndianRcSlice<addr2line::gimli::RunTimeEndian>>,
+    /// Unwind tables for the file
+    cfi: Option<CallFrameInfo>,
+    /// What was added to the addresses in the file to get the addresses it is loaded at. This is
+    /// zero for executables that aren't position-independent, and for PIEs until they are run.
+    bias: usize,
+    /// The entry point and the range of addresses the segments occupy, at link time
+    entry: usize,
+    segments: Range<usize>,
+    /// Functions from the ELF symbol tables (at link time addresses), for code without debug
+    /// info such as most of libc
+    symbols: Vec<Symbol>,
+    libraries: Vec<DwarfData>,
+}
+
+struct Symbol {
+    name: String,
+    address: usize,
+    size: usize,
 }
 
 impl fmt::Debug for DwarfData {
@@ -38,20 +67,163 @@ impl DwarfData {
         } else {
             gimli::RunTimeEndian::Big
         };
+        let (files, mut types) = gimli_wrapper::load_file(&object, endian)?;
+        // Expressions may cast to any of the C base types, even those the program never uses.
+        // Give the missing ones offsets past anything .debug_info could contain.
+        for (i, (name, size, encoding)) in C_BASE_TYPES.iter().enumerate() {
+            if !types.values().any(|ty| ty.name == *name) {
+                let ty = Type::new(name.to_string(), *size, TypeKind::Base(*encoding));
+                types.insert(usize::MAX - i, ty);
+            }
+        }
+        let start = object.segments().map(|s| s.address()).min().unwrap_or(0);
+        let end = object
+            .segments()
+            .map(|s| s.address() + s.size())
+            .max()
+            .unwrap_or(0);
+        let symbols: Vec<Symbol> = object
+            .symbols()
+            .chain(object.dynamic_symbols())
+            .filter(|(_, symbol)| symbol.kind() == SymbolKind::Text && symbol.address() != 0)
+            .filter_map(|(_, symbol)| {
+                Some(Symbol {
+                    name: symbol.name()?.to_string(),
+                    address: symbol.address() as usize,
+                    size: symbol.size() as usize,
+                })
+            })
+            .collect();
         Ok(DwarfData {
-            files: gimli_wrapper::load_file(&object, endian)?,
+            path: path.to_string(),
+            files,
+            types,
             addr2line: Context::new(&object).or_else(|e| Err(gimli_wrapper::Error::from(e)))?,
+            cfi: CallFrameInfo::from_object(&object, 0),
+            bias: 0,
+            entry: object.entry() as usize,
+            segments: start as usize..end as usize,
+            symbols,
+            libraries: Vec::new(),
         })
     }
 
-    #[allow(dead_code)]
+    /// Loads a shared library that is mapped `bias` bytes past its link time address. Only its
+    /// functions, lines and symbols are of use: variables and return types refer to types by
+    /// offset, and offsets are looked up in the executable's types, so those are dropped.
+    fn load_library(path: &str, bias: usize) -> Result<DwarfData, Error> {
+        let mut library = DwarfData::from_file(path)?;
+        for file in &mut library.files {
+            file.global_variables.clear();
+            for func in &mut file.functions {
+                func.variables.clear();
+                func.return_type = None;
+            }
+        }
+        library.relocate(bias);
+        Ok(library)
+    }
+
+    pub fn call_frame_info(&self) -> Option<&CallFrameInfo> {
+        self.cfi.as_ref()
+    }
+
+    pub fn bias(&self) -> usize {
+        self.bias
+    }
+
+    /// The link time address of the entry point. The kernel reports where the entry point ended
+    /// up, and the difference is the bias to relocate by.
+    pub fn entry(&self) -> usize {
+        self.entry
+    }
+
+    /// The run time addresses the file's segments occupy.
+    pub fn address_range(&self) -> Range<usize> {
+        self.segments.start.wrapping_add(self.bias)..self.segments.end.wrapping_add(self.bias)
+    }
+
+    /// Moves every address to where the file is loaded, `bias` bytes past its link time address.
+    pub fn relocate(&mut self, bias: usize) {
+        let delta = bias.wrapping_sub(self.bias);
+        let relocate_variable = |var: &mut Variable| {
+            if let Location::Address(addr) = &mut var.location {
+                *addr = addr.wrapping_add(delta);
+            }
+        };
+        for file in &mut self.files {
+            file.global_variables.iter_mut().for_each(relocate_variable);
+            for func in &mut file.functions {
+                func.address = func.address.wrapping_add(delta);
+                func.variables.iter_mut().for_each(relocate_variable);
+            }
+            for line in &mut file.lines {
+                line.address = line.address.wrapping_add(delta);
+            }
+        }
+        if let Some(cfi) = &mut self.cfi {
+            cfi.relocate(bias as u64);
+        }
+        self.bias = bias;
+    }
+
+    /// Brings the shared libraries up to date with the (path, load bias) pairs the dynamic linker
+    /// reports, loading the ones we haven't seen before. Libraries that can't be read are left
+    /// out.
+    pub fn update_libraries(&mut self, loaded: &[(String, usize)]) {
+        self.libraries
+            .retain(|library| loaded.contains(&(library.path.clone(), library.bias)));
+        for (path, bias) in loaded {
+            let known = self
+                .libraries
+                .iter()
+                .any(|library| library.path == *path && library.bias == *bias);
+            if !known {
+                if let Ok(library) = DwarfData::load_library(path, *bias) {
+                    self.libraries.push(library);
+                }
+            }
+        }
+    }
+
+    /// The path of the shared library `addr` is in, if it is in one.
+    pub fn library_path(&self, addr: usize) -> Option<&str> {
+        self.libraries
+            .iter()
+            .find(|library| library.address_range().contains(&addr))
+            .map(|library| library.path.as_str())
+    }
+
+    /// The object (the executable or one of its libraries) that `addr` belongs to. Addresses that
+    /// are in none of them are looked up in the executable, and come to nothing.
+    fn object_at(&self, addr: usize) -> &DwarfData {
+        self.libraries
+            .iter()
+            .find(|library| library.address_range().contains(&addr))
+            .unwrap_or(self)
+    }
+
     fn get_target_file(&self, file: &str) -> Option<&File> {
         self.files.iter().find(|f| {
             f.name == file || (!file.contains("/") && f.name.ends_with(&format!("/{}", file)))
         })
     }
 
-    #[allow(dead_code)]
+    /// Finds the compilation unit of a source file, named as the user typed it ("bt.c"), as the
+    /// compiler was given it, or by its full path, in the executable or one of its libraries.
+    pub fn get_file(&self, file: &str) -> Option<&File> {
+        std::iter::once(self)
+            .chain(self.libraries.iter())
+            .find_map(|object| {
+                object.get_target_file(file).or_else(|| {
+                    object
+                        .files
+                        .iter()
+                        .find(|f| Path::new(&f.comp_dir).join(&f.name) == Path::new(file))
+                })
+            })
+    }
+
     pub fn get_addr_for_line(&self, file: Option<&str>, line_number: usize) -> Option<usize> {
         let target_file = match file {
             Some(filename) => self.get_target_file(filename)?,
@@ -66,19 +238,39 @@ impl DwarfData {
         )
     }
 
-    #[allow(dead_code)]
+    /// Finds a function by name, first in the debug info of the executable and its libraries and
+    /// then in their symbol tables.
     pub fn get_addr_for_function(&self, file: Option<&str>, func_name: &str) -> Option<usize> {
+        let objects = || std::iter::once(self).chain(self.libraries.iter());
+        if let Some(addr) = objects().find_map(|object| object.find_function(file, func_name)) {
+            return Some(addr);
+        }
+        if file.is_some() {
+            return None;
+        }
+        objects().find_map(|object| {
+            let symbol = object
+                .symbols
+                .iter()
+                .find(|symbol| symbol.name == func_name)?;
+            Some(symbol.address.wrapping_add(object.bias))
+        })
+    }
+
+    fn find_function(&self, file: Option<&str>, func_name: &str) -> Option<usize> {
+        // Functions the file only declares (those it calls in a library) have no text
+        let defines = |func: &&Function| func.name == func_name && func.text_length > 0;
         match file {
             Some(filename) => Some(
                 self.get_target_file(filename)?
                     .functions
                     .iter()
-                    .find(|func| func.name == func_name)?
+                    .find(defines)?
                     .address,
             ),
             None => {
                 for file in &self.files {
-                    if let Some(func) = file.functions.iter().find(|func| func.name == func_name) {
+                    if let Some(func) = file.functions.iter().find(defines) {
                         return Some(func.address);
                     }
                 }
@@ -87,11 +279,116 @@ impl DwarfData {
         }
     }
 
-    #[allow(dead_code)]
+    /// Returns the address of the first line-table row past the prologue of the function starting
+    /// at `func_addr`, which is where gdb places a breakpoint on a function name. Stopping there
+    /// (rather than on `push rbp`) means the frame pointer has already been set up.
+    pub fn get_addr_after_prologue(&self, func_addr: usize) -> Option<usize> {
+        for file in &self.object_at(func_addr).files {
+            if let Some(func) = file.functions.iter().find(|func| func.address == func_addr) {
+                return file
+                    .lines
+                    .iter()
+                    .map(|line| line.address)
+                    .filter(|&addr| addr > func.address && addr < func.address + func.text_length)
+                    .min();
+            }
+        }
+        None
+    }
+
+    /// Returns true if `curr_addr` is where a row of the line table begins, i.e. the first
+    /// instruction generated for some source line.
+    pub fn is_line_start(&self, curr_addr: usize) -> bool {
+        self.object_at(curr_addr)
+            .files
+            .iter()
+            .any(|file| file.lines.iter().any(|line| line.address == curr_addr))
+    }
+
+    /// The source files with debug info in the program and its libraries.
+    pub fn files(&self) -> impl Iterator<Item = &File> {
+        std::iter::once(self)
+            .chain(self.libraries.iter())
+            .flat_map(|object| object.files.iter())
+    }
+
+    /// Returns the function whose text contains `curr_addr`.
+    pub fn get_function_containing(&self, curr_addr: usize) -> Option<&Function> {
+        self.object_at(curr_addr)
+            .files
+            .iter()
+            .flat_map(|file| file.functions.iter())
+            .find(|func| curr_addr >= func.address && curr_addr < func.address + func.text_length)
+    }
+
+    /// Looks up a variable by name as seen from `curr_addr`: locals and parameters of the
+    /// enclosing function shadow globals.
+    pub fn get_variable(&self, curr_addr: usize, name: &str) -> Option<&Variable> {
+        if let Some(func) = self.get_function_containing(curr_addr) {
+            if let Some(var) = func.variables.iter().find(|var| var.name == name) {
+                return Some(var);
+            }
+        }
+        self.files
+            .iter()
+            .flat_map(|file| file.global_variables.iter())
+            .find(|var| var.name == name)
+    }
+
+    /// Looks up a type that another type refers to. None stands for void.
+    pub fn get_type(&self, offset: Option<usize>) -> Option<&Type> {
+        lookup(&self.types, offset)
+    }
+
+    /// Strips typedefs and qualifiers to get at the type that determines how a value is laid out.
+    pub fn resolve_type<'a>(&'a self, mut ty: &'a Type) -> &'a Type {
+        while let TypeKind::Typedef(target) | TypeKind::Qualified(_, target) = ty.kind {
+            match self.get_type(target) {
+                Some(target) => ty = target,
+                None => break,
+            }
+        }
+        ty
+    }
+
+    /// Finds a type by its C name, e.g. "struct point", "char *" or "unsigned long", and returns
+    /// its offset.
+    pub fn find_type(&self, name: &str) -> Option<usize> {
+        let name = C_SYNONYMS
+            .iter()
+            .find(|(synonym, _)| *synonym == name)
+            .map_or(name, |(_, canonical)| canonical);
+        // Anonymous structs all share the name "struct {...}", so they can't be looked up
+        if name.contains("{...}") {
+            return None;
+        }
+        self.types
+            .iter()
+            .find(|(_, ty)| ty.name == name)
+            .map(|(offset, _)| *offset)
+    }
+
+    /// Looks up an enumerator by name, returning its value and the enum it belongs to.
+    pub fn find_enumerator(&self, name: &str) -> Option<(i64, &Type)> {
+        self.types.values().find_map(|ty| match &ty.kind {
+            TypeKind::Enum { enumerators, .. } => enumerators
+                .iter()
+                .find(|enumerator| enumerator.name == name)
+                .map(|enumerator| (enumerator.value, ty)),
+            _ => None,
+        })
+    }
+
+    /// Formats a C declaration of `declarator` with type `ty`; see `declare`.
+    pub fn declare(&self, ty: Option<&Type>, declarator: &str) -> String {
+        declare(&self.types, ty, declarator)
+    }
+
     pub fn get_line_from_addr(&self, curr_addr: usize) -> Option<Line> {
-        let location = self
+        let object = self.object_at(curr_addr);
+        let location = object
             .addr2line
-            .find_location(curr_addr.try_into().unwrap())
+            .find_location(curr_addr.checked_sub(object.bias)?.try_into().unwrap())
             .ok()??;
         Some(Line {
             file: location.file?.to_string(),
@@ -100,15 +397,44 @@ impl DwarfData {
         })
     }
 
-    #[allow(dead_code)]
+    /// Names the function containing `curr_addr`, from the debug info if there is any and
+    /// otherwise from the symbol table.
     pub fn get_function_from_addr(&self, curr_addr: usize) -> Option<String> {
-        let frame = self
+        let object = self.object_at(curr_addr);
+        let addr = curr_addr.checked_sub(object.bias)?;
+        let name = object
             .addr2line
-            .find_frames(curr_addr.try_into().unwrap())
-            .ok()?
-            .next()
-            .ok()??;
-        Some(frame.function?.raw_name().ok()?.to_string())
+            .find_frames(addr.try_into().unwrap())
+            .ok()
+            .and_then(|mut frames| frames.next().ok()?)
+            .and_then(|frame| Some(frame.function?.raw_name().ok()?.to_string()));
+        name.or_else(|| Some(object.symbol_containing(addr)?.name.clone()))
+    }
+
+    /// Finds the symbol-table function containing `addr`, a link time address.
+    fn symbol_containing(&self, addr: usize) -> Option<&Symbol> {
+        // Of several names for the same function (`puts` and `_IO_puts`, say), the one with the
+        // fewest leading underscores is usually the one people know it by
+        self.symbols
+            .iter()
+            .filter(|symbol| symbol.address <= addr && addr < symbol.address + symbol.size.max(1))
+            .max_by_key(|symbol| {
+                let underscores = symbol.name.len() - symbol.name.trim_start_matches('_').len();
+                (symbol.address, Reverse(underscores))
+            })
+    }
+
+    /// The name and run time address range of the function containing `curr_addr`, from the
+    /// debug info if there is any and otherwise from the symbol table.
+    pub fn get_function_bounds(&self, curr_addr: usize) -> Option<(String, Range<usize>)> {
+        if let Some(function) = self.get_function_containing(curr_addr) {
+            let start = function.address;
+            return Some((function.name.clone(), start..start + function.text_length));
+        }
+        let object = self.object_at(curr_addr);
+        let symbol = object.symbol_containing(curr_addr.checked_sub(object.bias)?)?;
+        let start = symbol.address.wrapping_add(object.bias);
+        Some((symbol.name.clone(), start..start + symbol.size.max(1)))
     }
 
     #[allow(dead_code)]
@@ -148,18 +474,257 @@ impl DwarfData {
     }
 }
 
-#[derive(Debug, Clone, Default)]
+/// How the bytes of a base type should be interpreted (DW_AT_encoding).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    Signed,
+    Unsigned,
+    SignedChar,
+    UnsignedChar,
+    Float,
+    Boolean,
+    Unknown,
+}
+
+/// The C base types, spelled the way gcc names them in DWARF.
+const C_BASE_TYPES: [(&str, usize, Encoding); 14] = [
+    ("char", 1, Encoding::SignedChar),
+    ("signed char", 1, Encoding::SignedChar),
+    ("unsigned char", 1, Encoding::UnsignedChar),
+    ("short int", 2, Encoding::Signed),
+    ("short unsigned int", 2, Encoding::Unsigned),
+    ("int", 4, Encoding::Signed),
+    ("unsigned int", 4, Encoding::Unsigned),
+    ("long int", 8, Encoding::Signed),
+    ("long unsigned int", 8, Encoding::Unsigned),
+    ("long long int", 8, Encoding::Signed),
+    ("long long unsigned int", 8, Encoding::Unsigned),
+    ("float", 4, Encoding::Float),
+    ("double", 8, Encoding::Float),
+    ("_Bool", 1, Encoding::Boolean),
+];
+
+/// Other ways of writing the base types above.
+const C_SYNONYMS: [(&str, &str); 14] = [
+    ("short", "short int"),
+    ("signed short", "short int"),
+    ("unsigned short", "short unsigned int"),
+    ("signed", "int"),
+    ("signed int", "int"),
+    ("unsigned", "unsigned int"),
+    ("long", "long int"),
+    ("signed long", "long int"),
+    ("unsigned long", "long unsigned int"),
+    ("unsigned long int", "long unsigned int"),
+    ("long long", "long long int"),
+    ("unsigned long long", "long long unsigned int"),
+    ("unsigned long long int", "long long unsigned int"),
+    ("bool", "_Bool"),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Qualifier {
+    Const,
+    Volatile,
+    Restrict,
+}
+
+impl fmt::Display for Qualifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Qualifier::Const => write!(f, "const"),
+            Qualifier::Volatile => write!(f, "volatile"),
+            Qualifier::Restrict => write!(f, "restrict"),
+        }
+    }
+}
+
+/// A field of a struct or union.
+#[derive(Debug, Clone)]
+pub struct Member {
+    pub name: String,
+    pub type_offset: usize,
+    /// Byte offset from the start of the enclosing struct
+    pub offset: usize,
+    /// For bit fields, the width in bits and the offset in bits past `offset`
+    pub bit_size: Option<usize>,
+    pub bit_offset: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct Enumerator {
+    pub name: String,
+    pub value: i64,
+}
+
+/// What kind of type a Type is. Types refer to each other by their .debug_info offset (see
+/// DwarfData::get_type) rather than by value, so that self-referential structs are representable.
+/// A target of None stands for void.
+#[derive(Debug, Clone)]
+pub enum TypeKind {
+    Base(Encoding),
+    Pointer(Option<usize>),
+    Struct {
+        tag: Option<String>,
+        members: Vec<Member>,
+    },
+    Union {
+        tag: Option<String>,
+        members: Vec<Member>,
+    },
+    Enum {
+        tag: Option<String>,
+        enumerators: Vec<Enumerator>,
+    },
+    /// The element count of each dimension, outermost first (None if the bound is unknown, as for
+    /// a flexible array member)
+    Array {
+        element: usize,
+        dimensions: Vec<Option<usize>>,
+    },
+    Typedef(Option<usize>),
+    Qualified(Qualifier, Option<usize>),
+    Function {
+        return_type: Option<usize>,
+        parameters: Vec<usize>,
+    },
+}
+
+#[derive(Debug, Clone)]
 pub struct Type {
+    /// The name as written in C, e.g. "unsigned int", "struct point" or "char *[4]"
     pub name: String,
     pub size: usize,
+    pub kind: TypeKind,
 }
 
 impl Type {
-    pub fn new(name: String, size: usize) -> Self {
-        Type {
-            name: name,
-            size: size,
+    pub fn new(name: String, size: usize, kind: TypeKind) -> Self {
+        Type { name, size, kind }
+    }
+}
+
+fn lookup(types: &HashMap<usize, Type>, offset: Option<usize>) -> Option<&Type> {
+    types.get(&offset?)
+}
+
+/// Writes a C declaration of `declarator` with type `ty` (None for void), e.g. "int (*cb)(int)" or
+/// "char *names[4]". With an empty declarator this is the name of the type itself.
+pub fn declare(types: &HashMap<usize, Type>, ty: Option<&Type>, declarator: &str) -> String {
+    let ty = match ty {
+        Some(ty) => ty,
+        None => return join_declaration("void", declarator),
+    };
+    match &ty.kind {
+        TypeKind::Base(_) | TypeKind::Typedef(_) => join_declaration(&ty.name, declarator),
+        TypeKind::Struct { tag, .. } => join_declaration(&tag_name("struct", tag), declarator),
+        TypeKind::Union { tag, .. } => join_declaration(&tag_name("union", tag), declarator),
+        TypeKind::Enum { tag, .. } => join_declaration(&tag_name("enum", tag), declarator),
+        TypeKind::Pointer(target) => {
+            declare_pointer(types, lookup(types, *target), format!("*{}", declarator))
+        }
+        TypeKind::Array {
+            element,
+            dimensions,
+        } => {
+            let mut declarator = declarator.to_string();
+            for dimension in dimensions {
+                match dimension {
+                    Some(count) => declarator.push_str(&format!("[{}]", count)),
+                    None => declarator.push_str("[]"),
+                }
+            }
+            declare(types, types.get(element), &declarator)
+        }
+        TypeKind::Qualified(qualifier, target) => match lookup(types, *target) {
+            // A qualified pointer puts the qualifier after the star: "char * const p"
+            Some(Type {
+                kind: TypeKind::Pointer(pointee),
+                ..
+            }) => {
+                let declarator = if declarator.is_empty() {
+                    format!("* {}", qualifier)
+                } else {
+                    format!("* {} {}", qualifier, declarator)
+                };
+                declare_pointer(types, lookup(types, *pointee), declarator)
+            }
+            target => format!("{} {}", qualifier, declare(types, target, declarator)),
+        },
+        TypeKind::Function {
+            return_type,
+            parameters,
+        } => {
+            let parameters: Vec<String> = parameters
+                .iter()
+                .map(|param| declare(types, types.get(param), ""))
+                .collect();
+            let parameters = if parameters.is_empty() {
+                "void".to_string()
+            } else {
+                parameters.join(", ")
+            };
+            declare(
+                types,
+                lookup(types, *return_type),
+                &format!("{}({})", declarator, parameters),
+            )
+        }
+    }
+}
+
+/// Declares a pointer whose declarator (already starting with "*") is `declarator`. Pointers to
+/// arrays and functions need parentheses, as in "int (*)[3]".
+fn declare_pointer(
+    types: &HashMap<usize, Type>,
+    pointee: Option<&Type>,
+    declarator: String,
+) -> String {
+    match pointee.map(|ty| &ty.kind) {
+        Some(TypeKind::Array { .. }) | Some(TypeKind::Function { .. }) => {
+            declare(types, pointee, &format!("({})", declarator))
+        }
+        _ => declare(types, pointee, &declarator),
+    }
+}
+
+fn join_declaration(base: &str, declarator: &str) -> String {
+    if declarator.is_empty() {
+        base.to_string()
+    } else {
+        format!("{} {}", base, declarator)
+    }
+}
+
+fn tag_name(keyword: &str, tag: &Option<String>) -> String {
+    match tag {
+        Some(tag) => format!("{} {}", keyword, tag),
+        None => format!("{} {{...}}", keyword),
+    }
+}
+
+/// Works out the size of a type that has no DW_AT_byte_size of its own from what it refers to.
+pub fn type_size(types: &HashMap<usize, Type>, ty: &Type) -> usize {
+    if ty.size > 0 {
+        return ty.size;
+    }
+    match &ty.kind {
+        TypeKind::Pointer(_) => 8,
+        TypeKind::Typedef(target) | TypeKind::Qualified(_, target) => {
+            lookup(types, *target).map_or(0, |target| type_size(types, target))
         }
+        TypeKind::Array {
+            element,
+            dimensions,
+        } => {
+            let element_size = types
+                .get(element)
+                .map_or(0, |element| type_size(types, element));
+            dimensions
+                .iter()
+                .fold(element_size, |size, count| size * count.unwrap_or(0))
+        }
+        _ => 0,
     }
 }
 
@@ -191,6 +756,7 @@ pub struct Variable {
     pub entity_type: Type,
     pub location: Location,
     pub line_number: usize, // Line number in source file
+    pub is_parameter: bool,
 }
 
 #[derive(Debug, Default, Clone)]
@@ -200,11 +766,14 @@ pub struct Function {
     pub text_length: usize,
     pub line_number: usize, // Line number in source file
     pub variables: Vec<Variable>,
+    pub return_type: Option<Type>, // None for void functions
 }
 
 #[derive(Debug, Default, Clone)]
 pub struct File {
     pub name: String,
+    /// The directory the compiler ran in, which a relative `name` is relative to
+    pub comp_dir: String,
     pub global_variables: Vec<Variable>,
     pub functions: Vec<Function>,
     pub lines: Vec<Line>,
@@ -223,4 +792,99 @@ impl fmt::Display for Line {
     }
 }
 
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn table() -> HashMap<usize, Type> {
+        let mut types = HashMap::new();
+        let int = Type::new("int".to_string(), 4, TypeKind::Base(Encoding::Signed));
+        let char_type = Type::new("char".to_string(), 1, TypeKind::Base(Encoding::SignedChar));
+        types.insert(1, int);
+        types.insert(2, char_type);
+        types.insert(3, Type::new(String::new(), 0, TypeKind::Pointer(Some(2))));
+        types.insert(
+            4,
+            Type::new(
+                String::new(),
+                0,
+                TypeKind::Qualified(Qualifier::Const, Some(2)),
+            ),
+        );
+        types.insert(5, Type::new(String::new(), 0, TypeKind::Pointer(Some(4))));
+        types.insert(
+            6,
+            Type::new(
+                String::new(),
+                0,
+                TypeKind::Qualified(Qualifier::Const, Some(3)),
+            ),
+        );
+        types.insert(
+            7,
+            Type::new(
+                String::new(),
+                0,
+                TypeKind::Array {
+                    element: 3,
+                    dimensions: vec![Some(4), Some(2)],
+                },
+            ),
+        );
+        types.insert(
+            8,
+            Type::new(
+                String::new(),
+                0,
+                TypeKind::Function {
+                    return_type: Some(1),
+                    parameters: vec![1, 5],
+                },
+            ),
+        );
+        types.insert(9, Type::new(String::new(), 0, TypeKind::Pointer(Some(8))));
+        types.insert(
+            10,
+            Type::new(
+                String::new(),
+                8,
+                TypeKind::Struct {
+                    tag: Some("point".to_string()),
+                    members: Vec::new(),
+                },
+            ),
+        );
+        types.insert(
+            11,
+            Type::new("point_t".to_string(), 0, TypeKind::Typedef(Some(10))),
+        );
+        types
+    }
+
+    #[test]
+    fn test_declare() {
+        let types = table();
+        let name = |offset| declare(&types, types.get(&offset), "");
+        assert_eq!(name(3), "char *");
+        assert_eq!(name(5), "const char *");
+        assert_eq!(name(6), "char * const");
+        assert_eq!(name(7), "char *[4][2]");
+        assert_eq!(name(9), "int (*)(int, const char *)");
+        assert_eq!(name(10), "struct point");
+        assert_eq!(declare(&types, types.get(&7), "names"), "char *names[4][2]");
+        assert_eq!(
+            declare(&types, types.get(&9), "cb"),
+            "int (*cb)(int, const char *)"
+        );
+        assert_eq!(declare(&types, None, ""), "void");
+    }
 
+    #[test]
+    fn test_type_size() {
+        let types = table();
+        assert_eq!(type_size(&types, &types[&3]), 8);
+        assert_eq!(type_size(&types, &types[&4]), 1);
+        assert_eq!(type_size(&types, &types[&7]), 64);
+        assert_eq!(type_size(&types, &types[&11]), 8);
+    }
+}
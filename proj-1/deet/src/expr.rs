@@ -0,0 +1,1241 @@
+//! A small C-like expression language, used by `print`, `set var` and breakpoint conditions.
+//! Expressions are parsed into an `Expr` tree up front and evaluated against the inferior (if any)
+//! by an `Evaluator`.
+
+use crate::dwarf_data::{DwarfData, Encoding, Type, TypeKind};
+use crate::registers;
+use crate::target::Target;
+use crate::unwind::Frame;
+use crate::value;
+use std::cmp::Ordering;
+use std::convert::{TryFrom, TryInto};
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnaryOp {
+    Neg,
+    Not,
+    BitNot,
+    Deref,
+    AddressOf,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinaryOp {
+    Mul,
+    Div,
+    Rem,
+    Add,
+    Sub,
+    Shl,
+    Shr,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Eq,
+    Ne,
+    BitAnd,
+    BitXor,
+    BitOr,
+    And,
+    Or,
+}
+
+/// Binary operators from the loosest binding to the tightest. Within a level, longer operators come
+/// first so that "<=" is not mistaken for "<".
+const BINARY_OPS: [&[(&str, BinaryOp)]; 10] = [
+    &[("||", BinaryOp::Or)],
+    &[("&&", BinaryOp::And)],
+    &[("|", BinaryOp::BitOr)],
+    &[("^", BinaryOp::BitXor)],
+    &[("&", BinaryOp::BitAnd)],
+    &[("==", BinaryOp::Eq), ("!=", BinaryOp::Ne)],
+    &[
+        ("<=", BinaryOp::Le),
+        (">=", BinaryOp::Ge),
+        ("<", BinaryOp::Lt),
+        (">", BinaryOp::Gt),
+    ],
+    &[("<<", BinaryOp::Shl), (">>", BinaryOp::Shr)],
+    &[("+", BinaryOp::Add), ("-", BinaryOp::Sub)],
+    &[
+        ("*", BinaryOp::Mul),
+        ("/", BinaryOp::Div),
+        ("%", BinaryOp::Rem),
+    ],
+];
+
+/// The name of a type in a cast, e.g. `(struct point *)`. Qualifiers are dropped.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TypeName {
+    pub base: String,
+    pub pointers: usize,
+}
+
+impl fmt::Display for TypeName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.pointers == 0 {
+            write!(f, "{}", self.base)
+        } else {
+            write!(f, "{} {}", self.base, "*".repeat(self.pointers))
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Integer(i64),
+    Float(f64),
+    Char(u8),
+    Variable(String),
+    Register(String),
+    /// `a.b`
+    Member(Box<Expr>, String),
+    /// `a->b`
+    PointerMember(Box<Expr>, String),
+    Index(Box<Expr>, Box<Expr>),
+    Unary(UnaryOp, Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+    Cast(TypeName, Box<Expr>),
+    Assign(Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Integer(i64),
+    Float(f64),
+    Char(u8),
+    Ident(String),
+    Register(String),
+    Punct(&'static str),
+}
+
+// Longer punctuation comes first so that "->" is not read as "-" ">".
+const PUNCTUATION: [&str; 28] = [
+    "->", "<<", ">>", "<=", ">=", "==", "!=", "&&", "||", ".", "[", "]", "(", ")", "*", "&", "+",
+    "-", "/", "%", "<", ">", "!", "~", "|", "^", "=", ",",
+];
+
+/// Words that can only start a type name, which is how `(int) x` is told apart from `(x)`.
+const TYPE_KEYWORDS: [&str; 13] = [
+    "struct", "union", "enum", "unsigned", "signed", "char", "short", "int", "long", "float",
+    "double", "void", "_Bool",
+];
+
+fn parse_number(text: &str) -> Result<Token, String> {
+    let invalid = || format!("Invalid number \"{}\".", text);
+    if text.contains('.') || text.contains('e') && !text.starts_with("0x") {
+        return text.parse::<f64>().map(Token::Float).map_err(|_| invalid());
+    }
+    // Integer suffixes (u, l, ul, ...) don't matter to us
+    let digits = text.trim_end_matches(&['u', 'U', 'l', 'L'][..]);
+    let value = if let Some(hex) = digits
+        .strip_prefix("0x")
+        .or_else(|| digits.strip_prefix("0X"))
+    {
+        u64::from_str_radix(hex, 16)
+    } else if digits.len() > 1 && digits.starts_with('0') {
+        u64::from_str_radix(&digits[1..], 8)
+    } else {
+        digits.parse::<u64>()
+    };
+    value
+        .map(|value| Token::Integer(value as i64))
+        .map_err(|_| invalid())
+}
+
+fn unescape(c: u8) -> u8 {
+    match c {
+        b'n' => b'\n',
+        b't' => b'\t',
+        b'r' => b'\r',
+        b'0' => 0,
+        other => other,
+    }
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let bytes = text.as_bytes();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let c = bytes[i];
+        if c.is_ascii_whitespace() {
+            i += 1;
+        } else if c.is_ascii_digit() {
+            let start = i;
+            while i < bytes.len() && (bytes[i].is_ascii_alphanumeric() || bytes[i] == b'.') {
+                i += 1;
+            }
+            tokens.push(parse_number(&text[start..i])?);
+        } else if c.is_ascii_alphabetic() || c == b'_' || c == b'$' {
+            let start = i;
+            i += 1;
+            while i < bytes.len() && (bytes[i].is_ascii_alphanumeric() || bytes[i] == b'_') {
+                i += 1;
+            }
+            let word = &text[start..i];
+            match word.strip_prefix('$') {
+                Some(name) if registers::is_register(name) => {
+                    tokens.push(Token::Register(name.to_string()))
+                }
+                Some(name) => return Err(format!("Unknown register ${}", name)),
+                None => tokens.push(Token::Ident(word.to_string())),
+            }
+        } else if c == b'\'' {
+            let (value, len) = match (bytes.get(i + 1), bytes.get(i + 2), bytes.get(i + 3)) {
+                (Some(b'\\'), Some(&escaped), Some(b'\'')) => (unescape(escaped), 4),
+                (Some(&value), Some(b'\''), _) if value != b'\\' => (value, 3),
+                _ => return Err("Unmatched single quote.".to_string()),
+            };
+            tokens.push(Token::Char(value));
+            i += len;
+        } else {
+            match PUNCTUATION
+                .iter()
+                .find(|punct| text[i..].starts_with(*punct))
+            {
+                Some(punct) => {
+                    tokens.push(Token::Punct(punct));
+                    i += punct.len();
+                }
+                None => return Err(format!("Invalid character '{}' in expression.", c as char)),
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn peek_punct(&self, punct: &str) -> bool {
+        matches!(self.peek(), Some(Token::Punct(p)) if *p == punct)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn expect(&mut self, punct: &str) -> Result<(), String> {
+        if self.peek_punct(punct) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(self.syntax_error())
+        }
+    }
+
+    fn syntax_error(&self) -> String {
+        match self.peek() {
+            Some(token) => format!("A syntax error in expression, near `{}'.", token),
+            None => "A syntax error in expression, near `'.".to_string(),
+        }
+    }
+
+    fn parse_assignment(&mut self) -> Result<Expr, String> {
+        let lhs = self.parse_binary(0)?;
+        if self.peek_punct("=") {
+            self.pos += 1;
+            let rhs = self.parse_assignment()?;
+            return Ok(Expr::Assign(Box::new(lhs), Box::new(rhs)));
+        }
+        Ok(lhs)
+    }
+
+    fn parse_binary(&mut self, level: usize) -> Result<Expr, String> {
+        if level == BINARY_OPS.len() {
+            return self.parse_unary();
+        }
+        let mut lhs = self.parse_binary(level + 1)?;
+        'operators: loop {
+            for (punct, op) in BINARY_OPS[level].iter() {
+                if self.peek_punct(punct) {
+                    self.pos += 1;
+                    let rhs = self.parse_binary(level + 1)?;
+                    lhs = Expr::Binary(*op, Box::new(lhs), Box::new(rhs));
+                    continue 'operators;
+                }
+            }
+            return Ok(lhs);
+        }
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr, String> {
+        let op = match self.peek() {
+            Some(Token::Punct("-")) => UnaryOp::Neg,
+            Some(Token::Punct("!")) => UnaryOp::Not,
+            Some(Token::Punct("~")) => UnaryOp::BitNot,
+            Some(Token::Punct("*")) => UnaryOp::Deref,
+            Some(Token::Punct("&")) => UnaryOp::AddressOf,
+            Some(Token::Punct("+")) => {
+                self.pos += 1;
+                return self.parse_unary();
+            }
+            Some(Token::Punct("(")) => {
+                if let Some(type_name) = self.parse_cast_type() {
+                    return Ok(Expr::Cast(type_name, Box::new(self.parse_unary()?)));
+                }
+                return self.parse_postfix();
+            }
+            _ => return self.parse_postfix(),
+        };
+        self.pos += 1;
+        Ok(Expr::Unary(op, Box::new(self.parse_unary()?)))
+    }
+
+    /// If the parenthesis at the current position opens a cast like `(unsigned int)` or
+    /// `(node_t *)`, consumes it and returns the type name. A lone name in parentheses is only a
+    /// cast if something that can be cast follows, so `(x) - 1` is still a subtraction.
+    fn parse_cast_type(&mut self) -> Option<TypeName> {
+        let mut words: Vec<String> = Vec::new();
+        let mut pointers = 0;
+        let mut end = self.pos + 1;
+        loop {
+            match self.tokens.get(end)? {
+                Token::Ident(word) if pointers == 0 => words.push(word.clone()),
+                Token::Punct("*") => pointers += 1,
+                Token::Punct(")") => break,
+                _ => return None,
+            }
+            end += 1;
+        }
+        words.retain(|word| word != "const" && word != "volatile");
+        let first = words.first()?;
+        let is_cast = TYPE_KEYWORDS.contains(&first.as_str())
+            || pointers > 0
+            || (words.len() == 1
+                && matches!(
+                    self.tokens.get(end + 1),
+                    Some(Token::Ident(_))
+                        | Some(Token::Integer(_))
+                        | Some(Token::Float(_))
+                        | Some(Token::Char(_))
+                        | Some(Token::Register(_))
+                        | Some(Token::Punct("("))
+                ));
+        if !is_cast {
+            return None;
+        }
+        self.pos = end + 1;
+        Some(TypeName {
+            base: words.join(" "),
+            pointers,
+        })
+    }
+
+    fn parse_postfix(&mut self) -> Result<Expr, String> {
+        let mut expr = self.parse_primary()?;
+        loop {
+            if self.peek_punct(".") || self.peek_punct("->") {
+                let arrow = self.peek_punct("->");
+                self.pos += 1;
+                let member = match self.next() {
+                    Some(Token::Ident(member)) => member,
+                    _ => {
+                        self.pos -= 1;
+                        return Err(self.syntax_error());
+                    }
+                };
+                expr = if arrow {
+                    Expr::PointerMember(Box::new(expr), member)
+                } else {
+                    Expr::Member(Box::new(expr), member)
+                };
+            } else if self.peek_punct("[") {
+                self.pos += 1;
+                let index = self.parse_assignment()?;
+                self.expect("]")?;
+                expr = Expr::Index(Box::new(expr), Box::new(index));
+            } else {
+                return Ok(expr);
+            }
+        }
+    }
+
+    fn parse_primary(&mut self) -> Result<Expr, String> {
+        match self.next() {
+            Some(Token::Integer(value)) => Ok(Expr::Integer(value)),
+            Some(Token::Float(value)) => Ok(Expr::Float(value)),
+            Some(Token::Char(value)) => Ok(Expr::Char(value)),
+            Some(Token::Ident(name)) => Ok(Expr::Variable(name)),
+            Some(Token::Register(name)) => Ok(Expr::Register(name)),
+            Some(Token::Punct("(")) => {
+                let expr = self.parse_assignment()?;
+                self.expect(")")?;
+                Ok(expr)
+            }
+            _ => {
+                self.pos -= 1;
+                Err(self.syntax_error())
+            }
+        }
+    }
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Integer(value) => write!(f, "{}", value),
+            Token::Float(value) => write!(f, "{}", value),
+            Token::Char(value) => write!(f, "'{}'", *value as char),
+            Token::Ident(name) => write!(f, "{}", name),
+            Token::Register(name) => write!(f, "${}", name),
+            Token::Punct(punct) => write!(f, "{}", punct),
+        }
+    }
+}
+
+/// Parses an expression such as `p->next->value + 1` or `arr[i] = 3`.
+pub fn parse(text: &str) -> Result<Expr, String> {
+    let mut parser = Parser {
+        tokens: tokenize(text)?,
+        pos: 0,
+    };
+    if parser.tokens.is_empty() {
+        return Err("Empty expression.".to_string());
+    }
+    let expr = parser.parse_assignment()?;
+    if parser.pos < parser.tokens.len() {
+        return Err(parser.syntax_error());
+    }
+    Ok(expr)
+}
+
+/// Where a value came from, if it can be assigned to.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Lvalue {
+    Memory(usize),
+    Register(String),
+}
+
+/// The result of evaluating an expression: some bytes and how to interpret them.
+#[derive(Debug, Clone)]
+pub struct Value {
+    pub ty: Type,
+    /// Where `ty` lives in DwarfData, when known; needed to take the value's address
+    type_offset: Option<usize>,
+    pub bytes: Vec<u8>,
+    pub lvalue: Option<Lvalue>,
+}
+
+/// A value reduced to what arithmetic needs.
+#[derive(Clone, Copy)]
+enum Scalar {
+    Int {
+        value: i64,
+        size: usize,
+        signed: bool,
+    },
+    Float(f64),
+    Pointer {
+        addr: u64,
+        pointee: Option<usize>,
+    },
+}
+
+pub struct Evaluator<'a> {
+    debug_data: &'a DwarfData,
+    inferior: Option<&'a dyn Target>,
+    /// The frame whose variables and registers expressions see; the innermost one if None
+    frame: Option<Frame>,
+}
+
+impl<'a> Evaluator<'a> {
+    pub fn new(debug_data: &'a DwarfData, inferior: Option<&'a dyn Target>) -> Evaluator<'a> {
+        Evaluator {
+            debug_data,
+            inferior,
+            frame: None,
+        }
+    }
+
+    /// Evaluates in the context of `frame` (as selected with `up`, `down` or `frame`) rather than
+    /// the innermost frame.
+    pub fn in_frame(mut self, frame: Option<Frame>) -> Evaluator<'a> {
+        self.frame = frame;
+        self
+    }
+
+    pub fn evaluate(&self, expr: &Expr) -> Result<Value, String> {
+        match expr {
+            Expr::Integer(value) => Ok(if i32::try_from(*value).is_ok() {
+                self.int_value(*value, 4, true)
+            } else {
+                self.int_value(*value, 8, true)
+            }),
+            Expr::Float(value) => Ok(self.base_value("double", &value.to_le_bytes())),
+            Expr::Char(value) => Ok(self.base_value("char", &[*value])),
+            Expr::Variable(name) => self.variable(name),
+            Expr::Register(name) => self.register(name),
+            Expr::Member(base, member) => {
+                let base = self.evaluate(base)?;
+                // Like gdb, let `.` see through a pointer
+                let base = match self.resolved(&base).kind {
+                    TypeKind::Pointer(_) => self.deref(&base)?,
+                    _ => base,
+                };
+                self.member(&base, member)
+            }
+            Expr::PointerMember(base, member) => {
+                let base = self.evaluate(base)?;
+                self.member(&self.deref(&base)?, member)
+            }
+            Expr::Index(base, index) => {
+                let base = self.evaluate(base)?;
+                let index = self.evaluate(index)?;
+                self.index(&base, &index)
+            }
+            Expr::Unary(op, operand) => self.unary(*op, &self.evaluate(operand)?),
+            Expr::Binary(op, lhs, rhs) => {
+                self.binary(*op, &self.evaluate(lhs)?, &self.evaluate(rhs)?)
+            }
+            Expr::Cast(type_name, operand) => {
+                let (ty, type_offset) = self.lookup_type(type_name)?;
+                self.cast(&self.evaluate(operand)?, ty, type_offset)
+            }
+            // Writing to the inferior needs more than a shared borrow of it, so assignments are
+            // carried out by the caller (see prepare_assignment)
+            Expr::Assign(_, _) => Err("Assignment is not allowed here.".to_string()),
+        }
+    }
+
+    /// Evaluates both sides of an assignment and returns where to write, what to write there,
+    /// and the left hand side as it was before.
+    pub fn prepare_assignment(&self, lhs: &Expr, rhs: &Expr) -> Result<(Lvalue, Vec<u8>), String> {
+        let lhs = self.evaluate(lhs)?;
+        let rhs = self.evaluate(rhs)?;
+        let lvalue = match &lhs.lvalue {
+            Some(lvalue) => lvalue.clone(),
+            None => return Err("Left operand of assignment is not an lvalue.".to_string()),
+        };
+        let bytes = self.convert(&rhs, &lhs.ty)?;
+        Ok((lvalue, bytes))
+    }
+
+    /// Formats a value the way `print` shows it.
+    pub fn format(&self, value: &Value) -> String {
+        value::format_value(&value.bytes, &value.ty, self.debug_data, self.inferior)
+    }
+
+    /// Whether a value counts as true in a condition.
+    pub fn is_true(&self, value: &Value) -> Result<bool, String> {
+        Ok(match self.scalar(value)? {
+            Scalar::Int { value, .. } => value != 0,
+            Scalar::Float(value) => value != 0.0,
+            Scalar::Pointer { addr, .. } => addr != 0,
+        })
+    }
+
+    /// Interprets a value as an address, as `x` does: pointers and integers stand for themselves,
+    /// and arrays and structs for where they live.
+    pub fn as_address(&self, value: &Value) -> Result<usize, String> {
+        match (&self.resolved(value).kind, &value.lvalue) {
+            (TypeKind::Struct { .. }, Some(Lvalue::Memory(addr)))
+            | (TypeKind::Union { .. }, Some(Lvalue::Memory(addr))) => return Ok(*addr),
+            _ => {}
+        }
+        match self.scalar(value)? {
+            Scalar::Int { value, .. } => Ok(value as usize),
+            Scalar::Pointer { addr, .. } => Ok(addr as usize),
+            Scalar::Float(_) => Err("Invalid address.".to_string()),
+        }
+    }
+
+    fn resolved<'v>(&'v self, value: &'v Value) -> &'v Type {
+        self.debug_data.resolve_type(&value.ty)
+    }
+
+    fn inferior(&self) -> Result<&dyn Target, String> {
+        self.inferior
+            .ok_or_else(|| "The program has no registers now.".to_string())
+    }
+
+    fn read_memory(&self, addr: usize, len: usize) -> Result<Vec<u8>, String> {
+        self.inferior
+            .and_then(|inferior| inferior.read_memory(addr, len).ok())
+            .ok_or_else(|| format!("Cannot access memory at address {:#x}", addr))
+    }
+
+    fn typed_value(&self, ty: Type, type_offset: Option<usize>, bytes: Vec<u8>) -> Value {
+        Value {
+            ty,
+            type_offset,
+            bytes,
+            lvalue: None,
+        }
+    }
+
+    /// A value of one of the C base types, which DwarfData always knows about.
+    fn base_value(&self, name: &str, bytes: &[u8]) -> Value {
+        let type_offset = self.debug_data.find_type(name);
+        let ty = self.debug_data.get_type(type_offset).unwrap().clone();
+        let size = ty.size;
+        self.typed_value(ty, type_offset, bytes[..size].to_vec())
+    }
+
+    fn int_value(&self, value: i64, size: usize, signed: bool) -> Value {
+        let name = match (size, signed) {
+            (8, true) => "long",
+            (8, false) => "unsigned long",
+            (_, true) => "int",
+            (_, false) => "unsigned int",
+        };
+        self.base_value(name, &value.to_le_bytes())
+    }
+
+    /// Makes a pointer type to the type at `pointee` (None for void).
+    fn pointer_type(&self, pointee: Option<usize>) -> Type {
+        let mut ty = Type::new(String::new(), 8, TypeKind::Pointer(pointee));
+        ty.name = self.debug_data.declare(Some(&ty), "");
+        ty
+    }
+
+    fn pointer_value(&self, addr: u64, pointee: Option<usize>) -> Value {
+        let ty = self.pointer_type(pointee);
+        let type_offset = self.debug_data.find_type(&ty.name);
+        self.typed_value(ty, type_offset, addr.to_le_bytes().to_vec())
+    }
+
+    fn lookup_type(&self, type_name: &TypeName) -> Result<(Type, Option<usize>), String> {
+        let no_type = || format!("No symbol \"{}\" in current context.", type_name);
+        if let Some(offset) = self.debug_data.find_type(&type_name.to_string()) {
+            return Ok((
+                self.debug_data.get_type(Some(offset)).unwrap().clone(),
+                Some(offset),
+            ));
+        }
+        let pointee = if type_name.base == "void" {
+            None
+        } else {
+            Some(
+                self.debug_data
+                    .find_type(&type_name.base)
+                    .ok_or_else(no_type)?,
+            )
+        };
+        match type_name.pointers {
+            // Pointers to pointers have to exist in the program already
+            1 => Ok((self.pointer_type(pointee), None)),
+            _ => Err(no_type()),
+        }
+    }
+
+    /// The frame to evaluate in, unwinding the innermost one if none was chosen.
+    fn frame(&self) -> Result<Frame, String> {
+        if let Some(frame) = &self.frame {
+            return Ok(frame.clone());
+        }
+        let inferior = self
+            .inferior
+            .ok_or_else(|| "No frame selected.".to_string())?;
+        let mut frames = inferior
+            .frames(self.debug_data, Some(1))
+            .map_err(|e| e.to_string())?;
+        Ok(frames.remove(0))
+    }
+
+    fn variable(&self, name: &str) -> Result<Value, String> {
+        let frame = match self.inferior {
+            Some(_) => Some(self.frame()?),
+            None => None,
+        };
+        let pc = frame.as_ref().map_or(0, |frame| frame.lookup_pc());
+        if let Some(var) = self.debug_data.get_variable(pc, name) {
+            let frame = frame.ok_or_else(|| "No frame selected.".to_string())?;
+            let addr = frame
+                .variable_address(var)
+                .ok_or_else(|| format!("Cannot find the frame base of \"{}\".", name))?;
+            let bytes = self.read_memory(addr, var.entity_type.size)?;
+            return Ok(Value {
+                ty: var.entity_type.clone(),
+                type_offset: self.debug_data.find_type(&var.entity_type.name),
+                bytes,
+                lvalue: Some(Lvalue::Memory(addr)),
+            });
+        }
+        if let Some((value, enum_type)) = self.debug_data.find_enumerator(name) {
+            let type_offset = self.debug_data.find_type(&enum_type.name);
+            let bytes = value.to_le_bytes()[..enum_type.size].to_vec();
+            return Ok(self.typed_value(enum_type.clone(), type_offset, bytes));
+        }
+        // A function name stands for its address
+        if let Some(addr) = self.debug_data.get_addr_for_function(None, name) {
+            let mut value = self.pointer_value(addr as u64, None);
+            value.ty.name = "void (*)()".to_string();
+            return Ok(value);
+        }
+        Err(format!("No symbol \"{}\" in current context.", name))
+    }
+
+    fn register(&self, name: &str) -> Result<Value, String> {
+        let regs = self
+            .inferior()?
+            .get_registers()
+            .map_err(|e| e.to_string())?;
+        let mut raw = registers::get_register(&regs, name).unwrap();
+        // Outer frames see the registers as their callees saved them. Only the innermost frame's
+        // registers can be assigned to.
+        let outer_frame = self.frame.as_ref().filter(|frame| frame.level > 0);
+        if let Some(frame) = outer_frame {
+            raw = match frame.register(name) {
+                Some(raw) => raw,
+                None if Frame::tracks(name) => {
+                    return Err(format!("value of ${} is not available", name))
+                }
+                None => raw,
+            };
+        }
+        let mut value = match name {
+            "rip" | "pc" => {
+                let mut value = self.pointer_value(raw, None);
+                value.ty.name = "void (*)()".to_string();
+                value
+            }
+            "rsp" | "sp" | "rbp" | "fp" => self.pointer_value(raw, None),
+            _ => self.int_value(raw as i64, 8, true),
+        };
+        if outer_frame.is_none() {
+            value.lvalue = Some(Lvalue::Register(name.to_string()));
+        }
+        Ok(value)
+    }
+
+    fn scalar(&self, value: &Value) -> Result<Scalar, String> {
+        let ty = self.resolved(value);
+        match &ty.kind {
+            TypeKind::Base(Encoding::Float) => match value.bytes.len() {
+                4 => Ok(Scalar::Float(
+                    f32::from_le_bytes(value.bytes[..].try_into().unwrap()) as f64,
+                )),
+                8 => Ok(Scalar::Float(f64::from_le_bytes(
+                    value.bytes[..].try_into().unwrap(),
+                ))),
+                _ => Err(format!("Cannot handle values of type {}.", value.ty.name)),
+            },
+            TypeKind::Pointer(pointee) => Ok(Scalar::Pointer {
+                addr: value::as_integer(&value.bytes, ty, self.debug_data).unwrap() as u64,
+                pointee: *pointee,
+            }),
+            // Arrays decay to a pointer to their first element
+            TypeKind::Array { element, .. } => match value.lvalue {
+                Some(Lvalue::Memory(addr)) => Ok(Scalar::Pointer {
+                    addr: addr as u64,
+                    pointee: Some(*element),
+                }),
+                _ => Err("Attempt to take address of value not located in memory.".to_string()),
+            },
+            _ => match value::as_integer(&value.bytes, ty, self.debug_data) {
+                Some(int) => Ok(Scalar::Int {
+                    value: int,
+                    size: ty.size,
+                    signed: value::is_signed(ty, self.debug_data) == Some(true),
+                }),
+                None => Err(format!(
+                    "Value of type {} can't be used as a number.",
+                    value.ty.name
+                )),
+            },
+        }
+    }
+
+    fn pointee_size(&self, pointee: Option<usize>) -> u64 {
+        // Arithmetic on void * works in bytes, as in gcc
+        match self.debug_data.get_type(pointee) {
+            Some(ty) => self.debug_data.resolve_type(ty).size.max(1) as u64,
+            None => 1,
+        }
+    }
+
+    /// Reads the object a pointer points at.
+    fn deref(&self, value: &Value) -> Result<Value, String> {
+        let (addr, pointee) = match self.scalar(value)? {
+            Scalar::Pointer { addr, pointee } => (addr as usize, pointee),
+            // Like gdb, an integer is taken to be the address of an int
+            Scalar::Int { value, .. } => (value as usize, self.debug_data.find_type("int")),
+            Scalar::Float(_) => {
+                return Err("Attempt to take contents of a non-pointer value.".to_string())
+            }
+        };
+        let ty = match self.debug_data.get_type(pointee) {
+            Some(ty)
+                if !matches!(
+                    self.debug_data.resolve_type(ty).kind,
+                    TypeKind::Function { .. }
+                ) =>
+            {
+                ty.clone()
+            }
+            _ => return Err("Attempt to take contents of a non-pointer value.".to_string()),
+        };
+        let bytes = self.read_memory(addr, ty.size)?;
+        Ok(Value {
+            ty,
+            type_offset: pointee,
+            bytes,
+            lvalue: Some(Lvalue::Memory(addr)),
+        })
+    }
+
+    fn member(&self, base: &Value, name: &str) -> Result<Value, String> {
+        let members = match &self.resolved(base).kind {
+            TypeKind::Struct { members, .. } | TypeKind::Union { members, .. } => members,
+            _ => {
+                return Err(
+                    "Attempt to extract a component of a value that is not a structure."
+                        .to_string(),
+                )
+            }
+        };
+        let member = members
+            .iter()
+            .find(|member| member.name == name)
+            .ok_or_else(|| format!("There is no member named {}.", name))?;
+        let ty = self
+            .debug_data
+            .get_type(Some(member.type_offset))
+            .ok_or_else(|| format!("Member {} has an unknown type.", name))?;
+        let bytes = value::member_bytes(&base.bytes, member, ty, self.debug_data)
+            .ok_or_else(|| format!("Member {} is out of bounds.", name))?;
+        // Bit fields don't have an address of their own
+        let lvalue = match (&base.lvalue, member.bit_size) {
+            (Some(Lvalue::Memory(addr)), None) => Some(Lvalue::Memory(addr + member.offset)),
+            _ => None,
+        };
+        Ok(Value {
+            ty: ty.clone(),
+            type_offset: Some(member.type_offset),
+            bytes,
+            lvalue,
+        })
+    }
+
+    fn index(&self, base: &Value, index: &Value) -> Result<Value, String> {
+        let index = match self.scalar(index)? {
+            Scalar::Int { value, .. } => value,
+            _ => return Err("Array index must be an integer.".to_string()),
+        };
+        let ty = self.resolved(base);
+        let (element, element_offset) = match &ty.kind {
+            // Indexing the outer dimension of int[2][3] gives an int[3]
+            TypeKind::Array {
+                element,
+                dimensions,
+            } if dimensions.len() > 1 => {
+                let mut row = Type::new(
+                    String::new(),
+                    ty.size / dimensions[0].unwrap_or(1).max(1),
+                    TypeKind::Array {
+                        element: *element,
+                        dimensions: dimensions[1..].to_vec(),
+                    },
+                );
+                row.name = self.debug_data.declare(Some(&row), "");
+                (row, None)
+            }
+            TypeKind::Array {
+                element: pointee, ..
+            }
+            | TypeKind::Pointer(Some(pointee)) => match self.debug_data.get_type(Some(*pointee)) {
+                Some(element) => (element.clone(), Some(*pointee)),
+                None => return Err("Cannot index an array of unknown type.".to_string()),
+            },
+            _ => {
+                return Err(format!(
+                    "cannot subscript something of type `{}'",
+                    base.ty.name
+                ))
+            }
+        };
+        let size = element.size;
+        match (&ty.kind, &base.lvalue) {
+            (TypeKind::Pointer(_), _) | (_, Some(Lvalue::Memory(_))) => {
+                let start = match self.scalar(base)? {
+                    Scalar::Pointer { addr, .. } => addr as i64,
+                    _ => unreachable!(),
+                };
+                let addr = start.wrapping_add(index.wrapping_mul(size as i64)) as usize;
+                Ok(Value {
+                    ty: element,
+                    type_offset: element_offset,
+                    bytes: self.read_memory(addr, size)?,
+                    lvalue: Some(Lvalue::Memory(addr)),
+                })
+            }
+            // Arrays that don't live in memory are indexed in place
+            _ => {
+                let offset = index as usize * size;
+                let bytes = base
+                    .bytes
+                    .get(offset..offset + size)
+                    .ok_or_else(|| "no such vector element".to_string())?
+                    .to_vec();
+                Ok(self.typed_value(element, element_offset, bytes))
+            }
+        }
+    }
+
+    fn unary(&self, op: UnaryOp, operand: &Value) -> Result<Value, String> {
+        match op {
+            UnaryOp::Deref => return self.deref(operand),
+            UnaryOp::AddressOf => {
+                return match operand.lvalue {
+                    Some(Lvalue::Memory(addr)) => {
+                        Ok(self.pointer_value(addr as u64, operand.type_offset))
+                    }
+                    _ => Err("Attempt to take address of value not located in memory.".to_string()),
+                }
+            }
+            _ => {}
+        }
+        match (op, self.scalar(operand)?) {
+            (UnaryOp::Not, scalar) => {
+                let is_true = match scalar {
+                    Scalar::Int { value, .. } => value != 0,
+                    Scalar::Float(value) => value != 0.0,
+                    Scalar::Pointer { addr, .. } => addr != 0,
+                };
+                Ok(self.int_value(!is_true as i64, 4, true))
+            }
+            (UnaryOp::Neg, Scalar::Float(value)) => {
+                Ok(self.base_value("double", &(-value).to_le_bytes()))
+            }
+            (
+                UnaryOp::Neg,
+                Scalar::Int {
+                    value,
+                    size,
+                    signed,
+                },
+            ) => Ok(self.int_value(value.wrapping_neg(), size.max(4), signed)),
+            (
+                UnaryOp::BitNot,
+                Scalar::Int {
+                    value,
+                    size,
+                    signed,
+                },
+            ) => Ok(self.int_value(!value, size.max(4), signed)),
+            _ => Err("Argument to arithmetic operation not a number or boolean.".to_string()),
+        }
+    }
+
+    fn binary(&self, op: BinaryOp, lhs: &Value, rhs: &Value) -> Result<Value, String> {
+        let (lhs, rhs) = (self.scalar(lhs)?, self.scalar(rhs)?);
+        let truth = |value: bool| Ok(self.int_value(value as i64, 4, true));
+        if let BinaryOp::And | BinaryOp::Or = op {
+            let is_true = |scalar| match scalar {
+                Scalar::Int { value, .. } => value != 0,
+                Scalar::Float(value) => value != 0.0,
+                Scalar::Pointer { addr, .. } => addr != 0,
+            };
+            return match op {
+                BinaryOp::And => truth(is_true(lhs) && is_true(rhs)),
+                _ => truth(is_true(lhs) || is_true(rhs)),
+            };
+        }
+        match (lhs, rhs) {
+            (Scalar::Pointer { addr, pointee }, Scalar::Int { value, .. })
+            | (Scalar::Int { value, .. }, Scalar::Pointer { addr, pointee })
+                if op == BinaryOp::Add =>
+            {
+                let offset = value.wrapping_mul(self.pointee_size(pointee) as i64);
+                Ok(self.pointer_value(addr.wrapping_add(offset as u64), pointee))
+            }
+            (Scalar::Pointer { addr, pointee }, Scalar::Int { value, .. })
+                if op == BinaryOp::Sub =>
+            {
+                let offset = value.wrapping_mul(self.pointee_size(pointee) as i64);
+                Ok(self.pointer_value(addr.wrapping_sub(offset as u64), pointee))
+            }
+            (Scalar::Pointer { addr: a, pointee }, Scalar::Pointer { addr: b, .. })
+                if op == BinaryOp::Sub =>
+            {
+                let difference = a.wrapping_sub(b) as i64 / self.pointee_size(pointee) as i64;
+                Ok(self.int_value(difference, 8, true))
+            }
+            (Scalar::Float(_), _) | (_, Scalar::Float(_)) => {
+                let as_float = |scalar| match scalar {
+                    Scalar::Float(value) => value,
+                    Scalar::Int {
+                        value,
+                        signed: false,
+                        ..
+                    } => value as u64 as f64,
+                    Scalar::Int { value, .. } => value as f64,
+                    Scalar::Pointer { addr, .. } => addr as f64,
+                };
+                let (a, b) = (as_float(lhs), as_float(rhs));
+                let result = match op {
+                    BinaryOp::Add => a + b,
+                    BinaryOp::Sub => a - b,
+                    BinaryOp::Mul => a * b,
+                    BinaryOp::Div => a / b,
+                    BinaryOp::Lt => return truth(a < b),
+                    BinaryOp::Le => return truth(a <= b),
+                    BinaryOp::Gt => return truth(a > b),
+                    BinaryOp::Ge => return truth(a >= b),
+                    BinaryOp::Eq => return truth(a == b),
+                    BinaryOp::Ne => return truth(a != b),
+                    _ => return Err("Integer only operation.".to_string()),
+                };
+                Ok(self.base_value("double", &result.to_le_bytes()))
+            }
+            _ => {
+                // The usual arithmetic conversions, more or less: widen to at least int, and
+                // the result is unsigned if an unsigned operand is at least as wide
+                let as_int = |scalar| match scalar {
+                    Scalar::Int {
+                        value,
+                        size,
+                        signed,
+                    } => (value, size, signed),
+                    Scalar::Pointer { addr, .. } => (addr as i64, 8, false),
+                    Scalar::Float(_) => unreachable!(),
+                };
+                let (a, a_size, a_signed) = as_int(lhs);
+                let (b, b_size, b_signed) = as_int(rhs);
+                let size = a_size.max(b_size).max(4);
+                let unsigned = (!a_signed && a_size >= size) || (!b_signed && b_size >= size);
+                let signed = !unsigned;
+                // Redo the sign extension at the common width so that comparisons come out right
+                let normalize = |value: i64| {
+                    let bytes = value.to_le_bytes();
+                    value::as_integer(
+                        &bytes[..size],
+                        &self.int_value(0, size, signed).ty,
+                        self.debug_data,
+                    )
+                    .unwrap()
+                };
+                let (a, b) = (normalize(a), normalize(b));
+                let compare = |ordering: Ordering| {
+                    if signed {
+                        a.cmp(&b) == ordering
+                    } else {
+                        (a as u64).cmp(&(b as u64)) == ordering
+                    }
+                };
+                let result = match op {
+                    BinaryOp::Add => a.wrapping_add(b),
+                    BinaryOp::Sub => a.wrapping_sub(b),
+                    BinaryOp::Mul => a.wrapping_mul(b),
+                    BinaryOp::Div | BinaryOp::Rem if b == 0 => {
+                        return Err("Division by zero".to_string())
+                    }
+                    BinaryOp::Div if signed => a.wrapping_div(b),
+                    BinaryOp::Div => ((a as u64) / (b as u64)) as i64,
+                    BinaryOp::Rem if signed => a.wrapping_rem(b),
+                    BinaryOp::Rem => ((a as u64) % (b as u64)) as i64,
+                    BinaryOp::Shl => a.wrapping_shl(b as u32),
+                    BinaryOp::Shr if signed => a.wrapping_shr(b as u32),
+                    BinaryOp::Shr => ((a as u64).wrapping_shr(b as u32)) as i64,
+                    BinaryOp::BitAnd => a & b,
+                    BinaryOp::BitXor => a ^ b,
+                    BinaryOp::BitOr => a | b,
+                    BinaryOp::Lt => return truth(compare(Ordering::Less)),
+                    BinaryOp::Le => return truth(!compare(Ordering::Greater)),
+                    BinaryOp::Gt => return truth(compare(Ordering::Greater)),
+                    BinaryOp::Ge => return truth(!compare(Ordering::Less)),
+                    BinaryOp::Eq => return truth(a == b),
+                    BinaryOp::Ne => return truth(a != b),
+                    BinaryOp::And | BinaryOp::Or => unreachable!(),
+                };
+                // Shifts take the type of their left operand
+                let (size, signed) = match op {
+                    BinaryOp::Shl | BinaryOp::Shr => (a_size.max(4), a_signed),
+                    _ => (size, signed),
+                };
+                Ok(self.int_value(result, size, signed))
+            }
+        }
+    }
+
+    fn cast(&self, value: &Value, ty: Type, type_offset: Option<usize>) -> Result<Value, String> {
+        let bytes = self.convert(value, &ty)?;
+        // Reinterpreting an object in memory (say, a struct) keeps it addressable
+        let lvalue = match self.debug_data.resolve_type(&ty).kind {
+            TypeKind::Struct { .. } | TypeKind::Union { .. } | TypeKind::Array { .. } => {
+                value.lvalue.clone()
+            }
+            _ => None,
+        };
+        Ok(Value {
+            ty,
+            type_offset,
+            bytes,
+            lvalue,
+        })
+    }
+
+    /// Converts a value to the representation of another type, as for a cast or an assignment.
+    fn convert(&self, value: &Value, ty: &Type) -> Result<Vec<u8>, String> {
+        let target = self.debug_data.resolve_type(ty);
+        match target.kind {
+            TypeKind::Base(Encoding::Float) => {
+                let float = match self.scalar(value)? {
+                    Scalar::Float(value) => value,
+                    Scalar::Int {
+                        value,
+                        signed: false,
+                        ..
+                    } => value as u64 as f64,
+                    Scalar::Int { value, .. } => value as f64,
+                    Scalar::Pointer { .. } => return Err("Invalid cast.".to_string()),
+                };
+                match target.size {
+                    4 => Ok((float as f32).to_le_bytes().to_vec()),
+                    8 => Ok(float.to_le_bytes().to_vec()),
+                    _ => Err(format!("Cannot convert to {}.", ty.name)),
+                }
+            }
+            TypeKind::Base(_) | TypeKind::Enum { .. } | TypeKind::Pointer(_) => {
+                let int = match self.scalar(value)? {
+                    Scalar::Float(value) => value as i64,
+                    Scalar::Int { value, .. } => value,
+                    Scalar::Pointer { addr, .. } => addr as i64,
+                };
+                let int = if matches!(target.kind, TypeKind::Base(Encoding::Boolean)) {
+                    (int != 0) as i64
+                } else {
+                    int
+                };
+                Ok(int.to_le_bytes()[..target.size.min(8)].to_vec())
+            }
+            _ => {
+                let source = self.resolved(value);
+                if source.name == target.name && source.size == target.size {
+                    Ok(value.bytes.clone())
+                } else {
+                    Err("Invalid cast.".to_string())
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn var(name: &str) -> Box<Expr> {
+        Box::new(Expr::Variable(name.to_string()))
+    }
+
+    #[test]
+    fn test_parse_precedence() {
+        assert_eq!(
+            parse("a + b * 2 == 7 && !c").unwrap(),
+            Expr::Binary(
+                BinaryOp::And,
+                Box::new(Expr::Binary(
+                    BinaryOp::Eq,
+                    Box::new(Expr::Binary(
+                        BinaryOp::Add,
+                        var("a"),
+                        Box::new(Expr::Binary(
+                            BinaryOp::Mul,
+                            var("b"),
+                            Box::new(Expr::Integer(2))
+                        ))
+                    )),
+                    Box::new(Expr::Integer(7))
+                )),
+                Box::new(Expr::Unary(UnaryOp::Not, var("c")))
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse_postfix() {
+        assert_eq!(
+            parse("*p->next[0x10].value").unwrap(),
+            Expr::Unary(
+                UnaryOp::Deref,
+                Box::new(Expr::Member(
+                    Box::new(Expr::Index(
+                        Box::new(Expr::PointerMember(var("p"), "next".to_string())),
+                        Box::new(Expr::Integer(16))
+                    )),
+                    "value".to_string()
+                ))
+            )
+        );
+        assert_eq!(
+            parse("&arr[1]").unwrap(),
+            Expr::Unary(
+                UnaryOp::AddressOf,
+                Box::new(Expr::Index(var("arr"), Box::new(Expr::Integer(1))))
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse_cast() {
+        let cast = |base: &str, pointers, operand| {
+            Expr::Cast(
+                TypeName {
+                    base: base.to_string(),
+                    pointers,
+                },
+                operand,
+            )
+        };
+        assert_eq!(
+            parse("(unsigned char) x").unwrap(),
+            cast("unsigned char", 0, var("x"))
+        );
+        assert_eq!(
+            parse("(const struct point **) $rsp").unwrap(),
+            cast(
+                "struct point",
+                2,
+                Box::new(Expr::Register("rsp".to_string()))
+            )
+        );
+        assert_eq!(parse("(node_t) y").unwrap(), cast("node_t", 0, var("y")));
+        // A parenthesized variable followed by an operator is not a cast
+        assert_eq!(
+            parse("(x) - 1").unwrap(),
+            Expr::Binary(BinaryOp::Sub, var("x"), Box::new(Expr::Integer(1)))
+        );
+    }
+
+    #[test]
+    fn test_parse_literals() {
+        assert_eq!(parse("'a'").unwrap(), Expr::Char(b'a'));
+        assert_eq!(parse("'\\n'").unwrap(), Expr::Char(b'\n'));
+        assert_eq!(parse("010").unwrap(), Expr::Integer(8));
+        assert_eq!(parse("10UL").unwrap(), Expr::Integer(10));
+        assert_eq!(parse("1.5").unwrap(), Expr::Float(1.5));
+        assert_eq!(
+            parse("x = y = 1").unwrap(),
+            Expr::Assign(
+                var("x"),
+                Box::new(Expr::Assign(var("y"), Box::new(Expr::Integer(1))))
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(parse("").is_err());
+        assert!(parse("1abc").is_err());
+        assert!(parse("a +").is_err());
+        assert!(parse("(a").is_err());
+        assert!(parse("a b").is_err());
+        assert!(parse("$notareg").is_err());
+        assert!(parse("p->").is_err());
+        assert!(parse("'ab'").is_err());
+    }
+}
@@ -0,0 +1,760 @@
+//! `deet --gdbserver`: lets another debugger (gdb, lldb, or an IDE that speaks to one of them)
+//! drive our ptrace backend over the GDB Remote Serial Protocol, the way gdbserver does.
+//!
+//! Every message is a packet, `$<data>#<checksum>`, where the checksum is the sum of the data
+//! bytes modulo 256 in two hex digits. The receiving end acknowledges each packet with `+` (or
+//! asks for it again with `-`) until both sides agree to drop acknowledgements (QStartNoAckMode).
+//! The client asks for registers, memory and breakpoints, and tells us when to resume; we answer
+//! each resumption with a stop reply once the inferior stops again.
+
+use nix::sys::signal::Signal;
+use nix::unistd::Pid;
+use std::convert::TryFrom;
+use std::io::{self, BufReader, Read, Write};
+use std::net::TcpListener;
+
+use crate::breakpoint::BreakpointTable;
+use crate::debugger::load_debug_data;
+use crate::dwarf_data::DwarfData;
+use crate::inferior::{Inferior, Status};
+use crate::signals::SignalTable;
+use crate::target::Target;
+
+/// The largest packet we accept, which we advertise in reply to qSupported.
+const PACKET_SIZE: usize = 0x4000;
+
+/// The most memory an `m` or `M` packet moves: its bytes take two hex digits each, and have to
+/// fit in a packet.
+const MAX_MEMORY_TRANSFER: usize = PACKET_SIZE / 2;
+
+/// gdb numbers signals its own way (after the traditional Unix numbering) rather than the way
+/// Linux does, so stop replies and `C` packets have to be translated. Signals missing from this
+/// table are numbered alike in both.
+const GDB_SIGNALS: [(Signal, u8); 14] = [
+    (Signal::SIGBUS, 10),
+    (Signal::SIGUSR1, 30),
+    (Signal::SIGUSR2, 31),
+    (Signal::SIGSTKFLT, 0x8f),
+    (Signal::SIGCHLD, 20),
+    (Signal::SIGCONT, 19),
+    (Signal::SIGSTOP, 17),
+    (Signal::SIGTSTP, 18),
+    (Signal::SIGTTIN, 21),
+    (Signal::SIGTTOU, 22),
+    (Signal::SIGURG, 16),
+    (Signal::SIGIO, 23),
+    (Signal::SIGPWR, 32),
+    (Signal::SIGSYS, 12),
+];
+
+fn gdb_signal_number(signal: Signal) -> u8 {
+    GDB_SIGNALS
+        .iter()
+        .find(|(other, _)| *other == signal)
+        .map_or(signal as u8, |(_, number)| *number)
+}
+
+fn signal_from_gdb(number: u8) -> Option<Signal> {
+    match GDB_SIGNALS.iter().find(|(_, other)| *other == number) {
+        Some((signal, _)) => Some(*signal),
+        None if GDB_SIGNALS
+            .iter()
+            .any(|(signal, _)| *signal as u8 == number) =>
+        {
+            None
+        }
+        None => Signal::try_from(i32::from(number)).ok(),
+    }
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(text: &str) -> Option<Vec<u8>> {
+    let digits = text.as_bytes().chunks_exact(2);
+    if !digits.remainder().is_empty() {
+        return None;
+    }
+    digits
+        .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok())
+        .collect()
+}
+
+fn parse_hex(text: &str) -> Option<usize> {
+    usize::from_str_radix(text, 16).ok()
+}
+
+/// Parses the "addr,length" that memory packets start with. Ranges that would run past the top
+/// of the address space are rejected.
+fn parse_range(text: &str) -> Option<(usize, usize)> {
+    let (addr, len) = text.split_once(',')?;
+    let (addr, len) = (parse_hex(addr)?, parse_hex(len)?);
+    addr.checked_add(len)?;
+    Some((addr, len))
+}
+
+/// Escapes binary data for a packet: `#`, `$`, `}` and `*` are sent as `}` followed by the byte
+/// xor 0x20.
+fn escape_binary(data: &[u8]) -> Vec<u8> {
+    let mut escaped = Vec::with_capacity(data.len());
+    for byte in data {
+        match byte {
+            b'#' | b'$' | b'}' | b'*' => escaped.extend_from_slice(&[b'}', byte ^ 0x20]),
+            _ => escaped.push(*byte),
+        }
+    }
+    escaped
+}
+
+/// One end of a remote protocol connection, which takes care of the framing and
+/// acknowledgements.
+pub struct Connection<S: Read + Write> {
+    reader: BufReader<S>,
+    /// False once the client has asked for QStartNoAckMode
+    acks: bool,
+    /// The last packet we sent, in case the other end asks for it again
+    last_sent: Vec<u8>,
+}
+
+impl<S: Read + Write> Connection<S> {
+    pub fn new(stream: S) -> Connection<S> {
+        Connection {
+            reader: BufReader::new(stream),
+            acks: true,
+            last_sent: Vec::new(),
+        }
+    }
+
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        let mut byte = [0];
+        match self.reader.read(&mut byte)? {
+            0 => Ok(None),
+            _ => Ok(Some(byte[0])),
+        }
+    }
+
+    fn write_raw(&mut self, bytes: &[u8]) -> io::Result<()> {
+        let stream = self.reader.get_mut();
+        stream.write_all(bytes)?;
+        stream.flush()
+    }
+
+    /// Waits for the next packet and returns its data, or None once the other end hangs up.
+    /// Acknowledgements and packets with a bad checksum (which we ask for again) are skipped, as
+    /// is an interrupt (^C) that arrives while the inferior is already stopped.
+    pub fn read_packet(&mut self) -> io::Result<Option<Vec<u8>>> {
+        loop {
+            match self.read_byte()? {
+                None => return Ok(None),
+                Some(b'$') => {}
+                Some(b'-') => {
+                    let last = self.last_sent.clone();
+                    self.write_raw(&last)?;
+                    continue;
+                }
+                Some(_) => continue,
+            }
+            let mut data = Vec::new();
+            loop {
+                match self.read_byte()? {
+                    None => return Ok(None),
+                    Some(b'#') => break,
+                    Some(byte) => data.push(byte),
+                }
+            }
+            let mut sum = [0; 2];
+            self.reader.read_exact(&mut sum)?;
+            let valid = std::str::from_utf8(&sum)
+                .ok()
+                .and_then(|sum| u8::from_str_radix(sum, 16).ok())
+                == Some(checksum(&data));
+            if !self.acks {
+                return Ok(Some(data));
+            }
+            if valid {
+                self.write_raw(b"+")?;
+                return Ok(Some(data));
+            }
+            self.write_raw(b"-")?;
+        }
+    }
+
+    /// Sends a packet. With acknowledgements on, we wait for the other end to confirm it, sending
+    /// it again for as long as it asks.
+    pub fn send_packet(&mut self, data: &[u8]) -> io::Result<()> {
+        let mut packet = Vec::with_capacity(data.len() + 4);
+        packet.push(b'$');
+        packet.extend_from_slice(data);
+        packet.extend_from_slice(format!("#{:02x}", checksum(data)).as_bytes());
+        self.write_raw(&packet)?;
+        self.last_sent = packet;
+        if !self.acks {
+            return Ok(());
+        }
+        loop {
+            match self.read_byte()? {
+                None | Some(b'+') => return Ok(()),
+                Some(b'-') => {
+                    let last = self.last_sent.clone();
+                    self.write_raw(&last)?;
+                }
+                Some(_) => {}
+            }
+        }
+    }
+}
+
+/// The registers of a `g` packet, in gdb's amd64 order: the general-purpose registers (8 bytes
+/// each, rip last), eflags and the segment registers (4 bytes each), the x87 registers (10
+/// bytes each), the x87 control registers (4 bytes each), xmm0-15 and mxcsr. gdb treats the
+/// registers its description has beyond these as unavailable.
+fn encode_registers(regs: &libc::user_regs_struct, fp: &libc::user_fpregs_struct) -> String {
+    let mut bytes = Vec::new();
+    for value in &[
+        regs.rax, regs.rbx, regs.rcx, regs.rdx, regs.rsi, regs.rdi, regs.rbp, regs.rsp, regs.r8,
+        regs.r9, regs.r10, regs.r11, regs.r12, regs.r13, regs.r14, regs.r15, regs.rip,
+    ] {
+        bytes.extend_from_slice(&value.to_le_bytes());
+    }
+    for value in &[
+        regs.eflags,
+        regs.cs,
+        regs.ss,
+        regs.ds,
+        regs.es,
+        regs.fs,
+        regs.gs,
+    ] {
+        bytes.extend_from_slice(&(*value as u32).to_le_bytes());
+    }
+    // FXSAVE keeps each x87 register in 16 bytes, of which the first 10 are used
+    for st in fp.st_space.chunks_exact(4) {
+        let mut value = Vec::new();
+        for word in st {
+            value.extend_from_slice(&word.to_le_bytes());
+        }
+        bytes.extend_from_slice(&value[..10]);
+    }
+    // FXSAVE only keeps a bit per register saying whether it is empty. gdb wants the full tag
+    // word, so call every register that isn't empty valid.
+    let tag = (0..8).fold(0u32, |tag, i| {
+        if fp.ftw & (1 << i) == 0 {
+            tag | 3 << (2 * i)
+        } else {
+            tag
+        }
+    });
+    for value in &[
+        u32::from(fp.cwd),
+        u32::from(fp.swd),
+        tag,
+        (fp.rip >> 32) as u32,
+        fp.rip as u32,
+        (fp.rdp >> 32) as u32,
+        fp.rdp as u32,
+        u32::from(fp.fop),
+    ] {
+        bytes.extend_from_slice(&value.to_le_bytes());
+    }
+    for word in fp.xmm_space.iter() {
+        bytes.extend_from_slice(&word.to_le_bytes());
+    }
+    bytes.extend_from_slice(&fp.mxcsr.to_le_bytes());
+    to_hex(&bytes)
+}
+
+/// Stores the general-purpose and segment registers of a `G` packet into `regs`. Writing the
+/// floating point registers isn't supported, so the rest of the packet is ignored.
+fn decode_registers(data: &[u8], regs: &mut libc::user_regs_struct) -> Option<()> {
+    let word = |i: usize| -> Option<u64> {
+        let mut word = [0; 8];
+        word.copy_from_slice(data.get(i * 8..i * 8 + 8)?);
+        Some(u64::from_le_bytes(word))
+    };
+    let half = |i: usize| -> Option<u64> {
+        let offset = 17 * 8 + i * 4;
+        let mut half = [0; 4];
+        half.copy_from_slice(data.get(offset..offset + 4)?);
+        Some(u64::from(u32::from_le_bytes(half)))
+    };
+    for (i, slot) in [
+        &mut regs.rax,
+        &mut regs.rbx,
+        &mut regs.rcx,
+        &mut regs.rdx,
+        &mut regs.rsi,
+        &mut regs.rdi,
+        &mut regs.rbp,
+        &mut regs.rsp,
+        &mut regs.r8,
+        &mut regs.r9,
+        &mut regs.r10,
+        &mut regs.r11,
+        &mut regs.r12,
+        &mut regs.r13,
+        &mut regs.r14,
+        &mut regs.r15,
+        &mut regs.rip,
+    ]
+    .iter_mut()
+    .enumerate()
+    {
+        **slot = word(i)?;
+    }
+    for (i, slot) in [
+        &mut regs.eflags,
+        &mut regs.cs,
+        &mut regs.ss,
+        &mut regs.ds,
+        &mut regs.es,
+        &mut regs.fs,
+        &mut regs.gs,
+    ]
+    .iter_mut()
+    .enumerate()
+    {
+        **slot = half(i)?;
+    }
+    Some(())
+}
+
+/// Answers a `qXfer:<object>:read::<offset>,<length>` request for `object`, which is `data`.
+fn transfer(data: &[u8], range: &str) -> Vec<u8> {
+    let (offset, len) = match parse_range(range) {
+        Some(range) => range,
+        None => return b"E01".to_vec(),
+    };
+    let chunk = data.get(offset..).unwrap_or(&[]);
+    let chunk = &chunk[..chunk.len().min(len)];
+    // 'l' marks the last chunk, 'm' says there is more to come
+    let mut reply = vec![if chunk.len() < len { b'l' } else { b'm' }];
+    reply.extend(escape_binary(chunk));
+    reply
+}
+
+/// The inferior, as seen from the other end of the connection.
+struct Server {
+    inferior: Option<Inferior>,
+    /// Breakpoints the client has asked for, which also tell the inferior where to stop
+    breakpoints: BreakpointTable,
+    debug_data: DwarfData,
+    signals: SignalTable,
+    /// The reply to `?`: why the inferior last stopped (or that it is gone)
+    stop_reply: String,
+    /// Set once the client has killed or detached from the inferior
+    done: bool,
+}
+
+impl Server {
+    /// Describes a stop for the client. The process is gone after an exit reply.
+    fn stop_reply(&mut self, status: Status) -> String {
+        let reply = match status {
+            Status::Stopped(signal, _) => {
+                let tid = self
+                    .inferior
+                    .as_ref()
+                    .map_or(0, |inferior| inferior.tid().as_raw());
+                format!("T{:02x}thread:{:x};", gdb_signal_number(signal), tid)
+            }
+            Status::Exec => {
+                let tid = self
+                    .inferior
+                    .as_ref()
+                    .map_or(0, |inferior| inferior.tid().as_raw());
+                format!("T05thread:{:x};", tid)
+            }
+            Status::Exited(code) => {
+                println!("\nChild exited with status {}", code);
+                self.inferior = None;
+                format!("W{:02x}", code as u8)
+            }
+            Status::Signaled(signal) => {
+                println!(
+                    "\nChild terminated with signal = {:#x} ({})",
+                    signal as i32, signal
+                );
+                self.inferior = None;
+                format!("X{:02x}", gdb_signal_number(signal))
+            }
+        };
+        self.stop_reply = reply.clone();
+        reply
+    }
+
+    /// Makes the thread with id `tid` current. 0 and -1 mean "any thread", so the current one is
+    /// as good as any.
+    fn select_thread(&mut self, tid: &str) -> bool {
+        let inferior = match self.inferior.as_mut() {
+            Some(inferior) => inferior,
+            None => return false,
+        };
+        if tid == "0" || tid == "-1" {
+            return true;
+        }
+        let tid = match i32::from_str_radix(tid, 16) {
+            Ok(tid) => Pid::from_raw(tid),
+            Err(_) => return false,
+        };
+        match inferior.threads().iter().find(|(_, other)| *other == tid) {
+            Some((number, _)) => inferior.select_thread(*number),
+            None => false,
+        }
+    }
+
+    /// Resumes the inferior, stepping a single instruction with `step`, and delivering `signal`
+    /// (in gdb's numbering) rather than the signal it stopped with.
+    fn resume(&mut self, step: bool, signal: Option<u8>) -> String {
+        let inferior = match self.inferior.as_mut() {
+            Some(inferior) => inferior,
+            None => return "E01".to_string(),
+        };
+        inferior.set_pending_signal(signal.and_then(signal_from_gdb));
+        let status = if step {
+            inferior.step_instruction(
+                false,
+                &mut self.breakpoints,
+                &self.debug_data,
+                &self.signals,
+            )
+        } else {
+            inferior.continues(&mut self.breakpoints, &self.debug_data, &self.signals)
+        };
+        match status {
+            Ok(status) => self.stop_reply(status),
+            Err(_) => "E01".to_string(),
+        }
+    }
+
+    /// Handles `vCont;<action>[:<thread>]...`. Only the first action counts: the current thread
+    /// (or the one named) steps or continues, and with all-stop every other thread follows.
+    fn resume_threads(&mut self, actions: &str) -> String {
+        let action = actions.split(';').next().unwrap_or("");
+        let (action, tid) = match action.split_once(':') {
+            Some((action, tid)) => (action, Some(tid)),
+            None => (action, None),
+        };
+        if let Some(tid) = tid {
+            if !self.select_thread(tid) {
+                return "E01".to_string();
+            }
+        }
+        let signal = action
+            .get(1..)
+            .and_then(|sig| u8::from_str_radix(sig, 16).ok());
+        match action.chars().next() {
+            Some('c') => self.resume(false, None),
+            Some('s') => self.resume(true, None),
+            Some('C') if signal.is_some() => self.resume(false, signal),
+            Some('S') if signal.is_some() => self.resume(true, signal),
+            _ => "E01".to_string(),
+        }
+    }
+
+    /// Handles `Z0,addr,kind` and `z0,addr,kind`. Breakpoints go into our breakpoint table as well
+    /// as into the inferior, so that the inferior stops when it reaches them.
+    fn set_breakpoint(&mut self, args: &str, insert: bool) -> String {
+        let inferior = match self.inferior.as_mut() {
+            Some(inferior) => inferior,
+            None => return "E01".to_string(),
+        };
+        let addr = match args.split(',').next().and_then(parse_hex) {
+            Some(addr) => addr,
+            None => return "E01".to_string(),
+        };
+        let existing: Vec<usize> = self.breakpoints.at(addr).map(|bp| bp.number).collect();
+        let result = if insert {
+            if existing.is_empty() {
+                let spec = format!("*{:#x}", addr);
+                self.breakpoints.add(addr, spec.clone(), spec);
+            }
+            inferior.insert_breakpoint(addr)
+        } else {
+            for number in existing {
+                self.breakpoints.remove(number);
+            }
+            inferior.remove_breakpoint(addr)
+        };
+        match result {
+            Ok(()) => "OK".to_string(),
+            Err(_) => "E01".to_string(),
+        }
+    }
+
+    /// Works out the reply to a packet.
+    fn handle(&mut self, packet: &[u8]) -> Vec<u8> {
+        // Everything but the data of `X` (which we don't support) is text
+        let packet = String::from_utf8_lossy(packet);
+        let kind = packet.chars().next().unwrap_or_default();
+        let args = &packet[kind.len_utf8().min(packet.len())..];
+        let reply = match (kind, args) {
+            ('?', _) => self.stop_reply.clone(),
+            ('g', _) => match &self.inferior {
+                Some(inferior) => match (inferior.get_registers(), inferior.get_fp_registers()) {
+                    (Ok(regs), Ok(fp)) => encode_registers(&regs, &fp),
+                    _ => "E01".to_string(),
+                },
+                None => "E01".to_string(),
+            },
+            ('G', data) => {
+                let result = (|| {
+                    let inferior = self.inferior.as_mut()?;
+                    let mut regs = inferior.get_registers().ok()?;
+                    decode_registers(&from_hex(data)?, &mut regs)?;
+                    inferior.set_registers(regs).ok()
+                })();
+                match result {
+                    Some(()) => "OK".to_string(),
+                    None => "E01".to_string(),
+                }
+            }
+            ('m', args) => {
+                // gdb asks again for whatever is missing from a short read
+                let bytes = parse_range(args).and_then(|(addr, len)| {
+                    let len = len.min(MAX_MEMORY_TRANSFER);
+                    self.inferior.as_ref()?.read_memory(addr, len).ok()
+                });
+                match bytes {
+                    Some(bytes) => to_hex(&bytes),
+                    None => "E01".to_string(),
+                }
+            }
+            ('M', args) => {
+                let result = (|| {
+                    let (range, data) = args.split_once(':')?;
+                    let (addr, len) =
+                        parse_range(range).filter(|(_, len)| *len <= MAX_MEMORY_TRANSFER)?;
+                    let bytes = from_hex(data).filter(|bytes| bytes.len() == len)?;
+                    self.inferior.as_mut()?.write_memory(addr, &bytes).ok()
+                })();
+                match result {
+                    Some(()) => "OK".to_string(),
+                    None => "E01".to_string(),
+                }
+            }
+            ('c', _) => self.resume(false, None),
+            ('s', _) => self.resume(true, None),
+            ('C', args) => {
+                let signal = args
+                    .split(';')
+                    .next()
+                    .and_then(|sig| u8::from_str_radix(sig, 16).ok());
+                self.resume(false, signal)
+            }
+            ('S', args) => {
+                let signal = args
+                    .split(';')
+                    .next()
+                    .and_then(|sig| u8::from_str_radix(sig, 16).ok());
+                self.resume(true, signal)
+            }
+            ('Z', args) if args.starts_with("0,") => self.set_breakpoint(&args[2..], true),
+            ('z', args) if args.starts_with("0,") => self.set_breakpoint(&args[2..], false),
+            ('H', args) if args.len() > 1 => {
+                // Hg picks the thread registers and memory come from; Hc the one to resume,
+                // which is the current one too, since continuing resumes every thread anyway.
+                // The packet came through from_utf8_lossy, so the letter may not be one byte
+                if matches!(args.get(1..), Some(tid) if self.select_thread(tid)) {
+                    "OK".to_string()
+                } else {
+                    "E01".to_string()
+                }
+            }
+            ('T', tid) => match (&self.inferior, i32::from_str_radix(tid, 16)) {
+                (Some(inferior), Ok(tid))
+                    if inferior
+                        .threads()
+                        .iter()
+                        .any(|(_, other)| other.as_raw() == tid) =>
+                {
+                    "OK".to_string()
+                }
+                _ => "E01".to_string(),
+            },
+            ('k', _) => {
+                // Dropping the inferior kills it
+                self.inferior = None;
+                self.done = true;
+                return Vec::new();
+            }
+            ('D', _) => {
+                self.done = true;
+                match self.inferior.take().map(|inferior| inferior.detach()) {
+                    Some(Err(_)) => "E01".to_string(),
+                    _ => "OK".to_string(),
+                }
+            }
+            _ => return self.query(&packet),
+        };
+        reply.into_bytes()
+    }
+
+    /// Handles the packets with multi-letter names: queries and the `v` packets. Anything we
+    /// don't know gets an empty reply, which tells the client it isn't supported.
+    fn query(&mut self, packet: &str) -> Vec<u8> {
+        if packet.starts_with("qSupported") {
+            return format!(
+                "PacketSize={:x};QStartNoAckMode+;qXfer:auxv:read+;vContSupported+",
+                PACKET_SIZE
+            )
+            .into_bytes();
+        }
+        if let Some(range) = packet.strip_prefix("qXfer:auxv:read::") {
+            return match self.inferior.as_ref().and_then(|inferior| inferior.auxv()) {
+                Some(auxv) => transfer(&auxv, range),
+                None => b"E01".to_vec(),
+            };
+        }
+        if let Some(actions) = packet.strip_prefix("vCont;") {
+            return self.resume_threads(actions).into_bytes();
+        }
+        let threads = self
+            .inferior
+            .as_ref()
+            .map_or_else(Vec::new, |inferior| inferior.threads());
+        let reply = match packet {
+            "QStartNoAckMode" => "OK".to_string(),
+            "vCont?" => "vCont;c;C;s;S".to_string(),
+            "qC" => match &self.inferior {
+                Some(inferior) => format!("QC{:x}", inferior.tid().as_raw()),
+                None => "E01".to_string(),
+            },
+            "qfThreadInfo" => {
+                let tids: Vec<String> = threads
+                    .iter()
+                    .map(|(_, tid)| format!("{:x}", tid.as_raw()))
+                    .collect();
+                format!("m{}", tids.join(","))
+            }
+            "qsThreadInfo" => "l".to_string(),
+            // We started the process, so it should be killed rather than detached from on quit
+            "qAttached" => "0".to_string(),
+            _ => String::new(),
+        };
+        reply.into_bytes()
+    }
+}
+
+/// Starts `target` with `args` and serves it to one client connecting to `listener`, until the
+/// client kills it, detaches, or hangs up.
+pub fn serve(listener: TcpListener, target: &str, args: &[String]) -> Result<(), String> {
+    let debug_data = load_debug_data(target)?;
+    let inferior = Inferior::new(target, &args.to_vec())
+        .ok_or_else(|| format!("Error starting subprocess {}", target))?;
+    println!("Process {} created; pid = {}", target, inferior.pid());
+    let port = listener.local_addr().map_err(|e| e.to_string())?.port();
+    println!("Listening on port {}", port);
+    let (stream, peer) = listener.accept().map_err(|e| e.to_string())?;
+    println!("Remote debugging from host {}", peer.ip());
+
+    let mut server = Server {
+        inferior: Some(inferior),
+        breakpoints: BreakpointTable::new(),
+        debug_data,
+        signals: SignalTable::new(),
+        stop_reply: "S05".to_string(),
+        done: false,
+    };
+    let mut connection = Connection::new(stream);
+    let io_error = |e: io::Error| format!("Remote connection failed: {}", e);
+    while let Some(packet) = connection.read_packet().map_err(io_error)? {
+        let reply = server.handle(&packet);
+        if server.done && packet.starts_with(b"k") {
+            // `k` gets no reply
+            break;
+        }
+        connection.send_packet(&reply).map_err(io_error)?;
+        if packet == b"QStartNoAckMode" {
+            connection.acks = false;
+        }
+        // Like gdbserver, we are done once the inferior is
+        if server.done || server.inferior.is_none() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::TcpStream;
+
+    /// A bare-bones remote protocol client.
+    fn request(client: &mut Connection<TcpStream>, packet: &str) -> String {
+        client.send_packet(packet.as_bytes()).unwrap();
+        String::from_utf8(client.read_packet().unwrap().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_packets() {
+        assert_eq!(checksum(b"qSupported"), 0x37);
+        assert_eq!(from_hex("00ff10"), Some(vec![0, 0xff, 0x10]));
+        assert_eq!(from_hex("0g"), None);
+        assert_eq!(parse_range("401000,10"), Some((0x401000, 0x10)));
+        assert_eq!(parse_range("fffffffffffffffc,10"), None);
+        assert_eq!(escape_binary(b"a#}"), b"a}\x03}]".to_vec());
+        assert_eq!(transfer(b"abcdef", "4,4"), b"lef".to_vec());
+        assert_eq!(transfer(b"abcdef", "0,4"), b"mabcd".to_vec());
+        assert_eq!(gdb_signal_number(Signal::SIGSEGV), 11);
+        assert_eq!(gdb_signal_number(Signal::SIGUSR1), 30);
+        assert_eq!(signal_from_gdb(30), Some(Signal::SIGUSR1));
+        assert_eq!(signal_from_gdb(10), Some(Signal::SIGBUS));
+    }
+
+    #[test]
+    fn test_serve_sample() {
+        let _tracing = crate::inferior::test::lock_tracing();
+        let program = crate::inferior::test::build_sample("segfault");
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let served = program.to_string();
+        let server = std::thread::spawn(move || serve(listener, &served, &Vec::new()));
+        let mut client = Connection::new(TcpStream::connect(addr).unwrap());
+
+        assert!(request(&mut client, "qSupported:multiprocess+").contains("PacketSize"));
+        assert_eq!(request(&mut client, "?"), "S05");
+        assert_eq!(request(&mut client, "QStartNoAckMode"), "OK");
+        client.acks = false;
+        assert_eq!(request(&mut client, "H\u{e9}1"), "E01");
+        let regs = from_hex(&request(&mut client, "g")).unwrap();
+        let debug_data = load_debug_data(&program).unwrap();
+        let func2 = debug_data.get_addr_for_function(None, "func2").unwrap();
+
+        // Breakpoints stop the program, and don't show up in memory
+        let code = request(&mut client, &format!("m{:x},4", func2));
+        assert_eq!(request(&mut client, &format!("Z0,{:x},1", func2)), "OK");
+        assert_eq!(request(&mut client, &format!("m{:x},4", func2)), code);
+        let long_read = request(&mut client, &format!("m{:x},ffffffff", func2));
+        assert_eq!(long_read.len(), PACKET_SIZE);
+        assert!(long_read.starts_with(&code));
+        let long_write = format!(
+            "M{:x},{:x}:{}",
+            func2,
+            PACKET_SIZE,
+            "90".repeat(PACKET_SIZE)
+        );
+        assert_eq!(request(&mut client, &long_write), "E01");
+        assert!(request(&mut client, "vCont;c").starts_with("T05thread:"));
+        let regs_at_func2 = from_hex(&request(&mut client, "g")).unwrap();
+        assert_eq!(regs_at_func2.len(), regs.len());
+        let word = |regs: &[u8], i: usize| {
+            let mut word = [0; 8];
+            word.copy_from_slice(&regs[i * 8..i * 8 + 8]);
+            u64::from_le_bytes(word) as usize
+        };
+        assert_eq!(word(&regs_at_func2, 16), func2);
+        // func1 calls func2(42 % 5), with the argument in rdi
+        assert_eq!(word(&regs_at_func2, 5), 2);
+
+        // Then it crashes
+        assert_eq!(request(&mut client, &format!("z0,{:x},1", func2)), "OK");
+        assert!(request(&mut client, "c").starts_with("T0bthread:"));
+        assert_eq!(request(&mut client, "C0b"), "X0b");
+        server.join().unwrap().unwrap();
+    }
+}
@@ -10,13 +10,20 @@ use gimli::{UnitOffset, UnitSectionOffset};
 use object::Object;
 use std::borrow;
 //use std::io::{BufWriter, Write};
-use crate::dwarf_data::{File, Function, Line, Location, Type, Variable};
+use crate::dwarf_data;
+use crate::dwarf_data::{
+    Encoding, Enumerator, File, Function, Line, Location, Member, Qualifier, Type, TypeKind,
+    Variable,
+};
 use std::collections::HashMap;
 use std::convert::TryInto;
 use std::fmt::Write;
 use std::{io, path};
 
-pub fn load_file(object: &object::File, endian: gimli::RunTimeEndian) -> Result<Vec<File>, Error> {
+pub fn load_file(
+    object: &object::File,
+    endian: gimli::RunTimeEndian,
+) -> Result<(Vec<File>, HashMap<usize, Type>), Error> {
     // Load a section and return as `Cow<[u8]>`.
     let load_section = |id: gimli::SectionId| -> Result<borrow::Cow<[u8]>, gimli::Error> {
         Ok(object
@@ -49,6 +56,10 @@ pub fn load_file(object: &object::File, endian: gimli::RunTimeEndian) -> Result<
     while let Some(header) = iter.next()? {
         let unit = dwarf.unit(header)?;
 
+        // Variables may refer to types that are declared later in the unit (a pointer type often
+        // follows its first use), so collect the types in a first pass.
+        load_types(&unit, &dwarf, &mut offset_to_type)?;
+
         // Iterate over the Debugging Information Entries (DIEs) in the unit.
         let mut depth = 0;
         let mut entries = unit.entries();
@@ -67,40 +78,18 @@ pub fn load_file(object: &object::File, endian: gimli::RunTimeEndian) -> Result<
                     } else {
                         "<unknown>".to_string()
                     };
+                    let comp_dir = match unit.comp_dir {
+                        Some(ref dir) => dir.to_string_lossy().into_owned(),
+                        None => String::new(),
+                    };
                     compilation_units.push(File {
                         name,
+                        comp_dir,
                         global_variables: Vec::new(),
                         functions: Vec::new(),
                         lines: Vec::new(),
                     });
                 }
-                gimli::DW_TAG_base_type => {
-                    let name = if let Ok(Some(attr)) = entry.attr(gimli::DW_AT_name) {
-                        if let Ok(DebugValue::Str(name)) = get_attr_value(&attr, &unit, &dwarf) {
-                            name
-                        } else {
-                            "<unknown>".to_string()
-                        }
-                    } else {
-                        "<unknown>".to_string()
-                    };
-                    let byte_size = if let Ok(Some(attr)) = entry.attr(gimli::DW_AT_byte_size) {
-                        if let Ok(DebugValue::Uint(byte_size)) =
-                            get_attr_value(&attr, &unit, &dwarf)
-                        {
-                            byte_size
-                        } else {
-                            // TODO: report error?
-                            0
-                        }
-                    } else {
-                        // TODO: report error?
-                        0
-                    };
-                    let type_offset = entry.offset().0;
-                    offset_to_type
-                        .insert(type_offset, Type::new(name, byte_size.try_into().unwrap()));
-                }
                 gimli::DW_TAG_subprogram => {
                     let mut func: Function = Default::default();
                     let mut attrs = entry.attrs();
@@ -129,12 +118,18 @@ pub fn load_file(object: &object::File, endian: gimli::RunTimeEndian) -> Result<
                                     func.line_number = line_number.try_into().unwrap();
                                 }
                             }
+                            gimli::DW_AT_type => {
+                                if let Ok(DebugValue::Size(offset)) = val {
+                                    func.return_type = offset_to_type.get(&offset).cloned();
+                                }
+                            }
                             _ => {}
                         }
                     }
                     compilation_units.last_mut().unwrap().functions.push(func);
                 }
                 gimli::DW_TAG_formal_parameter | gimli::DW_TAG_variable => {
+                    let is_parameter = entry.tag() == gimli::DW_TAG_formal_parameter;
                     let mut name = String::new();
                     let mut entity_type: Option<Type> = None;
                     let mut location: Option<Location> = None;
@@ -175,6 +170,7 @@ pub fn load_file(object: &object::File, endian: gimli::RunTimeEndian) -> Result<
                             entity_type: entity_type.unwrap(),
                             location: location.unwrap(),
                             line_number: line_number.try_into().unwrap(),
+                            is_parameter,
                         };
                         if depth == 1 {
                             compilation_units
@@ -240,7 +236,226 @@ pub fn load_file(object: &object::File, endian: gimli::RunTimeEndian) -> Result<
             }
         }
     }
-    Ok(compilation_units)
+    Ok((compilation_units, offset_to_type))
+}
+
+fn section_offset<R: Reader>(offset: UnitOffset, unit: &gimli::Unit<R>) -> usize {
+    match offset.to_unit_section_offset(unit) {
+        UnitSectionOffset::DebugInfoOffset(goff) => goff.0,
+        UnitSectionOffset::DebugTypesOffset(goff) => goff.0,
+    }
+}
+
+fn get_type_ref<R: Reader>(
+    entry: &gimli::DebuggingInformationEntry<R>,
+    unit: &gimli::Unit<R>,
+) -> Result<Option<usize>, Error> {
+    Ok(match entry.attr_value(gimli::DW_AT_type)? {
+        Some(gimli::AttributeValue::UnitRef(offset)) => Some(section_offset(offset, unit)),
+        Some(gimli::AttributeValue::DebugInfoRef(offset)) => Some(offset.0),
+        _ => None,
+    })
+}
+
+fn get_name<R: Reader>(
+    entry: &gimli::DebuggingInformationEntry<R>,
+    unit: &gimli::Unit<R>,
+    dwarf: &gimli::Dwarf<R>,
+) -> Result<Option<String>, Error> {
+    Ok(match entry.attr(gimli::DW_AT_name)? {
+        Some(attr) => match get_attr_value(&attr, unit, dwarf) {
+            Ok(DebugValue::Str(name)) => Some(name),
+            _ => None,
+        },
+        None => None,
+    })
+}
+
+fn get_udata<R: Reader>(
+    entry: &gimli::DebuggingInformationEntry<R>,
+    name: gimli::DwAt,
+) -> Result<Option<usize>, Error> {
+    Ok(entry
+        .attr_value(name)?
+        .and_then(|value| value.udata_value())
+        .map(|value| value as usize))
+}
+
+/// Member offsets are usually a constant, but older producers emit DW_OP_plus_uconst.
+fn get_member_offset<R: Reader>(
+    entry: &gimli::DebuggingInformationEntry<R>,
+    unit: &gimli::Unit<R>,
+) -> Result<usize, Error> {
+    Ok(match entry.attr_value(gimli::DW_AT_data_member_location)? {
+        Some(gimli::AttributeValue::Exprloc(expr)) => {
+            let mut pc = expr.0;
+            match gimli::Operation::parse(&mut pc, unit.encoding())? {
+                gimli::Operation::PlusConstant { value } => value as usize,
+                _ => 0,
+            }
+        }
+        Some(value) => value.udata_value().unwrap_or(0) as usize,
+        None => 0,
+    })
+}
+
+/// Records every type of a unit in `offset_to_type`, keyed by .debug_info offset. Struct members,
+/// array bounds, enumerators and function parameters are children of the type they belong to.
+fn load_types<R: Reader>(
+    unit: &gimli::Unit<R>,
+    dwarf: &gimli::Dwarf<R>,
+    offset_to_type: &mut HashMap<usize, Type>,
+) -> Result<(), Error> {
+    let mut unit_types: Vec<usize> = Vec::new();
+    // The type we are inside of, and its depth, so that its children can be attached to it
+    let mut parent: Option<(isize, usize)> = None;
+    let mut depth = 0;
+    let mut entries = unit.entries();
+    while let Some((delta_depth, entry)) = entries.next_dfs()? {
+        depth += delta_depth;
+        let parent_type = match parent {
+            Some((parent_depth, parent_offset)) if parent_depth == depth - 1 => {
+                offset_to_type.get_mut(&parent_offset)
+            }
+            _ => None,
+        };
+        if let Some(parent_type) = parent_type {
+            match (entry.tag(), &mut parent_type.kind) {
+                (gimli::DW_TAG_member, TypeKind::Struct { members, .. })
+                | (gimli::DW_TAG_member, TypeKind::Union { members, .. }) => {
+                    if let Some(type_offset) = get_type_ref(entry, unit)? {
+                        let bit_size = get_udata(entry, gimli::DW_AT_bit_size)?;
+                        let bit_offset = match get_udata(entry, gimli::DW_AT_data_bit_offset)? {
+                            Some(bit_offset) => bit_offset,
+                            // DWARF 2 style: counted from the most significant bit of a storage
+                            // unit of DW_AT_byte_size bytes, which on little-endian machines is
+                            // the far end
+                            None => match (
+                                get_udata(entry, gimli::DW_AT_bit_offset)?,
+                                get_udata(entry, gimli::DW_AT_byte_size)?,
+                            ) {
+                                (Some(bit_offset), Some(byte_size)) => (byte_size * 8)
+                                    .saturating_sub(bit_offset + bit_size.unwrap_or(0)),
+                                _ => 0,
+                            },
+                        };
+                        members.push(Member {
+                            name: get_name(entry, unit, dwarf)?.unwrap_or_default(),
+                            type_offset,
+                            offset: get_member_offset(entry, unit)?,
+                            bit_size,
+                            bit_offset,
+                        });
+                    }
+                }
+                (gimli::DW_TAG_subrange_type, TypeKind::Array { dimensions, .. }) => {
+                    let count = match get_udata(entry, gimli::DW_AT_count)? {
+                        Some(count) => Some(count),
+                        None => get_udata(entry, gimli::DW_AT_upper_bound)?.map(|upper| upper + 1),
+                    };
+                    dimensions.push(count);
+                }
+                (gimli::DW_TAG_enumerator, TypeKind::Enum { enumerators, .. }) => {
+                    let value = match entry.attr_value(gimli::DW_AT_const_value)? {
+                        Some(gimli::AttributeValue::Sdata(value)) => value,
+                        Some(value) => value.udata_value().unwrap_or(0) as i64,
+                        None => 0,
+                    };
+                    enumerators.push(Enumerator {
+                        name: get_name(entry, unit, dwarf)?.unwrap_or_default(),
+                        value,
+                    });
+                }
+                (gimli::DW_TAG_formal_parameter, TypeKind::Function { parameters, .. }) => {
+                    if let Some(type_offset) = get_type_ref(entry, unit)? {
+                        parameters.push(type_offset);
+                    }
+                }
+                _ => {}
+            }
+            continue;
+        }
+
+        let name = get_name(entry, unit, dwarf)?;
+        let target = get_type_ref(entry, unit)?;
+        let kind = match entry.tag() {
+            gimli::DW_TAG_base_type => {
+                let encoding = match entry.attr_value(gimli::DW_AT_encoding)? {
+                    Some(gimli::AttributeValue::Encoding(ate)) => match ate {
+                        gimli::DW_ATE_signed => Encoding::Signed,
+                        gimli::DW_ATE_unsigned => Encoding::Unsigned,
+                        gimli::DW_ATE_signed_char => Encoding::SignedChar,
+                        gimli::DW_ATE_unsigned_char => Encoding::UnsignedChar,
+                        gimli::DW_ATE_float => Encoding::Float,
+                        gimli::DW_ATE_boolean => Encoding::Boolean,
+                        _ => Encoding::Unknown,
+                    },
+                    _ => Encoding::Unknown,
+                };
+                TypeKind::Base(encoding)
+            }
+            gimli::DW_TAG_pointer_type => TypeKind::Pointer(target),
+            gimli::DW_TAG_structure_type => TypeKind::Struct {
+                tag: name.clone(),
+                members: Vec::new(),
+            },
+            gimli::DW_TAG_union_type => TypeKind::Union {
+                tag: name.clone(),
+                members: Vec::new(),
+            },
+            gimli::DW_TAG_enumeration_type => TypeKind::Enum {
+                tag: name.clone(),
+                enumerators: Vec::new(),
+            },
+            gimli::DW_TAG_array_type => match target {
+                Some(element) => TypeKind::Array {
+                    element,
+                    dimensions: Vec::new(),
+                },
+                None => continue,
+            },
+            gimli::DW_TAG_typedef => TypeKind::Typedef(target),
+            gimli::DW_TAG_const_type => TypeKind::Qualified(Qualifier::Const, target),
+            gimli::DW_TAG_volatile_type => TypeKind::Qualified(Qualifier::Volatile, target),
+            gimli::DW_TAG_restrict_type => TypeKind::Qualified(Qualifier::Restrict, target),
+            gimli::DW_TAG_subroutine_type => TypeKind::Function {
+                return_type: target,
+                parameters: Vec::new(),
+            },
+            _ => continue,
+        };
+        let type_offset = section_offset(entry.offset(), unit);
+        if entry.has_children() {
+            parent = Some((depth, type_offset));
+        }
+        let byte_size = get_udata(entry, gimli::DW_AT_byte_size)?.unwrap_or(0);
+        offset_to_type.insert(
+            type_offset,
+            Type::new(name.unwrap_or_default(), byte_size, kind),
+        );
+        unit_types.push(type_offset);
+    }
+
+    // Now that everything a type can refer to is known, fill in the C names ("struct point",
+    // "char *[4]") and the sizes of types that have no DW_AT_byte_size.
+    let mut resolved: Vec<(usize, String, usize)> = Vec::new();
+    for type_offset in unit_types {
+        let ty = &offset_to_type[&type_offset];
+        resolved.push((
+            type_offset,
+            dwarf_data::declare(offset_to_type, Some(ty), ""),
+            dwarf_data::type_size(offset_to_type, ty),
+        ));
+    }
+    for (type_offset, name, size) in resolved {
+        let ty = offset_to_type.get_mut(&type_offset).unwrap();
+        // Typedefs and base types keep their own name, which `declare` relies on
+        if !matches!(ty.kind, TypeKind::Base(_) | TypeKind::Typedef(_)) {
+            ty.name = name;
+        }
+        ty.size = size;
+    }
+    Ok(())
 }
 
 #[derive(Debug, Clone)]
@@ -344,6 +559,14 @@ fn get_attr_value<R: Reader>(
                 Ok(DebugValue::Str(format!("<.debug_str+0x{:08x}>", offset.0)))
             }
         }
+        // DWARF 5 keeps the names of source files and directories in .debug_line_str
+        gimli::AttributeValue::DebugLineStrRef(offset) => {
+            if let Ok(s) = dwarf.debug_line_str.get_str(offset) {
+                Ok(DebugValue::Str(format!("{}", s.to_string_lossy()?)))
+            } else {
+                Ok(DebugValue::Str(format!("<.debug_line_str+0x{:08x}>", offset.0)))
+            }
+        }
         gimli::AttributeValue::Sdata(data) => Ok(DebugValue::Int(data)),
         gimli::AttributeValue::Addr(data) => Ok(DebugValue::Uint(data)),
         gimli::AttributeValue::Udata(data) => Ok(DebugValue::Uint(data)),
@@ -1,17 +1,22 @@
-use libc::SIGTRAP;
-use libc::WIFSTOPPED;
-use nix::errno::errno;
+use nix::errno::Errno;
 use nix::sys::ptrace;
 use nix::sys::signal;
 use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
 use nix::unistd::Pid;
-use nix::Error;
-use std::io;
+use std::collections::HashMap;
+use std::fs;
+use std::mem::{size_of, MaybeUninit};
+use std::os::unix::fs::FileExt;
 use std::os::unix::process::CommandExt;
 use std::process::Child;
 use std::process::Command;
 
+use crate::breakpoint::{BreakpointTable, WatchKind};
+use crate::coredump::ThreadState;
 use crate::dwarf_data::DwarfData;
+use crate::signals::{describe_signal, SignalTable};
+use crate::solib::{self, DynamicLinker};
+use crate::target::Target;
 
 pub enum Status {
     /// Indicates inferior stopped. Contains the signal that stopped the process, as well as the
@@ -24,6 +29,18 @@ pub enum Status {
     /// Indicates the inferior exited due to a signal. Contains the signal that killed the
     /// process.
     Signaled(signal::Signal),
+
+    /// Indicates the inferior called exec. Its breakpoints went with its old memory image.
+    Exec,
+}
+
+/// What to do when the inferior forks (`set follow-fork-mode`, `set detach-on-fork`).
+#[derive(Clone, Copy)]
+pub struct ForkPolicy {
+    /// Carry on debugging the child rather than the parent
+    pub follow_child: bool,
+    /// Let go of the process we aren't following, rather than keeping it stopped
+    pub detach_on_fork: bool,
 }
 
 /// This function calls ptrace with PTRACE_TRACEME to enable debugging on a process. You should use
@@ -35,103 +52,1554 @@ fn child_traceme() -> Result<(), std::io::Error> {
     )))
 }
 
+/// Turns off address space randomization for the child, as gdb does by default, so that the
+/// program and its libraries land at the same addresses on every run and breakpoints carry over
+/// from one run to the next. Like gdb, we carry on regardless if that isn't allowed.
+fn disable_aslr() -> Result<(), std::io::Error> {
+    unsafe {
+        let persona = libc::personality(0xffff_ffff);
+        if persona != -1 {
+            libc::personality((persona | libc::ADDR_NO_RANDOMIZE) as libc::c_ulong);
+        }
+    }
+    Ok(())
+}
+
+fn align_addr_to_word(addr: usize) -> usize {
+    addr & (-(size_of::<usize>() as isize) as usize)
+}
+
+/// The end of `len` bytes of memory at `addr`, or EFAULT if they would run past the top of the
+/// address space.
+fn memory_end(addr: usize, len: usize) -> Result<usize, nix::Error> {
+    addr.checked_add(len).ok_or(nix::Error::Sys(Errno::EFAULT))
+}
+
+/// A software breakpoint patched into the inferior's text. `orig_byte` holds the byte that the
+/// INT3 (0xcc) instruction replaced.
+#[derive(Clone)]
+struct Breakpoint {
+    addr: usize,
+    orig_byte: u8,
+}
+
+/// A watchpoint as armed in the debug registers. Each register watches an aligned 1, 2, 4 or
+/// 8 byte range, so one watchpoint may take up several of them.
+#[derive(Clone, Copy, PartialEq)]
+struct HardwareWatchpoint {
+    addr: usize,
+    len: usize,
+    kind: WatchKind,
+}
+
+/// Offset of `u_debugreg` in the kernel's `struct user`, which is what PTRACE_PEEKUSER and
+/// PTRACE_POKEUSER index into.
+const DEBUG_REGISTER_OFFSET: usize = 848;
+
+/// Number of debug registers (DR0-DR3) that can hold an address.
+pub const DEBUG_REGISTER_COUNT: usize = 4;
+
+fn debug_register_offset(index: usize) -> usize {
+    DEBUG_REGISTER_OFFSET + index * size_of::<u64>()
+}
+
+fn peek_user(tid: Pid, offset: usize) -> Result<u64, nix::Error> {
+    let res = unsafe {
+        Errno::clear();
+        libc::ptrace(
+            libc::PTRACE_PEEKUSER,
+            libc::pid_t::from(tid),
+            offset as *mut libc::c_void,
+            std::ptr::null_mut::<libc::c_void>(),
+        )
+    };
+    // -1 is a legitimate value, so only errno tells us whether the call failed
+    match Errno::result(res) {
+        Ok(_) | Err(nix::Error::Sys(Errno::UnknownErrno)) => Ok(res as u64),
+        Err(e) => Err(e),
+    }
+}
+
+fn poke_user(tid: Pid, offset: usize, value: u64) -> Result<(), nix::Error> {
+    let res = unsafe {
+        libc::ptrace(
+            libc::PTRACE_POKEUSER,
+            libc::pid_t::from(tid),
+            offset as *mut libc::c_void,
+            value as *mut libc::c_void,
+        )
+    };
+    Errno::result(res).map(drop)
+}
+
+/// Reads the x87 and SSE state of a thread. nix has no wrapper for PTRACE_GETFPREGS, so this
+/// calls ptrace directly.
+fn get_fp_registers(tid: Pid) -> Result<libc::user_fpregs_struct, nix::Error> {
+    let mut fpregs = MaybeUninit::<libc::user_fpregs_struct>::uninit();
+    let res = unsafe {
+        libc::ptrace(
+            libc::PTRACE_GETFPREGS,
+            libc::pid_t::from(tid),
+            std::ptr::null_mut::<libc::c_void>(),
+            fpregs.as_mut_ptr(),
+        )
+    };
+    Errno::result(res)?;
+    Ok(unsafe { fpregs.assume_init() })
+}
+
+/// Splits `len` bytes at `addr` into the naturally aligned 1, 2, 4 and 8 byte ranges that debug
+/// registers can watch.
+pub fn debug_register_ranges(addr: usize, len: usize) -> Vec<(usize, usize)> {
+    let mut ranges = Vec::new();
+    let end = addr + len;
+    let mut addr = addr;
+    while addr < end {
+        let mut size = 8;
+        while addr & (size - 1) != 0 || addr + size > end {
+            size /= 2;
+        }
+        ranges.push((addr, size));
+        addr += size;
+    }
+    ranges
+}
+
+/// Writes a single byte into the memory of `tid`, returning the byte that was there before.
+fn poke_byte(tid: Pid, addr: usize, val: u8) -> Result<u8, nix::Error> {
+    let aligned_addr = align_addr_to_word(addr);
+    let byte_offset = addr - aligned_addr;
+    let word = ptrace::read(tid, aligned_addr as ptrace::AddressType)? as u64;
+    let orig_byte = (word >> (8 * byte_offset)) & 0xff;
+    let masked_word = word & !(0xff << (8 * byte_offset));
+    let updated_word = masked_word | ((val as u64) << (8 * byte_offset));
+    ptrace::write(
+        tid,
+        aligned_addr as ptrace::AddressType,
+        updated_word as *mut std::ffi::c_void,
+    )?;
+    Ok(orig_byte as u8)
+}
+
+/// A thread of the inferior. Like gdb, we number threads from 1 in the order we see them, and
+/// never reuse a number.
+struct Thread {
+    number: usize,
+    tid: Pid,
+    /// The signal to deliver when the thread is next resumed
+    pending_signal: Option<signal::Signal>,
+    /// A stop (a watchpoint hit, or a fork or exec) that the thread made while we were stopping
+    /// it for another thread's sake. The thread is left in that stop until the inferior is next
+    /// resumed, when it is reported as though it had just happened.
+    pending_status: Option<WaitStatus>,
+    /// Whether a SIGSTOP we sent the thread has yet to turn up, which it does once the thread
+    /// is let go of its pending stop
+    stop_sent: bool,
+}
+
 pub struct Inferior {
-    child: Child,
+    pid: Pid,
+    /// The process we spawned, or None if we attached to one that was already running
+    child: Option<Child>,
+    /// False once we have detached, after which the process is none of our business
+    traced: bool,
+    /// Every thread of the process, in order of creation
+    threads: Vec<Thread>,
+    /// The thread that last stopped, or that the user switched to
+    current: Pid,
+    next_thread_number: usize,
+    /// New threads and processes whose first stop showed up before their parent's event
+    early_threads: Vec<Pid>,
+    fork_policy: ForkPolicy,
+    /// Processes (or rather their threads) kept stopped after a fork with detach-on-fork off
+    held: Vec<Pid>,
+    /// Whether our breakpoints are missing from memory because a vfork child we let go of is
+    /// sharing it until it execs or exits
+    breakpoints_lifted: bool,
+    breakpoints: HashMap<usize, Breakpoint>,
+    /// The dynamic linker, on whose event function we keep a breakpoint of our own so as to hear
+    /// about shared libraries being loaded. None for statically linked programs.
+    dynamic_linker: Option<DynamicLinker>,
+    /// What each of DR0-DR3 is watching
+    debug_registers: [Option<HardwareWatchpoint>; DEBUG_REGISTER_COUNT],
 }
 
 impl Drop for Inferior {
+    /// Kills a process we started, but leaves one we attached to running, the way we found it.
     fn drop(&mut self) {
-        if let Ok(_) = self.child.kill() {
-            println!("Killing running inferior (pid {})", self.pid());
-            let _ = self.wait(None);
+        if !self.traced {
+            return;
+        }
+        if self.child.is_some() {
+            for tid in &self.held {
+                let _ = signal::kill(*tid, signal::Signal::SIGKILL);
+            }
+            // After following a fork, the process we are debugging isn't `child` any more
+            if signal::kill(self.pid, signal::Signal::SIGKILL).is_ok() {
+                println!("Killing running inferior (pid {})", self.pid());
+                self.reap();
+            }
+        } else {
+            println!("Detaching from process {}", self.pid());
+            let _ = self.release();
         }
     }
 }
 
 impl Inferior {
     /// Attempts to start a new inferior process. Returns Some(Inferior) if successful, or None if
-    /// an error is encountered.
+    /// an error is encountered. The inferior is stopped before its first instruction, so that
+    /// breakpoints can be inserted once it is known where the program was loaded.
     pub fn new(target: &str, args: &Vec<String>) -> Option<Inferior> {
         let mut cmd = Command::new(target);
         unsafe {
+            cmd.pre_exec(disable_aslr);
             cmd.pre_exec(child_traceme);
         }
-        let inferior = Inferior {
-            child: cmd.args(args).spawn().ok()?,
+        let child = cmd.args(args).spawn().ok()?;
+        let pid = Pid::from_raw(child.id() as i32);
+        let mut inferior = Inferior {
+            pid,
+            child: Some(child),
+            traced: true,
+            threads: Vec::new(),
+            current: pid,
+            next_thread_number: 1,
+            early_threads: Vec::new(),
+            fork_policy: ForkPolicy {
+                follow_child: false,
+                detach_on_fork: true,
+            },
+            held: Vec::new(),
+            breakpoints_lifted: false,
+            breakpoints: HashMap::new(),
+            dynamic_linker: None,
+            debug_registers: [None; DEBUG_REGISTER_COUNT],
         };
         if let Ok(Status::Stopped(_, _)) = inferior.wait(Some(WaitPidFlag::WSTOPPED)) {
+            inferior.track_thread(pid).ok()?;
+            inferior.watch_library_loads();
             Some(inferior)
         } else {
             None
         }
     }
 
-    pub fn print_backtrace(&self, debug_data: &DwarfData) -> Result<(), nix::Error> {
-        let regs = ptrace::getregs(self.pid())?;
+    /// Attaches to a process that is already running and stops it. Fails if we aren't allowed to
+    /// trace the process.
+    pub fn attach(pid: Pid) -> Result<Inferior, nix::Error> {
+        ptrace::attach(pid)?;
+        let mut inferior = Inferior {
+            pid,
+            child: None,
+            traced: true,
+            threads: Vec::new(),
+            current: pid,
+            next_thread_number: 1,
+            early_threads: Vec::new(),
+            fork_policy: ForkPolicy {
+                follow_child: false,
+                detach_on_fork: true,
+            },
+            held: Vec::new(),
+            breakpoints_lifted: false,
+            breakpoints: HashMap::new(),
+            dynamic_linker: None,
+            debug_registers: [None; DEBUG_REGISTER_COUNT],
+        };
+        // PTRACE_ATTACH sends a SIGSTOP, which we swallow here rather than report
+        match inferior.wait(None)? {
+            Status::Stopped(_, _) => {}
+            // The process died before we got hold of it
+            _ => {
+                inferior.traced = false;
+                return Err(nix::Error::Sys(Errno::ESRCH));
+            }
+        }
+        inferior.track_thread(pid)?;
+        // Each of the other threads has to be attached to separately
+        let tids: Vec<Pid> = fs::read_dir(format!("/proc/{}/task", pid))
+            .map_err(|_| nix::Error::Sys(Errno::ESRCH))?
+            .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+            .map(Pid::from_raw)
+            .filter(|tid| *tid != pid)
+            .collect();
+        for tid in tids {
+            if ptrace::attach(tid).is_ok() {
+                waitpid(tid, Some(WaitPidFlag::__WALL))?;
+                inferior.track_thread(tid)?;
+            }
+        }
+        inferior.watch_library_loads();
+        Ok(inferior)
+    }
+
+    /// Starts keeping track of a thread that has just stopped for the first time, and asks to
+    /// hear about any threads or processes it creates, and about exec.
+    fn track_thread(&mut self, tid: Pid) -> Result<(), nix::Error> {
+        ptrace::setoptions(
+            tid,
+            ptrace::Options::PTRACE_O_TRACECLONE
+                | ptrace::Options::PTRACE_O_TRACEFORK
+                | ptrace::Options::PTRACE_O_TRACEVFORK
+                | ptrace::Options::PTRACE_O_TRACEVFORKDONE
+                | ptrace::Options::PTRACE_O_TRACEEXEC,
+        )?;
+        self.threads.push(Thread {
+            number: self.next_thread_number,
+            tid,
+            pending_signal: None,
+            pending_status: None,
+            stop_sent: false,
+        });
+        self.next_thread_number += 1;
+        Ok(())
+    }
 
-        let print_function_name_and_line = |addr: usize| {
-            if let Some(function) = debug_data.get_function_from_addr(addr) {
-                print!("{} ", function);
+    /// Deals with a PTRACE_EVENT_CLONE from `parent`: waits for the new thread's first stop, and
+    /// gives it the same watchpoints as everyone else. The new thread is left stopped. Returns
+    /// its thread id.
+    fn add_thread(&mut self, parent: Pid) -> Result<Pid, nix::Error> {
+        let tid = Pid::from_raw(ptrace::getevent(parent)? as i32);
+        self.wait_for_first_stop(tid)?;
+        self.threads.push(Thread {
+            number: self.next_thread_number,
+            tid,
+            pending_signal: None,
+            pending_status: None,
+            stop_sent: false,
+        });
+        self.next_thread_number += 1;
+        self.copy_debug_registers(parent, tid)?;
+        println!("[New thread {} (LWP {})]", self.next_thread_number - 1, tid);
+        Ok(tid)
+    }
+
+    /// Waits for the SIGSTOP that a new thread or process starts out with, unless it has already
+    /// turned up.
+    fn wait_for_first_stop(&mut self, tid: Pid) -> Result<(), nix::Error> {
+        match self.early_threads.iter().position(|early| *early == tid) {
+            Some(index) => {
+                self.early_threads.remove(index);
             }
-            if let Some(line) = debug_data.get_line_from_addr(addr) {
-                print!("{} ", line);
+            None => {
+                waitpid(tid, Some(WaitPidFlag::__WALL))?;
             }
+        }
+        Ok(())
+    }
+
+    /// Gives a new thread or process our watchpoints. Debug registers aren't inherited across
+    /// clone or fork.
+    fn copy_debug_registers(&self, from: Pid, to: Pid) -> Result<(), nix::Error> {
+        if self.debug_registers.iter().any(Option::is_some) {
+            for number in (0..DEBUG_REGISTER_COUNT).chain(std::iter::once(7)) {
+                let value = peek_user(from, debug_register_offset(number))?;
+                poke_user(to, debug_register_offset(number), value)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Sets what happens to the two processes when the inferior forks.
+    pub fn set_fork_policy(&mut self, policy: ForkPolicy) {
+        self.fork_policy = policy;
+    }
+
+    /// Deals with a fork or vfork event from `parent`. Depending on the fork policy we either
+    /// carry on with the parent, or switch over to the child, which has our breakpoints in its
+    /// copy of memory. The other process is either let go of, minus our breakpoints, or kept
+    /// stopped. Returns the thread to resume.
+    fn handle_fork(
+        &mut self,
+        parent: Pid,
+        vfork: bool,
+        signals: &SignalTable,
+    ) -> Result<Pid, nix::Error> {
+        let child = Pid::from_raw(ptrace::getevent(parent)? as i32);
+        self.wait_for_first_stop(child)?;
+        let call = if vfork { "vfork" } else { "fork" };
+        if !self.fork_policy.follow_child {
+            if self.fork_policy.detach_on_fork {
+                // A vfork child shares our memory, so the breakpoints have to come out until it
+                // is done with it
+                self.lift_breakpoints(child)?;
+                self.breakpoints_lifted = vfork;
+                ptrace::detach(child, None)?;
+                println!("[Detaching after {} from child process {}]", call, child);
+            } else {
+                self.held.push(child);
+                println!("[Keeping child process {} stopped after {}]", child, call);
+            }
+            return Ok(parent);
+        }
+
+        // Everything the parent is running has to be stopped before we can let go of it
+        self.current = parent;
+        self.stop_other_threads(signals)?;
+        let parent_threads: Vec<Pid> = self.threads.iter().map(|thread| thread.tid).collect();
+        self.copy_debug_registers(parent, child)?;
+        if self.fork_policy.detach_on_fork {
+            // After a vfork this is the child's memory too, so the child goes without breakpoints
+            // until it execs
+            self.lift_breakpoints(parent)?;
+            self.cancel_sent_stops()?;
+            for tid in parent_threads {
+                let pending = self
+                    .thread_mut(tid)
+                    .and_then(|thread| thread.pending_signal);
+                ptrace::detach(tid, pending)?;
+            }
+            println!(
+                "[Detaching after {} from parent process {}]",
+                call, self.pid
+            );
+        } else {
+            self.held.extend(parent_threads);
+            println!(
+                "[Keeping parent process {} stopped after {}]",
+                self.pid, call
+            );
+        }
+        println!(
+            "[Attaching after process {} {} to child process {}]",
+            self.pid, call, child
+        );
+        self.pid = child;
+        self.current = child;
+        self.threads = vec![Thread {
+            number: self.next_thread_number,
+            tid: child,
+            pending_signal: None,
+            pending_status: None,
+            stop_sent: false,
+        }];
+        self.next_thread_number += 1;
+        Ok(child)
+    }
+
+    /// Catches up with an exec: the old memory image, with our breakpoints and watchpoints, is
+    /// gone, and so is every thread but the one that called exec, which now has the process id.
+    fn handle_exec(&mut self) {
+        let pid = self.pid;
+        self.threads.retain(|thread| thread.tid == pid);
+        if self.threads.is_empty() {
+            self.threads.push(Thread {
+                number: self.next_thread_number,
+                tid: pid,
+                pending_signal: None,
+                pending_status: None,
+                stop_sent: false,
+            });
+            self.next_thread_number += 1;
+        }
+        self.current = pid;
+        self.breakpoints.clear();
+        self.debug_registers = [None; DEBUG_REGISTER_COUNT];
+        self.breakpoints_lifted = false;
+        self.watch_library_loads();
+    }
+
+    fn remove_thread(&mut self, tid: Pid) {
+        if let Some(index) = self.threads.iter().position(|thread| thread.tid == tid) {
+            let thread = self.threads.remove(index);
+            println!("[Thread {} (LWP {}) exited]", thread.number, tid);
+        }
+    }
+
+    fn thread_mut(&mut self, tid: Pid) -> Option<&mut Thread> {
+        self.threads.iter_mut().find(|thread| thread.tid == tid)
+    }
+
+    /// Lists the threads as (number, thread id) pairs.
+    pub fn threads(&self) -> Vec<(usize, Pid)> {
+        self.threads
+            .iter()
+            .map(|thread| (thread.number, thread.tid))
+            .collect()
+    }
+
+    /// The number of the current thread.
+    pub fn current_thread(&self) -> usize {
+        self.threads
+            .iter()
+            .find(|thread| thread.tid == self.current)
+            .map_or(0, |thread| thread.number)
+    }
+
+    /// Makes thread `number` the current thread. Returns false if there is no such thread.
+    pub fn select_thread(&mut self, number: usize) -> bool {
+        match self.threads.iter().find(|thread| thread.number == number) {
+            Some(thread) => {
+                self.current = thread.tid;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Snapshots the registers of every thread for a core file, the current thread first.
+    pub fn thread_states(&self) -> Result<Vec<ThreadState>, nix::Error> {
+        let mut threads: Vec<&Thread> = self.threads.iter().collect();
+        threads.sort_by_key(|thread| thread.tid != self.current);
+        threads
+            .into_iter()
+            .map(|thread| {
+                Ok(ThreadState {
+                    tid: thread.tid,
+                    signal: thread.pending_signal,
+                    regs: ptrace::getregs(thread.tid)?,
+                    fp_regs: get_fp_registers(thread.tid).ok(),
+                })
+            })
+            .collect()
+    }
+
+    /// The name of a thread, as set with prctl or pthread_setname_np.
+    pub fn thread_name(&self, tid: Pid) -> Option<String> {
+        let name = fs::read_to_string(format!("/proc/{}/task/{}/comm", self.pid, tid)).ok()?;
+        Some(name.trim_end().to_string())
+    }
+
+    /// Finds the dynamic linker of a freshly started (or exec'd, or attached) process, and puts a
+    /// breakpoint on the function it calls after loading or unloading shared libraries.
+    fn watch_library_loads(&mut self) {
+        self.dynamic_linker = solib::dynamic_linker(self);
+        if let Some(linker) = self.dynamic_linker {
+            if self.insert_breakpoint(linker.event).is_err() {
+                self.dynamic_linker = None;
+            }
+        }
+    }
+
+    fn is_library_event(&self, addr: usize) -> bool {
+        self.dynamic_linker.map(|linker| linker.event) == Some(addr)
+    }
+
+    /// Lists the shared libraries that are loaded, as (path, load bias) pairs.
+    pub fn shared_libraries(&self) -> Vec<(String, usize)> {
+        match &self.dynamic_linker {
+            Some(linker) => solib::loaded_libraries(self, linker),
+            None => Vec::new(),
+        }
+    }
+
+    /// Called when the dynamic linker has loaded or unloaded shared libraries. Breakpoints in a
+    /// library that wasn't there when they were inserted can go in now, so try them all again;
+    /// those still pointing nowhere fail quietly.
+    fn libraries_changed(&mut self, breakpoints: &BreakpointTable) {
+        for addr in breakpoints.enabled_addrs() {
+            let _ = self.insert_breakpoint(addr);
+        }
+    }
+
+    /// Removes all breakpoints and watchpoints and lets the process carry on without us.
+    pub fn detach(mut self) -> Result<(), nix::Error> {
+        self.release()
+    }
+
+    fn release(&mut self) -> Result<(), nix::Error> {
+        let addrs: Vec<usize> = self.breakpoints.keys().copied().collect();
+        for addr in addrs {
+            self.remove_breakpoint(addr)?;
+        }
+        for index in 0..DEBUG_REGISTER_COUNT {
+            if let Some(watchpoint) = self.debug_registers[index] {
+                self.remove_watchpoint(watchpoint.addr, watchpoint.len, watchpoint.kind)?;
+            }
+        }
+        self.cancel_sent_stops()?;
+        for thread in &self.threads {
+            ptrace::detach(thread.tid, thread.pending_signal)?;
+        }
+        for tid in &self.held {
+            self.lift_breakpoints(*tid)?;
+            ptrace::detach(*tid, None)?;
+        }
+        self.traced = false;
+        Ok(())
+    }
+
+    /// Writes a single byte into the inferior's memory, returning the byte that was there before.
+    fn write_byte(&mut self, addr: usize, val: u8) -> Result<u8, nix::Error> {
+        poke_byte(self.tid(), addr, val)
+    }
+
+    /// Puts back the original bytes under our breakpoints in the memory of `tid`, which is a
+    /// forked copy of ours that we are about to let go of. Our own breakpoint table is left alone.
+    fn lift_breakpoints(&self, tid: Pid) -> Result<(), nix::Error> {
+        for breakpoint in self.breakpoints.values() {
+            poke_byte(tid, breakpoint.addr, breakpoint.orig_byte)?;
+        }
+        Ok(())
+    }
+
+    /// Patches an INT3 instruction into the inferior at `addr`. Inserting a breakpoint twice at
+    /// the same address is a no-op.
+    pub fn insert_breakpoint(&mut self, addr: usize) -> Result<(), nix::Error> {
+        if self.breakpoints.contains_key(&addr) {
+            return Ok(());
+        }
+        let orig_byte = self.write_byte(addr, 0xcc)?;
+        self.breakpoints
+            .insert(addr, Breakpoint { addr, orig_byte });
+        Ok(())
+    }
+
+    /// Restores the original byte under a breakpoint and forgets about it. Removing a breakpoint
+    /// that was never inserted is a no-op.
+    pub fn remove_breakpoint(&mut self, addr: usize) -> Result<(), nix::Error> {
+        if let Some(breakpoint) = self.breakpoints.remove(&addr) {
+            self.write_byte(breakpoint.addr, breakpoint.orig_byte)?;
+        }
+        Ok(())
+    }
+
+    /// Sets a debug register in every thread, since each thread has a set of its own.
+    fn set_debug_register(&self, number: usize, value: u64) -> Result<(), nix::Error> {
+        for thread in &self.threads {
+            poke_user(thread.tid, debug_register_offset(number), value)?;
+        }
+        Ok(())
+    }
+
+    /// Programs debug registers to watch `len` bytes at `addr`. Returns false, without touching
+    /// anything, if there aren't enough free debug registers. Inserting the same watchpoint twice
+    /// is a no-op.
+    pub fn insert_watchpoint(
+        &mut self,
+        addr: usize,
+        len: usize,
+        kind: WatchKind,
+    ) -> Result<bool, nix::Error> {
+        let watchpoint = HardwareWatchpoint { addr, len, kind };
+        if self.debug_registers.contains(&Some(watchpoint)) {
+            return Ok(true);
+        }
+        let ranges = debug_register_ranges(addr, len);
+        let free: Vec<usize> = (0..DEBUG_REGISTER_COUNT)
+            .filter(|&index| self.debug_registers[index].is_none())
+            .collect();
+        if ranges.len() > free.len() {
+            return Ok(false);
+        }
+        // x86 can't trap on reads alone, so read watchpoints trap on any access and we sort it
+        // out afterwards
+        let access_bits = match kind {
+            WatchKind::Write => 0b01,
+            WatchKind::Read | WatchKind::Access => 0b11,
         };
+        let mut dr7 = peek_user(self.tid(), debug_register_offset(7))?;
+        for (&(range_addr, range_len), &index) in ranges.iter().zip(free.iter()) {
+            self.set_debug_register(index, range_addr as u64)?;
+            let len_bits = match range_len {
+                1 => 0b00,
+                2 => 0b01,
+                4 => 0b11,
+                _ => 0b10,
+            };
+            dr7 &= !(0b1111 << (16 + index * 4));
+            dr7 |= (access_bits | len_bits << 2) << (16 + index * 4);
+            // The local enable bit
+            dr7 |= 1 << (index * 2);
+            self.debug_registers[index] = Some(watchpoint);
+        }
+        self.set_debug_register(7, dr7)?;
+        Ok(true)
+    }
 
-        let mut index = 0;
-        print!("{}: ", index);
-        index += 1;
-        print_function_name_and_line(regs.rip as usize);
+    /// Frees the debug registers watching `len` bytes at `addr`. Removing a watchpoint that was
+    /// never inserted is a no-op.
+    pub fn remove_watchpoint(
+        &mut self,
+        addr: usize,
+        len: usize,
+        kind: WatchKind,
+    ) -> Result<(), nix::Error> {
+        let watchpoint = Some(HardwareWatchpoint { addr, len, kind });
+        if !self.debug_registers.contains(&watchpoint) {
+            return Ok(());
+        }
+        let mut dr7 = peek_user(self.tid(), debug_register_offset(7))?;
+        for index in 0..DEBUG_REGISTER_COUNT {
+            if self.debug_registers[index] == watchpoint {
+                dr7 &= !(1 << (index * 2));
+                self.debug_registers[index] = None;
+            }
+        }
+        self.set_debug_register(7, dr7)
+    }
 
-        println!("rip: {:#x}", regs.rip);
+    /// Whether the SIGTRAP thread `tid` stopped with came from a watchpoint, going by DR6, which
+    /// is left as it is.
+    fn watchpoint_fired(&self, tid: Pid) -> Result<bool, nix::Error> {
+        if self.debug_registers.iter().all(Option::is_none) {
+            return Ok(false);
+        }
+        Ok(peek_user(tid, debug_register_offset(6))? & 0xf != 0)
+    }
+
+    /// Reads DR6 to find out which watchpoints fired, and clears it for next time. Returns the
+    /// addresses of the watchpoints (as passed to insert_watchpoint) that triggered.
+    fn triggered_watchpoints(&mut self) -> Result<Vec<usize>, nix::Error> {
+        if self.debug_registers.iter().all(Option::is_none) {
+            return Ok(Vec::new());
+        }
+        let dr6 = peek_user(self.tid(), debug_register_offset(6))?;
+        let mut addrs = Vec::new();
+        for (index, watchpoint) in self.debug_registers.iter().enumerate() {
+            if let Some(watchpoint) = watchpoint {
+                if dr6 & (1 << index) != 0 && !addrs.contains(&watchpoint.addr) {
+                    addrs.push(watchpoint.addr);
+                }
+            }
+        }
+        if dr6 & 0xf != 0 {
+            poke_user(self.tid(), debug_register_offset(6), 0)?;
+        }
+        Ok(addrs)
+    }
 
-        // try to get all backtrace (cdecl)
-        let mut bp = regs.rbp;
+    fn read_proc_mem(&self, addr: usize, len: usize) -> std::io::Result<Vec<u8>> {
+        let file = fs::File::open(format!("/proc/{}/mem", self.pid()))?;
+        let mut bytes = vec![0; len];
+        file.read_exact_at(&mut bytes, addr as u64)?;
+        Ok(bytes)
+    }
+
+    /// Reads memory a word at a time with PTRACE_PEEKDATA.
+    fn peek_memory(&self, addr: usize, len: usize) -> Result<Vec<u8>, nix::Error> {
+        let end = memory_end(addr, len)?;
+        let mut bytes = Vec::with_capacity(len);
+        let mut word_addr = align_addr_to_word(addr);
+        while word_addr < end {
+            let word = ptrace::read(self.tid(), word_addr as ptrace::AddressType)? as u64;
+            for i in 0..size_of::<usize>() {
+                let byte_addr = word_addr + i;
+                if byte_addr >= addr && byte_addr < end {
+                    bytes.push((word >> (8 * i)) as u8);
+                }
+            }
+            word_addr += size_of::<usize>();
+        }
+        Ok(bytes)
+    }
+
+    /// Writes `bytes` into the inferior's memory at `addr`. Bytes that land on an INT3 we have
+    /// patched in update the saved original byte instead, so the breakpoint stays in place.
+    pub fn write_memory(&mut self, addr: usize, bytes: &[u8]) -> Result<(), nix::Error> {
+        let end = memory_end(addr, bytes.len())?;
+        let mut word_addr = align_addr_to_word(addr);
+        while word_addr < end {
+            let word = ptrace::read(self.tid(), word_addr as ptrace::AddressType)? as u64;
+            let mut word_bytes = word.to_le_bytes();
+            for (i, word_byte) in word_bytes.iter_mut().enumerate() {
+                let byte_addr = word_addr + i;
+                if byte_addr < addr || byte_addr >= end {
+                    continue;
+                }
+                match self.breakpoints.get_mut(&byte_addr) {
+                    Some(breakpoint) => breakpoint.orig_byte = bytes[byte_addr - addr],
+                    None => *word_byte = bytes[byte_addr - addr],
+                }
+            }
+            ptrace::write(
+                self.tid(),
+                word_addr as ptrace::AddressType,
+                u64::from_le_bytes(word_bytes) as *mut std::ffi::c_void,
+            )?;
+            word_addr += size_of::<usize>();
+        }
+        Ok(())
+    }
+
+    pub fn set_registers(&mut self, regs: libc::user_regs_struct) -> Result<(), nix::Error> {
+        ptrace::setregs(self.tid(), regs)
+    }
+
+    /// Resumes the inferior and waits for it to stop. With `step`, only the current thread runs,
+    /// for a single instruction; otherwise every thread runs until one of them stops, and then
+    /// the others are stopped too (all-stop), with the thread that stopped becoming current.
+    /// Threads are resumed with the signal they last stopped with if we are meant to pass it on.
+    ///
+    /// Thread creation and exit, and signals that `signals` says not to stop for, are dealt with
+    /// here, so what comes back is a SIGTRAP, a signal worth stopping for, or the end of the
+    /// process.
+    fn resume(&mut self, step: bool, signals: &SignalTable) -> Result<Status, nix::Error> {
+        let mut step = step;
+        if step {
+            let tid = self.tid();
+            if let Some(thread) = self.thread_mut(tid) {
+                if thread.pending_status.is_none() {
+                    let pending = thread.pending_signal.take();
+                    ptrace::step(tid, pending)?;
+                }
+            }
+        } else {
+            self.resume_all_threads()?;
+        }
+        let restart = |tid: Pid, step: bool, signal: Option<signal::Signal>| {
+            if step {
+                ptrace::step(tid, signal)
+            } else {
+                ptrace::cont(tid, signal)
+            }
+        };
         loop {
-            match ptrace::read(self.pid(), bp as ptrace::AddressType) {
-                Ok(new_bp) => {
-                    match ptrace::read(self.pid(), (bp + 8) as ptrace::AddressType) {
-                        Ok(addr) => {
-                            print!("{}: ", index);
-                            print_function_name_and_line(addr as usize);
-                            println!("rip: {:#x}", addr as usize);
-                            index += 1;
+            let target = if step { self.tid() } else { Pid::from_raw(-1) };
+            let status = match self.take_pending_status(step) {
+                Some(status) => status,
+                None => waitpid(target, Some(WaitPidFlag::__WALL))?,
+            };
+            match status {
+                WaitStatus::PtraceEvent(tid, _, libc::PTRACE_EVENT_CLONE) => {
+                    let new_tid = self.add_thread(tid)?;
+                    restart(tid, step, None)?;
+                    if !step {
+                        ptrace::cont(new_tid, None)?;
+                    }
+                }
+                WaitStatus::PtraceEvent(tid, _, event)
+                    if event == libc::PTRACE_EVENT_FORK || event == libc::PTRACE_EVENT_VFORK =>
+                {
+                    let vfork = event == libc::PTRACE_EVENT_VFORK;
+                    let followed = self.handle_fork(tid, vfork, signals)?;
+                    restart(followed, step, None)?;
+                }
+                WaitStatus::PtraceEvent(tid, _, libc::PTRACE_EVENT_VFORK_DONE) => {
+                    // The vfork child has exec'd or exited, so our memory is our own again
+                    if self.breakpoints_lifted {
+                        for addr in self.breakpoints.keys() {
+                            poke_byte(tid, *addr, 0xcc)?;
                         }
-                        Err(_) => break,
+                        self.breakpoints_lifted = false;
                     }
-                    bp = new_bp as u64;
+                    restart(tid, step, None)?;
                 }
-                Err(_) => {
-                    // end the bt
-                    break;
+                WaitStatus::PtraceEvent(_, _, libc::PTRACE_EVENT_EXEC) => {
+                    self.handle_exec();
+                    return Ok(Status::Exec);
                 }
+                WaitStatus::PtraceEvent(tid, _, _) => restart(tid, step, None)?,
+                WaitStatus::Exited(tid, exit_code) if tid == self.pid => {
+                    return Ok(Status::Exited(exit_code))
+                }
+                WaitStatus::Signaled(tid, signal, _) if tid == self.pid => {
+                    return Ok(Status::Signaled(signal))
+                }
+                WaitStatus::Exited(tid, _) | WaitStatus::Signaled(tid, _, _) => {
+                    self.remove_thread(tid);
+                    if step && tid == self.tid() {
+                        // The thread we were stepping is gone, so let the others run instead
+                        self.current = self.pid;
+                        step = false;
+                        self.resume_all_threads()?;
+                    }
+                }
+                WaitStatus::Stopped(tid, received) => {
+                    if self.threads.iter().all(|thread| thread.tid != tid) {
+                        // A new thread's first stop, which beat its parent's clone event here
+                        self.early_threads.push(tid);
+                        continue;
+                    }
+                    if received == signal::Signal::SIGSTOP {
+                        let thread = self.thread_mut(tid).filter(|thread| thread.stop_sent);
+                        if let Some(thread) = thread {
+                            // Only sent to stop it before it made the stop it had pending
+                            thread.stop_sent = false;
+                            restart(tid, step, None)?;
+                            continue;
+                        }
+                    }
+                    if received != signal::Signal::SIGTRAP {
+                        let policy = signals.policy(received);
+                        let pending = if policy.pass { Some(received) } else { None };
+                        if !policy.stop {
+                            if policy.print {
+                                println!(
+                                    "\nProgram received signal {}, {}.",
+                                    received.as_ref(),
+                                    describe_signal(received)
+                                );
+                            }
+                            restart(tid, step, pending)?;
+                            continue;
+                        }
+                        if let Some(thread) = self.thread_mut(tid) {
+                            thread.pending_signal = pending;
+                        }
+                    }
+                    if tid != self.current {
+                        self.current = tid;
+                        println!(
+                            "[Switching to thread {} (LWP {})]",
+                            self.current_thread(),
+                            tid
+                        );
+                    }
+                    if !step {
+                        self.stop_other_threads(signals)?;
+                    }
+                    let regs = ptrace::getregs(tid)?;
+                    return Ok(Status::Stopped(received, regs.rip as usize));
+                }
+                _ => {}
             }
         }
+    }
 
+    /// Lets every thread run, with the signals they are to be given. Threads with a stop still to
+    /// report stay where they are.
+    fn resume_all_threads(&mut self) -> Result<(), nix::Error> {
+        for thread in self.threads.iter_mut() {
+            if thread.pending_status.is_some() {
+                continue;
+            }
+            match ptrace::cont(thread.tid, thread.pending_signal.take()) {
+                // The threads resumed before this one may have ended the whole process already,
+                // in which case its exit turns up with the others
+                Err(nix::Error::Sys(Errno::ESRCH)) => {}
+                result => result?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Leaves thread `tid` in the stop it made instead of the one our SIGSTOP would have made.
+    fn keep_pending(&mut self, tid: Pid, status: WaitStatus) {
+        if let Some(thread) = self.thread_mut(tid) {
+            thread.pending_status = Some(status);
+            thread.stop_sent = true;
+        }
+    }
+
+    /// Throws away the SIGSTOPs we sent that have yet to turn up (as sending SIGCONT does), before
+    /// we let go of the process and they would stop it for good.
+    fn cancel_sent_stops(&self) -> Result<(), nix::Error> {
+        if self.threads.iter().any(|thread| thread.stop_sent) {
+            signal::kill(self.pid, signal::Signal::SIGCONT)?;
+        }
+        Ok(())
+    }
+
+    /// Takes the stop a thread made while we were stopping it, for `resume` to carry on from as
+    /// though it had just waited for it. When only the current thread is being stepped, only its
+    /// own stop will do.
+    fn take_pending_status(&mut self, step: bool) -> Option<WaitStatus> {
+        let current = self.current;
+        self.threads
+            .iter_mut()
+            .filter(|thread| !step || thread.tid == current)
+            .find_map(|thread| thread.pending_status.take())
+    }
+
+    /// Stops every thread but the current one, after the current one has stopped. A thread that
+    /// hits a breakpoint before our SIGSTOP gets to it is backed up onto the INT3, so that it
+    /// hits it again once resumed; a signal it receives instead is kept to be delivered later.
+    /// A watchpoint hit, fork or exec can't be done over like that, so the thread is left in
+    /// that stop, which is reported the next time the inferior is resumed.
+    fn stop_other_threads(&mut self, signals: &SignalTable) -> Result<(), nix::Error> {
+        // Threads still holding on to a stop were never resumed
+        let others: Vec<(Pid, bool)> = self
+            .threads
+            .iter()
+            .filter(|thread| thread.tid != self.current && thread.pending_status.is_none())
+            .map(|thread| (thread.tid, thread.stop_sent))
+            .collect();
+        for (tid, stop_sent) in others {
+            // A SIGSTOP sent before may already be on its way, and another would only stop the
+            // thread again later
+            let sent = if stop_sent {
+                0
+            } else {
+                unsafe {
+                    libc::syscall(
+                        libc::SYS_tgkill,
+                        libc::pid_t::from(self.pid),
+                        libc::pid_t::from(tid),
+                        libc::SIGSTOP,
+                    )
+                }
+            };
+            if sent != 0 {
+                // Already gone; its exit will turn up later
+                continue;
+            }
+            loop {
+                match waitpid(tid, Some(WaitPidFlag::__WALL)) {
+                    Ok(WaitStatus::Stopped(_, signal::Signal::SIGSTOP)) => {
+                        if let Some(thread) = self.thread_mut(tid) {
+                            thread.stop_sent = false;
+                        }
+                        break;
+                    }
+                    Ok(status @ WaitStatus::Stopped(_, signal::Signal::SIGTRAP))
+                        if self.watchpoint_fired(tid)? =>
+                    {
+                        self.keep_pending(tid, status);
+                        break;
+                    }
+                    Ok(WaitStatus::Stopped(_, signal::Signal::SIGTRAP)) => {
+                        let mut regs = ptrace::getregs(tid)?;
+                        if self
+                            .breakpoints
+                            .contains_key(&(regs.rip as usize).wrapping_sub(1))
+                        {
+                            regs.rip -= 1;
+                            ptrace::setregs(tid, regs)?;
+                        }
+                        ptrace::cont(tid, None)?;
+                    }
+                    Ok(WaitStatus::Stopped(_, received)) => {
+                        if signals.policy(received).pass {
+                            if let Some(thread) = self.thread_mut(tid) {
+                                thread.pending_signal = Some(received);
+                            }
+                        }
+                        ptrace::cont(tid, None)?;
+                    }
+                    Ok(WaitStatus::PtraceEvent(_, _, libc::PTRACE_EVENT_CLONE)) => {
+                        // The new thread is left stopped, which is just what we want
+                        self.add_thread(tid)?;
+                        ptrace::cont(tid, None)?;
+                    }
+                    Ok(status @ WaitStatus::PtraceEvent(_, _, event))
+                        if event == libc::PTRACE_EVENT_FORK
+                            || event == libc::PTRACE_EVENT_VFORK
+                            || event == libc::PTRACE_EVENT_EXEC =>
+                    {
+                        self.keep_pending(tid, status);
+                        break;
+                    }
+                    Ok(WaitStatus::PtraceEvent(_, _, _)) => ptrace::cont(tid, None)?,
+                    Ok(WaitStatus::Exited(_, _)) | Ok(WaitStatus::Signaled(_, _, _)) | Err(_) => {
+                        self.remove_thread(tid);
+                        break;
+                    }
+                    Ok(_) => {}
+                }
+            }
+        }
         Ok(())
     }
 
-    pub fn continues(&self) -> Result<Status, nix::Error> {
-        ptrace::cont(self.pid(), None)?;
-        self.wait(None)
+    /// Collects the exit statuses of every thread after the process has been killed, which has
+    /// to happen before the kernel reports the exit of the process as a whole.
+    fn reap(&self) {
+        loop {
+            match waitpid(Pid::from_raw(-1), Some(WaitPidFlag::__WALL)) {
+                Ok(WaitStatus::Exited(tid, _)) | Ok(WaitStatus::Signaled(tid, _, _))
+                    if tid == self.pid =>
+                {
+                    return
+                }
+                Err(_) => return,
+                Ok(_) => {}
+            }
+        }
+    }
+
+    /// Chooses the signal delivered when the current thread is next resumed (`signal`),
+    /// replacing the one it stopped with.
+    pub fn set_pending_signal(&mut self, signal: Option<signal::Signal>) {
+        let tid = self.tid();
+        if let Some(thread) = self.thread_mut(tid) {
+            thread.pending_signal = signal;
+        }
+    }
+
+    /// Executes exactly one instruction. If the inferior is sitting on a breakpoint, the original
+    /// instruction under it is executed and the INT3 is put back afterwards so the breakpoint
+    /// fires next time around.
+    fn single_step(&mut self, signals: &SignalTable) -> Result<Status, nix::Error> {
+        let rip = ptrace::getregs(self.tid())?.rip as usize;
+        let breakpoint = self.breakpoints.get(&rip).cloned();
+        if let Some(breakpoint) = &breakpoint {
+            self.write_byte(breakpoint.addr, breakpoint.orig_byte)?;
+        }
+        let status = self.resume(true, signals)?;
+        if let (Some(breakpoint), Status::Stopped(_, _)) = (&breakpoint, &status) {
+            self.write_byte(breakpoint.addr, 0xcc)?;
+        }
+        Ok(status)
+    }
+
+    /// Having just single-stepped the instruction at `old_rip`, checks whether it was a call. A
+    /// call pushes a return address pointing just past itself (x86 instructions are at most 15
+    /// bytes long) and jumps somewhere else. Returns the return address if so.
+    fn call_return_address(
+        &self,
+        old_rip: usize,
+        old_rsp: usize,
+    ) -> Result<Option<usize>, nix::Error> {
+        let regs = ptrace::getregs(self.tid())?;
+        if regs.rsp as usize != old_rsp - 8 {
+            return Ok(None);
+        }
+        let ret = ptrace::read(self.tid(), regs.rsp as ptrace::AddressType)? as usize;
+        if ret > old_rip && ret <= old_rip + 15 && regs.rip as usize != ret {
+            Ok(Some(ret))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Finds where frame `level` returns to. Returns the return address together with the stack
+    /// pointer the caller will have once the `ret` has executed.
+    fn return_address(
+        &self,
+        debug_data: &DwarfData,
+        level: usize,
+    ) -> Result<(usize, usize), nix::Error> {
+        let frames = self.frames(debug_data, Some(level + 2))?;
+        match (
+            frames.get(level).and_then(|frame| frame.cfa),
+            frames.get(level + 1),
+        ) {
+            (Some(cfa), Some(caller)) => Ok((caller.pc, cfa)),
+            _ => Err(nix::Error::UnsupportedOperation),
+        }
+    }
+
+    /// Runs until execution reaches `addr` with the stack pointer at or above `sp`, using a
+    /// temporary breakpoint. Checking the stack pointer keeps recursive calls that pass through
+    /// `addr` in deeper frames from ending the run early. User breakpoints hit along the way still
+    /// stop the inferior.
+    fn run_until(
+        &mut self,
+        addr: usize,
+        sp: usize,
+        breakpoints: &mut BreakpointTable,
+        debug_data: &DwarfData,
+        signals: &SignalTable,
+    ) -> Result<Status, nix::Error> {
+        let temporary = !self.breakpoints.contains_key(&addr);
+        if temporary {
+            self.insert_breakpoint(addr)?;
+        }
+        let result = loop {
+            match self.continue_until(breakpoints, debug_data, signals, Some(addr)) {
+                Ok(Status::Stopped(signal::Signal::SIGTRAP, rip)) if rip == addr => {
+                    match ptrace::getregs(self.tid()) {
+                        Ok(regs) if (regs.rsp as usize) < sp => continue,
+                        Ok(_) => break Ok(Status::Stopped(signal::Signal::SIGTRAP, rip)),
+                        Err(e) => break Err(e),
+                    }
+                }
+                other => break other,
+            }
+        };
+        if temporary {
+            // The inferior may have exited in the meantime, in which case there is nothing to undo
+            let _ = self.remove_breakpoint(addr);
+        }
+        result
+    }
+
+    /// Executes a single instruction (`stepi`). With `step_over_calls` (`nexti`), a call is run to
+    /// completion rather than stepped into.
+    pub fn step_instruction(
+        &mut self,
+        step_over_calls: bool,
+        breakpoints: &mut BreakpointTable,
+        debug_data: &DwarfData,
+        signals: &SignalTable,
+    ) -> Result<Status, nix::Error> {
+        let regs = ptrace::getregs(self.tid())?;
+        let status = self.single_step(signals)?;
+        if !step_over_calls {
+            return Ok(status);
+        }
+        if let Status::Stopped(signal::Signal::SIGTRAP, _) = status {
+            if let Some(ret) = self.call_return_address(regs.rip as usize, regs.rsp as usize)? {
+                return self.run_until(ret, regs.rsp as usize, breakpoints, debug_data, signals);
+            }
+        }
+        Ok(status)
+    }
+
+    /// Steps until execution reaches a different source line (`step`). Calls into functions with
+    /// debug info are entered and we stop once their prologue has run; with `step_over_calls`
+    /// (`next`), or for functions without line information such as libc routines, the call is
+    /// run to completion with a temporary breakpoint on the return address.
+    pub fn step_line(
+        &mut self,
+        step_over_calls: bool,
+        breakpoints: &mut BreakpointTable,
+        debug_data: &DwarfData,
+        signals: &SignalTable,
+    ) -> Result<Status, nix::Error> {
+        let start_rip = ptrace::getregs(self.tid())?.rip as usize;
+        let start_line = match debug_data.get_line_from_addr(start_rip) {
+            Some(line) => line,
+            // Without line information there is no notion of "the next line"
+            None => {
+                return self.step_instruction(step_over_calls, breakpoints, debug_data, signals)
+            }
+        };
+        let start_function = debug_data
+            .get_function_containing(start_rip)
+            .map(|func| func.address);
+        loop {
+            let regs = ptrace::getregs(self.tid())?;
+            let mut status = self.single_step(signals)?;
+            let mut rip = match status {
+                Status::Stopped(signal::Signal::SIGTRAP, rip) => rip,
+                other => return Ok(other),
+            };
+            if let Some(ret) = self.call_return_address(regs.rip as usize, regs.rsp as usize)? {
+                let has_line_info = debug_data.get_line_from_addr(rip).is_some();
+                if step_over_calls || !has_line_info {
+                    status =
+                        self.run_until(ret, regs.rsp as usize, breakpoints, debug_data, signals)?;
+                    match status {
+                        Status::Stopped(signal::Signal::SIGTRAP, addr) if addr == ret => rip = ret,
+                        other => return Ok(other),
+                    }
+                } else {
+                    // Stepped into a function we have source for; stop after its prologue
+                    return match debug_data.get_addr_after_prologue(rip) {
+                        Some(body) => self.run_until(body, 0, breakpoints, debug_data, signals),
+                        None => Ok(status),
+                    };
+                }
+            }
+            match debug_data.get_line_from_addr(rip) {
+                // Returned into code without line information (e.g. from main into libc), so
+                // there is no next line to stop at
+                None => return self.continues(breakpoints, debug_data, signals),
+                Some(line) => {
+                    let same_line =
+                        line.file == start_line.file && line.number == start_line.number;
+                    let function = debug_data
+                        .get_function_containing(rip)
+                        .map(|func| func.address);
+                    // Stop at the start of a new line, or as soon as we have returned to the
+                    // caller (which is usually in the middle of the line containing the call)
+                    if !same_line && (debug_data.is_line_start(rip) || function != start_function) {
+                        return Ok(status);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Runs until frame `level` returns (`finish`). The caller can read the return value out of
+    /// rax afterwards.
+    pub fn finish(
+        &mut self,
+        breakpoints: &mut BreakpointTable,
+        debug_data: &DwarfData,
+        signals: &SignalTable,
+        level: usize,
+    ) -> Result<Status, nix::Error> {
+        let (ret, sp) = self.return_address(debug_data, level)?;
+        self.run_until(ret, sp, breakpoints, debug_data, signals)
+    }
+
+    /// Resumes the inferior and waits for it to stop again. When the inferior stops on one of our
+    /// breakpoints, rip is rewound onto the breakpoint address, so the returned status points at
+    /// the instruction that is about to execute. The INT3 stays in place for any other thread that
+    /// gets there, and is stepped over when the thread that hit it resumes. Breakpoint hits
+    /// are reported to `breakpoints`, which decides (based on conditions and ignore counts)
+    /// whether we stop or silently resume.
+    pub fn continues(
+        &mut self,
+        breakpoints: &mut BreakpointTable,
+        debug_data: &DwarfData,
+        signals: &SignalTable,
+    ) -> Result<Status, nix::Error> {
+        self.continue_until(breakpoints, debug_data, signals, None)
+    }
+
+    /// Like `continues`, but always stops at the (internal) breakpoint at `target`, regardless of
+    /// what the breakpoint table thinks about that address.
+    fn continue_until(
+        &mut self,
+        breakpoints: &mut BreakpointTable,
+        debug_data: &DwarfData,
+        signals: &SignalTable,
+        target: Option<usize>,
+    ) -> Result<Status, nix::Error> {
+        // Forget about accesses made while stepping (`step`, `stepi`), which aren't reported
+        self.triggered_watchpoints()?;
+        if breakpoints.has_software_watchpoints() {
+            return self.step_until_watchpoint(breakpoints, debug_data, signals, target);
+        }
+        loop {
+            let rip = ptrace::getregs(self.tid())?.rip as usize;
+            if self.breakpoints.contains_key(&rip) {
+                let status = self.single_step(signals)?;
+                match status {
+                    Status::Stopped(signal::Signal::SIGTRAP, _) => {}
+                    other => return Ok(other),
+                }
+                if let Some(true) = self.check_watchpoints(breakpoints)? {
+                    return Ok(status);
+                }
+            }
+            let status = self.resume(false, signals)?;
+            if let Status::Stopped(signal::Signal::SIGTRAP, rip) = status {
+                // Watchpoints trap after the access, so unlike breakpoints there is no rip to
+                // rewind
+                match self.check_watchpoints(breakpoints)? {
+                    Some(true) => return Ok(status),
+                    Some(false) => continue,
+                    None => {}
+                }
+                if let Some(breakpoint) = self.breakpoints.get(&rip.wrapping_sub(1)).cloned() {
+                    let mut regs = ptrace::getregs(self.tid())?;
+                    regs.rip = breakpoint.addr as u64;
+                    ptrace::setregs(self.tid(), regs)?;
+                    let internal = !breakpoints.is_enabled_at(breakpoint.addr);
+                    if internal && self.is_library_event(breakpoint.addr) {
+                        self.libraries_changed(breakpoints);
+                        if target != Some(breakpoint.addr) {
+                            continue;
+                        }
+                    }
+                    let user_stop = breakpoints.should_stop(breakpoint.addr, self, debug_data);
+                    if !user_stop && target != Some(breakpoint.addr) {
+                        continue;
+                    }
+                    return Ok(Status::Stopped(signal::Signal::SIGTRAP, breakpoint.addr));
+                }
+            }
+            return Ok(status);
+        }
+    }
+
+    /// Checks whether a SIGTRAP came from the debug registers. Returns None if no hardware
+    /// watchpoint fired, and otherwise whether `breakpoints` wants the inferior to stay stopped.
+    fn check_watchpoints(
+        &mut self,
+        breakpoints: &mut BreakpointTable,
+    ) -> Result<Option<bool>, nix::Error> {
+        let triggered = self.triggered_watchpoints()?;
+        if triggered.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(
+            breakpoints.hardware_watchpoints_triggered(&triggered, self),
+        ))
+    }
+
+    /// Software watchpoints have to look at memory after every instruction, so instead of
+    /// letting the inferior run, single-step it until a watched value changes or a breakpoint is
+    /// reached.
+    fn step_until_watchpoint(
+        &mut self,
+        breakpoints: &mut BreakpointTable,
+        debug_data: &DwarfData,
+        signals: &SignalTable,
+        target: Option<usize>,
+    ) -> Result<Status, nix::Error> {
+        loop {
+            let status = self.single_step(signals)?;
+            let rip = match status {
+                Status::Stopped(signal::Signal::SIGTRAP, rip) => rip,
+                other => return Ok(other),
+            };
+            let hardware_stop = self.check_watchpoints(breakpoints)? == Some(true);
+            if breakpoints.software_watchpoints_changed(self) || hardware_stop {
+                return Ok(status);
+            }
+            // Single-stepping stops before the INT3 at rip runs, so check for breakpoints here
+            if self.is_library_event(rip) && !breakpoints.is_enabled_at(rip) {
+                self.libraries_changed(breakpoints);
+                if target != Some(rip) {
+                    continue;
+                }
+            }
+            if self.breakpoints.contains_key(&rip)
+                && (breakpoints.should_stop(rip, self, debug_data) || target == Some(rip))
+            {
+                return Ok(status);
+            }
+        }
     }
 
     /// Returns the pid of this inferior.
     pub fn pid(&self) -> Pid {
-        nix::unistd::Pid::from_raw(self.child.id() as i32)
+        self.pid
     }
 
-    /// Calls waitpid on this inferior and returns a Status to indicate the state of the process
-    /// after the waitpid call.
-    pub fn wait(&self, options: Option<WaitPidFlag>) -> Result<Status, nix::Error> {
-        Ok(match waitpid(self.pid(), options)? {
+    /// Returns the thread id of the current thread, which register access and stepping apply to.
+    pub fn tid(&self) -> Pid {
+        self.current
+    }
+
+    /// Calls waitpid on the current thread and returns a Status to indicate its state after the
+    /// waitpid call. This is only good for the first stop of a new inferior; `resume` does the
+    /// waiting after that.
+    fn wait(&self, options: Option<WaitPidFlag>) -> Result<Status, nix::Error> {
+        Ok(match waitpid(self.tid(), options)? {
             WaitStatus::Exited(_pid, exit_code) => Status::Exited(exit_code),
             WaitStatus::Signaled(_pid, signal, _core_dumped) => Status::Signaled(signal),
             WaitStatus::Stopped(_pid, signal) => {
-                let regs = ptrace::getregs(self.pid())?;
+                let regs = ptrace::getregs(self.tid())?;
                 Status::Stopped(signal, regs.rip as usize)
             }
-            other => panic!("waitpid returned unexpected status: {:?}", other),
+            // Thread and fork events can't happen before we have asked for them
+            _ => return Err(nix::Error::UnsupportedOperation),
         })
     }
 }
+
+impl Target for Inferior {
+    /// Reads `len` bytes of the inferior's memory starting at `addr`. Bytes that we have patched
+    /// with INT3 are reported with their original values.
+    fn read_memory(&self, addr: usize, len: usize) -> Result<Vec<u8>, nix::Error> {
+        let end = memory_end(addr, len)?;
+        // One read of /proc/<pid>/mem beats a PTRACE_PEEKDATA per word for anything but tiny
+        // reads; fall back to peeking if the file can't be read (e.g. /proc isn't mounted)
+        let mut bytes = if len > size_of::<usize>() {
+            match self.read_proc_mem(addr, len) {
+                Ok(bytes) => bytes,
+                Err(_) => self.peek_memory(addr, len)?,
+            }
+        } else {
+            self.peek_memory(addr, len)?
+        };
+        for breakpoint in self.breakpoints.values() {
+            if breakpoint.addr >= addr && breakpoint.addr < end {
+                bytes[breakpoint.addr - addr] = breakpoint.orig_byte;
+            }
+        }
+        Ok(bytes)
+    }
+
+    fn get_registers(&self) -> Result<libc::user_regs_struct, nix::Error> {
+        ptrace::getregs(self.tid())
+    }
+
+    fn get_fp_registers(&self) -> Result<libc::user_fpregs_struct, nix::Error> {
+        get_fp_registers(self.tid())
+    }
+
+    fn auxv(&self) -> Option<Vec<u8>> {
+        fs::read(format!("/proc/{}/auxv", self.pid)).ok()
+    }
+
+    fn mapped_file(&self, addr: usize) -> Option<(String, u64)> {
+        solib::mapped_file(self.pid, addr)
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Mutex, MutexGuard};
+
+    /// Held by tests that run an inferior: `resume` waits for any child at all, so two inferiors
+    /// in one test process would pick up each other's events.
+    static TRACING: Mutex<()> = Mutex::new(());
+
+    pub fn lock_tracing() -> MutexGuard<'static, ()> {
+        TRACING.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// A program built from one of the samples, removed again when the test is done with it.
+    /// Derefs to its path.
+    pub struct Sample(String);
+
+    impl std::ops::Deref for Sample {
+        type Target = str;
+
+        fn deref(&self) -> &str {
+            &self.0
+        }
+    }
+
+    impl Drop for Sample {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    /// Compiles samples/<name>.c the way the Makefile does, except not as a position-independent
+    /// executable, so that the code runs at the addresses in its debug info.
+    pub fn build_sample(name: &str) -> Sample {
+        static BUILT: AtomicUsize = AtomicUsize::new(0);
+        let program = std::env::temp_dir().join(format!(
+            "deet-{}-{}-{}",
+            name,
+            std::process::id(),
+            BUILT.fetch_add(1, Ordering::SeqCst)
+        ));
+        let program = program.to_str().unwrap().to_string();
+        let source = format!("{}/samples/{}.c", env!("CARGO_MANIFEST_DIR"), name);
+        let status = Command::new("cc")
+            .args([
+                "-O0",
+                "-g",
+                "-fno-omit-frame-pointer",
+                "-pthread",
+                "-no-pie",
+                "-o",
+                &program,
+                &source,
+            ])
+            .status()
+            .unwrap();
+        assert!(status.success());
+        Sample(program)
+    }
+
+    #[test]
+    fn test_debug_register_ranges() {
+        assert_eq!(debug_register_ranges(0x1000, 8), vec![(0x1000, 8)]);
+        assert_eq!(debug_register_ranges(0x1004, 4), vec![(0x1004, 4)]);
+        assert_eq!(
+            debug_register_ranges(0x1003, 6),
+            vec![(0x1003, 1), (0x1004, 4), (0x1008, 1)]
+        );
+        assert_eq!(
+            debug_register_ranges(0x1000, 20),
+            vec![(0x1000, 8), (0x1008, 8), (0x1010, 4)]
+        );
+    }
+
+    #[test]
+    fn test_step_and_finish() {
+        let _tracing = lock_tracing();
+        let program = build_sample("add");
+        // Not a PIE, so the addresses in the debug info are the ones the program runs at
+        let debug_data = DwarfData::from_file(&program).unwrap();
+        let mut breakpoints = BreakpointTable::new();
+        let signals = SignalTable::new();
+        let mut inferior = Inferior::new(&program, &Vec::new()).unwrap();
+        let main = debug_data.get_addr_for_function(None, "main").unwrap();
+        let main_body = debug_data.get_addr_after_prologue(main).unwrap();
+        breakpoints.add(main_body, "main".to_string(), "main".to_string());
+        inferior.insert_breakpoint(main_body).unwrap();
+        let line = |status: Result<Status, nix::Error>| match status.unwrap() {
+            Status::Stopped(signal::Signal::SIGTRAP, addr) => {
+                debug_data.get_line_from_addr(addr).unwrap().number
+            }
+            _ => panic!("the program didn't stop"),
+        };
+
+        let status = inferior.continues(&mut breakpoints, &debug_data, &signals);
+        assert_eq!(line(status), 9);
+        // Into add, past its prologue
+        let status = inferior.step_line(false, &mut breakpoints, &debug_data, &signals);
+        assert_eq!(line(status), 4);
+        let status = inferior.step_line(true, &mut breakpoints, &debug_data, &signals);
+        assert_eq!(line(status), 5);
+        // Back in the middle of the line that called it, with the sum in rax
+        let status = inferior.finish(&mut breakpoints, &debug_data, &signals, 0);
+        assert_eq!(line(status), 9);
+        assert_eq!(inferior.get_registers().unwrap().rax, 5);
+        // Over printf rather than into it
+        let status = inferior.step_line(true, &mut breakpoints, &debug_data, &signals);
+        assert_eq!(line(status), 10);
+        let status = inferior.step_line(true, &mut breakpoints, &debug_data, &signals);
+        assert_eq!(line(status), 11);
+        match inferior.continues(&mut breakpoints, &debug_data, &signals) {
+            Ok(Status::Exited(0)) => {}
+            _ => panic!("the program didn't exit"),
+        }
+    }
+}
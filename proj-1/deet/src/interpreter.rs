@@ -0,0 +1,185 @@
+//! `deet --interpreter=json`: a line-oriented protocol for editors and other programs driving the
+//! debugger, in the spirit of gdb's MI.
+//!
+//! Each line of input is a JSON object holding a command in the usual syntax and an optional id,
+//! e.g. `{"id": 3, "command": "break main"}`. Each command gets exactly one result record,
+//! `{"type": "result", "id": 3, "class": "done", ...}` with whatever the command found out (the
+//! breakpoint it set, the frames of a backtrace, the value of an expression), or `"class":
+//! "error"` with a `"message"`. Things that happen to the program come as notifications, most
+//! importantly `{"type": "notify", "event": "stopped", "reason": ..., "thread": ..., "frame":
+//! ...}`, ahead of the result of the command that ran it.
+//!
+//! A `define` or `commands` block is sent as a single command, its lines separated by newlines.
+//!
+//! Records are the only thing written to stdout. The human-readable output of each command, and
+//! whatever the program itself prints, goes to stderr instead.
+
+use nix::unistd::{dup, dup2};
+use serde_json::{Map, Value};
+use std::fs::File;
+use std::io::{self, Write};
+use std::os::unix::io::FromRawFd;
+
+pub struct JsonInterpreter {
+    /// Where the records go: the original stdout
+    out: Box<dyn Write>,
+    /// The id of the command being carried out, echoed back in its result. None between
+    /// commands.
+    id: Option<Value>,
+    /// What the command being carried out has to say for itself
+    fields: Map<String, Value>,
+    /// Why the command being carried out failed, if it did
+    error: Option<String>,
+}
+
+/// Claims stdout for a protocol, returning a handle on it and pointing file descriptor 1 at stderr
+/// instead, so that anything else printed there (by us, or by a program we start) stays out of
+/// the way.
+pub fn take_stdout() -> Result<File, nix::Error> {
+    let out = dup(libc::STDOUT_FILENO)?;
+    dup2(libc::STDERR_FILENO, libc::STDOUT_FILENO)?;
+    Ok(unsafe { File::from_raw_fd(out) })
+}
+
+/// Parses a line of input into the id and the text of a command.
+fn parse_request(line: &str) -> Result<(Value, String), String> {
+    let request: Value =
+        serde_json::from_str(line).map_err(|e| format!("Invalid JSON in request: {}", e))?;
+    let command = request
+        .get("command")
+        .and_then(Value::as_str)
+        .ok_or_else(|| "Request is missing a \"command\" string.".to_string())?;
+    let id = request.get("id").cloned().unwrap_or(Value::Null);
+    Ok((id, command.to_string()))
+}
+
+/// Makes a record of the given type out of `fields`. The type and the fields that say what the
+/// record is about (the id and class of a result, the event of a notification) come first.
+fn make_record(kind: &str, head: Vec<(&str, Value)>, fields: Map<String, Value>) -> Value {
+    let mut record = Map::new();
+    record.insert("type".to_string(), Value::from(kind));
+    for (key, value) in head {
+        record.insert(key.to_string(), value);
+    }
+    record.extend(fields);
+    Value::Object(record)
+}
+
+impl JsonInterpreter {
+    /// Takes over stdout for the records.
+    pub fn new() -> Result<JsonInterpreter, nix::Error> {
+        Ok(JsonInterpreter::with_output(Box::new(take_stdout()?)))
+    }
+
+    /// Writes the records to `out`.
+    pub fn with_output(out: Box<dyn Write>) -> JsonInterpreter {
+        JsonInterpreter {
+            out,
+            id: None,
+            fields: Map::new(),
+            error: None,
+        }
+    }
+
+    /// Waits for the next command and returns its text, or None at the end of the input. Lines
+    /// that aren't proper requests are answered with an error straight away.
+    pub fn read_command(&mut self) -> Option<String> {
+        loop {
+            let mut line = String::new();
+            match io::stdin().read_line(&mut line) {
+                Ok(0) | Err(_) => return None,
+                Ok(_) if line.trim().is_empty() => continue,
+                Ok(_) => {}
+            }
+            match parse_request(&line) {
+                Ok((id, command)) => {
+                    self.begin(id);
+                    return Some(command);
+                }
+                Err(err) => {
+                    self.begin(Value::Null);
+                    self.fail(&err);
+                    self.finish();
+                }
+            }
+        }
+    }
+
+    /// Starts on the command with the given id, whose result `finish` sends.
+    pub fn begin(&mut self, id: Value) {
+        self.id = Some(id);
+        self.fields.clear();
+        self.error = None;
+    }
+
+    /// Adds a field to the result of the command being carried out.
+    pub fn set(&mut self, key: &str, value: Value) {
+        self.fields.insert(key.to_string(), value);
+    }
+
+    /// Marks the command being carried out as failed. The first error is the one reported.
+    pub fn fail(&mut self, message: &str) {
+        if self.error.is_none() {
+            self.error = Some(message.to_string());
+        }
+    }
+
+    /// Sends a notification.
+    pub fn notify(&mut self, event: &str, fields: Map<String, Value>) {
+        let record = make_record("notify", vec![("event", Value::from(event))], fields);
+        self.emit(&record);
+    }
+
+    /// Sends the result of the command that was carried out, if there was one.
+    pub fn finish(&mut self) {
+        let id = match self.id.take() {
+            Some(id) => id,
+            None => return,
+        };
+        let mut fields = std::mem::take(&mut self.fields);
+        let class = match self.error.take() {
+            Some(message) => {
+                fields.insert("message".to_string(), Value::from(message));
+                "error"
+            }
+            None => "done",
+        };
+        let head = vec![("id", id), ("class", Value::from(class))];
+        let record = make_record("result", head, fields);
+        self.emit(&record);
+    }
+
+    fn emit(&mut self, record: &Value) {
+        // If the other end has gone away, there is no one to complain to
+        let _ = writeln!(self.out, "{}", record);
+        let _ = self.out.flush();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_records() {
+        assert_eq!(
+            parse_request(r#"{"id": 7, "command": "break main"}"#),
+            Ok((json!(7), "break main".to_string()))
+        );
+        assert_eq!(
+            parse_request(r#"{"command": "bt"}"#),
+            Ok((Value::Null, "bt".to_string()))
+        );
+        assert!(parse_request(r#"{"id": 7}"#).is_err());
+        assert!(parse_request("break main").is_err());
+
+        let mut fields = Map::new();
+        fields.insert("reason".to_string(), json!("breakpoint-hit"));
+        let record = make_record("notify", vec![("event", json!("stopped"))], fields);
+        assert_eq!(
+            record.to_string(),
+            r#"{"type":"notify","event":"stopped","reason":"breakpoint-hit"}"#
+        );
+    }
+}
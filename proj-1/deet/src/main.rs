@@ -1,24 +1,142 @@
+mod breakpoint;
+mod completion;
+mod coredump;
+mod dap;
 mod debugger;
 mod debugger_command;
+mod disasm;
 mod dwarf_data;
+mod expr;
+mod gdbserver;
 mod gimli_wrapper;
 mod inferior;
+mod interpreter;
+mod registers;
+mod script;
+mod signals;
+mod solib;
+mod source;
+mod target;
+mod unwind;
+mod value;
 
 use crate::debugger::Debugger;
 use nix::sys::signal::{signal, SigHandler, Signal};
+use nix::unistd::Pid;
 use std::env;
+use std::net::TcpListener;
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        println!("Usage: {} <target program>", args[0]);
-        std::process::exit(1);
+    let mut args: Vec<String> = env::args().collect();
+    // `--interpreter=json` (spelled like gdb's --interpreter=mi) and `-x <script>` can go in front
+    // of any of the forms below
+    let mut json = false;
+    let mut scripts = Vec::new();
+    while args.len() > 1 {
+        if args[1].starts_with("--interpreter=") {
+            let interpreter = args.remove(1);
+            if interpreter != "--interpreter=json" {
+                println!(
+                    "Interpreter `{}' unrecognized",
+                    &interpreter["--interpreter=".len()..]
+                );
+                std::process::exit(1);
+            }
+            json = true;
+        } else if args[1] == "-x" && args.len() > 2 {
+            args.remove(1);
+            scripts.push(args.remove(1));
+        } else {
+            break;
+        }
     }
-    let target = &args[1];
+    if args.len() == 2 && args[1] == "--dap" {
+        if let Err(err) = dap::run() {
+            println!("{}", err);
+            std::process::exit(1);
+        }
+        std::process::exit(0);
+    }
+    if args.len() >= 4 && args[1] == "--gdbserver" {
+        serve_remote(&args[2], &args[3], &args[4..]);
+    }
+    // Either a program to run (or whose core dump to look at), or `-p <pid>` for a process to
+    // attach to
+    let pid = match args.len() {
+        2 if args[1] != "-p" => None,
+        4 if args[2] == "--core" => None,
+        3 if args[1] == "-p" => match args[2].parse() {
+            Ok(pid) => Some(Pid::from_raw(pid)),
+            Err(_) => {
+                println!("Invalid process id {}", args[2]);
+                std::process::exit(1);
+            }
+        },
+        _ => {
+            println!(
+                "Usage: {0} <target program>\n       {0} <target program> --core <corefile>\n       {0} -p <pid>\n       {0} --interpreter=json <any of the above>\n       {0} -x <script> <any of the above>\n       {0} --gdbserver [host]:<port> <target program> [args...]\n       {0} --dap",
+                args[0]
+            );
+            std::process::exit(1);
+        }
+    };
+    let target = match pid {
+        Some(pid) => match debugger::process_executable(pid) {
+            Ok(exe) => exe,
+            Err(e) => {
+                println!("Could not find the executable of process {}: {}", pid, e);
+                std::process::exit(1);
+            }
+        },
+        None => args[1].clone(),
+    };
 
     // Disable handling of ctrl+c in this process (so that ctrl+c only gets delivered to child
     // processes)
     unsafe { signal(Signal::SIGINT, SigHandler::SigIgn) }.expect("Error disabling SIGINT handling");
 
-    Debugger::new(target).run();
+    let mut debugger = Debugger::new(&target);
+    if json {
+        if let Err(err) = debugger.use_json_interpreter() {
+            println!("{}", err);
+            std::process::exit(1);
+        }
+    }
+    if !debugger.source_init_files() {
+        return;
+    }
+    if let Some(pid) = pid {
+        debugger.attach(pid);
+    } else if args.len() == 4 {
+        debugger.load_core(&args[3]);
+    }
+    for script in &scripts {
+        if !debugger.source_file(script) {
+            return;
+        }
+    }
+    debugger.run();
+}
+
+/// Runs `target` under a remote protocol server listening on `addr` ("host:port", or ":port" for
+/// every interface, as with gdbserver), and exits once the client is done with it.
+fn serve_remote(addr: &str, target: &str, args: &[String]) -> ! {
+    let addr = match addr.strip_prefix(':') {
+        Some(port) => format!("0.0.0.0:{}", port),
+        None => addr.to_string(),
+    };
+    let listener = match TcpListener::bind(&addr) {
+        Ok(listener) => listener,
+        Err(e) => {
+            println!("Can't bind address {}: {}", addr, e);
+            std::process::exit(1);
+        }
+    };
+    match gdbserver::serve(listener, target, args) {
+        Ok(()) => std::process::exit(0),
+        Err(err) => {
+            println!("{}", err);
+            std::process::exit(1);
+        }
+    }
 }
@@ -0,0 +1,301 @@
+use libc::{user_fpregs_struct, user_regs_struct};
+use std::convert::TryInto;
+
+use crate::value;
+
+/// The general-purpose registers, in the order gdb lists them.
+pub const REGISTER_NAMES: [&str; 26] = [
+    "rax", "rbx", "rcx", "rdx", "rsi", "rdi", "rbp", "rsp", "r8", "r9", "r10", "r11", "r12", "r13",
+    "r14", "r15", "rip", "eflags", "cs", "ss", "ds", "es", "fs", "gs", "fs_base", "gs_base",
+];
+
+/// Maps gdb's architecture-neutral aliases onto the x86-64 register they stand for.
+pub fn canonical_name(name: &str) -> &str {
+    match name {
+        "pc" => "rip",
+        "sp" => "rsp",
+        "fp" => "rbp",
+        other => other,
+    }
+}
+
+/// Finds the field holding a register by name (without the leading `$`).
+fn register_mut<'a>(regs: &'a mut user_regs_struct, name: &str) -> Option<&'a mut u64> {
+    Some(match canonical_name(name) {
+        "rax" => &mut regs.rax,
+        "rbx" => &mut regs.rbx,
+        "rcx" => &mut regs.rcx,
+        "rdx" => &mut regs.rdx,
+        "rsi" => &mut regs.rsi,
+        "rdi" => &mut regs.rdi,
+        "rbp" => &mut regs.rbp,
+        "rsp" => &mut regs.rsp,
+        "r8" => &mut regs.r8,
+        "r9" => &mut regs.r9,
+        "r10" => &mut regs.r10,
+        "r11" => &mut regs.r11,
+        "r12" => &mut regs.r12,
+        "r13" => &mut regs.r13,
+        "r14" => &mut regs.r14,
+        "r15" => &mut regs.r15,
+        "rip" => &mut regs.rip,
+        "eflags" => &mut regs.eflags,
+        "cs" => &mut regs.cs,
+        "ss" => &mut regs.ss,
+        "ds" => &mut regs.ds,
+        "es" => &mut regs.es,
+        "fs" => &mut regs.fs,
+        "gs" => &mut regs.gs,
+        "fs_base" => &mut regs.fs_base,
+        "gs_base" => &mut regs.gs_base,
+        "orig_rax" => &mut regs.orig_rax,
+        _ => return None,
+    })
+}
+
+/// Looks up a register by name (without the leading `$`).
+pub fn get_register(regs: &user_regs_struct, name: &str) -> Option<u64> {
+    let mut regs = *regs;
+    register_mut(&mut regs, name).map(|value| *value)
+}
+
+/// Stores into a register by name (without the leading `$`). Returns false if there is no such
+/// register.
+pub fn set_register(regs: &mut user_regs_struct, name: &str, value: u64) -> bool {
+    match register_mut(regs, name) {
+        Some(slot) => {
+            *slot = value;
+            true
+        }
+        None => false,
+    }
+}
+
+/// Returns true if `name` (without the leading `$`) names a register we know about.
+pub fn is_register(name: &str) -> bool {
+    let name = canonical_name(name);
+    name == "orig_rax" || REGISTER_NAMES.contains(&name)
+}
+
+const EFLAGS: [(u32, &str); 9] = [
+    (0, "CF"),
+    (2, "PF"),
+    (4, "AF"),
+    (6, "ZF"),
+    (7, "SF"),
+    (8, "TF"),
+    (9, "IF"),
+    (10, "DF"),
+    (11, "OF"),
+];
+
+const MXCSR_FLAGS: [(u32, &str); 14] = [
+    (0, "IE"),
+    (1, "DE"),
+    (2, "ZE"),
+    (3, "OE"),
+    (4, "UE"),
+    (5, "PE"),
+    (6, "DAZ"),
+    (7, "IM"),
+    (8, "DM"),
+    (9, "ZM"),
+    (10, "OM"),
+    (11, "UM"),
+    (12, "PM"),
+    (15, "FZ"),
+];
+
+/// Lists the flags set in a flags register, e.g. `[ PF ZF IF ]`.
+fn format_flags(value: u64, flags: &[(u32, &str)]) -> String {
+    let set: Vec<&str> = flags
+        .iter()
+        .filter(|(bit, _)| value & (1 << bit) != 0)
+        .map(|(_, name)| *name)
+        .collect();
+    if set.is_empty() {
+        "[ ]".to_string()
+    } else {
+        format!("[ {} ]", set.join(" "))
+    }
+}
+
+/// Decodes eflags the way gdb shows it.
+pub fn format_eflags(value: u64) -> String {
+    format_flags(value, &EFLAGS)
+}
+
+/// The x87 and SSE registers, in the order `info all-registers` lists them.
+pub const FP_REGISTER_NAMES: [&str; 33] = [
+    "st0", "st1", "st2", "st3", "st4", "st5", "st6", "st7", "fctrl", "fstat", "ftag", "fiseg",
+    "fioff", "foseg", "fooff", "fop", "mxcsr", "xmm0", "xmm1", "xmm2", "xmm3", "xmm4", "xmm5",
+    "xmm6", "xmm7", "xmm8", "xmm9", "xmm10", "xmm11", "xmm12", "xmm13", "xmm14", "xmm15",
+];
+
+/// Returns the 10 bytes of x87 register ST(i).
+fn st_bytes(fpregs: &user_fpregs_struct, i: usize) -> Vec<u8> {
+    // Each register occupies 16 bytes, of which the low 10 are used
+    fpregs.st_space[i * 4..i * 4 + 4]
+        .iter()
+        .flat_map(|word| word.to_le_bytes().to_vec())
+        .take(10)
+        .collect()
+}
+
+/// Converts an 80-bit extended precision float to the nearest f64.
+fn extended_to_f64(bytes: &[u8]) -> f64 {
+    let mantissa = u64::from_le_bytes(bytes[..8].try_into().unwrap());
+    let sign_exponent = u16::from_le_bytes([bytes[8], bytes[9]]);
+    let sign = if sign_exponent & 0x8000 != 0 {
+        -1.0
+    } else {
+        1.0
+    };
+    let exponent = (sign_exponent & 0x7fff) as i32;
+    if exponent == 0x7fff {
+        return if mantissa << 1 == 0 {
+            sign * f64::INFINITY
+        } else {
+            f64::NAN
+        };
+    }
+    // The top mantissa bit is explicit, so the mantissa is a fixed-point number in [0, 2)
+    let fraction = mantissa as f64 / (1u64 << 63) as f64;
+    let exponent = if exponent == 0 {
+        -16382
+    } else {
+        exponent - 16383
+    };
+    sign * fraction * 2f64.powi(exponent)
+}
+
+/// Expands the abridged tag word the kernel hands back into the full x87 tag word: two bits per
+/// physical register, 0 valid, 1 zero, 2 special and 3 empty.
+fn full_tag_word(fpregs: &user_fpregs_struct) -> u64 {
+    let top = ((fpregs.swd >> 11) & 7) as usize;
+    (0..8).fold(0, |tag, physical| {
+        let bits = if fpregs.ftw & (1 << physical) == 0 {
+            3
+        } else {
+            let bytes = st_bytes(fpregs, (physical + 8 - top) % 8);
+            let mantissa = u64::from_le_bytes(bytes[..8].try_into().unwrap());
+            let exponent = u16::from_le_bytes([bytes[8], bytes[9]]) & 0x7fff;
+            match exponent {
+                0 if mantissa == 0 => 1,
+                0 | 0x7fff => 2,
+                _ if mantissa >> 63 == 0 => 2,
+                _ => 0,
+            }
+        };
+        tag | bits << (physical * 2)
+    })
+}
+
+/// Renders an SSE register as gdb's union of vector views.
+fn format_xmm(bytes: &[u8]) -> String {
+    fn lanes(values: impl Iterator<Item = f64>, precision: usize) -> String {
+        let values: Vec<String> = values
+            .map(|value| value::format_float(value, precision))
+            .collect();
+        format!("{{{}}}", values.join(", "))
+    }
+    fn hex_lanes(bytes: &[u8], size: usize) -> String {
+        let values: Vec<String> = bytes
+            .chunks(size)
+            .map(|chunk| {
+                let mut raw = [0u8; 8];
+                raw[..size].copy_from_slice(chunk);
+                format!("{:#x}", u64::from_le_bytes(raw))
+            })
+            .collect();
+        format!("{{{}}}", values.join(", "))
+    }
+    let floats = bytes
+        .chunks(4)
+        .map(|chunk| f32::from_le_bytes(chunk.try_into().unwrap()) as f64);
+    let doubles = bytes
+        .chunks(8)
+        .map(|chunk| f64::from_le_bytes(chunk.try_into().unwrap()));
+    format!(
+        "{{v4_float = {}, v2_double = {}, v16_int8 = {}, v8_int16 = {}, v4_int32 = {}, \
+         v2_int64 = {}, uint128 = {:#x}}}",
+        lanes(floats, 9),
+        lanes(doubles, 17),
+        hex_lanes(bytes, 1),
+        hex_lanes(bytes, 2),
+        hex_lanes(bytes, 4),
+        hex_lanes(bytes, 8),
+        u128::from_le_bytes(bytes.try_into().unwrap())
+    )
+}
+
+/// Describes an x87 or SSE register for `info registers`: its raw value, then its natural
+/// rendering. Returns None if there is no such register.
+pub fn describe_fp_register(fpregs: &user_fpregs_struct, name: &str) -> Option<(String, String)> {
+    let hex = |value: u64| (format!("{:#x}", value), value.to_string());
+    Some(match name {
+        "fctrl" => hex(fpregs.cwd as u64),
+        "fstat" => hex(fpregs.swd as u64),
+        "ftag" => hex(full_tag_word(fpregs)),
+        "fiseg" => hex(fpregs.rip >> 32),
+        "fioff" => hex(fpregs.rip & 0xffff_ffff),
+        "foseg" => hex(fpregs.rdp >> 32),
+        "fooff" => hex(fpregs.rdp & 0xffff_ffff),
+        "fop" => hex(fpregs.fop as u64 & 0x7ff),
+        "mxcsr" => (
+            format!("{:#x}", fpregs.mxcsr),
+            format_flags(fpregs.mxcsr as u64, &MXCSR_FLAGS),
+        ),
+        _ => {
+            if let Some(i) = name
+                .strip_prefix("st")
+                .and_then(|i| i.parse::<usize>().ok())
+            {
+                if i >= 8 {
+                    return None;
+                }
+                let bytes = st_bytes(fpregs, i);
+                let raw: String = bytes.iter().rev().map(|b| format!("{:02x}", b)).collect();
+                (
+                    value::format_float(extended_to_f64(&bytes), 17),
+                    format!("(raw 0x{})", raw),
+                )
+            } else if let Some(i) = name
+                .strip_prefix("xmm")
+                .and_then(|i| i.parse::<usize>().ok())
+            {
+                if i >= 16 {
+                    return None;
+                }
+                let bytes: Vec<u8> = fpregs.xmm_space[i * 4..i * 4 + 4]
+                    .iter()
+                    .flat_map(|word| word.to_le_bytes().to_vec())
+                    .collect();
+                (format_xmm(&bytes), String::new())
+            } else {
+                return None;
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_format_eflags() {
+        assert_eq!(format_eflags(0x246), "[ PF ZF IF ]");
+        assert_eq!(format_eflags(0x2), "[ ]");
+    }
+
+    #[test]
+    fn test_extended_to_f64() {
+        // 1.0 and -2.5 as 80-bit extended precision
+        let one = [0, 0, 0, 0, 0, 0, 0, 0x80, 0xff, 0x3f];
+        assert_eq!(extended_to_f64(&one), 1.0);
+        let minus_two_and_a_half = [0, 0, 0, 0, 0, 0, 0, 0xa0, 0x00, 0xc0];
+        assert_eq!(extended_to_f64(&minus_two_and_a_half), -2.5);
+        assert_eq!(extended_to_f64(&[0; 10]), 0.0);
+    }
+}
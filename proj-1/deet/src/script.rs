@@ -0,0 +1,134 @@
+use std::fs;
+
+/// Commands that take a block of lines after them, up to a line saying just "end".
+const BLOCK_COMMANDS: &[&str] = &["define", "commands"];
+
+/// A sequence of command lines being carried out: a file read with `source` (or `-x`, or an init
+/// file), the body of a user-defined command, or the commands attached to a breakpoint. Commands
+/// that take a block (`define` and `commands`) read it from the script they appear in.
+pub struct Script {
+    /// Where the lines came from, for error messages
+    name: String,
+    lines: Vec<String>,
+    /// Number of lines handed out so far
+    next: usize,
+    /// Whether these are the commands of a breakpoint
+    actions: bool,
+}
+
+impl Script {
+    pub fn new(name: &str, lines: Vec<String>) -> Script {
+        Script {
+            name: name.to_string(),
+            lines,
+            next: 0,
+            actions: false,
+        }
+    }
+
+    /// The commands of the breakpoints the program stopped at.
+    pub fn actions(lines: Vec<String>) -> Script {
+        Script {
+            actions: true,
+            ..Script::new("breakpoint commands", lines)
+        }
+    }
+
+    pub fn is_actions(&self) -> bool {
+        self.actions
+    }
+
+    pub fn from_file(path: &str) -> Result<Script, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}.", path, e))?;
+        Ok(Script::new(path, text.lines().map(String::from).collect()))
+    }
+
+    /// Returns the next line, or None at the end of the script.
+    pub fn next_line(&mut self) -> Option<String> {
+        let line = self.lines.get(self.next)?.clone();
+        self.next += 1;
+        Some(line)
+    }
+
+    /// Names the line last handed out, like "cmds.deet:3".
+    pub fn location(&self) -> String {
+        format!("{}:{}", self.name, self.next)
+    }
+}
+
+/// Keeps track of the block a `define` or `commands` is reading, which can have blocks of its
+/// own inside.
+pub struct BlockReader {
+    depth: usize,
+    lines: Vec<String>,
+}
+
+impl BlockReader {
+    pub fn new() -> BlockReader {
+        BlockReader {
+            depth: 0,
+            lines: Vec::new(),
+        }
+    }
+
+    /// Adds a line of the block. Returns the whole block once this line ends it.
+    pub fn push(&mut self, line: &str) -> Option<Vec<String>> {
+        let line = line.trim();
+        match line.split_whitespace().next() {
+            Some("end") if self.depth == 0 => return Some(std::mem::take(&mut self.lines)),
+            Some("end") => self.depth -= 1,
+            Some(command) if BLOCK_COMMANDS.contains(&command) => self.depth += 1,
+            _ => {}
+        }
+        self.lines.push(line.to_string());
+        None
+    }
+}
+
+/// Whether a line has nothing to carry out: blank, or a `#` comment.
+pub fn is_blank(line: &str) -> bool {
+    let line = line.trim_start();
+    line.is_empty() || line.starts_with('#')
+}
+
+/// Fills in the arguments of a user-defined command: `$arg0` through `$argN`, and `$argc` for how
+/// many there are.
+pub fn substitute_args(line: &str, args: &[&str]) -> String {
+    let mut line = line.replace("$argc", &args.len().to_string());
+    // Highest first, so that $arg1 doesn't eat the start of $arg10
+    for (i, arg) in args.iter().enumerate().rev() {
+        line = line.replace(&format!("$arg{}", i), arg);
+    }
+    line
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_blocks() {
+        let mut reader = BlockReader::new();
+        assert_eq!(reader.push("  print $arg0"), None);
+        assert_eq!(reader.push("define inner"), None);
+        assert_eq!(reader.push("bt"), None);
+        assert_eq!(reader.push("end"), None);
+        assert_eq!(
+            reader.push("end"),
+            Some(vec![
+                "print $arg0".to_string(),
+                "define inner".to_string(),
+                "bt".to_string(),
+                "end".to_string()
+            ])
+        );
+
+        let args: Vec<&str> = "a b c d e f g h i j k".split(' ').collect();
+        assert_eq!(
+            substitute_args("print $arg0 + $arg1 + $arg10 + $argc", &args),
+            "print a + b + k + 11"
+        );
+        assert!(is_blank("   # a comment"));
+        assert!(!is_blank("bt # not a comment"));
+    }
+}
@@ -0,0 +1,154 @@
+use nix::sys::signal::Signal;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::ffi::CStr;
+
+/// What to do when the inferior receives a signal.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct SignalPolicy {
+    /// Stop the inferior and return to the prompt
+    pub stop: bool,
+    /// Say that the signal arrived
+    pub print: bool,
+    /// Deliver the signal when the inferior is resumed, instead of discarding it
+    pub pass: bool,
+}
+
+const STOP_PRINT_PASS: SignalPolicy = SignalPolicy {
+    stop: true,
+    print: true,
+    pass: true,
+};
+
+/// The `handle` settings for every signal. The defaults are gdb's: signals that programs use in
+/// the normal course of things go straight through, anything else stops the inferior.
+pub struct SignalTable {
+    policies: HashMap<Signal, SignalPolicy>,
+}
+
+impl SignalTable {
+    pub fn new() -> SignalTable {
+        let mut policies = HashMap::new();
+        for signal in Signal::iterator() {
+            let policy = match signal {
+                Signal::SIGALRM
+                | Signal::SIGURG
+                | Signal::SIGCHLD
+                | Signal::SIGWINCH
+                | Signal::SIGIO
+                | Signal::SIGVTALRM
+                | Signal::SIGPROF => SignalPolicy {
+                    stop: false,
+                    print: false,
+                    pass: true,
+                },
+                // These are the debugger's business (ctrl+c and breakpoints), not the program's.
+                // Passing SIGSTOP back would only stop the process again.
+                Signal::SIGINT | Signal::SIGTRAP | Signal::SIGSTOP => SignalPolicy {
+                    pass: false,
+                    ..STOP_PRINT_PASS
+                },
+                _ => STOP_PRINT_PASS,
+            };
+            policies.insert(signal, policy);
+        }
+        SignalTable { policies }
+    }
+
+    pub fn policy(&self, signal: Signal) -> SignalPolicy {
+        self.policies
+            .get(&signal)
+            .copied()
+            .unwrap_or(STOP_PRINT_PASS)
+    }
+
+    /// Applies one of `handle`'s keywords to a signal. As in gdb, stopping implies printing, and
+    /// not printing implies not stopping.
+    pub fn update(&mut self, signal: Signal, keyword: &str) -> Result<(), String> {
+        let mut policy = self.policy(signal);
+        match keyword {
+            "stop" => {
+                policy.stop = true;
+                policy.print = true;
+            }
+            "nostop" => policy.stop = false,
+            "print" => policy.print = true,
+            "noprint" => {
+                policy.print = false;
+                policy.stop = false;
+            }
+            "pass" | "noignore" => policy.pass = true,
+            "nopass" | "ignore" => policy.pass = false,
+            _ => {
+                return Err(format!(
+                    "Unrecognized or ambiguous flag word: \"{}\".",
+                    keyword
+                ))
+            }
+        }
+        self.policies.insert(signal, policy);
+        Ok(())
+    }
+}
+
+/// Parses a signal given by name ("SIGUSR1", in any case) or by number. The "SIG" is required
+/// so that `handle SIGSTOP stop` is unambiguous.
+pub fn parse_signal(text: &str) -> Option<Signal> {
+    match text.parse::<i32>() {
+        Ok(number) => Signal::try_from(number).ok(),
+        Err(_) => text.to_uppercase().parse().ok(),
+    }
+}
+
+/// The C library's description of a signal, e.g. "User defined signal 1".
+pub fn describe_signal(signal: Signal) -> String {
+    // strsignal returns a pointer into static storage (or a thread-local buffer for unknown
+    // signals), which we copy out of right away
+    let description = unsafe { CStr::from_ptr(libc::strsignal(signal as libc::c_int)) };
+    description.to_string_lossy().into_owned()
+}
+
+/// Formats the `info signals` table, one row per signal.
+pub fn format_signal_table(table: &SignalTable, signals: &[Signal]) -> String {
+    let yes_no = |flag: bool| if flag { "Yes" } else { "No" };
+    let mut lines = vec!["Signal        Stop\tPrint\tPass to program\tDescription".to_string()];
+    for &signal in signals {
+        let policy = table.policy(signal);
+        lines.push(format!(
+            "{:<14}{}\t{}\t{}\t\t{}",
+            signal.as_ref(),
+            yes_no(policy.stop),
+            yes_no(policy.print),
+            yes_no(policy.pass),
+            describe_signal(signal)
+        ));
+    }
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_signal() {
+        assert_eq!(parse_signal("SIGUSR1"), Some(Signal::SIGUSR1));
+        assert_eq!(parse_signal("sigusr1"), Some(Signal::SIGUSR1));
+        assert_eq!(parse_signal("stop"), None);
+        assert_eq!(parse_signal("14"), Some(Signal::SIGALRM));
+        assert_eq!(parse_signal("SIGBOGUS"), None);
+        assert_eq!(parse_signal("0"), None);
+    }
+
+    #[test]
+    fn test_update_policy() {
+        let mut table = SignalTable::new();
+        assert!(!table.policy(Signal::SIGALRM).stop);
+        table.update(Signal::SIGALRM, "stop").unwrap();
+        assert_eq!(table.policy(Signal::SIGALRM), STOP_PRINT_PASS);
+        table.update(Signal::SIGUSR1, "noprint").unwrap();
+        let policy = table.policy(Signal::SIGUSR1);
+        assert!(!policy.stop && !policy.print && policy.pass);
+        assert!(table.update(Signal::SIGUSR1, "bogus").is_err());
+    }
+}
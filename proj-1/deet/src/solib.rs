@@ -0,0 +1,187 @@
+//! Where the inferior's code is loaded. A position-independent executable is mapped some distance
+//! (its load bias) away from the addresses it was linked at, and shared libraries go wherever the
+//! dynamic linker finds room. The kernel tells us where the executable went through the auxiliary
+//! vector, and the dynamic linker keeps a list of the objects it has loaded (the link_map chain
+//! hanging off its `_r_debug` structure) for debuggers to read.
+
+use nix::unistd::Pid;
+use object::{Object, ObjectSegment};
+use std::fs;
+use std::path::Path;
+
+use crate::target::Target;
+
+/// Offset of `r_map` in `struct r_debug`, which starts with an int (padded to 8 bytes)
+const R_MAP: usize = 8;
+/// Offsets of `l_addr`, `l_name` and `l_next` in `struct link_map`
+const L_ADDR: usize = 0;
+const L_NAME: usize = 8;
+const L_NEXT: usize = 24;
+
+/// Stop walking a corrupted link_map chain eventually.
+const MAX_OBJECTS: usize = 4096;
+
+/// The parts of the dynamic linker we hook into, as run time addresses.
+#[derive(Clone, Copy, Debug)]
+pub struct DynamicLinker {
+    /// Its `struct r_debug`, which leads to the list of loaded objects
+    pub r_debug: usize,
+    /// `_dl_debug_state`, an empty function it calls whenever that list changes, for debuggers to
+    /// put a breakpoint on
+    pub event: usize,
+}
+
+/// Reads an entry of the auxiliary vector the kernel handed the program when it started.
+fn auxv_entry(target: &dyn Target, key: libc::c_ulong) -> Option<usize> {
+    find_auxv_entry(&target.auxv()?, key)
+}
+
+fn find_auxv_entry(auxv: &[u8], key: libc::c_ulong) -> Option<usize> {
+    // The vector is a list of (key, value) pairs of native words
+    auxv.chunks_exact(16).find_map(|entry| {
+        let mut word = [0; 8];
+        word.copy_from_slice(&entry[..8]);
+        if u64::from_ne_bytes(word) != key {
+            return None;
+        }
+        word.copy_from_slice(&entry[8..]);
+        Some(u64::from_ne_bytes(word) as usize)
+    })
+}
+
+/// The run time address of the executable's entry point. Comparing it to the entry point in the
+/// ELF header gives the executable's load bias.
+pub fn entry_point(target: &dyn Target) -> Option<usize> {
+    auxv_entry(target, libc::AT_ENTRY)
+}
+
+/// Finds the dynamic linker and looks up the symbols we need in it. Returns None for a statically
+/// linked program, which has no dynamic linker (and no shared libraries).
+pub fn dynamic_linker(target: &dyn Target) -> Option<DynamicLinker> {
+    let base = auxv_entry(target, libc::AT_BASE).filter(|base| *base != 0)?;
+    let (path, _) = target.mapped_file(base)?;
+    let data = fs::read(path).ok()?;
+    let object = object::File::parse(&*data).ok()?;
+    // Segments are page aligned in memory, so the first one starts at a page boundary
+    let bias = base - (object.segments().map(|s| s.address()).min()? as usize & !0xfff);
+    let symbol = |name: &str| {
+        object
+            .dynamic_symbols()
+            .find(|(_, symbol)| symbol.name() == Some(name))
+            .map(|(_, symbol)| symbol.address() as usize + bias)
+    };
+    Some(DynamicLinker {
+        r_debug: symbol("_r_debug")?,
+        event: symbol("_dl_debug_state")?,
+    })
+}
+
+fn read_word(target: &dyn Target, addr: usize) -> Option<usize> {
+    let bytes = target.read_memory(addr, 8).ok()?;
+    let mut word = [0; 8];
+    word.copy_from_slice(&bytes);
+    Some(u64::from_le_bytes(word) as usize)
+}
+
+/// Reads a NUL-terminated string out of the program's memory.
+fn read_string(target: &dyn Target, addr: usize) -> Option<String> {
+    let mut bytes = Vec::new();
+    loop {
+        let chunk = target.read_memory(addr + bytes.len(), 8).ok()?;
+        match chunk.iter().position(|&byte| byte == 0) {
+            Some(end) => {
+                bytes.extend_from_slice(&chunk[..end]);
+                return Some(String::from_utf8_lossy(&bytes).into_owned());
+            }
+            None if bytes.len() < libc::PATH_MAX as usize => bytes.extend_from_slice(&chunk),
+            None => return None,
+        }
+    }
+}
+
+/// Lists the shared libraries the dynamic linker has loaded, as (path, load bias) pairs. The
+/// executable itself (whose entry has an empty name) and the vDSO (which isn't a file) are left
+/// out. The list is empty until the dynamic linker has started up.
+pub fn loaded_libraries(target: &dyn Target, linker: &DynamicLinker) -> Vec<(String, usize)> {
+    let mut libraries = Vec::new();
+    let mut entry = read_word(target, linker.r_debug + R_MAP).unwrap_or(0);
+    while entry != 0 && libraries.len() < MAX_OBJECTS {
+        let bias = read_word(target, entry + L_ADDR);
+        let name = read_word(target, entry + L_NAME).and_then(|name| read_string(target, name));
+        if let (Some(bias), Some(name)) = (bias, name) {
+            if !name.is_empty() && Path::new(&name).is_file() {
+                libraries.push((name, bias));
+            }
+        }
+        entry = read_word(target, entry + L_NEXT).unwrap_or(0);
+    }
+    libraries
+}
+
+/// A line of /proc/<pid>/maps: `start..end` is mapped with permissions like "r-xp", from
+/// `offset` in the file at `path`. Anonymous mappings have an empty path, or a name in brackets
+/// such as "[stack]".
+pub struct Mapping {
+    pub start: usize,
+    pub end: usize,
+    pub perms: String,
+    pub offset: u64,
+    pub path: String,
+}
+
+/// Lists the memory mappings of process `pid`.
+pub fn memory_maps(pid: Pid) -> Option<Vec<Mapping>> {
+    let maps = fs::read_to_string(format!("/proc/{}/maps", pid)).ok()?;
+    // Each line reads "start-end perms offset dev inode path"
+    Some(
+        maps.lines()
+            .filter_map(|line| {
+                let fields: Vec<&str> = line.split_whitespace().collect();
+                let (start, end) = fields.get(0)?.split_at(fields[0].find('-')?);
+                Some(Mapping {
+                    start: usize::from_str_radix(start, 16).ok()?,
+                    end: usize::from_str_radix(&end[1..], 16).ok()?,
+                    perms: fields.get(1)?.to_string(),
+                    offset: u64::from_str_radix(fields.get(2)?, 16).ok()?,
+                    path: fields.get(5..).unwrap_or(&[]).join(" "),
+                })
+            })
+            .collect(),
+    )
+}
+
+/// Finds the file mapped at `addr` in process `pid`, along with the address its first page is
+/// mapped at, according to /proc/<pid>/maps.
+pub fn mapped_file(pid: Pid, addr: usize) -> Option<(String, u64)> {
+    let mappings: Vec<Mapping> = memory_maps(pid)?
+        .into_iter()
+        .filter(|mapping| mapping.path.starts_with('/'))
+        .collect();
+    let mapping = mappings
+        .iter()
+        .find(|mapping| mapping.start <= addr && addr < mapping.end)?;
+    let base = mappings
+        .iter()
+        .filter(|other| other.path == mapping.path)
+        .map(|other| other.start)
+        .min()?;
+    Some((mapping.path.clone(), base as u64))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_auxv_entries() {
+        let auxv = fs::read("/proc/self/auxv").unwrap();
+        let expected = unsafe { libc::getauxval(libc::AT_ENTRY) } as usize;
+        assert_eq!(find_auxv_entry(&auxv, libc::AT_ENTRY), Some(expected));
+        // The test binary is dynamically linked, so the dynamic linker is mapped
+        let base = find_auxv_entry(&auxv, libc::AT_BASE).unwrap();
+        let (path, start) = mapped_file(nix::unistd::getpid(), base).unwrap();
+        assert!(path.contains("ld-linux"));
+        assert_eq!(start as usize, base);
+        assert_eq!(find_auxv_entry(&auxv, 0xdead), None);
+    }
+}
@@ -0,0 +1,107 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Where to look for source files. The debugging information names each file the way the
+/// compiler was given it, which may be relative to the directory the compiler ran in (the
+/// compilation unit's DW_AT_comp_dir). When the sources have moved since, the directories added
+/// with `directory` are searched too, like gdb's source path.
+pub struct SourcePath {
+    /// Searched before the compilation directory, most recently added first
+    directories: Vec<PathBuf>,
+}
+
+impl SourcePath {
+    pub fn new() -> SourcePath {
+        SourcePath {
+            directories: Vec::new(),
+        }
+    }
+
+    /// Puts `dir` at the front of the search path (or moves it there, if it is already in it).
+    pub fn add_directory(&mut self, dir: &str) {
+        let dir = PathBuf::from(dir);
+        self.directories.retain(|other| *other != dir);
+        self.directories.insert(0, dir);
+    }
+
+    /// Forgets the directories added with `directory`.
+    pub fn reset(&mut self) {
+        self.directories.clear();
+    }
+
+    /// Describes the search path like gdb's `show directories`, with $cdir standing for the
+    /// compilation directory and $cwd for the current one.
+    pub fn describe(&self) -> String {
+        let mut dirs: Vec<String> = self
+            .directories
+            .iter()
+            .map(|dir| dir.to_string_lossy().into_owned())
+            .collect();
+        dirs.push("$cdir".to_string());
+        dirs.push("$cwd".to_string());
+        format!("Source directories searched: {}", dirs.join(":"))
+    }
+
+    /// Finds a source file. A path that exists as it is wins; otherwise we try each directory on
+    /// the search path, first with the whole (relative) path and then with just the file name,
+    /// then the compilation directory and finally the current directory.
+    pub fn find(&self, file: &str, comp_dir: Option<&str>) -> Option<PathBuf> {
+        let path = Path::new(file);
+        if path.is_absolute() && path.is_file() {
+            return Some(path.to_path_buf());
+        }
+        let relative = path.strip_prefix("/").unwrap_or(path);
+        let mut candidates = Vec::new();
+        for dir in &self.directories {
+            candidates.push(dir.join(relative));
+            if let Some(name) = path.file_name() {
+                candidates.push(dir.join(name));
+            }
+        }
+        if let Some(comp_dir) = comp_dir {
+            candidates.push(Path::new(comp_dir).join(path));
+        }
+        candidates.push(relative.to_path_buf());
+        candidates.into_iter().find(|candidate| candidate.is_file())
+    }
+
+    /// Reads the lines of a source file, or explains why we couldn't.
+    pub fn read_lines(&self, file: &str, comp_dir: Option<&str>) -> Result<Vec<String>, String> {
+        let path = self
+            .find(file, comp_dir)
+            .ok_or_else(|| format!("{}: No such file or directory.", file))?;
+        let bytes = fs::read(&path).map_err(|e| format!("{}: {}.", path.display(), e))?;
+        Ok(String::from_utf8_lossy(&bytes)
+            .lines()
+            .map(|line| line.to_string())
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_find_source() {
+        let root = std::env::temp_dir().join(format!("deet-source-{}", std::process::id()));
+        fs::create_dir_all(root.join("old/src")).unwrap();
+        fs::create_dir_all(root.join("new")).unwrap();
+        fs::write(root.join("old/src/a.c"), "int a;\n").unwrap();
+        fs::write(root.join("new/a.c"), "int b;\n").unwrap();
+        let old = root.join("old");
+        let old = old.to_str();
+
+        let mut path = SourcePath::new();
+        assert_eq!(path.find("src/a.c", old), Some(root.join("old/src/a.c")));
+        assert_eq!(path.find("src/a.c", None), None);
+        // Added directories come first, and are searched by file name too
+        path.add_directory(root.join("new").to_str().unwrap());
+        assert_eq!(path.find("src/a.c", old), Some(root.join("new/a.c")));
+        assert_eq!(path.read_lines("src/a.c", old).unwrap(), vec!["int b;"]);
+        path.reset();
+        assert_eq!(path.find("src/a.c", old), Some(root.join("old/src/a.c")));
+
+        fs::remove_dir_all(root).unwrap();
+    }
+}
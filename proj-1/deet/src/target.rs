@@ -0,0 +1,56 @@
+//! What it takes to inspect a stopped program, whether it is a live process we are tracing or the
+//! core dump a dead one left behind. Unwinding, expression evaluation and value formatting only
+//! look at the program through this trait, so they work the same way on both.
+
+use crate::dwarf_data::{DwarfData, Variable};
+use crate::unwind::{Frame, Unwinder};
+
+pub trait Target {
+    /// Reads `len` bytes of memory starting at `addr`.
+    fn read_memory(&self, addr: usize, len: usize) -> Result<Vec<u8>, nix::Error>;
+
+    /// The general-purpose registers of the current thread.
+    fn get_registers(&self) -> Result<libc::user_regs_struct, nix::Error>;
+
+    /// The x87 and SSE registers of the current thread.
+    fn get_fp_registers(&self) -> Result<libc::user_fpregs_struct, nix::Error>;
+
+    /// The auxiliary vector the kernel handed the program when it started: (key, value) pairs of
+    /// native words, as found in /proc/<pid>/auxv.
+    fn auxv(&self) -> Option<Vec<u8>>;
+
+    /// Finds the file mapped at `addr`, along with the address its first page is mapped at.
+    fn mapped_file(&self, addr: usize) -> Option<(String, u64)>;
+
+    /// Unwinds up to `limit` frames of the stack, innermost first.
+    fn frames(
+        &self,
+        debug_data: &DwarfData,
+        limit: Option<usize>,
+    ) -> Result<Vec<Frame>, nix::Error> {
+        Unwinder::new(self, debug_data).frames(limit)
+    }
+
+    /// Reads the bytes of a variable as seen from `frame`.
+    fn read_variable(&self, var: &Variable, frame: &Frame) -> Result<Vec<u8>, nix::Error> {
+        let addr = frame
+            .variable_address(var)
+            .ok_or(nix::Error::UnsupportedOperation)?;
+        self.read_memory(addr, var.entity_type.size)
+    }
+
+    fn print_backtrace(&self, debug_data: &DwarfData) -> Result<(), nix::Error> {
+        for frame in self.frames(debug_data, None)? {
+            let addr = frame.lookup_pc();
+            print!("{}: ", frame.level);
+            if let Some(function) = debug_data.get_function_from_addr(addr) {
+                print!("{} ", function);
+            }
+            if let Some(line) = debug_data.get_line_from_addr(addr) {
+                print!("{} ", line);
+            }
+            println!("rip: {:#x}", frame.pc);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,380 @@
+//! Stack unwinding driven by call frame information: the `.eh_frame` and `.debug_frame` tables
+//! compilers emit to describe, for every instruction, where the caller's registers and return
+//! address have been saved. Unlike walking the rbp chain, this works in functions that don't keep
+//! a frame pointer, including optimized code and most of libc.
+
+use gimli::{
+    BaseAddresses, CfaRule, DebugFrame, EhFrame, EhFrameHdr, LittleEndian, Register, RegisterRule,
+    UninitializedUnwindContext, UnwindSection,
+};
+use object::{Object, ObjectSection, ObjectSegment};
+use std::fs;
+
+use crate::dwarf_data::{DwarfData, Location, Variable};
+use crate::registers;
+use crate::target::Target;
+
+/// The x86-64 registers CFI can describe, indexed by DWARF register number. Number 16 is the
+/// return address column, which holds rip.
+const DWARF_REGISTERS: [&str; 17] = [
+    "rax", "rdx", "rcx", "rbx", "rsi", "rdi", "rbp", "rsp", "r8", "r9", "r10", "r11", "r12", "r13",
+    "r14", "r15", "rip",
+];
+const RBP: usize = 6;
+const RSP: usize = 7;
+const RETURN_ADDRESS: usize = 16;
+
+/// Registers the ABI requires a callee to preserve. A rule that doesn't mention one of these
+/// means it still holds the caller's value.
+const CALLEE_SAVED: [usize; 6] = [3, 6, 12, 13, 14, 15];
+
+/// Stop runaway unwinding of a corrupted stack.
+const MAX_FRAMES: usize = 4096;
+
+/// One stack frame, innermost first. Registers that a callee was free to clobber (and so can't be
+/// recovered for outer frames) are None.
+#[derive(Clone, Debug)]
+pub struct Frame {
+    /// 0 for the innermost frame, 1 for its caller and so on
+    pub level: usize,
+    /// Where execution is in the innermost frame, or will resume in the others
+    pub pc: usize,
+    /// The canonical frame address: the value rsp had just before the call into this frame, which
+    /// gcc uses as the frame base. None if the frame couldn't be unwound.
+    pub cfa: Option<usize>,
+    /// Where this frame saved its caller's registers, as (name, address) pairs
+    pub saved_registers: Vec<(&'static str, usize)>,
+    registers: [Option<u64>; 17],
+}
+
+impl Frame {
+    fn innermost(regs: &libc::user_regs_struct) -> Frame {
+        let mut registers = [None; 17];
+        for (number, name) in DWARF_REGISTERS.iter().enumerate() {
+            registers[number] = registers::get_register(regs, name);
+        }
+        Frame {
+            level: 0,
+            pc: regs.rip as usize,
+            cfa: None,
+            saved_registers: Vec::new(),
+            registers,
+        }
+    }
+
+    /// Computes where a variable lives in this frame. gcc describes the frame base with
+    /// DW_OP_call_frame_cfa; without CFI, assume the prologue has set up rbp as usual.
+    pub fn variable_address(&self, var: &Variable) -> Option<usize> {
+        match var.location {
+            Location::Address(addr) => Some(addr),
+            Location::FramePointerOffset(offset) => {
+                let frame_base = match self.cfa {
+                    Some(cfa) => cfa as isize,
+                    None => self.register("rbp")? as isize + 16,
+                };
+                Some((frame_base + offset) as usize)
+            }
+        }
+    }
+
+    /// Whether unwinding recovers `name` for outer frames. Other registers (eflags, segment
+    /// registers) aren't described by CFI.
+    pub fn tracks(name: &str) -> bool {
+        DWARF_REGISTERS.contains(&registers::canonical_name(name))
+    }
+
+    /// Looks up a register (without the leading `$`) as it was in this frame.
+    pub fn register(&self, name: &str) -> Option<u64> {
+        let name = registers::canonical_name(name);
+        let number = DWARF_REGISTERS.iter().position(|reg| *reg == name)?;
+        self.registers[number]
+    }
+
+    /// The address to look up when asking which line or function a frame is in. Outer frames
+    /// resume just past a call, which may be the first instruction of the next line (or past the
+    /// end of a function that never returns), so look at the call itself instead.
+    pub fn lookup_pc(&self) -> usize {
+        if self.level == 0 {
+            self.pc
+        } else {
+            self.pc.wrapping_sub(1)
+        }
+    }
+}
+
+/// How to recover one of the caller's registers; a subset of gimli's RegisterRule that doesn't
+/// borrow the section data.
+enum Rule {
+    Undefined,
+    SameValue,
+    /// Saved at CFA + offset
+    Offset(i64),
+    /// Equal to CFA + offset
+    ValOffset(i64),
+    /// Held in another register
+    Register(usize),
+    /// DWARF expressions, which we don't evaluate
+    Unsupported,
+}
+
+/// The unwind rules at one address: the CFA is `cfa_register` + `cfa_offset`, and the caller's
+/// registers are found according to `registers` (indexed like DWARF_REGISTERS).
+struct Rules {
+    cfa_register: usize,
+    cfa_offset: i64,
+    registers: Vec<Rule>,
+}
+
+/// The call frame information of one object file.
+pub struct CallFrameInfo {
+    /// What to add to the addresses in the file to get run time addresses
+    bias: u64,
+    /// The range of run time addresses the file's segments occupy
+    start: u64,
+    end: u64,
+    eh_frame: Vec<u8>,
+    eh_frame_addr: u64,
+    eh_frame_hdr: Vec<u8>,
+    eh_frame_hdr_addr: u64,
+    debug_frame: Vec<u8>,
+    text_addr: u64,
+}
+
+impl CallFrameInfo {
+    /// Copies the CFI sections out of an object file loaded `bias` bytes away from its link time
+    /// address. Returns None if the file has neither `.eh_frame` nor `.debug_frame`.
+    pub fn from_object(object: &object::File, bias: u64) -> Option<CallFrameInfo> {
+        let section = |name: &str| -> (Vec<u8>, u64) {
+            let data = object
+                .section_data_by_name(name)
+                .map(|data| data.into_owned())
+                .unwrap_or_default();
+            let addr = object.section_by_name(name).map_or(0, |s| s.address());
+            (data, addr)
+        };
+        let (eh_frame, eh_frame_addr) = section(".eh_frame");
+        let (eh_frame_hdr, eh_frame_hdr_addr) = section(".eh_frame_hdr");
+        let (debug_frame, _) = section(".debug_frame");
+        let (_, text_addr) = section(".text");
+        if eh_frame.is_empty() && debug_frame.is_empty() {
+            return None;
+        }
+        let start = object.segments().map(|s| s.address()).min()?;
+        let end = object.segments().map(|s| s.address() + s.size()).max()?;
+        Some(CallFrameInfo {
+            bias,
+            start: start + bias,
+            end: end + bias,
+            eh_frame,
+            eh_frame_addr,
+            eh_frame_hdr,
+            eh_frame_hdr_addr,
+            debug_frame,
+            text_addr,
+        })
+    }
+
+    /// Reads the CFI of a shared library whose first page is mapped at `base`.
+    fn load(path: &str, base: u64) -> Option<CallFrameInfo> {
+        let data = fs::read(path).ok()?;
+        let object = object::File::parse(&*data).ok()?;
+        // Segments are page aligned in memory, so the first one starts at a page boundary
+        let first = object.segments().map(|s| s.address()).min()? & !0xfff;
+        CallFrameInfo::from_object(&object, base - first)
+    }
+
+    /// Moves the tables to a file loaded `bias` bytes away from its link time address.
+    pub fn relocate(&mut self, bias: u64) {
+        self.start = self.start.wrapping_sub(self.bias).wrapping_add(bias);
+        self.end = self.end.wrapping_sub(self.bias).wrapping_add(bias);
+        self.bias = bias;
+    }
+
+    fn contains(&self, pc: usize) -> bool {
+        pc as u64 >= self.start && (pc as u64) < self.end
+    }
+
+    /// Works out where the caller's registers are at `pc`.
+    fn rules(&self, pc: usize) -> Option<Rules> {
+        let address = pc as u64 - self.bias;
+        let bases = BaseAddresses::default()
+            .set_eh_frame_hdr(self.eh_frame_hdr_addr)
+            .set_eh_frame(self.eh_frame_addr)
+            .set_text(self.text_addr);
+        let mut ctx = UninitializedUnwindContext::new();
+        let mut row = None;
+        if !self.eh_frame.is_empty() {
+            let eh_frame = EhFrame::new(&self.eh_frame, LittleEndian);
+            // .eh_frame_hdr has a sorted table we can binary search; otherwise scan every FDE
+            let hdr = EhFrameHdr::new(&self.eh_frame_hdr, LittleEndian)
+                .parse(&bases, 8)
+                .ok();
+            let fde = match hdr.as_ref().and_then(|hdr| hdr.table()) {
+                Some(table) => {
+                    table.fde_for_address(&eh_frame, &bases, address, EhFrame::cie_from_offset)
+                }
+                None => eh_frame.fde_for_address(&bases, address, EhFrame::cie_from_offset),
+            };
+            row = fde
+                .and_then(|fde| fde.unwind_info_for_address(&eh_frame, &bases, &mut ctx, address))
+                .ok();
+        }
+        if row.is_none() && !self.debug_frame.is_empty() {
+            let debug_frame = DebugFrame::new(&self.debug_frame, LittleEndian);
+            row = debug_frame
+                .unwind_info_for_address(&bases, &mut ctx, address, DebugFrame::cie_from_offset)
+                .ok();
+        }
+        let row = row?;
+        let (cfa_register, cfa_offset) = match row.cfa() {
+            CfaRule::RegisterAndOffset { register, offset } => (register.0 as usize, *offset),
+            CfaRule::Expression(_) => return None,
+        };
+        let registers = (0..DWARF_REGISTERS.len())
+            .map(|number| match row.register(Register(number as u16)) {
+                RegisterRule::Undefined => Rule::Undefined,
+                RegisterRule::SameValue => Rule::SameValue,
+                RegisterRule::Offset(offset) => Rule::Offset(offset),
+                RegisterRule::ValOffset(offset) => Rule::ValOffset(offset),
+                RegisterRule::Register(register) => Rule::Register(register.0 as usize),
+                _ => Rule::Unsupported,
+            })
+            .collect();
+        Some(Rules {
+            cfa_register,
+            cfa_offset,
+            registers,
+        })
+    }
+}
+
+/// Walks the stack of a stopped program. CFI for the executable comes with its debug info;
+/// shared libraries are found through the target's memory map and their CFI loaded as the walk
+/// reaches them.
+pub struct Unwinder<'a, T: Target + ?Sized> {
+    target: &'a T,
+    debug_data: &'a DwarfData,
+    libraries: Vec<CallFrameInfo>,
+}
+
+impl<'a, T: Target + ?Sized> Unwinder<'a, T> {
+    pub fn new(target: &'a T, debug_data: &'a DwarfData) -> Unwinder<'a, T> {
+        Unwinder {
+            target,
+            debug_data,
+            libraries: Vec::new(),
+        }
+    }
+
+    /// Unwinds up to `limit` frames, innermost first. The walk ends when the CFI says there is no
+    /// return address (as it does in `_start`), or when a frame can't be unwound.
+    pub fn frames(&mut self, limit: Option<usize>) -> Result<Vec<Frame>, nix::Error> {
+        let limit = limit.unwrap_or(MAX_FRAMES).min(MAX_FRAMES);
+        let mut frame = Frame::innermost(&self.target.get_registers()?);
+        let mut frames = Vec::new();
+        loop {
+            // Unwind one more frame than we return, since that is what fills in the CFA
+            let caller = self.caller(&mut frame);
+            frames.push(frame);
+            match caller {
+                Some(caller) if frames.len() < limit => frame = caller,
+                _ => return Ok(frames),
+            }
+        }
+    }
+
+    fn read_word(&self, addr: u64) -> Option<u64> {
+        let bytes = self.target.read_memory(addr as usize, 8).ok()?;
+        Some(u64::from_le_bytes([
+            bytes[0], bytes[1], bytes[2], bytes[3], bytes[4], bytes[5], bytes[6], bytes[7],
+        ]))
+    }
+
+    /// Finds the CFI covering `pc`, loading the shared library it belongs to if need be.
+    fn call_frame_info(&mut self, pc: usize) -> Option<&CallFrameInfo> {
+        if let Some(cfi) = self.debug_data.call_frame_info() {
+            if cfi.contains(pc) {
+                return self.debug_data.call_frame_info();
+            }
+        }
+        if let Some(index) = self.libraries.iter().position(|cfi| cfi.contains(pc)) {
+            return self.libraries.get(index);
+        }
+        let (path, base) = self.target.mapped_file(pc)?;
+        let cfi = CallFrameInfo::load(&path, base)?;
+        self.libraries.push(cfi);
+        self.libraries.last()
+    }
+
+    /// Recovers the registers of `frame`'s caller, and records `frame`'s CFA along the way.
+    fn caller(&mut self, frame: &mut Frame) -> Option<Frame> {
+        let pc = frame.lookup_pc();
+        let rules = self.call_frame_info(pc).and_then(|cfi| cfi.rules(pc));
+        let current = frame.registers;
+        let register = |number: usize| current.get(number).copied().flatten();
+        let mut registers = [None; 17];
+        let cfa = match rules {
+            Some(rules) => {
+                let cfa = (register(rules.cfa_register)? as i64 + rules.cfa_offset) as u64;
+                for (number, rule) in rules.registers.iter().enumerate() {
+                    if let Rule::Offset(offset) = rule {
+                        let addr = (cfa as i64 + offset) as usize;
+                        frame.saved_registers.push((DWARF_REGISTERS[number], addr));
+                    }
+                    registers[number] = match rule {
+                        Rule::Undefined if CALLEE_SAVED.contains(&number) => register(number),
+                        Rule::Undefined | Rule::Unsupported => None,
+                        Rule::SameValue => register(number),
+                        Rule::Offset(offset) => self.read_word((cfa as i64 + offset) as u64),
+                        Rule::ValOffset(offset) => Some((cfa as i64 + offset) as u64),
+                        Rule::Register(other) => register(*other),
+                    };
+                }
+                cfa
+            }
+            // No CFI (hand-written assembly, say): hope for a standard rbp frame
+            None => {
+                let rbp = register(RBP)?;
+                for &number in CALLEE_SAVED.iter() {
+                    registers[number] = register(number);
+                }
+                registers[RBP] = self.read_word(rbp);
+                registers[RETURN_ADDRESS] = self.read_word(rbp + 8);
+                frame.saved_registers = vec![("rbp", rbp as usize), ("rip", rbp as usize + 8)];
+                rbp + 16
+            }
+        };
+        frame.cfa = Some(cfa as usize);
+        registers[RSP] = Some(cfa);
+        let pc = registers[RETURN_ADDRESS]? as usize;
+        // The stack grows down, so each caller's frame must be above its callee's
+        if pc == 0 || Some(cfa) <= frame.registers[RSP] {
+            return None;
+        }
+        Some(Frame {
+            level: frame.level + 1,
+            pc,
+            cfa: None,
+            saved_registers: Vec::new(),
+            registers,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_rules_for_own_code() {
+        // Look ourselves up the way we would a shared library in the inferior
+        let pc = test_rules_for_own_code as fn() as usize;
+        let (path, base) = crate::solib::mapped_file(nix::unistd::getpid(), pc).unwrap();
+        let cfi = CallFrameInfo::load(&path, base).unwrap();
+        assert!(cfi.contains(pc));
+        // On entry to a function, the CFA is rsp + 8 and the return address sits just below it
+        let rules = cfi.rules(pc).unwrap();
+        assert_eq!((rules.cfa_register, rules.cfa_offset), (RSP, 8));
+        assert!(matches!(rules.registers[RETURN_ADDRESS], Rule::Offset(-8)));
+    }
+}
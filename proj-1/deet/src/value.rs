@@ -0,0 +1,528 @@
+use crate::dwarf_data::{DwarfData, Encoding, Member, Type, TypeKind};
+use crate::target::Target;
+use std::convert::TryInto;
+
+/// Like gdb's `print elements`: arrays and strings longer than this are cut off with "...".
+const PRINT_ELEMENTS: usize = 200;
+
+/// Interprets up to 8 little-endian bytes as an integer, sign-extending when the type is signed.
+/// Returns None for types that are not integral (floats, aggregates, or values wider than 64
+/// bits). Enums and pointers count as integers.
+pub fn as_integer(bytes: &[u8], entity_type: &Type, debug_data: &DwarfData) -> Option<i64> {
+    extend(bytes, is_signed(entity_type, debug_data)?)
+}
+
+/// Whether a type is integral: an integer, character, bool, enum or pointer.
+pub fn is_integral(entity_type: &Type, debug_data: &DwarfData) -> bool {
+    is_signed(entity_type, debug_data).is_some()
+}
+
+/// Whether an integral type is signed, or None if the type is not integral.
+pub fn is_signed(entity_type: &Type, debug_data: &DwarfData) -> Option<bool> {
+    match debug_data.resolve_type(entity_type).kind {
+        TypeKind::Base(Encoding::Signed) | TypeKind::Base(Encoding::SignedChar) => Some(true),
+        TypeKind::Base(Encoding::Unsigned)
+        | TypeKind::Base(Encoding::UnsignedChar)
+        | TypeKind::Base(Encoding::Boolean)
+        | TypeKind::Pointer(_) => Some(false),
+        TypeKind::Enum { .. } => Some(true),
+        _ => None,
+    }
+}
+
+fn extend(bytes: &[u8], signed: bool) -> Option<i64> {
+    let size = bytes.len();
+    if size == 0 || size > 8 {
+        return None;
+    }
+    let mut buf = if signed && bytes[size - 1] & 0x80 != 0 {
+        [0xff; 8]
+    } else {
+        [0; 8]
+    };
+    buf[..size].copy_from_slice(bytes);
+    Some(i64::from_le_bytes(buf))
+}
+
+/// Formats a character the way gdb does inside quotes, e.g. a, \n or \377. `quote` is the
+/// surrounding quote character, which needs escaping.
+fn format_char(c: u8, quote: u8) -> String {
+    match c {
+        b'\n' => "\\n".to_string(),
+        b'\t' => "\\t".to_string(),
+        b'\r' => "\\r".to_string(),
+        b'\\' => "\\\\".to_string(),
+        _ if c == quote => format!("\\{}", c as char),
+        0x20..=0x7e => (c as char).to_string(),
+        _ => format!("\\{:03o}", c),
+    }
+}
+
+/// Formats a string up to its first NUL, e.g. "hi\n".
+pub fn format_string(bytes: &[u8], truncated: bool) -> String {
+    let text: String = bytes
+        .iter()
+        .take_while(|&&c| c != 0)
+        .map(|&c| format_char(c, b'"'))
+        .collect();
+    format!("\"{}\"{}", text, if truncated { "..." } else { "" })
+}
+
+fn is_char(ty: &Type) -> bool {
+    ty.size == 1
+        && matches!(
+            ty.kind,
+            TypeKind::Base(Encoding::SignedChar) | TypeKind::Base(Encoding::UnsignedChar)
+        )
+}
+
+/// Reads a NUL-terminated string out of the inferior, giving up after PRINT_ELEMENTS characters.
+/// Also returns whether the string was cut short.
+pub fn read_string(inferior: Option<&dyn Target>, addr: usize) -> Option<(Vec<u8>, bool)> {
+    let inferior = inferior?;
+    let mut bytes = Vec::new();
+    while bytes.len() < PRINT_ELEMENTS {
+        // Read a word at a time, but don't fail the whole string if it ends just before an
+        // unmapped page
+        let chunk = match inferior.read_memory(addr + bytes.len(), 8) {
+            Ok(chunk) => chunk,
+            Err(_) if !bytes.is_empty() => return Some((bytes, false)),
+            Err(_) => return None,
+        };
+        for c in chunk {
+            if c == 0 {
+                return Some((bytes, false));
+            }
+            bytes.push(c);
+        }
+    }
+    bytes.truncate(PRINT_ELEMENTS);
+    Some((bytes, true))
+}
+
+/// Formats the raw bytes of a value according to its type, in the style of gdb's `print`. The
+/// inferior is needed to follow `char *` pointers to the string they point at.
+pub fn format_value(
+    bytes: &[u8],
+    entity_type: &Type,
+    debug_data: &DwarfData,
+    inferior: Option<&dyn Target>,
+) -> String {
+    format_inner(bytes, entity_type, debug_data, inferior, true)
+}
+
+/// Names the function an address points into, e.g. "<main+4>", the way gdb annotates pointers.
+pub fn describe_code_address(addr: usize, debug_data: &DwarfData) -> Option<String> {
+    let (name, range) = debug_data.get_function_bounds(addr)?;
+    if addr == range.start {
+        Some(format!("<{}>", name))
+    } else {
+        Some(format!("<{}+{}>", name, addr - range.start))
+    }
+}
+
+/// `top_level` is false for struct members and array elements.
+fn format_inner(
+    bytes: &[u8],
+    entity_type: &Type,
+    debug_data: &DwarfData,
+    inferior: Option<&dyn Target>,
+    top_level: bool,
+) -> String {
+    let ty = debug_data.resolve_type(entity_type);
+    if bytes.len() < ty.size {
+        return format_bytes(bytes);
+    }
+    let bytes = &bytes[..ty.size];
+    match &ty.kind {
+        TypeKind::Base(encoding) => format_base(bytes, *encoding),
+        TypeKind::Pointer(target) => {
+            let addr = match extend(bytes, false) {
+                Some(addr) => addr as usize,
+                None => return format_bytes(bytes),
+            };
+            let pointee = debug_data
+                .get_type(*target)
+                .map(|t| debug_data.resolve_type(t));
+            match pointee {
+                Some(pointee) if is_char(pointee) && addr != 0 => {
+                    match read_string(inferior, addr) {
+                        Some((string, truncated)) => {
+                            format!("{:#x} {}", addr, format_string(&string, truncated))
+                        }
+                        None => format!(
+                            "{:#x} <error: Cannot access memory at address {:#x}>",
+                            addr, addr
+                        ),
+                    }
+                }
+                _ => {
+                    let symbol = match describe_code_address(addr, debug_data) {
+                        Some(symbol) => format!(" {}", symbol),
+                        None => String::new(),
+                    };
+                    // Struct members and array elements leave out the type to keep things short
+                    if top_level {
+                        format!("({}) {:#x}{}", entity_type.name, addr, symbol)
+                    } else {
+                        format!("{:#x}{}", addr, symbol)
+                    }
+                }
+            }
+        }
+        TypeKind::Struct { members, .. } | TypeKind::Union { members, .. } => {
+            let fields: Vec<String> = members
+                .iter()
+                .map(|member| {
+                    format!(
+                        "{} = {}",
+                        member.name,
+                        format_member(bytes, member, debug_data, inferior)
+                    )
+                })
+                .collect();
+            format!("{{{}}}", fields.join(", "))
+        }
+        TypeKind::Array {
+            element,
+            dimensions,
+        } => match debug_data.get_type(Some(*element)) {
+            Some(element) => format_array(bytes, element, dimensions, debug_data, inferior),
+            None => format_bytes(bytes),
+        },
+        TypeKind::Enum { enumerators, .. } => match extend(bytes, true) {
+            Some(value) => match enumerators.iter().find(|e| e.value == value) {
+                Some(enumerator) => enumerator.name.clone(),
+                None => value.to_string(),
+            },
+            None => format_bytes(bytes),
+        },
+        _ => format_bytes(bytes),
+    }
+}
+
+fn format_base(bytes: &[u8], encoding: Encoding) -> String {
+    match encoding {
+        Encoding::Signed => match extend(bytes, true) {
+            Some(value) => value.to_string(),
+            None => format_bytes(bytes),
+        },
+        Encoding::Unsigned => match extend(bytes, false) {
+            Some(value) => (value as u64).to_string(),
+            None => format_bytes(bytes),
+        },
+        Encoding::SignedChar | Encoding::UnsignedChar if bytes.len() == 1 => {
+            let value = extend(bytes, encoding == Encoding::SignedChar).unwrap();
+            format!("{} '{}'", value, format_char(bytes[0], b'\''))
+        }
+        Encoding::Boolean => match extend(bytes, false) {
+            Some(0) => "false".to_string(),
+            Some(1) => "true".to_string(),
+            Some(value) => value.to_string(),
+            None => format_bytes(bytes),
+        },
+        Encoding::Float => match bytes.len() {
+            4 => format_float(f32::from_le_bytes(bytes.try_into().unwrap()) as f64, 9),
+            8 => format_float(f64::from_le_bytes(bytes.try_into().unwrap()), 17),
+            _ => format_bytes(bytes),
+        },
+        _ => format_bytes(bytes),
+    }
+}
+
+/// Formats a float the way C's `%.*g` does, which is how gdb prints them: 9 significant digits
+/// are enough to round-trip a float and 17 a double.
+pub fn format_float(value: f64, precision: usize) -> String {
+    if value.is_nan() {
+        return if value.is_sign_negative() {
+            "-nan"
+        } else {
+            "nan"
+        }
+        .to_string();
+    }
+    if value.is_infinite() {
+        return if value < 0.0 { "-inf" } else { "inf" }.to_string();
+    }
+    let trim = |digits: &str| -> String {
+        if digits.contains('.') {
+            digits
+                .trim_end_matches('0')
+                .trim_end_matches('.')
+                .to_string()
+        } else {
+            digits.to_string()
+        }
+    };
+    // Round to the precision first, since that can carry into the exponent
+    let scientific = format!("{:.*e}", precision - 1, value);
+    let (mantissa, exponent) = scientific.split_at(scientific.find('e').unwrap());
+    let exponent: i32 = exponent[1..].parse().unwrap();
+    if exponent < -4 || exponent >= precision as i32 {
+        let sign = if exponent < 0 { '-' } else { '+' };
+        format!("{}e{}{:02}", trim(mantissa), sign, exponent.abs())
+    } else {
+        let decimals = (precision as i32 - 1 - exponent) as usize;
+        trim(&format!("{:.*}", decimals, value))
+    }
+}
+
+/// Formats one field of the struct or union whose bytes are `bytes`.
+fn format_member(
+    bytes: &[u8],
+    member: &Member,
+    debug_data: &DwarfData,
+    inferior: Option<&dyn Target>,
+) -> String {
+    let member_type = match debug_data.get_type(Some(member.type_offset)) {
+        Some(member_type) => member_type,
+        None => return "<unknown type>".to_string(),
+    };
+    match member_bytes(bytes, member, member_type, debug_data) {
+        Some(member_bytes) => format_inner(&member_bytes, member_type, debug_data, inferior, false),
+        None => "<unavailable>".to_string(),
+    }
+}
+
+/// Extracts a field out of the bytes of its struct or union. Bit fields are shifted down and
+/// sign-extended so that the result looks like a plain value of the member's type.
+pub fn member_bytes(
+    bytes: &[u8],
+    member: &Member,
+    member_type: &Type,
+    debug_data: &DwarfData,
+) -> Option<Vec<u8>> {
+    let bit_size = match member.bit_size.filter(|&bit_size| bit_size > 0) {
+        Some(bit_size) => bit_size,
+        None => {
+            return bytes
+                .get(member.offset..member.offset + member_type.size)
+                .map(|member_bytes| member_bytes.to_vec())
+        }
+    };
+    // Gather the bit field out of the bytes that hold it, then sign-extend it by hand
+    let first = member.offset + member.bit_offset / 8;
+    let mut buf = [0u8; 8];
+    let available = bytes.len().saturating_sub(first).min(8);
+    buf[..available].copy_from_slice(&bytes[first..first + available]);
+    let raw = u64::from_le_bytes(buf) >> (member.bit_offset % 8);
+    let mask = if bit_size >= 64 {
+        !0
+    } else {
+        (1u64 << bit_size) - 1
+    };
+    let mut value = raw & mask;
+    let signed = is_signed(member_type, debug_data) == Some(true);
+    if signed && bit_size < 64 && value & (1 << (bit_size - 1)) != 0 {
+        value |= !mask;
+    }
+    Some(value.to_le_bytes()[..member_type.size.min(8)].to_vec())
+}
+
+/// Formats a (possibly multi-dimensional) array. Arrays of char are shown as strings.
+fn format_array(
+    bytes: &[u8],
+    element: &Type,
+    dimensions: &[Option<usize>],
+    debug_data: &DwarfData,
+    inferior: Option<&dyn Target>,
+) -> String {
+    let count = dimensions.first().copied().flatten().unwrap_or(0);
+    if dimensions.len() <= 1 && is_char(debug_data.resolve_type(element)) {
+        let truncated = count > PRINT_ELEMENTS;
+        return format_string(&bytes[..count.min(PRINT_ELEMENTS)], truncated);
+    }
+    if count == 0 || bytes.is_empty() {
+        return "{}".to_string();
+    }
+    let stride = bytes.len() / count;
+    let mut items: Vec<String> = bytes
+        .chunks(stride)
+        .take(PRINT_ELEMENTS)
+        .map(|chunk| {
+            if dimensions.len() > 1 {
+                format_array(chunk, element, &dimensions[1..], debug_data, inferior)
+            } else {
+                format_inner(chunk, element, debug_data, inferior, false)
+            }
+        })
+        .collect();
+    if count > PRINT_ELEMENTS {
+        items.push("...".to_string());
+    }
+    format!("{{{}}}", items.join(", "))
+}
+
+/// Formats one unit of memory for `x`, e.g. 0x0000002a for 42 in format 'x' with a 4-byte unit.
+pub fn format_memory_unit(bytes: &[u8], format: char) -> String {
+    let signed = extend(bytes, true).unwrap_or(0);
+    let unsigned = extend(bytes, false).unwrap_or(0) as u64;
+    match format {
+        'd' => signed.to_string(),
+        'u' => unsigned.to_string(),
+        'o' if unsigned == 0 => "0".to_string(),
+        'o' => format!("0{:o}", unsigned),
+        't' => format!("{:0width$b}", unsigned, width = bytes.len() * 8),
+        'c' => format!("{} '{}'", signed, format_char(bytes[0], b'\'')),
+        _ => format!("0x{:0width$x}", unsigned, width = bytes.len() * 2),
+    }
+}
+
+/// Fallback for values we don't know how to interpret: the raw bytes in memory order.
+fn format_bytes(bytes: &[u8]) -> String {
+    let hex: Vec<String> = bytes.iter().map(|byte| format!("{:#04x}", byte)).collect();
+    format!("{{{}}}", hex.join(", "))
+}
+
+/// Formats a type for `ptype`: like its C name, but with typedefs at the top level unrolled and the
+/// body of a struct, union or enum spelled out, e.g. "struct point {\n    int x;\n    int y;\n} *".
+pub fn format_type_definition(entity_type: &Type, debug_data: &DwarfData) -> String {
+    let mut ty = entity_type;
+    while let TypeKind::Typedef(target) = ty.kind {
+        match debug_data.get_type(target) {
+            Some(target) => ty = target,
+            None => break,
+        }
+    }
+    let name = debug_data.declare(Some(ty), "");
+
+    // Find the struct/union/enum this type is built from, if any
+    let mut base = ty;
+    loop {
+        let next = match &base.kind {
+            TypeKind::Pointer(target) | TypeKind::Qualified(_, target) => *target,
+            TypeKind::Array { element, .. } => Some(*element),
+            _ => break,
+        };
+        match debug_data.get_type(next) {
+            Some(next) => base = next,
+            None => break,
+        }
+    }
+    let body = match &base.kind {
+        TypeKind::Struct { members, .. } | TypeKind::Union { members, .. } => {
+            let mut body = String::from(" {\n");
+            for member in members {
+                let member_type = debug_data.get_type(Some(member.type_offset));
+                body.push_str(&format!(
+                    "    {}",
+                    debug_data.declare(member_type, &member.name)
+                ));
+                if let Some(bit_size) = member.bit_size.filter(|&bit_size| bit_size > 0) {
+                    body.push_str(&format!(" : {}", bit_size));
+                }
+                body.push_str(";\n");
+            }
+            body.push('}');
+            body
+        }
+        TypeKind::Enum { enumerators, .. } => {
+            let mut next_value = 0;
+            let items: Vec<String> = enumerators
+                .iter()
+                .map(|enumerator| {
+                    // Like C, only spell out values that don't follow from the previous one
+                    let item = if enumerator.value == next_value {
+                        enumerator.name.clone()
+                    } else {
+                        format!("{} = {}", enumerator.name, enumerator.value)
+                    };
+                    next_value = enumerator.value.wrapping_add(1);
+                    item
+                })
+                .collect();
+            format!(" {{{}}}", items.join(", "))
+        }
+        _ => return name,
+    };
+    let base_name = debug_data.declare(Some(base), "");
+    // An anonymous "struct {...}" gets its real body in place of the dots
+    let definition = format!("{}{}", base_name.trim_end_matches(" {...}"), body);
+    name.replacen(&base_name, &definition, 1)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_format_float() {
+        assert_eq!(format_float(1.5, 17), "1.5");
+        assert_eq!(format_float(0.1, 17), "0.10000000000000001");
+        assert_eq!(format_float(0.1f32 as f64, 9), "0.100000001");
+        assert_eq!(format_float(0.0, 17), "0");
+        assert_eq!(format_float(1e20, 17), "1e+20");
+        assert_eq!(format_float(-2.5e-7, 9), "-2.5e-07");
+        assert_eq!(format_float(123456789.0, 9), "123456789");
+    }
+
+    #[test]
+    fn test_format_integers() {
+        assert_eq!(
+            format_base(&[0xff, 0xff, 0xff, 0xff], Encoding::Signed),
+            "-1"
+        );
+        assert_eq!(
+            format_base(&[0xff, 0xff, 0xff, 0xff], Encoding::Unsigned),
+            "4294967295"
+        );
+        assert_eq!(
+            format_base(&i64::MIN.to_le_bytes(), Encoding::Signed),
+            "-9223372036854775808"
+        );
+        assert_eq!(
+            format_base(&u64::MAX.to_le_bytes(), Encoding::Unsigned),
+            "18446744073709551615"
+        );
+        assert_eq!(format_base(&[42, 0], Encoding::Signed), "42");
+        assert_eq!(format_base(&[1], Encoding::Boolean), "true");
+        assert_eq!(format_base(&[2], Encoding::Boolean), "2");
+        // Too wide to be an integer
+        assert_eq!(
+            format_base(&[0; 16], Encoding::Signed),
+            format_bytes(&[0; 16])
+        );
+    }
+
+    #[test]
+    fn test_format_chars() {
+        assert_eq!(format_base(b"a", Encoding::SignedChar), "97 'a'");
+        assert_eq!(format_base(b"\n", Encoding::SignedChar), "10 '\\n'");
+        assert_eq!(format_base(b"'", Encoding::SignedChar), "39 '\\''");
+        assert_eq!(format_base(&[0xff], Encoding::SignedChar), "-1 '\\377'");
+        assert_eq!(format_base(&[0xff], Encoding::UnsignedChar), "255 '\\377'");
+        assert_eq!(
+            format_string(b"say \"hi\"\t\0junk", false),
+            "\"say \\\"hi\\\"\\t\""
+        );
+        assert_eq!(format_string(b"abc", true), "\"abc\"...");
+    }
+
+    #[test]
+    fn test_format_pointers() {
+        let program = crate::inferior::test::build_sample("exec");
+        let debug_data = DwarfData::from_file(&program).unwrap();
+        let pointer = |name: &str, addr: usize| {
+            let ty = debug_data.get_type(debug_data.find_type(name)).unwrap();
+            format_value(&addr.to_le_bytes(), ty, &debug_data, None)
+        };
+        let main = debug_data.get_addr_for_function(None, "main").unwrap();
+
+        assert_eq!(pointer("char **", 0), "(char **) 0x0");
+        assert_eq!(pointer("char **", 0x10), "(char **) 0x10");
+        assert_eq!(
+            pointer("char **", main),
+            format!("(char **) {:#x} <main>", main)
+        );
+        assert_eq!(
+            pointer("char **", main + 4),
+            format!("(char **) {:#x} <main+4>", main + 4)
+        );
+        // A char * is followed to its string, which needs a program to read it from
+        assert_eq!(pointer("char *", 0), "(char *) 0x0");
+        assert_eq!(
+            pointer("char *", 0x10),
+            "0x10 <error: Cannot access memory at address 0x10>"
+        );
+    }
+}